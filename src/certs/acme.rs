@@ -0,0 +1,947 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use x509_parser::extensions::GeneralName;
+
+use super::{CertBundle, CertProvider};
+use crate::config::AcmeConfig;
+
+const B64URL: base64::engine::GeneralPurpose = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+///how many times we ask the ca about an authorization or an order before giving up
+const POLL_ATTEMPTS: usize = 30;
+
+///orders certificates from an acme (rfc 8555) ca using the http-01 challenge.
+///the account key and the last issued certificate are cached on disk,
+///a new certificate is only ordered when the cached one is about to expire
+pub struct Acme {
+    pub config: AcmeConfig,
+}
+
+impl CertProvider for Acme {
+    async fn load(&self) -> Result<CertBundle> {
+        let cert_path = self.cache_dir().join("cert.pem");
+        let key_path = self.cache_dir().join("key.pem");
+        if let Some(bundle) = self.cached(&cert_path, &key_path) {
+            tracing::info!("using cached acme certificate");
+            return Ok(bundle);
+        }
+        tracing::info!(
+            "ordering a certificate for {} from {}",
+            self.config.hostnames.join(", "),
+            self.config.directory
+        );
+        let (chain, key) = self.order().await?;
+        tokio::fs::write(&cert_path, &chain).await?;
+        write_private(&key_path, key.as_bytes()).await?;
+        CertBundle::from_pem(&chain, &key)
+    }
+}
+
+impl Acme {
+    ///every certificate gets its own directory, there can be several acme sources with sni
+    fn cache_dir(&self) -> PathBuf {
+        let name = self.config.hostnames.first().map(String::as_str);
+        self.config.cache_dir.join(name.unwrap_or("default"))
+    }
+
+    ///returns the cached certificate unless it's missing, broken, for other
+    ///names or expires soon
+    fn cached(&self, cert_path: &Path, key_path: &Path) -> Option<CertBundle> {
+        let (Ok(chain), Ok(key)) = (
+            std::fs::read_to_string(cert_path),
+            std::fs::read_to_string(key_path),
+        ) else {
+            return None;
+        };
+        match self.check_cached(&chain, &key) {
+            Ok(bundle) => Some(bundle),
+            Err(e) => {
+                tracing::info!("not using the cached acme certificate: {:#}", e);
+                None
+            }
+        }
+    }
+
+    fn check_cached(&self, chain: &str, key: &str) -> Result<CertBundle> {
+        let bundle = CertBundle::from_pem(chain, key)?;
+        let (_, cert) = x509_parser::parse_x509_certificate(&bundle.certs[0])
+            .map_err(|e| anyhow!("it's invalid: {e}"))?;
+        let names = cert
+            .subject_alternative_name()
+            .map_err(|e| anyhow!("its names are invalid: {e}"))?
+            .map(|san| {
+                san.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if let Some(missing) = self
+            .config
+            .hostnames
+            .iter()
+            .find(|name| !names.contains(&name.to_ascii_lowercase()))
+        {
+            bail!("it isn't for {missing}");
+        }
+        let expires = cert.validity().not_after.timestamp();
+        let renew_at = chrono::Utc::now().timestamp()
+            + i64::from(self.config.renew_before_days) * 24 * 60 * 60;
+        if expires < renew_at {
+            bail!("it expires soon");
+        }
+        Ok(bundle)
+    }
+
+    ///runs the whole order flow, returns the certificate chain and its key as pem
+    async fn order(&self) -> Result<(String, String)> {
+        let cache_dir = self.cache_dir();
+        tokio::fs::create_dir_all(&cache_dir).await?;
+        let mut client = AcmeClient::new(&self.config, &cache_dir).await?;
+        client.register(&self.config.contact).await?;
+
+        let identifiers = self
+            .config
+            .hostnames
+            .iter()
+            .map(|name| json!({ "type": "dns", "value": name }))
+            .collect::<Vec<_>>();
+        let resp = client
+            .post(
+                &client.directory.new_order.clone(),
+                Some(json!({ "identifiers": identifiers })),
+            )
+            .await?;
+        let order: Order = resp.json()?;
+        let order_url = resp.location.context("order has no location")?;
+
+        //collect every pending http-01 challenge before starting the responder
+        let mut tokens = HashMap::new();
+        let mut pending = vec![];
+        for authz_url in &order.authorizations {
+            let authz: Authorization = client.post(authz_url, None).await?.json()?;
+            if authz.status == "valid" {
+                continue;
+            }
+            let challenge = authz
+                .challenges
+                .into_iter()
+                .find(|c| c.kind == "http-01")
+                .with_context(|| format!("no http-01 challenge for {}", authz.identifier.value))?;
+            let token = challenge.token.context("http-01 challenge without token")?;
+            tokens.insert(token.clone(), client.key_authorization(&token));
+            pending.push((authz_url.clone(), challenge.url));
+        }
+
+        if !pending.is_empty() {
+            let listener = TcpListener::bind(&self.config.challenge_address)
+                .await
+                .with_context(|| {
+                    format!(
+                        "couldn't bind the challenge responder to {}",
+                        self.config.challenge_address
+                    )
+                })?;
+            let responder = tokio::spawn(serve_challenges(listener, Arc::new(tokens)));
+            let result = client.complete_challenges(&pending).await;
+            responder.abort();
+            result?;
+        }
+
+        let key_pair = rcgen::KeyPair::generate()?;
+        let csr = rcgen::CertificateParams::new(self.config.hostnames.clone())?
+            .serialize_request(&key_pair)?;
+        client
+            .post(
+                &order.finalize,
+                Some(json!({ "csr": B64URL.encode(csr.der()) })),
+            )
+            .await?;
+
+        let mut certificate_url = None;
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = client.post(&order_url, None).await?.json()?;
+            match order.status.as_str() {
+                "valid" => {
+                    certificate_url = order.certificate;
+                    break;
+                }
+                "invalid" => bail!("the ca marked the order as invalid"),
+                _ => tokio::time::sleep(Duration::from_secs(2)).await,
+            }
+        }
+        let certificate_url = certificate_url.context("the order never became valid")?;
+        let chain = String::from_utf8(client.post(&certificate_url, None).await?.body)?;
+        tracing::info!("acme certificate issued");
+        Ok((chain, key_pair.serialize_pem()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: Option<String>,
+}
+
+struct AcmeResponse {
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl AcmeResponse {
+    fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+///talks to the ca, every request is a jws signed with the account key
+struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    ///the account url, known after registering
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(config: &AcmeConfig, cache_dir: &Path) -> Result<Self> {
+        let mut http = reqwest::Client::builder();
+        if let Some(root) = &config.ca_root {
+            let pem = tokio::fs::read(root)
+                .await
+                .with_context(|| format!("couldn't read {}", root.display()))?;
+            http = http.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        let http = http.build()?;
+        let directory = http
+            .get(&config.directory)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Directory>()
+            .await?;
+
+        let rng = SystemRandom::new();
+        let key_path = cache_dir.join("account.pk8");
+        let pkcs8 = match tokio::fs::read(&key_path).await {
+            Ok(x) => x,
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|e| anyhow!("couldn't generate the account key: {e}"))?;
+                write_private(&key_path, pkcs8.as_ref()).await?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| anyhow!("invalid account key: {e}"))?;
+        let jwk = jwk(key.public_key().as_ref())?;
+        Ok(Self {
+            http,
+            directory,
+            key,
+            rng,
+            jwk,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    ///creates the account, or finds the existing one for our key
+    async fn register(&mut self, contact: &[String]) -> Result<()> {
+        let resp = self
+            .post(
+                &self.directory.new_account.clone(),
+                Some(json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+        self.kid = Some(resp.location.context("account has no location")?);
+        Ok(())
+    }
+
+    ///rfc 8555 section 8.1
+    fn key_authorization(&self, token: &str) -> String {
+        //the members have to be in lexicographic order for the thumbprint (rfc 7638)
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            self.jwk["x"].as_str().unwrap_or_default(),
+            self.jwk["y"].as_str().unwrap_or_default()
+        );
+        let thumbprint = ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes());
+        format!("{token}.{}", B64URL.encode(thumbprint.as_ref()))
+    }
+
+    ///tells the ca to check the challenges and waits until the authorizations are valid
+    async fn complete_challenges(&mut self, pending: &[(String, String)]) -> Result<()> {
+        for (_, challenge_url) in pending {
+            self.post(challenge_url, Some(json!({}))).await?;
+        }
+        for (authz_url, _) in pending {
+            let mut valid = false;
+            for _ in 0..POLL_ATTEMPTS {
+                let authz: Authorization = self.post(authz_url, None).await?.json()?;
+                match authz.status.as_str() {
+                    "valid" => {
+                        valid = true;
+                        break;
+                    }
+                    "pending" | "processing" => tokio::time::sleep(Duration::from_secs(2)).await,
+                    x => bail!("authorization for {} is {x}", authz.identifier.value),
+                }
+            }
+            if !valid {
+                bail!("authorization {authz_url} never became valid");
+            }
+        }
+        Ok(())
+    }
+
+    async fn fresh_nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let resp = self.http.head(&self.directory.new_nonce).send().await?;
+        replay_nonce(&resp).context("the ca didn't give us a nonce")
+    }
+
+    ///signed POST, `None` as the payload makes it a POST-as-GET.
+    ///retries once if the ca rejects our nonce
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<AcmeResponse> {
+        let mut retried = false;
+        loop {
+            let nonce = self.fresh_nonce().await?;
+            let body = self.sign(url, &nonce, payload.as_ref())?;
+            let resp = self
+                .http
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(body)
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&resp);
+            let status = resp.status();
+            let location = resp
+                .headers()
+                .get("Location")
+                .and_then(|l| l.to_str().ok())
+                .map(str::to_string);
+            let body = resp.bytes().await?.to_vec();
+            if status.is_success() {
+                return Ok(AcmeResponse { location, body });
+            }
+            let problem = serde_json::from_slice::<Value>(&body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "acme request to {url} failed with {status}: {}",
+                problem["detail"].as_str().unwrap_or_default()
+            );
+        }
+    }
+
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<String> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = B64URL.encode(serde_json::to_vec(&protected)?);
+        let payload = match payload {
+            Some(x) => B64URL.encode(serde_json::to_vec(x)?),
+            None => String::new(),
+        };
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+            .map_err(|e| anyhow!("couldn't sign the acme request: {e}"))?;
+        Ok(serde_json::to_string(&json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": B64URL.encode(signature.as_ref()),
+        }))?)
+    }
+}
+
+fn replay_nonce(resp: &reqwest::Response) -> Option<String> {
+    resp.headers()
+        .get("Replay-Nonce")
+        .and_then(|n| n.to_str().ok())
+        .map(str::to_string)
+}
+
+///the jwk of an uncompressed p-256 public key (0x04 || x || y)
+fn jwk(public_key: &[u8]) -> Result<Value> {
+    let coords = public_key
+        .strip_prefix(&[0x04])
+        .filter(|c| c.len() == 64)
+        .context("unexpected public key format")?;
+    Ok(json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": B64URL.encode(&coords[..32]),
+        "y": B64URL.encode(&coords[32..]),
+    }))
+}
+
+///writes a key only we can read, replacing the old one
+#[cfg(unix)]
+async fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+    //the mode only applies to new files
+    file.set_permissions(Permissions::from_mode(0o600)).await?;
+    file.write_all(data).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    Ok(tokio::fs::write(path, data).await?)
+}
+
+///a tiny http server that answers the ca's http-01 requests
+async fn serve_challenges(listener: TcpListener, tokens: Arc<HashMap<String, String>>) {
+    loop {
+        let Ok((mut stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let tokens = tokens.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            let Ok(n) = stream.read(&mut buf).await else {
+                return;
+            };
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            tracing::debug!("challenge request for {path} from {addr}");
+            let response = match path
+                .strip_prefix(CHALLENGE_PATH)
+                .and_then(|token| tokens.get(token))
+            {
+                Some(key_auth) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    key_auth.len(),
+                    key_auth
+                ),
+                None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            stream.write_all(response.as_bytes()).await.ok();
+            stream.shutdown().await.ok();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use base64::Engine;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair, PublicKeyData,
+        SignatureAlgorithm, PKCS_ECDSA_P256_SHA256,
+    };
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use x509_parser::{
+        certification_request::X509CertificationRequest,
+        extensions::{GeneralName, ParsedExtension},
+        prelude::FromDer,
+    };
+
+    use super::{Acme, B64URL, CHALLENGE_PATH};
+    use crate::{certs::CertProvider, config::AcmeConfig};
+
+    ///a stand-in for an acme ca like pebble. it checks the jws of every
+    ///request and fetches the http-01 challenges from the responder
+    struct FakeCa {
+        base: String,
+        challenge_address: String,
+        cert: Certificate,
+        key: KeyPair,
+        state: Mutex<CaState>,
+    }
+
+    #[derive(Default)]
+    struct CaState {
+        next_nonce: u32,
+        nonces: Vec<String>,
+        ///the first signed request is answered with badNonce
+        rejected_nonce: bool,
+        jwk: Option<Value>,
+        names: Vec<String>,
+        ///the token of every authorization and whether it was validated
+        tokens: Vec<(String, bool)>,
+        orders: usize,
+        chain: Option<String>,
+    }
+
+    struct Response {
+        status: u16,
+        location: Option<String>,
+        body: Vec<u8>,
+    }
+
+    impl Response {
+        fn json(status: u16, location: Option<String>, body: Value) -> Self {
+            Self {
+                status,
+                location,
+                body: body.to_string().into_bytes(),
+            }
+        }
+
+        fn problem(status: u16, kind: &str, detail: &str) -> Self {
+            let kind = format!("urn:ietf:params:acme:error:{kind}");
+            Self::json(status, None, json!({ "type": kind, "detail": detail }))
+        }
+    }
+
+    ///the public key of a csr, to issue its certificate with
+    struct CsrKey(Vec<u8>);
+
+    impl PublicKeyData for CsrKey {
+        fn der_bytes(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn algorithm(&self) -> &SignatureAlgorithm {
+            &PKCS_ECDSA_P256_SHA256
+        }
+    }
+
+    impl FakeCa {
+        fn new(base: String, challenge_address: String) -> Self {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Self {
+                base,
+                challenge_address,
+                cert,
+                key,
+                state: Mutex::default(),
+            }
+        }
+
+        fn orders(&self) -> usize {
+            self.state.lock().unwrap().orders
+        }
+
+        async fn serve(self: Arc<Self>, listener: TcpListener) {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                tokio::spawn(self.clone().answer(stream));
+            }
+        }
+
+        async fn answer(self: Arc<Self>, mut stream: TcpStream) {
+            let (method, path, body) = read_request(&mut stream).await;
+            let response = match (method.as_str(), path.as_str()) {
+                ("GET", "/directory") => Response::json(
+                    200,
+                    None,
+                    json!({
+                        "newNonce": format!("{}/nonce", self.base),
+                        "newAccount": format!("{}/account", self.base),
+                        "newOrder": format!("{}/order", self.base),
+                    }),
+                ),
+                ("HEAD", "/nonce") => Response::json(200, None, json!({})),
+                ("POST", _) => match self.verify(&path, &body) {
+                    Ok(payload) => self.post(&path, payload).await,
+                    Err(problem) => problem,
+                },
+                _ => Response::problem(404, "malformed", "not found"),
+            };
+            let nonce = {
+                let mut state = self.state.lock().unwrap();
+                state.next_nonce += 1;
+                let nonce = format!("nonce{}", state.next_nonce);
+                state.nonces.push(nonce.clone());
+                nonce
+            };
+            let mut head = format!(
+                "HTTP/1.1 {} X\r\nReplay-Nonce: {nonce}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                response.status,
+                response.body.len()
+            );
+            if let Some(location) = response.location {
+                head += &format!("Location: {location}\r\n");
+            }
+            head += "\r\n";
+            stream.write_all(head.as_bytes()).await.unwrap();
+            if method != "HEAD" {
+                stream.write_all(&response.body).await.unwrap();
+            }
+            stream.shutdown().await.ok();
+        }
+
+        ///checks the jws like rfc 8555 section 6.2 says, returns its payload
+        fn verify(&self, path: &str, body: &[u8]) -> Result<Value, Response> {
+            let malformed = |detail| Response::problem(400, "malformed", detail);
+            let jws: Value = serde_json::from_slice(body).map_err(|_| malformed("not json"))?;
+            let field = |name: &str| {
+                B64URL
+                    .decode(jws[name].as_str().unwrap_or_default())
+                    .map_err(|_| malformed("invalid base64"))
+            };
+            let protected: Value =
+                serde_json::from_slice(&field("protected")?).map_err(|_| malformed("not json"))?;
+            let mut state = self.state.lock().unwrap();
+            let nonce = protected["nonce"].as_str().unwrap_or_default();
+            let Some(i) = state.nonces.iter().position(|n| n == nonce) else {
+                return Err(Response::problem(400, "badNonce", "unknown nonce"));
+            };
+            state.nonces.remove(i);
+            if !state.rejected_nonce {
+                state.rejected_nonce = true;
+                return Err(Response::problem(400, "badNonce", "try again"));
+            }
+            if protected["alg"] != "ES256" || protected["url"] != format!("{}{path}", self.base) {
+                return Err(malformed("wrong alg or url"));
+            }
+            let jwk = if path == "/account" {
+                state.jwk = Some(protected["jwk"].clone());
+                protected["jwk"].clone()
+            } else if protected["kid"] == format!("{}/account/1", self.base) {
+                state.jwk.clone().ok_or_else(|| malformed("no account"))?
+            } else {
+                return Err(malformed("wrong kid"));
+            };
+            let coordinate = |name: &str| B64URL.decode(jwk[name].as_str().unwrap_or_default());
+            let (Ok(x), Ok(y)) = (coordinate("x"), coordinate("y")) else {
+                return Err(malformed("invalid jwk"));
+            };
+            let public_key = [&[0x04][..], &x, &y].concat();
+            let signed = format!(
+                "{}.{}",
+                jws["protected"].as_str().unwrap_or_default(),
+                jws["payload"].as_str().unwrap_or_default()
+            );
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+                .verify(signed.as_bytes(), &field("signature")?)
+                .map_err(|_| malformed("bad signature"))?;
+            let payload = field("payload")?;
+            if payload.is_empty() {
+                return Ok(Value::Null);
+            }
+            serde_json::from_slice(&payload).map_err(|_| malformed("not json"))
+        }
+
+        async fn post(&self, path: &str, payload: Value) -> Response {
+            let base = &self.base;
+            if let Some(i) = path.strip_prefix("/challenge/") {
+                return self.validate(i.parse().unwrap()).await;
+            }
+            let mut state = self.state.lock().unwrap();
+            if let Some(i) = path.strip_prefix("/authz/") {
+                let i: usize = i.parse().unwrap();
+                let (token, valid) = &state.tokens[i];
+                return Response::json(
+                    200,
+                    None,
+                    json!({
+                        "status": if *valid { "valid" } else { "pending" },
+                        "identifier": { "type": "dns", "value": state.names[i] },
+                        "challenges": [
+                            { "type": "dns-01", "url": format!("{base}/nope"), "token": "x" },
+                            { "type": "http-01", "url": format!("{base}/challenge/{i}"), "token": token },
+                        ],
+                    }),
+                );
+            }
+            match path {
+                "/account" if payload["termsOfServiceAgreed"] == true => {
+                    Response::json(201, Some(format!("{base}/account/1")), json!({}))
+                }
+                "/order" => {
+                    state.orders += 1;
+                    state.chain = None;
+                    state.names = payload["identifiers"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|id| id["value"].as_str().unwrap().to_string())
+                        .collect();
+                    state.tokens = (0..state.names.len())
+                        .map(|i| (format!("token{}{i}", state.orders), false))
+                        .collect();
+                    Response::json(201, Some(format!("{base}/order/1")), self.order(&state))
+                }
+                "/order/1" => Response::json(200, None, self.order(&state)),
+                "/finalize" => {
+                    if !state.tokens.iter().all(|(_, valid)| *valid) {
+                        return Response::problem(403, "orderNotReady", "not validated");
+                    }
+                    let csr = B64URL.decode(payload["csr"].as_str().unwrap()).unwrap();
+                    let (_, csr) = X509CertificationRequest::from_der(&csr).unwrap();
+                    let mut names = csr
+                        .requested_extensions()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|ext| match ext {
+                            ParsedExtension::SubjectAlternativeName(san) => Some(san),
+                            _ => None,
+                        })
+                        .flat_map(|san| &san.general_names)
+                        .filter_map(|name| match name {
+                            GeneralName::DNSName(name) => Some(name.to_string()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    let mut ordered = state.names.clone();
+                    names.sort();
+                    ordered.sort();
+                    if names != ordered {
+                        return Response::problem(400, "badCSR", "wrong names");
+                    }
+                    let info = &csr.certification_request_info;
+                    let key = CsrKey(info.subject_pki.subject_public_key.data.to_vec());
+                    let leaf = CertificateParams::new(names)
+                        .unwrap()
+                        .signed_by(&key, &self.cert, &self.key)
+                        .unwrap();
+                    state.chain = Some(leaf.pem() + &self.cert.pem());
+                    Response::json(200, None, self.order(&state))
+                }
+                "/cert/1" => Response {
+                    status: 200,
+                    location: None,
+                    body: state.chain.clone().unwrap_or_default().into_bytes(),
+                },
+                _ => Response::problem(404, "malformed", "not found"),
+            }
+        }
+
+        fn order(&self, state: &CaState) -> Value {
+            let base = &self.base;
+            json!({
+                "status": if state.chain.is_some() { "valid" } else { "pending" },
+                "authorizations": (0..state.names.len())
+                    .map(|i| format!("{base}/authz/{i}"))
+                    .collect::<Vec<_>>(),
+                "finalize": format!("{base}/finalize"),
+                "certificate": state.chain.as_ref().map(|_| format!("{base}/cert/1")),
+            })
+        }
+
+        ///fetches the key authorization like a ca would from port 80
+        async fn validate(&self, i: usize) -> Response {
+            let (token, expected) = {
+                let state = self.state.lock().unwrap();
+                let jwk = state.jwk.as_ref().unwrap();
+                let canonical = format!(
+                    r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                    jwk["x"].as_str().unwrap(),
+                    jwk["y"].as_str().unwrap()
+                );
+                let thumbprint = ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes());
+                let token = state.tokens[i].0.clone();
+                let expected = format!("{token}.{}", B64URL.encode(thumbprint.as_ref()));
+                (token, expected)
+            };
+            let mut stream = TcpStream::connect(&self.challenge_address).await.unwrap();
+            let request = format!("GET {CHALLENGE_PATH}{token} HTTP/1.1\r\nHost: x\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let valid = response.ends_with(&format!("\r\n\r\n{expected}"));
+            self.state.lock().unwrap().tokens[i].1 = valid;
+            Response::json(
+                200,
+                None,
+                json!({ "status": if valid { "valid" } else { "invalid" } }),
+            )
+        }
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> (String, String, Vec<u8>) {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        let end = loop {
+            if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed mid request");
+            data.extend_from_slice(&buf[..n]);
+        };
+        let head = String::from_utf8_lossy(&data[..end]).to_string();
+        let length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value.trim().parse().unwrap())
+            .unwrap_or(0);
+        while data.len() < end + length {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed mid body");
+            data.extend_from_slice(&buf[..n]);
+        }
+        let mut request = head.split_whitespace();
+        let method = request.next().unwrap_or_default().to_string();
+        let path = request.next().unwrap_or_default().to_string();
+        (method, path, data[end..].to_vec())
+    }
+
+    fn dns_names(pem: &[u8]) -> Vec<String> {
+        let (_, pem) = x509_parser::pem::parse_x509_pem(pem).unwrap();
+        let cert = pem.parse_x509().unwrap();
+        let san = cert.subject_alternative_name().unwrap().unwrap();
+        san.value
+            .general_names
+            .iter()
+            .map(|name| match name {
+                GeneralName::DNSName(name) => name.to_string(),
+                x => panic!("unexpected name {x:?}"),
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &std::path::Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn test_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let challenge_address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let ca = Arc::new(FakeCa::new(base.clone(), challenge_address.clone()));
+        tokio::spawn(ca.clone().serve(listener));
+        let dir = std::env::temp_dir().join(format!("kakimail-acme-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let mut acme = Acme {
+            config: AcmeConfig {
+                directory: format!("{base}/directory"),
+                contact: vec!["mailto:admin@kaki.foo".to_string()],
+                hostnames: vec!["mx.kaki.foo".to_string(), "mta-sts.kaki.foo".to_string()],
+                challenge_address,
+                cache_dir: dir.clone(),
+                ca_root: None,
+                renew_before_days: 30,
+            },
+        };
+        let cache = dir.join("mx.kaki.foo");
+
+        let bundle = acme.load().await.unwrap();
+        assert_eq!(bundle.certs.len(), 2);
+        assert_eq!(ca.orders(), 1);
+        let chain = std::fs::read(cache.join("cert.pem")).unwrap();
+        assert_eq!(dns_names(&chain), ["mta-sts.kaki.foo", "mx.kaki.foo"]);
+        //the certificate is for the key we kept
+        let key =
+            KeyPair::from_pem(&std::fs::read_to_string(cache.join("key.pem")).unwrap()).unwrap();
+        let (_, leaf) = x509_parser::parse_x509_certificate(&bundle.certs[0]).unwrap();
+        assert_eq!(
+            &*leaf.public_key().subject_public_key.data,
+            key.public_key_raw()
+        );
+        #[cfg(unix)]
+        {
+            assert_eq!(mode(&cache.join("key.pem")), 0o600);
+            assert_eq!(mode(&cache.join("account.pk8")), 0o600);
+        }
+
+        //the cached one is good
+        acme.load().await.unwrap();
+        assert_eq!(ca.orders(), 1);
+
+        //but not for a name it doesn't have
+        acme.config.hostnames.push("kaki.foo".to_string());
+        acme.load().await.unwrap();
+        assert_eq!(ca.orders(), 2);
+        let chain = std::fs::read(cache.join("cert.pem")).unwrap();
+        assert!(dns_names(&chain).contains(&"kaki.foo".to_string()));
+
+        //a broken one is ordered again, the key is rewritten privately
+        std::fs::write(cache.join("cert.pem"), "garbage").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let public = std::fs::Permissions::from_mode(0o644);
+            std::fs::set_permissions(cache.join("key.pem"), public).unwrap();
+        }
+        acme.load().await.unwrap();
+        assert_eq!(ca.orders(), 3);
+        #[cfg(unix)]
+        assert_eq!(mode(&cache.join("key.pem")), 0o600);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
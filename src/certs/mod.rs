@@ -0,0 +1,141 @@
+use std::{io::Cursor, path::PathBuf};
+
+use anyhow::{Context, Result};
+use tokio_rustls::rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    sign::CertifiedKey,
+};
+
+use crate::config::{Config, TlsConfig};
+
+pub mod acme;
+pub mod pem;
+pub mod porkbun;
+pub mod resolver;
+pub mod self_signed;
+
+///a certificate chain together with its private key
+#[derive(Debug)]
+pub struct CertBundle {
+    pub certs: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl CertBundle {
+    pub fn from_pem(chain: &str, key: &str) -> Result<Self> {
+        let certs = rustls_pemfile::certs(&mut Cursor::new(chain))
+            .collect::<Result<Vec<_>, _>>()
+            .context("invalid certificate chain")?;
+        if certs.is_empty() {
+            anyhow::bail!("no certificates in the certificate chain");
+        }
+        let key = rustls_pemfile::private_key(&mut Cursor::new(key))
+            .context("invalid private key")?
+            .context("no private key found")?;
+        Ok(Self { certs, key })
+    }
+
+    pub fn certified_key(self) -> Result<CertifiedKey> {
+        let key = tokio_rustls::rustls::crypto::ring::sign::any_supported_type(&self.key)?;
+        Ok(CertifiedKey::new(self.certs, key))
+    }
+}
+
+///something that can hand out a certificate for our listeners
+pub trait CertProvider {
+    async fn load(&self) -> Result<CertBundle>;
+}
+
+///the provider picked in the config
+pub enum CertSource {
+    PemFiles(pem::PemFiles),
+    SelfSigned(self_signed::SelfSigned),
+    Porkbun(porkbun::Porkbun),
+    Acme(acme::Acme),
+}
+
+impl CertSource {
+    ///the provider for the main certificate
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            &config.tls,
+            &config.tls_hostnames(),
+            config.primary_domain(),
+        )
+    }
+
+    ///`hostnames` are used by the providers that need names but weren't given any
+    pub fn new(tls: &TlsConfig, hostnames: &[String], domain: &str) -> Self {
+        match tls {
+            TlsConfig::PemFiles { cert, key } => CertSource::PemFiles(pem::PemFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            TlsConfig::SelfSigned { hostnames: names } => {
+                let hostnames = if names.is_empty() {
+                    hostnames.to_vec()
+                } else {
+                    names.clone()
+                };
+                CertSource::SelfSigned(self_signed::SelfSigned { hostnames })
+            }
+            TlsConfig::Porkbun { domain: x } => CertSource::Porkbun(porkbun::Porkbun {
+                domain: x.clone().unwrap_or(domain.to_string()),
+            }),
+            TlsConfig::Acme(acme) => {
+                let mut acme = acme.clone();
+                if acme.hostnames.is_empty() {
+                    acme.hostnames.extend(hostnames.iter().take(1).cloned());
+                }
+                CertSource::Acme(acme::Acme { config: acme })
+            }
+        }
+    }
+
+    ///files that should trigger a reload when they change
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        match self {
+            CertSource::PemFiles(x) => vec![x.cert.clone(), x.key.clone()],
+            _ => vec![],
+        }
+    }
+}
+
+impl CertProvider for CertSource {
+    async fn load(&self) -> Result<CertBundle> {
+        match self {
+            CertSource::PemFiles(x) => x.load().await,
+            CertSource::SelfSigned(x) => x.load().await,
+            CertSource::Porkbun(x) => x.load().await,
+            CertSource::Acme(x) => x.load().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pem::PemFiles, self_signed::SelfSigned, CertProvider};
+
+    #[tokio::test]
+    async fn test_self_signed_to_pem_files() {
+        let hostnames = vec!["localhost".to_string()];
+        let (cert, key) = super::self_signed::generate_pem(&hostnames).unwrap();
+        let dir = std::env::temp_dir().join(format!("kakimail-certs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert).unwrap();
+        std::fs::write(dir.join("key.pem"), key).unwrap();
+        let bundle = PemFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+        }
+        .load()
+        .await
+        .unwrap();
+        assert_eq!(bundle.certs.len(), 1);
+        assert!(bundle.certified_key().is_ok());
+        std::fs::remove_dir_all(dir).ok();
+
+        let bundle = SelfSigned { hostnames }.load().await.unwrap();
+        assert!(bundle.certified_key().is_ok());
+    }
+}
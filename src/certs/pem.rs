@@ -0,0 +1,23 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use super::{CertBundle, CertProvider};
+
+///reads the certificate chain and the key from pem files on disk
+pub struct PemFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertProvider for PemFiles {
+    async fn load(&self) -> Result<CertBundle> {
+        let chain = tokio::fs::read_to_string(&self.cert)
+            .await
+            .with_context(|| format!("couldn't read {}", self.cert.display()))?;
+        let key = tokio::fs::read_to_string(&self.key)
+            .await
+            .with_context(|| format!("couldn't read {}", self.key.display()))?;
+        CertBundle::from_pem(&chain, &key)
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use super::{CertBundle, CertProvider};
+
+const API: &str = "https://api.porkbun.com/api/json/v3";
+
+///retrieves the certificate bundle porkbun generates for domains registered with them.
+///needs PORKBUN_API_KEY and PORKBUN_SECRET_API_KEY
+pub struct Porkbun {
+    pub domain: String,
+}
+
+impl CertProvider for Porkbun {
+    async fn load(&self) -> Result<CertBundle> {
+        tracing::info!("requesting certs for {} from porkbun...", self.domain);
+        retrieve(
+            API,
+            &self.domain,
+            &std::env::var("PORKBUN_API_KEY")?,
+            &std::env::var("PORKBUN_SECRET_API_KEY")?,
+        )
+        .await
+    }
+}
+
+async fn retrieve(api: &str, domain: &str, key: &str, secret: &str) -> Result<CertBundle> {
+    let resp = reqwest::Client::new()
+        .post(format!("{api}/ssl/retrieve/{domain}"))
+        .json(&serde_json::json!({
+            "secretapikey": secret,
+            "apikey": key,
+        }))
+        .send()
+        .await?
+        .json::<HashMap<String, String>>()
+        .await?;
+    let cert_chain = resp
+        .get("certificatechain")
+        .context("should provide certchain")?;
+    let key = resp
+        .get("privatekey")
+        .context("should provide private key")?;
+    CertBundle::from_pem(cert_chain, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::retrieve;
+    use crate::certs::self_signed::generate_pem;
+
+    ///answers one request like porkbun's api, returns what was asked
+    async fn serve_once(listener: TcpListener, response: Value) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        //the json body is one object, it's complete once the braces close
+        while !request.ends_with(b"}") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed mid request");
+            request.extend_from_slice(&buf[..n]);
+        }
+        let body = response.to_string();
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body.as_bytes()).await.unwrap();
+        stream.shutdown().await.ok();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retrieve() {
+        let (cert, key) = generate_pem(&["kaki.foo".to_string()]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(
+            listener,
+            json!({ "status": "SUCCESS", "certificatechain": cert, "privatekey": key }),
+        ));
+        let bundle = retrieve(&api, "kaki.foo", "pk1_key", "sk1_secret")
+            .await
+            .unwrap();
+        assert_eq!(bundle.certs.len(), 1);
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /ssl/retrieve/kaki.foo HTTP/1.1\r\n"));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "apikey": "pk1_key", "secretapikey": "sk1_secret" })
+        );
+
+        //an error has neither
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve_once(
+            listener,
+            json!({ "status": "ERROR", "message": "invalid api key" }),
+        ));
+        assert!(retrieve(&api, "kaki.foo", "pk1_key", "sk1_wrong")
+            .await
+            .is_err());
+    }
+}
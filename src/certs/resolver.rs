@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    TlsAcceptor,
+};
+
+use super::{CertBundle, CertProvider, CertSource};
+use crate::config::{Config, TlsReloadConfig};
+
+#[derive(Debug, Default)]
+struct Certs {
+    default: Option<Arc<CertifiedKey>>,
+    ///lowercased hostnames, wildcards are stored as "*.kaki.foo"
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+///holds the certificates the acceptor hands out. the acceptor only keeps a
+///reference to this, so swapping a certificate in here applies to every new
+///handshake without having to rebuild the listeners
+#[derive(Debug, Default)]
+pub struct CertStore {
+    certs: RwLock<Certs>,
+}
+
+impl CertStore {
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let config = tokio_rustls::rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    ///empty `hostnames` replaces the default certificate
+    pub fn insert(&self, hostnames: &[String], bundle: CertBundle) -> Result<()> {
+        let key = Arc::new(bundle.certified_key()?);
+        let mut certs = self
+            .certs
+            .write()
+            .map_err(|_| anyhow!("cert store poisoned"))?;
+        if hostnames.is_empty() {
+            certs.default = Some(key.clone());
+        }
+        for name in hostnames {
+            certs.by_name.insert(name.to_ascii_lowercase(), key.clone());
+        }
+        Ok(())
+    }
+
+    ///exact names win over wildcards, everything else gets the default
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().ok()?;
+        if let Some(name) = server_name.map(str::to_ascii_lowercase) {
+            if let Some(key) = certs.by_name.get(&name) {
+                return Some(key.clone());
+            }
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(key) = certs.by_name.get(&format!("*.{parent}")) {
+                    return Some(key.clone());
+                }
+            }
+        }
+        certs.default.clone()
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.lookup(client_hello.server_name())
+    }
+}
+
+///loads the configured certificates into a `CertStore` and reloads them on
+///SIGHUP, when a watched file changes, or every `interval_hours`
+pub struct CertReloader {
+    store: Arc<CertStore>,
+    ///empty hostnames is the default certificate
+    sources: Vec<(Vec<String>, CertSource)>,
+    settings: TlsReloadConfig,
+}
+
+impl CertReloader {
+    pub fn new(config: &Config) -> Self {
+        let mut sources = vec![(vec![], CertSource::from_config(config))];
+        for sni in &config.sni {
+            let domain = sni
+                .hostnames
+                .first()
+                .and_then(|name| name.split_once('.'))
+                .map(|(_, parent)| parent)
+                .unwrap_or(config.primary_domain());
+            let source = CertSource::new(&sni.tls, &sni.hostnames, domain);
+            sources.push((sni.hostnames.clone(), source));
+        }
+        Self {
+            store: Arc::new(CertStore::default()),
+            sources,
+            settings: config.tls_reload.clone(),
+        }
+    }
+
+    pub fn store(&self) -> Arc<CertStore> {
+        self.store.clone()
+    }
+
+    ///loads every certificate, fails if any of them can't be loaded
+    pub async fn load_all(&self) -> Result<()> {
+        for (hostnames, source) in &self.sources {
+            self.store.insert(hostnames, source.load().await?)?;
+        }
+        Ok(())
+    }
+
+    ///like `load_all`, but a failing source keeps its old certificate
+    async fn reload_all(&self) {
+        for (hostnames, source) in &self.sources {
+            match source.load().await {
+                Ok(bundle) => {
+                    if let Err(e) = self.store.insert(hostnames, bundle) {
+                        tracing::error!("couldn't swap in the reloaded certificate: {:?}", e);
+                    }
+                }
+                Err(e) => tracing::error!(
+                    "couldn't reload the certificate for {:?}, keeping the old one: {:?}",
+                    hostnames,
+                    e
+                ),
+            }
+        }
+        tracing::info!("certificates reloaded");
+    }
+
+    fn modified_times(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        self.sources
+            .iter()
+            .flat_map(|(_, source)| source.watched_files())
+            .map(|path| {
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                (path, modified)
+            })
+            .collect()
+    }
+
+    ///runs forever, should be spawned after `load_all`
+    pub async fn run(self) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let interval = |secs: u64| {
+            //a zero period would panic, disabled intervals aren't polled anyway
+            let period = secs.max(1);
+            let mut interval = tokio::time::interval(Duration::from_secs(period));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        };
+        let mut schedule = interval(self.settings.interval_hours.saturating_mul(60 * 60));
+        let mut watch = interval(self.settings.watch_secs);
+        //both fire right away
+        schedule.tick().await;
+        watch.tick().await;
+        let mut last_modified = self.modified_times();
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    tracing::info!("got SIGHUP, reloading certificates");
+                    self.reload_all().await;
+                }
+                _ = schedule.tick(), if self.settings.interval_hours != 0 => {
+                    tracing::info!("scheduled certificate reload");
+                    self.reload_all().await;
+                }
+                _ = watch.tick(), if self.settings.watch_secs != 0 => {
+                    let modified = self.modified_times();
+                    if modified != last_modified {
+                        tracing::info!("certificate files changed, reloading");
+                        self.reload_all().await;
+                    }
+                    last_modified = modified;
+                    continue;
+                }
+            }
+            last_modified = self.modified_times();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CertStore;
+    use crate::certs::{self_signed, CertBundle};
+
+    fn bundle(name: &str) -> CertBundle {
+        let (cert, key) = self_signed::generate_pem(&[name.to_string()]).unwrap();
+        CertBundle::from_pem(&cert, &key).unwrap()
+    }
+
+    #[test]
+    fn test_sni_lookup() {
+        let store = CertStore::default();
+        assert!(store.lookup(Some("smtp.kaki.foo")).is_none());
+        store.insert(&[], bundle("smtp.kaki.foo")).unwrap();
+        store
+            .insert(&["*.other.com".to_string()], bundle("*.other.com"))
+            .unwrap();
+        store
+            .insert(&["Mail.Third.com".to_string()], bundle("mail.third.com"))
+            .unwrap();
+        let default = store.lookup(None).unwrap();
+        let wildcard = store.lookup(Some("mail.other.com")).unwrap();
+        let exact = store.lookup(Some("mail.third.com")).unwrap();
+        assert_eq!(
+            default.cert,
+            store.lookup(Some("unknown.com")).unwrap().cert
+        );
+        assert_ne!(default.cert, wildcard.cert);
+        assert_ne!(wildcard.cert, exact.cert);
+        //wildcards only cover one label
+        assert_eq!(
+            default.cert,
+            store.lookup(Some("a.b.other.com")).unwrap().cert
+        );
+
+        //swapping the default applies to new lookups
+        store.insert(&[], bundle("smtp.kaki.foo")).unwrap();
+        assert_ne!(default.cert, store.lookup(None).unwrap().cert);
+    }
+}
@@ -0,0 +1,27 @@
+use anyhow::Result;
+
+use super::{CertBundle, CertProvider};
+
+///generates a new self-signed certificate every time it's loaded.
+///clients will complain about it, so only use it for development and tests
+pub struct SelfSigned {
+    pub hostnames: Vec<String>,
+}
+
+impl CertProvider for SelfSigned {
+    async fn load(&self) -> Result<CertBundle> {
+        tracing::warn!(
+            "using a self-signed certificate for {}",
+            self.hostnames.join(", ")
+        );
+        let (cert, key) = generate_pem(&self.hostnames)?;
+        CertBundle::from_pem(&cert, &key)
+    }
+}
+
+///returns the certificate and the private key, both pem encoded
+pub fn generate_pem(hostnames: &[String]) -> Result<(String, String)> {
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(hostnames.to_vec())?;
+    Ok((cert.pem(), key_pair.serialize_pem()))
+}
@@ -0,0 +1,816 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::mta_sts::{Mode, Policy};
+use crate::outbound_tls::TlsPolicy;
+use crate::supervisor::ConnectionLimits;
+
+///where the config file is looked up if no path is given
+pub const DEFAULT_CONFIG_PATH: &str = "kakimail.toml";
+
+///everything kakimail needs to know at startup, loaded from a toml file.
+///every field has a default so an empty (or missing) file is a valid config.
+///see `kakimail.example.toml` for an annotated example
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    ///the name of this server, used in greetings and EHLO, eg. smtp.kaki.foo
+    pub hostname: String,
+    ///the domains we accept mail for, each with its own users. if empty,
+    ///the hostname without its first label is used (smtp.kaki.foo -> kaki.foo)
+    pub domains: Vec<DomainConfig>,
+    pub listen: ListenConfig,
+    pub database: DatabaseConfig,
+    pub tls: TlsConfig,
+    ///extra certificates for other hostnames, picked by sni
+    pub sni: Vec<SniConfig>,
+    pub tls_reload: TlsReloadConfig,
+    pub limits: LimitsConfig,
+    pub smtp: SmtpConfig,
+    pub queue: QueueConfig,
+    pub dmarc: DmarcConfig,
+    pub dns: DnsConfig,
+    pub tls_rpt: TlsRptConfig,
+    pub logging: LoggingConfig,
+}
+
+///a hosted domain. in the file it's either just the name or a table with
+///the per-domain settings
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "DomainEntry")]
+pub struct DomainConfig {
+    pub name: String,
+    ///the user on this domain that gets mail for unknown addresses
+    pub catch_all: Option<String>,
+    ///the keys outgoing mail from this domain is signed with, every one
+    ///adds a signature
+    pub dkim: Vec<DkimConfig>,
+    ///local parts whose mail is sent on to other addresses, lowercase. it's
+    ///sealed with ARC using the first dkim key
+    pub forward: BTreeMap<String, Vec<String>>,
+    ///the MTA-STS policy we publish for the domain, if any
+    pub mta_sts: Option<MtaStsConfig>,
+}
+
+///served at https://mta-sts.<domain>/.well-known/mta-sts.txt, the host
+///needs a certificate for that name
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MtaStsConfig {
+    pub mode: Mode,
+    ///the hosts that may receive mail for the domain, our hostname if empty
+    pub mx: Vec<String>,
+    ///how long senders keep the policy, in seconds
+    pub max_age: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DkimConfig {
+    ///the key is published at <selector>._domainkey.<domain>
+    pub selector: String,
+    ///a pem private key, rsa or ed25519. `kakimail dkim-keygen` makes one
+    pub key: PathBuf,
+    ///the header fields that are signed if the message has them
+    #[serde(default = "default_dkim_headers")]
+    pub headers: Vec<String>,
+}
+
+fn default_dkim_headers() -> Vec<String> {
+    [
+        "from",
+        "reply-to",
+        "subject",
+        "date",
+        "to",
+        "cc",
+        "message-id",
+        "in-reply-to",
+        "references",
+        "mime-version",
+        "content-type",
+        "content-transfer-encoding",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl DomainConfig {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DomainEntry {
+    Name(String),
+    Table(DomainTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DomainTable {
+    name: String,
+    #[serde(default)]
+    catch_all: Option<String>,
+    #[serde(default)]
+    dkim: Vec<DkimConfig>,
+    #[serde(default)]
+    forward: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    mta_sts: Option<MtaStsConfig>,
+}
+
+impl From<DomainEntry> for DomainConfig {
+    fn from(entry: DomainEntry) -> Self {
+        match entry {
+            DomainEntry::Name(name) => DomainConfig::new(&name),
+            DomainEntry::Table(table) => DomainConfig {
+                name: table.name,
+                catch_all: table.catch_all,
+                dkim: table.dkim,
+                forward: table
+                    .forward
+                    .into_iter()
+                    .map(|(local, to)| (local.to_lowercase(), to))
+                    .collect(),
+                mta_sts: table.mta_sts,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub address: String,
+    pub smtp: u16,
+    pub submission: u16,
+    pub smtps: u16,
+    pub imap: u16,
+    pub imaps: u16,
+    ///serves the MTA-STS policies, only opened if a domain has one
+    pub https: u16,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: String,
+    ///the sqlite3-pcre extension, provides REGEXP for SEARCH
+    pub pcre_extension: String,
+}
+
+///where the tls certificate comes from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "source", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum TlsConfig {
+    ///a certificate chain and a private key in pem files
+    PemFiles { cert: PathBuf, key: PathBuf },
+    ///a freshly generated self-signed certificate, for local development and tests
+    SelfSigned {
+        ///defaults to the hostname and the served domains
+        #[serde(default)]
+        hostnames: Vec<String>,
+    },
+    ///fetch the certificate bundle from porkbun's ssl api.
+    ///the api keys are read from PORKBUN_API_KEY and PORKBUN_SECRET_API_KEY
+    Porkbun {
+        ///defaults to the first served domain
+        domain: Option<String>,
+    },
+    ///order a certificate from an acme (rfc 8555) ca, eg. let's encrypt
+    Acme(AcmeConfig),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeConfig {
+    ///the ca's directory url
+    pub directory: String,
+    ///eg. "mailto:admin@kaki.foo"
+    pub contact: Vec<String>,
+    ///defaults to the hostname
+    pub hostnames: Vec<String>,
+    ///where the http-01 challenge responder listens, the ca connects to port 80
+    pub challenge_address: String,
+    ///the account key and the issued certificate are kept here
+    pub cache_dir: PathBuf,
+    ///an extra root certificate to trust when talking to the ca,
+    ///eg. pebble's minica root when testing locally
+    pub ca_root: Option<PathBuf>,
+    ///a new certificate is ordered when the cached one expires in less than this
+    pub renew_before_days: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniConfig {
+    ///the names this certificate is served for, "*.kaki.foo" matches one label
+    pub hostnames: Vec<String>,
+    pub tls: TlsConfig,
+}
+
+///the certificates are always reloaded on SIGHUP, these add automatic reloads
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsReloadConfig {
+    ///reload every n hours (acme renews on these), 0 disables it
+    pub interval_hours: u64,
+    ///check the pem files for changes every n seconds, 0 disables it
+    pub watch_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    ///0 means unlimited
+    pub max_connections: usize,
+    ///0 means unlimited
+    pub max_connections_per_ip: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    ///the longest line we accept, including the CRLF
+    pub max_line_length: usize,
+    ///the biggest message we accept, in bytes
+    pub max_message_size: usize,
+    ///local parts that are refused at RCPT TO on every domain unless an
+    ///alias routes them, whether or not someone registered them. matched
+    ///case-insensitively, postmaster is never reserved (rfc 5321 4.5.1)
+    pub reserved_names: Vec<String>,
+    ///separates the detail in sub-addresses, "+" delivers alice+invoices@ to
+    ///alice. empty disables sub-addressing
+    pub recipient_delimiter: String,
+}
+
+impl SmtpConfig {
+    pub fn is_reserved(&self, local_part: &str) -> bool {
+        !local_part.eq_ignore_ascii_case("postmaster")
+            && self
+                .reserved_names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(local_part))
+    }
+}
+
+///the outbound queue. a message that can't be delivered is retried after
+///`retry_base_secs`, then after twice that, and so on up to `retry_max_secs`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    ///how often the queue is checked for due messages
+    pub poll_secs: u64,
+    pub retry_base_secs: u64,
+    pub retry_max_secs: u64,
+    ///recipients that still fail after this long are given up on and bounced
+    pub max_age_hours: u64,
+    ///the sender is told once when a message is still undelivered after
+    ///this long, 0 disables it
+    pub delay_warning_hours: u64,
+    ///how long we wait for the remote server, per reply
+    pub timeout_secs: u64,
+    ///the port remote mail hosts are dialed on, only changed for testing
+    pub remote_port: u16,
+    ///domains that only get mail over STARTTLS with a valid certificate,
+    ///everyone else gets it encrypted when they offer it
+    pub require_tls: Vec<String>,
+    ///when STARTTLS fails with a domain that doesn't require it, send the
+    ///mail in plaintext instead of trying again later
+    pub plaintext_fallback: bool,
+    ///follow the MTA-STS policies of the domains we deliver to (rfc 8461)
+    pub mta_sts: bool,
+    ///check the certificates of mail hosts with DNSSEC signed TLSA records
+    ///against them (rfc 7672)
+    pub dane: bool,
+}
+
+///how the DMARC policies of other domains are applied to incoming mail,
+///and the reports we send and receive about them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DmarcConfig {
+    ///the public suffix list organizational domains are found with, the
+    ///publicsuffix package installs it here
+    pub public_suffix_list: PathBuf,
+    ///where mail a p=quarantine policy applies to is delivered, made for
+    ///the user if they don't have one
+    pub junk_mailbox: String,
+    ///domains whose ARC sets are believed when a message fails DMARC, like
+    ///the mailing lists you're on. mail they saw pass isn't quarantined or
+    ///rejected
+    pub trusted_arc_sealers: Vec<String>,
+    ///send the daily aggregate reports domains ask for with rua=
+    pub send_reports: bool,
+    ///the address reports are sent from, dmarc-noreply@<primary domain> if
+    ///empty
+    pub report_sender: String,
+    ///aggregate reports mailed to this address are also read into the
+    ///dmarc_reports table. empty turns it off
+    pub report_address: String,
+}
+
+///the SMTP TLS reports (rfc 8460) other servers send about our domains
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsRptConfig {
+    ///reports mailed to this address, the one in your _smtp._tls record,
+    ///are read into the tls_reports tables. empty turns it off
+    pub report_address: String,
+}
+
+///the lookups for mail authentication and delivery, and how long their
+///answers are kept
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    ///how many answers are cached, 0 turns the cache off
+    pub cache_size: usize,
+    ///how long a lookup may take, retries included
+    pub timeout_secs: u64,
+    ///how long a name without records is remembered when the server
+    ///doesn't say
+    pub negative_ttl_secs: u64,
+    ///answers aren't kept longer than this, whatever their ttl
+    pub max_ttl_secs: u64,
+    ///answer from this zone file instead of asking dns, for tests and
+    ///offline setups
+    pub zone_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    ///a tracing-subscriber EnvFilter directive, eg. "info" or "kakimail=debug".
+    ///RUST_LOG takes precedence if it's set
+    pub filter: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hostname: "smtp.kaki.foo".to_string(),
+            domains: vec![],
+            listen: ListenConfig::default(),
+            database: DatabaseConfig::default(),
+            tls: TlsConfig::default(),
+            sni: vec![],
+            tls_reload: TlsReloadConfig::default(),
+            limits: LimitsConfig::default(),
+            smtp: SmtpConfig::default(),
+            queue: QueueConfig::default(),
+            dmarc: DmarcConfig::default(),
+            dns: DnsConfig::default(),
+            tls_rpt: TlsRptConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1".to_string(),
+            smtp: 25,
+            submission: 587,
+            smtps: 465,
+            imap: 143,
+            imaps: 993,
+            https: 443,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: "./data/kakimail.db".to_string(),
+            pcre_extension: "/usr/lib/libsqlite3-pcre.so".to_string(),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig::Porkbun { domain: None }
+    }
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        Self {
+            directory: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+            contact: vec![],
+            hostnames: vec![],
+            challenge_address: "0.0.0.0:80".to_string(),
+            cache_dir: PathBuf::from("./data/acme"),
+            ca_root: None,
+            renew_before_days: 30,
+        }
+    }
+}
+
+impl Default for TlsReloadConfig {
+    fn default() -> Self {
+        Self {
+            interval_hours: 24,
+            watch_secs: 60,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        let defaults = ConnectionLimits::default();
+        Self {
+            max_connections: defaults.max_connections,
+            max_connections_per_ip: defaults.max_per_ip,
+        }
+    }
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            //rfc 5321 4.5.3.1.6, commands are allowed to be this long too
+            //because of extension parameters
+            max_line_length: 1000,
+            max_message_size: 25 * 1024 * 1024,
+            //the addresses CAs send domain validation mail to (CA/B forum
+            //baseline requirements 3.2.2.4.4), so nobody can get a
+            //certificate for our domains by registering one of them
+            reserved_names: ["admin", "administrator", "hostmaster", "webmaster"]
+                .map(String::from)
+                .to_vec(),
+            recipient_delimiter: "+".to_string(),
+        }
+    }
+}
+
+impl QueueConfig {
+    ///how mail for `domain` has to be encrypted
+    pub fn tls_policy(&self, domain: &str) -> TlsPolicy {
+        if self
+            .require_tls
+            .iter()
+            .any(|d| d.eq_ignore_ascii_case(domain))
+        {
+            TlsPolicy::Required
+        } else {
+            TlsPolicy::Opportunistic
+        }
+    }
+}
+
+impl Default for MtaStsConfig {
+    fn default() -> Self {
+        Self {
+            //rfc 8461 suggests starting with testing
+            mode: Mode::Testing,
+            mx: vec![],
+            max_age: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: 4096,
+            timeout_secs: 10,
+            negative_ttl_secs: 5 * 60,
+            max_ttl_secs: 24 * 60 * 60,
+            zone_file: None,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            poll_secs: 60,
+            retry_base_secs: 5 * 60,
+            retry_max_secs: 4 * 60 * 60,
+            //rfc 5321 4.5.4.1 suggests at least 4-5 days
+            max_age_hours: 5 * 24,
+            delay_warning_hours: 4,
+            //rfc 5321 4.5.3.2 has 2 to 10 minutes depending on the command
+            timeout_secs: 5 * 60,
+            remote_port: 25,
+            require_tls: vec![],
+            plaintext_fallback: true,
+            mta_sts: true,
+            dane: true,
+        }
+    }
+}
+
+impl Default for DmarcConfig {
+    fn default() -> Self {
+        Self {
+            public_suffix_list: PathBuf::from("/usr/share/publicsuffix/public_suffix_list.dat"),
+            junk_mailbox: "Junk".to_string(),
+            trusted_arc_sealers: vec![],
+            send_reports: true,
+            report_sender: String::new(),
+            report_address: String::new(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+        }
+    }
+}
+
+impl Config {
+    ///loads the config from `path` and applies the environment overrides.
+    ///a missing file isn't an error, we just run on the defaults
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(raw) => Self::parse(&raw)
+                .with_context(|| format!("invalid config file: {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                //tracing isn't set up yet, the logging config lives in here
+                eprintln!(
+                    "config file {} not found, using the default config",
+                    path.display()
+                );
+                Self::default()
+            }
+            Err(e) => return Err(e).context(format!("couldn't read {}", path.display())),
+        };
+        config.apply_overrides(|name| std::env::var(name).ok())?;
+        config.fill_domains();
+        Ok(config)
+    }
+
+    pub fn parse(raw: &str) -> Result<Self> {
+        Ok(toml::from_str(raw)?)
+    }
+
+    ///overrides config values with KAKIMAIL_* variables.
+    ///`lookup` is std::env::var outside of tests
+    pub fn apply_overrides(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(x) = lookup("KAKIMAIL_HOSTNAME") {
+            self.hostname = x;
+        }
+        if let Some(x) = lookup("KAKIMAIL_DOMAINS") {
+            self.domains = x
+                .split(',')
+                .map(str::trim)
+                .filter(|d| !d.is_empty())
+                .map(DomainConfig::new)
+                .collect();
+        }
+        if let Some(x) = lookup("KAKIMAIL_LISTEN_ADDRESS") {
+            self.listen.address = x;
+        }
+        let ports = [
+            ("KAKIMAIL_SMTP_PORT", &mut self.listen.smtp),
+            ("KAKIMAIL_SUBMISSION_PORT", &mut self.listen.submission),
+            ("KAKIMAIL_SMTPS_PORT", &mut self.listen.smtps),
+            ("KAKIMAIL_IMAP_PORT", &mut self.listen.imap),
+            ("KAKIMAIL_IMAPS_PORT", &mut self.listen.imaps),
+        ];
+        for (name, port) in ports {
+            if let Some(x) = lookup(name) {
+                *port = x.parse().with_context(|| format!("{name} isn't a port"))?;
+            }
+        }
+        //SQLITE_URL is kept around for older deployments
+        if let Some(x) = lookup("KAKIMAIL_DB_PATH").or_else(|| lookup("SQLITE_URL")) {
+            self.database.path = x;
+        }
+        if let Some(x) = lookup("KAKIMAIL_PCRE_EXTENSION") {
+            self.database.pcre_extension = x;
+        }
+        if let Some(x) = lookup("KAKIMAIL_MAX_CONNECTIONS") {
+            self.limits.max_connections = x
+                .parse()
+                .context("KAKIMAIL_MAX_CONNECTIONS isn't a number")?;
+        }
+        if let Some(x) = lookup("KAKIMAIL_MAX_CONNECTIONS_PER_IP") {
+            self.limits.max_connections_per_ip = x
+                .parse()
+                .context("KAKIMAIL_MAX_CONNECTIONS_PER_IP isn't a number")?;
+        }
+        if let Some(x) = lookup("KAKIMAIL_LOG") {
+            self.logging.filter = x;
+        }
+        Ok(())
+    }
+
+    fn fill_domains(&mut self) {
+        for domain in &mut self.domains {
+            domain.name = domain.name.to_ascii_lowercase();
+        }
+        if self.domains.is_empty() {
+            //go from smtp.kaki.foo to kaki.foo
+            let stripped = self
+                .hostname
+                .split_once('.')
+                .map(|(_, rest)| rest)
+                .unwrap_or(&self.hostname);
+            self.domains
+                .push(DomainConfig::new(&stripped.to_ascii_lowercase()));
+        }
+    }
+
+    ///the names a certificate should be valid for
+    pub fn tls_hostnames(&self) -> Vec<String> {
+        let mut names = vec![self.hostname.clone()];
+        names.extend(self.domains.iter().map(|d| d.name.clone()));
+        names.extend(
+            self.domains
+                .iter()
+                .filter(|d| d.mta_sts.is_some())
+                .map(|d| format!("mta-sts.{}", d.name)),
+        );
+        names.dedup();
+        names
+    }
+
+    ///the main domain, used when something needs a single one
+    pub fn primary_domain(&self) -> &str {
+        self.domains
+            .first()
+            .map(|d| d.name.as_str())
+            .unwrap_or_default()
+    }
+
+    pub fn domain(&self, name: &str) -> Option<&DomainConfig> {
+        self.domains
+            .iter()
+            .find(|d| d.name.eq_ignore_ascii_case(name))
+    }
+
+    ///the MTA-STS policy served to `host`, which is mta-sts.<domain>
+    pub fn mta_sts_policy(&self, host: &str) -> Option<Policy> {
+        let host = host.to_ascii_lowercase();
+        let domain = self.domain(host.strip_prefix("mta-sts.")?)?;
+        let config = domain.mta_sts.as_ref()?;
+        let mx = if config.mx.is_empty() {
+            vec![self.hostname.clone()]
+        } else {
+            config.mx.clone()
+        };
+        Some(Policy {
+            mode: config.mode,
+            mx,
+            max_age: config.max_age,
+        })
+    }
+
+    ///where our DMARC reports come from
+    pub fn dmarc_report_sender(&self) -> String {
+        match self.dmarc.report_sender.as_str() {
+            "" => format!("dmarc-noreply@{}", self.primary_domain()),
+            sender => sender.to_string(),
+        }
+    }
+
+    pub fn serves_domain(&self, domain: &str) -> bool {
+        self.domain(domain).is_some()
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: self.limits.max_connections,
+            max_per_ip: self.limits.max_connections_per_ip,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Config, DomainConfig, TlsConfig};
+    use crate::mta_sts::Mode;
+
+    #[test]
+    fn test_parse() {
+        let mut config = Config::parse(
+            r#"
+            hostname = "mail.example.com"
+
+            [listen]
+            address = "0.0.0.0"
+            smtp = 2525
+
+            [tls]
+            source = "porkbun"
+            domain = "example.com"
+            "#,
+        )
+        .unwrap();
+        config.fill_domains();
+        assert_eq!(config.listen.smtp, 2525);
+        assert_eq!(config.listen.imaps, 993);
+        assert_eq!(config.primary_domain(), "example.com");
+        assert!(config.serves_domain("EXAMPLE.com"));
+        assert!(Config::parse("typo = 1").is_err());
+        let config = Config::parse(
+            r#"
+            [tls]
+            source = "acme"
+            directory = "https://localhost:14000/dir"
+            contact = ["mailto:admin@example.com"]
+            "#,
+        )
+        .unwrap();
+        let TlsConfig::Acme(acme) = config.tls else {
+            panic!("should be acme");
+        };
+        assert_eq!(acme.directory, "https://localhost:14000/dir");
+        assert_eq!(acme.renew_before_days, 30);
+        let config = Config::parse(
+            r#"
+            [[sni]]
+            hostnames = ["mail.other.com"]
+            tls = { source = "pem-files", cert = "a.pem", key = "b.pem" }
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(config.sni[0].tls, TlsConfig::PemFiles { .. }));
+        let config = Config::parse(
+            r#"
+            domains = ["a.com", { name = "b.com", catch_all = "alice" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.domains[0], DomainConfig::new("a.com"));
+        assert_eq!(
+            config.domain("B.com").unwrap().catch_all.as_deref(),
+            Some("alice")
+        );
+        assert!(Config::parse(r#"domains = [{ name = "a.com", typo = 1 }]"#).is_err());
+        let config = Config::parse(
+            r#"
+            domains = [{ name = "a.com", dkim = [{ selector = "s1", key = "a.pem" }] }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.domains[0].dkim[0].selector, "s1");
+        assert!(config.domains[0].dkim[0]
+            .headers
+            .contains(&"from".to_string()));
+        let config = Config::parse(
+            r#"
+            domains = [{ name = "a.com", forward = { Bob = ["bob@example.com"] } }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.domains[0].forward["bob"], ["bob@example.com"]);
+        let config = Config::parse(
+            r#"
+            hostname = "mx.a.com"
+            domains = [{ name = "a.com", mta_sts = { mode = "enforce" } }, "b.com"]
+            "#,
+        )
+        .unwrap();
+        let policy = config.mta_sts_policy("MTA-STS.a.com").unwrap();
+        assert_eq!(policy.mode, Mode::Enforce);
+        assert_eq!(policy.mx, ["mx.a.com"]);
+        assert!(config.mta_sts_policy("mta-sts.b.com").is_none());
+        assert!(config.mta_sts_policy("a.com").is_none());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env = HashMap::from([
+            ("KAKIMAIL_DOMAINS", "a.com, B.com"),
+            ("KAKIMAIL_IMAP_PORT", "1143"),
+            ("SQLITE_URL", "/tmp/old.db"),
+        ]);
+        let mut config = Config::default();
+        config
+            .apply_overrides(|name| env.get(name).map(|v| v.to_string()))
+            .unwrap();
+        config.fill_domains();
+        assert_eq!(
+            config.domains,
+            vec![DomainConfig::new("a.com"), DomainConfig::new("b.com")]
+        );
+        assert_eq!(config.listen.imap, 1143);
+        assert_eq!(config.database.path, "/tmp/old.db");
+        let mut config = Config::default();
+        assert!(config
+            .apply_overrides(|name| (name == "KAKIMAIL_SMTP_PORT").then(|| "nope".to_string()))
+            .is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use std::sync::Arc;
+
+use ring::digest;
+use tokio_rustls::rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+///the certificate is a trust anchor the server's chains up to
+pub const DANE_TA: u8 = 2;
+///the certificate is the server's own
+pub const DANE_EE: u8 = 3;
+
+///a TLSA record (rfc 6698 2.1), what certificate a tls service has to use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlsa {
+    pub usage: u8,
+    ///0 is the whole certificate, 1 only its public key
+    pub selector: u8,
+    ///0 is the data itself, 1 its sha-256 and 2 its sha-512
+    pub matching: u8,
+    pub data: Vec<u8>,
+}
+
+impl Tlsa {
+    ///SMTP only uses DANE-TA and DANE-EE, the PKIX usages would need a ca
+    ///list we can't agree on with the server (rfc 7672 3.1.3)
+    pub fn usable(&self) -> bool {
+        matches!(self.usage, DANE_TA | DANE_EE) && self.selector <= 1 && self.matching <= 2
+    }
+
+    ///whether `cert` is the one the record is about
+    pub fn matches(&self, cert: &[u8]) -> bool {
+        let Some(selected) = select(self.selector, cert) else {
+            return false;
+        };
+        match self.matching {
+            0 => selected == self.data,
+            1 => digest::digest(&digest::SHA256, selected).as_ref() == self.data,
+            2 => digest::digest(&digest::SHA512, selected).as_ref() == self.data,
+            _ => false,
+        }
+    }
+}
+
+fn select(selector: u8, cert: &[u8]) -> Option<&[u8]> {
+    match selector {
+        0 => Some(cert),
+        1 => {
+            let (_, parsed) = X509Certificate::from_der(cert).ok()?;
+            Some(parsed.tbs_certificate.subject_pki.raw)
+        }
+        _ => None,
+    }
+}
+
+///checks the server's certificate against its TLSA records instead of the
+///webpki roots
+#[derive(Debug)]
+pub struct DaneVerifier {
+    records: Vec<Tlsa>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl DaneVerifier {
+    pub fn new(records: Vec<Tlsa>, provider: Arc<CryptoProvider>) -> Self {
+        Self { records, provider }
+    }
+
+    ///the certificates a DANE-TA record names, from the chain the server
+    ///sent or from a record that holds a whole one
+    fn anchors<'a>(&'a self, chain: &'a [CertificateDer<'a>]) -> Vec<CertificateDer<'a>> {
+        let ta = || self.records.iter().filter(|r| r.usage == DANE_TA);
+        let mut anchors = chain
+            .iter()
+            .filter(|cert| ta().any(|r| r.matches(cert)))
+            .cloned()
+            .collect::<Vec<_>>();
+        anchors.extend(
+            ta().filter(|r| r.selector == 0 && r.matching == 0)
+                .map(|r| CertificateDer::from(&r.data[..])),
+        );
+        anchors
+    }
+}
+
+impl ServerCertVerifier for DaneVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        //a DANE-EE key is all there is to check, its names and dates don't
+        //matter (rfc 7672 3.1.1)
+        let pinned = self
+            .records
+            .iter()
+            .any(|r| r.usage == DANE_EE && r.matches(end_entity));
+        if pinned {
+            return Ok(ServerCertVerified::assertion());
+        }
+        //under a DANE-TA the chain is checked like usual, with the record's
+        //certificate as the only root (rfc 7672 3.1.2)
+        for anchor in self.anchors(intermediates) {
+            let mut roots = RootCertStore::empty();
+            if roots.add(anchor).is_err() {
+                continue;
+            }
+            let Ok(webpki) =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), self.provider.clone())
+                    .build()
+            else {
+                continue;
+            };
+            let verified = webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            );
+            if verified.is_ok() {
+                return verified;
+            }
+        }
+        Err(Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        ))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use ring::digest;
+    use tokio_rustls::rustls::{
+        client::danger::ServerCertVerifier,
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, ServerName, UnixTime},
+    };
+
+    use super::{select, DaneVerifier, Tlsa, DANE_EE, DANE_TA};
+
+    ///a ca and a certificate it issued for mx.example.com
+    fn chain() -> (CertificateDer<'static>, CertificateDer<'static>) {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let leaf = CertificateParams::new(vec!["mx.example.com".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        (leaf.der().clone(), ca.der().clone())
+    }
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        digest::digest(&digest::SHA256, data).as_ref().to_vec()
+    }
+
+    fn verify(
+        records: Vec<Tlsa>,
+        leaf: &CertificateDer,
+        chain: &[CertificateDer],
+        host: &str,
+    ) -> bool {
+        let verifier = DaneVerifier::new(records, Arc::new(default_provider()));
+        let name = ServerName::try_from(host.to_string()).unwrap();
+        verifier
+            .verify_server_cert(leaf, chain, &name, &[], UnixTime::now())
+            .is_ok()
+    }
+
+    #[test]
+    fn test_matches() {
+        let (leaf, ca) = chain();
+        let spki = select(1, &leaf).unwrap().to_vec();
+        let record = |usage, selector, matching, data| Tlsa {
+            usage,
+            selector,
+            matching,
+            data,
+        };
+        assert!(record(DANE_EE, 0, 0, leaf.to_vec()).matches(&leaf));
+        assert!(record(DANE_EE, 1, 1, sha256(&spki)).matches(&leaf));
+        assert!(!record(DANE_EE, 1, 1, sha256(&spki)).matches(&ca));
+        assert!(!record(DANE_EE, 0, 1, sha256(&spki)).matches(&leaf));
+        assert!(record(
+            DANE_EE,
+            1,
+            2,
+            digest::digest(&digest::SHA512, &spki).as_ref().to_vec()
+        )
+        .matches(&leaf));
+        assert!(!record(1, 1, 1, sha256(&spki)).usable());
+        assert!(!record(DANE_EE, 1, 3, sha256(&spki)).usable());
+        assert!(record(DANE_TA, 0, 1, sha256(&ca)).usable());
+    }
+
+    #[test]
+    fn test_verify() {
+        let (leaf, ca) = chain();
+        let spki = select(1, &leaf).unwrap().to_vec();
+        let ee = Tlsa {
+            usage: DANE_EE,
+            selector: 1,
+            matching: 1,
+            data: sha256(&spki),
+        };
+        //the name doesn't matter for DANE-EE
+        assert!(verify(vec![ee.clone()], &leaf, &[], "other.example.com"));
+        let ta = Tlsa {
+            usage: DANE_TA,
+            selector: 0,
+            matching: 1,
+            data: sha256(&ca),
+        };
+        let chain = [ca.clone()];
+        assert!(verify(vec![ta.clone()], &leaf, &chain, "mx.example.com"));
+        //but it does for DANE-TA
+        assert!(!verify(
+            vec![ta.clone()],
+            &leaf,
+            &chain,
+            "other.example.com"
+        ));
+        //the anchor has to be sent if the record only has its hash
+        assert!(!verify(vec![ta], &leaf, &[], "mx.example.com"));
+        let full = Tlsa {
+            usage: DANE_TA,
+            selector: 0,
+            matching: 0,
+            data: ca.to_vec(),
+        };
+        assert!(verify(vec![full], &leaf, &[], "mx.example.com"));
+        //a DANE-EE record of another key, or a DANE-TA one of the leaf
+        let other = Tlsa {
+            data: sha256(b"something else"),
+            ..ee
+        };
+        let leaf_ta = Tlsa {
+            usage: DANE_TA,
+            selector: 0,
+            matching: 1,
+            data: sha256(&leaf),
+        };
+        assert!(!verify(
+            vec![other, leaf_ta],
+            &leaf,
+            &chain,
+            "mx.example.com"
+        ));
+    }
+}
@@ -1,17 +1,24 @@
 use std::str::FromStr;
 
 use crate::{
+    config::Config,
+    dmarc::{
+        report::{DkimAuth, Feedback, Row},
+        DmarcRecord, Policy,
+    },
+    email_auth::AuthStatus,
     imap_op::search::{ReturnOptions, SearchKeys, SequenceSet},
     parsing::{self, imap::SearchArgs},
-    smtp_common::Mail,
+    smtp_common::{Body, Mail, MailParams},
+    tls_rpt::TlsReport,
     utils,
 };
 use anyhow::{anyhow, Context, Result};
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, NaiveDate};
 use fancy_regex::Regex;
 use libsql_client::Value;
 use rusqlite::*;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::broadcast::Sender;
 
 pub fn regex_capture(pattern: &str, text: &str, capture_idx: i32) -> Option<String> {
     let re = Regex::new(pattern).ok()?;
@@ -22,6 +29,127 @@ pub fn regex_capture(pattern: &str, text: &str, capture_idx: i32) -> Option<Stri
         .map(|l| l.as_str().to_string())
 }
 
+///mail is stored as text when it's valid utf-8, so the regex functions keep
+///working on it, and as a blob when it isn't, so binary bodies survive
+fn mail_data_to_sql(data: &[u8]) -> rusqlite::types::Value {
+    match std::str::from_utf8(data) {
+        Ok(text) => rusqlite::types::Value::Text(text.to_string()),
+        Err(_) => rusqlite::types::Value::Blob(data.to_vec()),
+    }
+}
+
+///the signatures of a dmarc report row, `domain selector result` separated
+///by commas
+fn dkim_auth_to_sql(dkim: &[DkimAuth]) -> String {
+    dkim.iter()
+        .map(|d| format!("{} {} {}", d.domain, d.selector, d.result.name()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn dkim_auth_from_sql(text: &str) -> Vec<DkimAuth> {
+    text.split(',')
+        .filter_map(|d| {
+            let mut parts = d.split(' ');
+            Some(DkimAuth {
+                domain: parts.next()?.to_string(),
+                selector: parts.next()?.to_string(),
+                result: AuthStatus::from_name(parts.next()?)?,
+            })
+        })
+        .collect()
+}
+
+fn mail_data_from_sql(value: rusqlite::types::ValueRef) -> Vec<u8> {
+    match value {
+        rusqlite::types::ValueRef::Text(x) | rusqlite::types::ValueRef::Blob(x) => x.to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+///users used to have globally unique names. gives the ones from before
+///multi-domain hosting a domain, sqlite can't drop the old UNIQUE(name) so
+///the table is rebuilt
+fn migrate_users_domain(db: &rusqlite::Connection, domain: &str) -> Result<()> {
+    let migrated = db
+        .prepare("SELECT 1 FROM pragma_table_info('users') WHERE name = 'domain'")?
+        .exists([])?;
+    if migrated {
+        return Ok(());
+    }
+    tracing::info!("giving the existing users the domain {domain}");
+    //can't be changed inside a transaction
+    db.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let tx = db.unchecked_transaction()?;
+    tx.execute_batch(
+        "CREATE TABLE users_new (id INTEGER PRIMARY KEY, name TEXT, domain TEXT NOT NULL, password TEXT, UNIQUE(name, domain));",
+    )?;
+    tx.execute(
+        "INSERT INTO users_new (id, name, domain, password) SELECT id, name, ?1, password FROM users",
+        [domain],
+    )?;
+    tx.execute_batch("DROP TABLE users; ALTER TABLE users_new RENAME TO users;")?;
+    tx.commit()?;
+    db.execute_batch("PRAGMA foreign_keys = ON;")?;
+    Ok(())
+}
+
+///puts a message in the outbound queue as part of a transaction, every
+///recipient is due right away. returns the message's id
+fn insert_queued(
+    db: &rusqlite::Connection,
+    mail: &Mail,
+    now: i64,
+    junk: bool,
+    stored: &[i32],
+) -> Result<i64> {
+    db.execute(
+        "INSERT INTO queue (sender, data, body, smtputf8, created, junk) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            mail.from,
+            mail_data_to_sql(&mail.data),
+            mail.params.body.keyword(),
+            mail.params.smtputf8,
+            now,
+            junk
+        ],
+    )?;
+    let id = db.last_insert_rowid();
+    for rcpt in &mail.to {
+        db.execute(
+            "INSERT INTO queue_recipients (message_id, recipient, status, attempts, next_attempt) VALUES (?1, ?2, ?3, 0, ?4)",
+            params![id, rcpt, QueueStatus::Pending.as_str(), now],
+        )?;
+    }
+    for m_id in stored {
+        db.execute(
+            "INSERT OR IGNORE INTO queue_stored (message_id, mailbox_id) VALUES (?1, ?2)",
+            params![id, m_id],
+        )?;
+    }
+    Ok(id)
+}
+
+///adds a column to a table created before the column existed
+fn add_column(
+    db: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists = db
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?1"
+        ))?
+        .exists([column])?;
+    if !exists {
+        db.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition};"
+        ))?;
+    }
+    Ok(())
+}
+
 pub fn rfc2822_to_date(input: &str) -> Result<String> {
     //could make more efficient
     let date = chrono::NaiveDate::parse_from_str(input, super::parsing::MAIL_NAIVE_DATE_FMT)?;
@@ -44,7 +172,7 @@ pub struct MailRow {
     pub date: DateTime<FixedOffset>,
     pub sender: String,
     pub recipients: String,
-    pub data: String,
+    pub data: Vec<u8>,
     pub flags: String,
 }
 
@@ -61,6 +189,57 @@ pub struct CopyResult {
     pub dest_uid: i32,
 }
 
+///a message waiting in the outbound queue
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    pub id: i64,
+    ///`to` is empty, the recipients have their own rows
+    pub mail: Mail,
+    pub created: i64,
+    ///the sender was told that delivery is taking a while
+    pub warned: bool,
+    ///local copies go to the junk mailboxes, DMARC said to quarantine it
+    pub junk: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedRecipient {
+    pub id: i64,
+    pub message_id: i64,
+    ///with its angle brackets, like `Mail.to`
+    pub recipient: String,
+    pub status: QueueStatus,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    ///the last reply or error we got for this recipient
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl QueueStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            QueueStatus::Pending => "pending",
+            QueueStatus::Delivered => "delivered",
+            QueueStatus::Failed => "failed",
+        }
+    }
+
+    fn from_sql(status: &str) -> Self {
+        match status {
+            "delivered" => QueueStatus::Delivered,
+            "failed" => QueueStatus::Failed,
+            _ => QueueStatus::Pending,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StoreMode {
     Replace,
@@ -68,29 +247,27 @@ pub enum StoreMode {
     Remove,
 }
 
+///an untagged response about a mailbox, for the IMAP sessions idling in it
+pub type Change = (i32, String);
+
 pub struct DBClient {
     db: rusqlite::Connection,
-    changes: tokio::sync::mpsc::Sender<String>,
+    ///every session gets them, none may be listening
+    changes: Sender<Change>,
+    ///the domain of logins without one
+    default_domain: String,
 }
 
 impl DBClient {
-    pub async fn new(tx: Sender<String>) -> Result<Self> {
-        let path = if let Ok(value) = std::env::var("SQLITE_URL") {
-            value
-        } else {
-            tracing::warn!(
-                "SQLITE_URL not set, using a default local database: ./data/kakimail.db"
-            );
-            "./data/kakimail.db".to_string()
-        };
-        let db = rusqlite::Connection::open(path)?;
+    pub async fn new(tx: Sender<Change>, config: &Config) -> Result<Self> {
+        let db = rusqlite::Connection::open(&config.database.path)?;
 
         //safety: trust me bro
         unsafe {
             let _guard = LoadExtensionGuard::new(&db)?;
             //NOTE: need to have sqlite3-pcre installed
             // db.load_extension("/usr/lib/sqlite3/pcre.so", None)?;
-            db.load_extension("/usr/lib/libsqlite3-pcre.so", None)?;
+            db.load_extension(&config.database.pcre_extension, None)?;
         }
 
         db.create_scalar_function(
@@ -99,7 +276,8 @@ impl DBClient {
             rusqlite::functions::FunctionFlags::SQLITE_UTF8,
             move |ctx| {
                 let pattern = ctx.get::<String>(0)?;
-                let text = ctx.get::<String>(1)?;
+                let text = mail_data_from_sql(ctx.get_raw(1));
+                let text = String::from_utf8_lossy(&text);
                 let capture_idx = ctx.get::<i32>(2)?;
                 match regex_capture(&pattern, &text, capture_idx) {
                     Some(result) => Ok(result),
@@ -118,17 +296,19 @@ impl DBClient {
             },
         )?;
 
-        //USERS TABLE, just in case kakimail-website didn't create it already
+        //USERS TABLE, just in case kakimail-website didn't create it already.
+        //names are unique per domain
         db.execute_batch(
             "PRAGMA foreign_keys = ON;
-            CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY, name TEXT UNIQUE, password TEXT);
-            CREATE INDEX IF NOT EXISTS users_name ON users(name);
+            CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY, name TEXT, domain TEXT NOT NULL, password TEXT, UNIQUE(name, domain));
             CREATE INDEX IF NOT EXISTS users_id ON users(id);"
         )
         .map_err(|e| {
                 tracing::error!("1. {:?}", e);
                 e
             })?;
+        migrate_users_domain(&db, config.primary_domain())?;
+        db.execute_batch("CREATE INDEX IF NOT EXISTS users_name_domain ON users(name, domain);")?;
 
         //MAILBOX TABLE
         db.execute_batch(
@@ -157,7 +337,57 @@ impl DBClient {
                 tracing::error!("3. {:?}", e);
                 e
             })?;
-        Ok(Self { db, changes: tx })
+
+        //ALIASES TABLE, one row per target user. addresses are lowercased,
+        //"@kaki.foo" is the catch-all for kaki.foo
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS aliases (address text not null, user_id integer not null, FOREIGN KEY(user_id) REFERENCES users(id), UNIQUE(address, user_id));
+            CREATE INDEX IF NOT EXISTS aliases_address ON aliases(address);"
+        )
+        .map_err(|e| {
+                tracing::error!("4. {:?}", e);
+                e
+            })?;
+
+        //OUTBOUND QUEUE, a message and one row per recipient. times are unix
+        //timestamps, status is "pending", "delivered" or "failed".
+        //queue_stored has the mailboxes a message was already copied to, so
+        //a retry doesn't copy it there again
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS queue (id integer primary key, sender text not null, data text, body text not null, smtputf8 integer not null, created integer not null, warned integer not null default 0, junk integer not null default 0);
+            CREATE TABLE IF NOT EXISTS queue_recipients (id integer primary key, message_id integer not null, recipient text not null, status text not null, attempts integer not null, next_attempt integer not null, last_error text, FOREIGN KEY(message_id) REFERENCES queue(id));
+            CREATE INDEX IF NOT EXISTS queue_recipients_due ON queue_recipients(status, next_attempt);
+            CREATE INDEX IF NOT EXISTS queue_recipients_message ON queue_recipients(message_id);
+            CREATE TABLE IF NOT EXISTS queue_stored (message_id integer not null, mailbox_id integer not null, FOREIGN KEY(message_id) REFERENCES queue(id), UNIQUE(message_id, mailbox_id));"
+        )
+        .map_err(|e| {
+                tracing::error!("5. {:?}", e);
+                e
+            })?;
+        add_column(&db, "queue", "warned", "integer not null default 0")?;
+        add_column(&db, "queue", "junk", "integer not null default 0")?;
+
+        //DMARC, the outcomes counted for the next aggregate reports, the
+        //policy each domain had that day, and the reports others sent us.
+        //days are like 2024-03-01 in utc
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS dmarc_results (day text not null, policy_domain text not null, source_ip text not null, header_from text not null, envelope_from text not null, disposition text not null, aligned_dkim integer not null, aligned_spf integer not null, dkim text not null, spf text not null, count integer not null, UNIQUE(day, policy_domain, source_ip, header_from, envelope_from, disposition, aligned_dkim, aligned_spf, dkim, spf));
+            CREATE TABLE IF NOT EXISTS dmarc_policies (day text not null, domain text not null, record text not null, UNIQUE(day, domain));
+            CREATE TABLE IF NOT EXISTS dmarc_reports (org_name text not null, report_id text not null, domain text not null, begin integer not null, end integer not null, source_ip text not null, count integer not null, header_from text not null, envelope_from text not null, disposition text not null, aligned_dkim integer not null, aligned_spf integer not null, dkim text not null, spf text not null, received integer not null);
+            CREATE INDEX IF NOT EXISTS dmarc_reports_domain ON dmarc_reports(domain, begin);
+            CREATE TABLE IF NOT EXISTS tls_reports (org_name text not null, report_id text not null, policy_domain text not null, policy_type text not null, begin integer not null, end integer not null, successful integer not null, failed integer not null, received integer not null);
+            CREATE TABLE IF NOT EXISTS tls_report_failures (org_name text not null, report_id text not null, policy_domain text not null, result_type text not null, sending_mta_ip text not null, receiving_mx_hostname text not null, receiving_ip text not null, failed integer not null, reason text not null);
+            CREATE INDEX IF NOT EXISTS tls_reports_domain ON tls_reports(policy_domain, begin);"
+        )
+        .map_err(|e| {
+                tracing::error!("6. {:?}", e);
+                e
+            })?;
+        Ok(Self {
+            db,
+            changes: tx,
+            default_domain: config.primary_domain().to_string(),
+        })
     }
     pub async fn next_uid(&self) -> i64 {
         self.biggest_uid_inner().await.map(|i| i + 1).unwrap_or(1)
@@ -196,7 +426,9 @@ impl DBClient {
         mailbox_id: i32,
         datetime: Option<chrono::DateTime<FixedOffset>>,
     ) -> Result<i32> {
-        self.changes.send("* 1 EXISTS\r\n".to_owned()).await?;
+        self.changes
+            .send((mailbox_id, "* 1 EXISTS\r\n".to_owned()))
+            .ok();
         let time = if let Some(x) = datetime {
             x.format(crate::parsing::DB_DATETIME_FMT).to_string()
         } else {
@@ -216,7 +448,7 @@ impl DBClient {
                 time,
                 mail.from,
                 mail.to.join(", "),
-                mail.data,
+                mail_data_to_sql(&mail.data),
                 mailbox_id,
                 "00000",
             ),
@@ -292,11 +524,12 @@ impl DBClient {
     ///if user doesn't exist or the password is incorrect, returns None
     ///otherwise returns the users id
     pub async fn check_user(&self, username: &str, password: &str) -> Option<i32> {
+        let (name, domain) = self.split_login(username);
         let result = self
             .db
-            .prepare("SELECT id, password FROM users WHERE name = ?")
+            .prepare("SELECT id, password FROM users WHERE name = ?1 AND domain = ?2")
             .ok()?
-            .query_row([username], |r| {
+            .query_row([name, &domain], |r| {
                 //genius
                 Ok(r.get::<_, i32>(0).ok().zip(r.get::<_, Vec<u8>>(1).ok()))
             })
@@ -310,18 +543,44 @@ impl DBClient {
             Some(result.0)
         }
     }
-    pub async fn get_user_id(&self, username: &str) -> Option<i32> {
-        let values = &self
+    ///`alice@b.com` -> ("alice", "b.com"), logins without a domain are on
+    ///the primary domain
+    fn split_login<'a>(&self, login: &'a str) -> (&'a str, String) {
+        match login.rsplit_once('@') {
+            Some((name, domain)) => (name, domain.to_ascii_lowercase()),
+            None => (login, self.default_domain.clone()),
+        }
+    }
+    ///none if there's no such user, an error if the database couldn't say
+    pub async fn get_user_id(&self, username: &str, domain: &str) -> Result<Option<i32>> {
+        let id = self
             .db
-            .prepare("SELECT id from users WHERE name = ?")
-            .ok()?
-            .query([username])
+            .query_row(
+                "SELECT id from users WHERE name = ?1 AND domain = ?2",
+                [username, &domain.to_ascii_lowercase()],
+                |row| row.get::<_, i32>(0),
+            )
+            .optional()?;
+        Ok(id)
+    }
+    ///the users an alias delivers to, empty if it isn't one
+    pub async fn get_alias_targets(&self, address: &str) -> Result<Vec<i32>> {
+        let targets = self
+            .db
+            .prepare("SELECT user_id FROM aliases WHERE address = ? ORDER BY user_id")?
+            .query_map([address], |row| row.get::<_, i32>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(targets)
+    }
+    ///like `get_mailbox_id`, but ignores case and never creates the mailbox
+    pub async fn find_mailbox_id(&self, user_id: i32, mailbox_name: &str) -> Option<i32> {
+        self.db
+            .prepare("SELECT id FROM mailboxes WHERE user_id = ?1 AND name = ?2 COLLATE NOCASE")
             .ok()?
-            .next()
-            .ok()??
-            .get::<_, i32>(0)
-            .ok()?;
-        return Some(*values);
+            .query_row(rusqlite::params![user_id, mailbox_name], |row| {
+                row.get::<_, i32>(0)
+            })
+            .ok()
     }
     pub async fn create_mailbox(&self, user_id: i32, mailbox_name: &str) -> Result<()> {
         let mailbox_name = canonical_mailbox_name(mailbox_name);
@@ -388,7 +647,9 @@ impl DBClient {
         Ok(vec)
     }
     pub async fn expunge(&self, mailbox_id: i32, uid: Option<SequenceSet>) -> Result<Vec<i32>> {
-        self.changes.send("* 1 EXPUNGE\r\n".to_owned()).await.ok();
+        self.changes
+            .send((mailbox_id, "* 1 EXPUNGE\r\n".to_owned()))
+            .ok();
         let deleted = IMAPFlags::Deleted.to_string();
         let mut sql =
             "SELECT seqnum, uid FROM mail WHERE mailbox_id = ? AND flags LIKE ?".to_string();
@@ -596,7 +857,7 @@ impl DBClient {
                     row.date.format(parsing::DB_DATETIME_FMT).to_string(),
                     row.sender,
                     row.recipients,
-                    row.data,
+                    mail_data_to_sql(&row.data),
                     dest_mailbox_id,
                     row.flags,
                 ],
@@ -629,6 +890,352 @@ impl DBClient {
         ))
     }
 
+    ///puts a message in the outbound queue, every recipient is due right away.
+    ///returns the message's id
+    pub async fn enqueue(&self, mail: &Mail, now: i64) -> Result<i64> {
+        self.enqueue_stored(mail, now, false, &[]).await
+    }
+
+    ///like `enqueue`, for local mail that's already in the `stored` mailboxes.
+    ///`junk` files the other copies in the recipients' junk mailboxes
+    pub async fn enqueue_stored(
+        &self,
+        mail: &Mail,
+        now: i64,
+        junk: bool,
+        stored: &[i32],
+    ) -> Result<i64> {
+        let tx = self.db.unchecked_transaction()?;
+        let id = insert_queued(&tx, mail, now, junk, stored)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    ///the pending recipients whose next attempt is due, oldest message first
+    pub async fn due_recipients(&self, now: i64) -> Result<Vec<QueuedRecipient>> {
+        self.queue_recipients_where(
+            "status = 'pending' AND next_attempt <= ?1 ORDER BY message_id, id",
+            now,
+        )
+    }
+
+    ///every recipient of a queued message, whatever their status
+    pub async fn queue_recipients(&self, message_id: i64) -> Result<Vec<QueuedRecipient>> {
+        self.queue_recipients_where("message_id = ?1 ORDER BY id", message_id)
+    }
+
+    fn queue_recipients_where(&self, condition: &str, param: i64) -> Result<Vec<QueuedRecipient>> {
+        let sql = format!(
+            "SELECT id, message_id, recipient, status, attempts, next_attempt, last_error FROM queue_recipients WHERE {condition}"
+        );
+        let rows = self
+            .db
+            .prepare(&sql)?
+            .query_map([param], |row| {
+                Ok(QueuedRecipient {
+                    id: row.get(0)?,
+                    message_id: row.get(1)?,
+                    recipient: row.get(2)?,
+                    status: QueueStatus::from_sql(&row.get::<_, String>(3)?),
+                    attempts: row.get(4)?,
+                    next_attempt: row.get(5)?,
+                    last_error: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    pub async fn queued_message(&self, message_id: i64) -> Result<QueuedMessage> {
+        let message = self.db.query_row(
+            "SELECT sender, data, body, smtputf8, created, warned, junk FROM queue WHERE id = ?1",
+            [message_id],
+            |row| {
+                let body = row.get::<_, String>(2)?;
+                Ok(QueuedMessage {
+                    id: message_id,
+                    mail: Mail {
+                        from: row.get(0)?,
+                        data: mail_data_from_sql(row.get_ref(1)?),
+                        params: MailParams {
+                            body: Body::from_keyword(&body).unwrap_or_default(),
+                            smtputf8: row.get(3)?,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    created: row.get(4)?,
+                    warned: row.get(5)?,
+                    junk: row.get(6)?,
+                })
+            },
+        )?;
+        Ok(message)
+    }
+
+    ///saves the result of a delivery attempt
+    pub async fn update_queue_recipient(&self, recipient: &QueuedRecipient) -> Result<()> {
+        self.db.execute(
+            "UPDATE queue_recipients SET status = ?1, attempts = ?2, next_attempt = ?3, last_error = ?4 WHERE id = ?5",
+            params![
+                recipient.status.as_str(),
+                recipient.attempts,
+                recipient.next_attempt,
+                recipient.last_error,
+                recipient.id
+            ],
+        )?;
+        Ok(())
+    }
+
+    ///the mailboxes a queued message was copied to
+    pub async fn stored_mailboxes(&self, message_id: i64) -> Result<Vec<i32>> {
+        let rows = self
+            .db
+            .prepare("SELECT mailbox_id FROM queue_stored WHERE message_id = ?1")?
+            .query_map([message_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    pub async fn set_queue_stored(&self, message_id: i64, mailbox_id: i32) -> Result<()> {
+        self.db.execute(
+            "INSERT OR IGNORE INTO queue_stored (message_id, mailbox_id) VALUES (?1, ?2)",
+            params![message_id, mailbox_id],
+        )?;
+        Ok(())
+    }
+
+    ///marks a message its sender was told is delayed. the `report` telling
+    ///them is queued in the same transaction, so it's sent once
+    pub async fn set_queue_warned(
+        &self,
+        message_id: i64,
+        report: Option<&Mail>,
+        now: i64,
+    ) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        if let Some(report) = report {
+            insert_queued(&tx, report, now, false, &[])?;
+        }
+        tx.execute("UPDATE queue SET warned = 1 WHERE id = ?1", [message_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    ///the queued messages none of whose recipients are pending anymore, and
+    ///that weren't put off past `now`
+    pub async fn finished_messages(&self, now: i64) -> Result<Vec<i64>> {
+        let rows = self
+            .db
+            .prepare("SELECT id FROM queue WHERE NOT EXISTS (SELECT 1 FROM queue_recipients WHERE message_id = queue.id AND (status = 'pending' OR next_attempt > ?1)) ORDER BY id")?
+            .query_map([now], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    ///puts off everything about a queued message until `until` at least
+    pub async fn defer_queued(&self, message_id: i64, until: i64) -> Result<()> {
+        self.db.execute(
+            "UPDATE queue_recipients SET next_attempt = MAX(next_attempt, ?2) WHERE message_id = ?1",
+            params![message_id, until],
+        )?;
+        Ok(())
+    }
+
+    ///removes a message that's done with from the queue. the `report` to its
+    ///sender is queued in the same transaction, so it's sent once whatever
+    ///fails
+    pub async fn dequeue(&self, message_id: i64, report: Option<&Mail>, now: i64) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        if let Some(report) = report {
+            insert_queued(&tx, report, now, false, &[])?;
+        }
+        tx.execute(
+            "DELETE FROM queue_recipients WHERE message_id = ?1",
+            [message_id],
+        )?;
+        tx.execute(
+            "DELETE FROM queue_stored WHERE message_id = ?1",
+            [message_id],
+        )?;
+        tx.execute("DELETE FROM queue WHERE id = ?1", [message_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    ///counts a message towards the report for `record`'s domain on `day`
+    pub async fn count_dmarc_result(
+        &self,
+        day: NaiveDate,
+        record: &DmarcRecord,
+        row: &Row,
+    ) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO dmarc_policies (day, domain, record) VALUES (?1, ?2, ?3)",
+            params![day, record.domain, record.to_txt()],
+        )?;
+        tx.execute(
+            "INSERT INTO dmarc_results (day, policy_domain, source_ip, header_from, envelope_from, disposition, aligned_dkim, aligned_spf, dkim, spf, count) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+            ON CONFLICT(day, policy_domain, source_ip, header_from, envelope_from, disposition, aligned_dkim, aligned_spf, dkim, spf) DO UPDATE SET count = count + excluded.count",
+            params![
+                day,
+                record.domain,
+                row.source_ip,
+                row.header_from,
+                row.envelope_from,
+                row.disposition.name(),
+                row.aligned_dkim,
+                row.aligned_spf,
+                dkim_auth_to_sql(&row.dkim),
+                row.spf.name(),
+                row.count
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    ///the days before `day` that have results to report
+    pub async fn dmarc_days_before(&self, day: NaiveDate) -> Result<Vec<NaiveDate>> {
+        let days = self
+            .db
+            .prepare("SELECT DISTINCT day FROM dmarc_policies WHERE day < ?1 ORDER BY day")?
+            .query_map([day], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(days)
+    }
+
+    ///the policies of the domains with results on `day`
+    pub async fn dmarc_policies(&self, day: NaiveDate) -> Result<Vec<DmarcRecord>> {
+        let policies = self
+            .db
+            .prepare("SELECT domain, record FROM dmarc_policies WHERE day = ?1")?
+            .query_map([day], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .into_iter()
+            .filter_map(|(domain, record)| DmarcRecord::parse(&domain, &record))
+            .collect();
+        Ok(policies)
+    }
+
+    pub async fn dmarc_results(&self, day: NaiveDate, domain: &str) -> Result<Vec<Row>> {
+        let rows = self
+            .db
+            .prepare(
+                "SELECT source_ip, count, header_from, envelope_from, disposition, aligned_dkim, aligned_spf, dkim, spf FROM dmarc_results WHERE day = ?1 AND policy_domain = ?2",
+            )?
+            .query_map(params![day, domain], |row| {
+                Ok(Row {
+                    source_ip: row.get(0)?,
+                    count: row.get(1)?,
+                    header_from: row.get(2)?,
+                    envelope_from: row.get(3)?,
+                    disposition: Policy::parse(&row.get::<_, String>(4)?).unwrap_or_default(),
+                    aligned_dkim: row.get(5)?,
+                    aligned_spf: row.get(6)?,
+                    dkim: dkim_auth_from_sql(&row.get::<_, String>(7)?),
+                    spf: AuthStatus::from_name(&row.get::<_, String>(8)?).unwrap_or_default(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    ///forgets the results of a day once it's reported
+    pub async fn delete_dmarc_day(&self, day: NaiveDate) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        tx.execute("DELETE FROM dmarc_results WHERE day = ?1", [day])?;
+        tx.execute("DELETE FROM dmarc_policies WHERE day = ?1", [day])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    ///stores a report another server sent us, a report sent again replaces
+    ///the first one
+    pub async fn save_dmarc_report(&self, feedback: &Feedback, received: i64) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM dmarc_reports WHERE org_name = ?1 AND report_id = ?2",
+            params![feedback.org_name, feedback.report_id],
+        )?;
+        for row in &feedback.rows {
+            tx.execute(
+                "INSERT INTO dmarc_reports (org_name, report_id, domain, begin, end, source_ip, count, header_from, envelope_from, disposition, aligned_dkim, aligned_spf, dkim, spf, received) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+                params![
+                    feedback.org_name,
+                    feedback.report_id,
+                    feedback.policy.domain,
+                    feedback.begin,
+                    feedback.end,
+                    row.source_ip,
+                    row.count,
+                    row.header_from,
+                    row.envelope_from,
+                    row.disposition.name(),
+                    row.aligned_dkim,
+                    row.aligned_spf,
+                    dkim_auth_to_sql(&row.dkim),
+                    row.spf.name(),
+                    received
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    ///stores a tls report another server sent us, one row per policy and
+    ///one per failure. a report sent again replaces the first one
+    pub async fn save_tls_report(&self, report: &TlsReport, received: i64) -> Result<()> {
+        let (begin, end) = report.range()?;
+        let tx = self.db.unchecked_transaction()?;
+        for table in ["tls_reports", "tls_report_failures"] {
+            tx.execute(
+                &format!("DELETE FROM {table} WHERE org_name = ?1 AND report_id = ?2"),
+                params![report.organization_name, report.report_id],
+            )?;
+        }
+        for results in &report.policies {
+            let domain = &results.policy.policy_domain;
+            tx.execute(
+                "INSERT INTO tls_reports (org_name, report_id, policy_domain, policy_type, begin, end, successful, failed, received) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    report.organization_name,
+                    report.report_id,
+                    domain,
+                    results.policy.policy_type,
+                    begin,
+                    end,
+                    results.summary.total_successful_session_count,
+                    results.summary.total_failure_session_count,
+                    received
+                ],
+            )?;
+            for failure in &results.failure_details {
+                tx.execute(
+                    "INSERT INTO tls_report_failures (org_name, report_id, policy_domain, result_type, sending_mta_ip, receiving_mx_hostname, receiving_ip, failed, reason) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        report.organization_name,
+                        report.report_id,
+                        domain,
+                        failure.result_type,
+                        failure.sending_mta_ip,
+                        failure.receiving_mx_hostname,
+                        failure.receiving_ip,
+                        failure.failed_session_count,
+                        failure.failure_reason_code
+                    ],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
     fn select_mail_rows(
         &self,
         mailbox_id: i32,
@@ -656,7 +1263,7 @@ impl DBClient {
                     date: row.get::<_, DateTime<FixedOffset>>(2)?,
                     sender: row.get::<_, String>(3)?,
                     recipients: row.get::<_, String>(4)?,
-                    data: row.get::<_, String>(5)?,
+                    data: mail_data_from_sql(row.get_ref(5)?),
                     flags: row.get::<_, String>(6)?,
                 })
             })?
@@ -794,3 +1401,40 @@ pub fn value_to_param<'a>(value: &'a Value) -> Option<&'a dyn ToSql> {
         Value::Integer { value: x } => Some(x),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::migrate_users_domain;
+
+    #[test]
+    fn test_migrate_users_domain() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT UNIQUE, password TEXT);
+            CREATE TABLE mailboxes (id integer primary key not null, name text, user_id integer not null, flags integer, FOREIGN KEY(user_id) REFERENCES users(id));
+            INSERT INTO users VALUES (1, 'alice', 'hash');
+            INSERT INTO mailboxes VALUES (1, 'INBOX', 1, 0);",
+        )
+        .unwrap();
+        migrate_users_domain(&db, "a.com").unwrap();
+        //running it again does nothing
+        migrate_users_domain(&db, "b.com").unwrap();
+        let domain: String = db
+            .query_row("SELECT domain FROM users WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(domain, "a.com");
+        //the same name on another domain is another user
+        db.execute("INSERT INTO users VALUES (2, 'alice', 'b.com', 'hash')", [])
+            .unwrap();
+        assert!(db
+            .execute("INSERT INTO users VALUES (3, 'alice', 'b.com', 'hash')", [])
+            .is_err());
+        //the mailboxes still point at their users
+        assert!(db
+            .execute("INSERT INTO mailboxes VALUES (2, 'INBOX', 5, 0)", [])
+            .is_err());
+    }
+}
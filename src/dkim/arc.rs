@@ -0,0 +1,454 @@
+use anyhow::{bail, Result};
+
+use super::sign::{sign_fields, DomainKey};
+use super::verify::{
+    canonical_header, canonicalization, decode_base64, public_key, signed_headers, tags,
+    without_signature, Algorithm, Canonicalization,
+};
+use super::{base64, relaxed_body, relaxed_header, sha256, simple_body, split_message, Field};
+use crate::email_auth::{AuthResolver, AuthStatus};
+
+///a chain longer than this fails (rfc 8617 4.2.1)
+const MAX_INSTANCES: usize = 50;
+
+///what one hop recorded when it sealed the message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArcSet {
+    pub instance: usize,
+    ///the d= of its ARC-Seal
+    pub sealer: String,
+    ///its ARC-Authentication-Results, without the i= tag
+    pub results: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArcResult {
+    ///pass, fail, or none if there are no ARC sets
+    pub status: AuthStatus,
+    ///oldest first, only kept if the chain passed
+    pub sets: Vec<ArcSet>,
+    ///why it failed
+    pub reason: Option<String>,
+}
+
+impl ArcResult {
+    pub fn new(status: AuthStatus) -> Self {
+        Self {
+            status,
+            sets: vec![],
+            reason: None,
+        }
+    }
+
+    fn fail(reason: impl Into<String>) -> Self {
+        Self {
+            reason: Some(reason.into()),
+            ..Self::new(AuthStatus::Fail)
+        }
+    }
+
+    ///the newest sealer in `trusted` that saw DMARC pass, if any
+    pub fn trusted_dmarc_pass(&self, trusted: &[String]) -> Option<&str> {
+        self.sets
+            .iter()
+            .rev()
+            .filter(|set| trusted.iter().any(|t| t.eq_ignore_ascii_case(&set.sealer)))
+            .find(|set| {
+                set.results.split(';').any(|result| {
+                    result
+                        .split_whitespace()
+                        .next()
+                        .is_some_and(|r| r.eq_ignore_ascii_case("dmarc=pass"))
+                })
+            })
+            .map(|set| set.sealer.as_str())
+    }
+}
+
+///the ARC-Authentication-Results, ARC-Message-Signature and ARC-Seal of one
+///instance, in the order the seal hashes them
+type Set<'a, 'b> = [&'a Field<'b>; 3];
+
+fn field_tags(raw: &[u8]) -> Vec<(String, String)> {
+    let value = String::from_utf8_lossy(raw);
+    tags(value.split_once(':').map(|(_, v)| v).unwrap_or_default())
+}
+
+fn tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+///the ARC sets of a message ordered by instance, an error if they don't
+///form a chain
+fn collect_sets<'a, 'b>(fields: &'a [Field<'b>]) -> Result<Vec<Set<'a, 'b>>, String> {
+    let mut sets: Vec<[Option<&Field>; 3]> = Vec::new();
+    for field in fields {
+        let kind = match field.name().to_ascii_lowercase().as_str() {
+            "arc-authentication-results" => 0,
+            "arc-message-signature" => 1,
+            "arc-seal" => 2,
+            _ => continue,
+        };
+        let instance = tag(&field_tags(field.raw), "i")
+            .and_then(|i| i.parse::<usize>().ok())
+            .filter(|i| (1..=MAX_INSTANCES).contains(i))
+            .ok_or(format!("invalid i= in {}", field.name()))?;
+        if sets.len() < instance {
+            sets.resize(instance, [None; 3]);
+        }
+        if sets[instance - 1][kind].replace(field).is_some() {
+            return Err(format!("two {} with i={instance}", field.name()));
+        }
+    }
+    sets.into_iter()
+        .enumerate()
+        .map(|(i, set)| match set {
+            [Some(results), Some(signature), Some(seal)] => Ok([results, signature, seal]),
+            _ => Err(format!("ARC set {} is incomplete", i + 1)),
+        })
+        .collect()
+}
+
+///validates the ARC chain of a message (rfc 8617 5.2)
+pub async fn verify(dns: &impl AuthResolver, data: &[u8]) -> ArcResult {
+    let (fields, body) = split_message(data);
+    let sets = match collect_sets(&fields) {
+        Ok(sets) if sets.is_empty() => return ArcResult::new(AuthStatus::None),
+        Ok(sets) => sets,
+        Err(reason) => return ArcResult::fail(reason),
+    };
+    for (i, set) in sets.iter().enumerate() {
+        let tags = field_tags(set[2].raw);
+        let cv = tag(&tags, "cv").unwrap_or_default();
+        let expected = if i == 0 { "none" } else { "pass" };
+        if !cv.eq_ignore_ascii_case(expected) {
+            return ArcResult::fail(format!("ARC-Seal {} has cv={cv}", i + 1));
+        }
+    }
+    //only the newest message signature has to hold
+    if let Err(reason) = verify_message_signature(dns, &fields, sets[sets.len() - 1][1], body).await
+    {
+        return ArcResult::fail(reason);
+    }
+    for n in (1..=sets.len()).rev() {
+        if let Err(reason) = verify_seal(dns, &sets[..n]).await {
+            return ArcResult::fail(reason);
+        }
+    }
+    let sets = sets
+        .iter()
+        .enumerate()
+        .map(|(i, set)| {
+            let results = String::from_utf8_lossy(set[0].raw);
+            let results = results.split_once(':').map(|(_, v)| v).unwrap_or_default();
+            //the results start after the i= tag
+            let results = results.split_once(';').map(|(_, r)| r).unwrap_or_default();
+            ArcSet {
+                instance: i + 1,
+                sealer: tag(&field_tags(set[2].raw), "d")
+                    .unwrap_or_default()
+                    .to_ascii_lowercase(),
+                results: results.split_whitespace().collect::<Vec<_>>().join(" "),
+            }
+        })
+        .collect();
+    ArcResult {
+        status: AuthStatus::Pass,
+        sets,
+        reason: None,
+    }
+}
+
+fn algorithm(tags: &[(String, String)]) -> Result<Algorithm, String> {
+    match tag(tags, "a") {
+        Some("rsa-sha256") => Ok(Algorithm::RsaSha256),
+        Some("ed25519-sha256") => Ok(Algorithm::Ed25519Sha256),
+        other => Err(format!(
+            "unsupported algorithm {}",
+            other.unwrap_or_default()
+        )),
+    }
+}
+
+fn required<'a>(tags: &'a [(String, String)], name: &str) -> Result<&'a str, String> {
+    tag(tags, name).ok_or(format!("missing {name}= tag"))
+}
+
+///checks an ARC-Message-Signature, which works like a DKIM-Signature
+async fn verify_message_signature(
+    dns: &impl AuthResolver,
+    fields: &[Field<'_>],
+    field: &Field<'_>,
+    body: &[u8],
+) -> Result<(), String> {
+    let tags = field_tags(field.raw);
+    let algorithm = algorithm(&tags)?;
+    let c = tag(&tags, "c").unwrap_or("simple/simple");
+    let (header_canon, body_canon) = match c.split_once('/') {
+        Some((header, body)) => (canonicalization(header)?, canonicalization(body)?),
+        None => (canonicalization(c)?, Canonicalization::Simple),
+    };
+    let headers = required(&tags, "h")?
+        .split(':')
+        .map(|h| h.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    if headers.iter().any(|h| h == "arc-seal") {
+        return Err("the message signature covers an ARC-Seal".to_string());
+    }
+    let body_hash = decode_base64(required(&tags, "bh")?).ok_or("invalid bh= tag")?;
+    let canonical_body = match body_canon {
+        Canonicalization::Simple => simple_body(body),
+        Canonicalization::Relaxed => relaxed_body(body),
+    };
+    if sha256(&canonical_body) != body_hash {
+        return Err("ARC body hash mismatch".to_string());
+    }
+    let mut hashed = signed_headers(fields, &headers, header_canon);
+    let mut own = canonical_header(header_canon, &without_signature(field.raw));
+    if own.ends_with(b"\r\n") {
+        own.truncate(own.len() - 2);
+    }
+    hashed.extend_from_slice(&own);
+    check_signature(dns, &tags, algorithm, &hashed).await
+}
+
+///checks the ARC-Seal of the last set in `sets`, it covers every set up to
+///its own
+async fn verify_seal(dns: &impl AuthResolver, sets: &[Set<'_, '_>]) -> Result<(), String> {
+    let seal = sets[sets.len() - 1][2];
+    let tags = field_tags(seal.raw);
+    if tag(&tags, "h").is_some() {
+        return Err("ARC-Seal with an h= tag".to_string());
+    }
+    let algorithm = algorithm(&tags)?;
+    let mut hashed = Vec::new();
+    for field in sets.iter().flatten() {
+        if std::ptr::eq(*field, seal) {
+            let mut own = relaxed_header(&without_signature(field.raw));
+            own.truncate(own.len() - 2);
+            hashed.extend_from_slice(&own);
+        } else {
+            hashed.extend_from_slice(&relaxed_header(field.raw));
+        }
+    }
+    check_signature(dns, &tags, algorithm, &hashed).await
+}
+
+async fn check_signature(
+    dns: &impl AuthResolver,
+    tags: &[(String, String)],
+    algorithm: Algorithm,
+    hashed: &[u8],
+) -> Result<(), String> {
+    let domain = required(tags, "d")?;
+    let selector = required(tags, "s")?;
+    let signature = decode_base64(required(tags, "b")?).ok_or("invalid b= tag")?;
+    let key = public_key(dns, selector, domain)
+        .await
+        .map_err(|(_, reason)| format!("{selector}._domainkey.{domain}: {reason}"))?;
+    if key.algorithm != algorithm {
+        return Err("the key is for another algorithm".to_string());
+    }
+    if !key.verify(hashed, &signature) {
+        return Err(format!("ARC signature from {domain} mismatch"));
+    }
+    Ok(())
+}
+
+///adds our ARC set to a message we pass on (rfc 8617 5.1). `results` is
+///the value of our Authentication-Results, `chain` what verify said about
+///the sets it already has. only a chain that passed, or a message without
+///one, is sealed, our seal says cv=pass for everything before it
+pub fn seal(
+    key: &DomainKey,
+    data: &[u8],
+    results: &str,
+    chain: &ArcResult,
+    now: i64,
+) -> Result<Vec<u8>> {
+    let (fields, body) = split_message(data);
+    let sets = collect_sets(&fields).map_err(anyhow::Error::msg)?;
+    let verified = match chain.status {
+        AuthStatus::Pass => chain.sets.len() == sets.len(),
+        AuthStatus::None => sets.is_empty(),
+        _ => false,
+    };
+    if !verified {
+        bail!("the ARC chain didn't pass ({:?})", chain.status);
+    }
+    let instance = sets.len() + 1;
+    if instance > MAX_INSTANCES {
+        bail!("the ARC chain is too long");
+    }
+    let algorithm = key.key.algorithm();
+    let results = format!("ARC-Authentication-Results: i={instance}; {results}\r\n");
+
+    let body_hash = base64(&sha256(&relaxed_body(body)));
+    let (signed_names, mut hashed) = sign_fields(&key.headers, &fields);
+    let signature = format!(
+        "ARC-Message-Signature: i={instance}; a={algorithm}; c=relaxed/relaxed;\r\n\
+         \td={}; s={}; t={now};\r\n\th={};\r\n\tbh={body_hash};\r\n\tb=",
+        key.domain,
+        key.selector,
+        signed_names.join(":"),
+    );
+    let mut own = relaxed_header(signature.as_bytes());
+    own.truncate(own.len() - 2);
+    hashed.extend_from_slice(&own);
+    let signature = format!("{signature}{}\r\n", base64(&key.key.sign(&hashed)?));
+
+    let cv = if instance == 1 { "none" } else { "pass" };
+    let seal = format!(
+        "ARC-Seal: i={instance}; a={algorithm}; t={now}; cv={cv};\r\n\td={}; s={};\r\n\tb=",
+        key.domain, key.selector,
+    );
+    let mut hashed = Vec::new();
+    for field in sets.iter().flatten() {
+        hashed.extend_from_slice(&relaxed_header(field.raw));
+    }
+    hashed.extend_from_slice(&relaxed_header(results.as_bytes()));
+    hashed.extend_from_slice(&relaxed_header(signature.as_bytes()));
+    let mut own = relaxed_header(seal.as_bytes());
+    own.truncate(own.len() - 2);
+    hashed.extend_from_slice(&own);
+    let seal = format!("{seal}{}\r\n", base64(&key.key.sign(&hashed)?));
+
+    let mut sealed = Vec::with_capacity(data.len() + seal.len() + signature.len() + results.len());
+    sealed.extend_from_slice(seal.as_bytes());
+    sealed.extend_from_slice(signature.as_bytes());
+    sealed.extend_from_slice(results.as_bytes());
+    sealed.extend_from_slice(data);
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::{seal, verify, ArcResult};
+    use crate::dkim::base64;
+    use crate::dkim::sign::{DomainKey, SigningKey};
+    use crate::email_auth::tests::FakeDns;
+    use crate::email_auth::AuthStatus;
+
+    const MESSAGE: &[u8] = b"From: alice@example.com\r\nTo: list@lists.example\r\n\
+        Subject: hello\r\n\r\nhi everyone\r\n";
+
+    fn key(domain: &str, dns: FakeDns) -> (DomainKey, FakeDns) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let dns = dns.txt(
+            &format!("arc._domainkey.{domain}"),
+            &format!(
+                "v=DKIM1; k=ed25519; p={}",
+                base64(pair.public_key().as_ref())
+            ),
+        );
+        let key = DomainKey {
+            domain: domain.to_string(),
+            selector: "arc".to_string(),
+            key: SigningKey::Ed25519(pair),
+            headers: ["from", "to", "subject"].map(str::to_string).to_vec(),
+        };
+        (key, dns)
+    }
+
+    #[tokio::test]
+    async fn test_seal_and_verify() {
+        let (list, dns) = key("lists.example", FakeDns::default());
+        let (relay, dns) = key("relay.example", dns);
+        let none = verify(&dns, MESSAGE).await;
+        assert_eq!(none, ArcResult::new(AuthStatus::None));
+
+        let first = seal(
+            &list,
+            MESSAGE,
+            "mx.lists.example; spf=pass smtp.mailfrom=example.com;\r\n\tdmarc=pass header.from=example.com",
+            &none,
+            1700000000,
+        )
+        .unwrap();
+        let result = verify(&dns, &first).await;
+        assert_eq!(result.status, AuthStatus::Pass, "{:?}", result.reason);
+        assert_eq!(result.sets.len(), 1);
+        assert_eq!(result.sets[0].sealer, "lists.example");
+        assert_eq!(
+            result.sets[0].results,
+            "mx.lists.example; spf=pass smtp.mailfrom=example.com; dmarc=pass header.from=example.com"
+        );
+
+        //the next hop seals on top
+        let second = seal(
+            &relay,
+            &first,
+            "mx.relay.example; dmarc=fail header.from=example.com",
+            &result,
+            1700000100,
+        )
+        .unwrap();
+        let result = verify(&dns, &second).await;
+        assert_eq!(result.status, AuthStatus::Pass, "{:?}", result.reason);
+        assert_eq!(result.sets.len(), 2);
+        let trusted = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            result.trusted_dmarc_pass(&trusted(&["lists.example"])),
+            Some("lists.example")
+        );
+        assert_eq!(
+            result.trusted_dmarc_pass(&trusted(&["relay.example"])),
+            None
+        );
+        assert_eq!(result.trusted_dmarc_pass(&[]), None);
+
+        //changing the body after the last seal breaks its message signature
+        let mut tampered = second.clone();
+        tampered.extend_from_slice(b"buy now\r\n");
+        let result = verify(&dns, &tampered).await;
+        assert_eq!(result.status, AuthStatus::Fail);
+        assert!(result.sets.is_empty());
+        //so does rewriting what an older hop recorded
+        let forged = String::from_utf8(second.clone()).unwrap().replace(
+            "i=1; mx.lists.example; spf=pass",
+            "i=1; mx.lists.example; spf=none",
+        );
+        let result = verify(&dns, forged.as_bytes()).await;
+        assert_eq!(result.status, AuthStatus::Fail);
+        //a chain that failed isn't sealed again
+        assert!(seal(&relay, &tampered, "x", &ArcResult::fail("broken"), 0).is_err());
+        //nor one that wasn't checked, or couldn't be
+        let unchecked = ArcResult::new(AuthStatus::None);
+        assert!(seal(&relay, &first, "x", &unchecked, 0).is_err());
+        let neutral = ArcResult::new(AuthStatus::Neutral);
+        assert!(seal(&relay, &first, "x", &neutral, 0).is_err());
+        assert!(seal(&relay, MESSAGE, "x", &neutral, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_broken_chains() {
+        let (list, dns) = key("lists.example", FakeDns::default());
+        let sealed = seal(
+            &list,
+            MESSAGE,
+            "mx.lists.example; none",
+            &ArcResult::new(AuthStatus::None),
+            0,
+        )
+        .unwrap();
+        let sealed = String::from_utf8(sealed).unwrap();
+        //a missing member of the set
+        let missing = sealed.replacen("ARC-Authentication-Results: i=1", "X-Results: i=1", 1);
+        let result = verify(&dns, missing.as_bytes()).await;
+        assert_eq!(result.reason.as_deref(), Some("ARC set 1 is incomplete"));
+        //the first seal has to say cv=none
+        let cv = sealed.replacen("cv=none", "cv=pass", 1);
+        assert_eq!(verify(&dns, cv.as_bytes()).await.status, AuthStatus::Fail);
+        let skipped = sealed.replace("i=1", "i=2");
+        assert_eq!(
+            verify(&dns, skipped.as_bytes()).await.status,
+            AuthStatus::Fail
+        );
+    }
+}
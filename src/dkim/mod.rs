@@ -0,0 +1,174 @@
+use base64::Engine;
+
+///ARC sets, dkim-like signatures that carry results across forwarding
+///hops (rfc 8617)
+pub mod arc;
+///signing outgoing mail
+mod sign;
+///checking the signatures on incoming mail
+pub mod verify;
+
+pub use sign::{keygen, DkimSigner};
+
+///a header field as it appears in the message, with its folding and CRLF
+pub struct Field<'a> {
+    pub raw: &'a [u8],
+}
+
+impl Field<'_> {
+    pub fn name(&self) -> &str {
+        let end = self
+            .raw
+            .iter()
+            .position(|&b| b == b':')
+            .unwrap_or(self.raw.len());
+        std::str::from_utf8(&self.raw[..end])
+            .unwrap_or_default()
+            .trim_end()
+    }
+}
+
+///splits a message into its header fields and its body
+pub fn split_message(data: &[u8]) -> (Vec<Field<'_>>, &[u8]) {
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    let mut pos = 0;
+    let mut body = data.len();
+    while pos < data.len() {
+        let end = data[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|i| pos + i + 1)
+            .unwrap_or(data.len());
+        let line = &data[pos..end];
+        if line == b"\r\n" || line == b"\n" {
+            body = end;
+            break;
+        }
+        match spans.last_mut() {
+            //a folded continuation of the field before
+            Some(span) if is_wsp(line[0]) => span.1 = end,
+            _ => spans.push((pos, end)),
+        }
+        pos = end;
+    }
+    let fields = spans
+        .into_iter()
+        .map(|(start, end)| Field {
+            raw: &data[start..end],
+        })
+        .collect();
+    (fields, &data[body..])
+}
+
+fn is_wsp(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+///runs of whitespace become one space, trailing whitespace goes
+fn compress_wsp(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    let mut in_wsp = false;
+    for &b in line {
+        if is_wsp(b) {
+            in_wsp = true;
+            continue;
+        }
+        if in_wsp {
+            out.push(b' ');
+        }
+        in_wsp = false;
+        out.push(b);
+    }
+    out
+}
+
+///the relaxed header canonicalization of rfc 6376 3.4.2, a field with its
+///CRLF becomes `name:value\r\n`
+pub fn relaxed_header(raw: &[u8]) -> Vec<u8> {
+    let colon = raw.iter().position(|&b| b == b':').unwrap_or(raw.len());
+    let name = raw[..colon].to_ascii_lowercase();
+    let value = raw.get(colon + 1..).unwrap_or_default();
+    let unfolded = value
+        .iter()
+        .copied()
+        .filter(|&b| b != b'\r' && b != b'\n')
+        .collect::<Vec<_>>();
+    let mut out = name.trim_ascii_end().to_vec();
+    out.push(b':');
+    let value = compress_wsp(&unfolded);
+    out.extend_from_slice(value.strip_prefix(b" ").unwrap_or(&value));
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+///the relaxed body canonicalization of rfc 6376 3.4.4
+pub fn relaxed_body(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut empty_lines = 0;
+    for line in body.split(|&b| b == b'\n') {
+        let line = compress_wsp(line.strip_suffix(b"\r").unwrap_or(line));
+        if line.is_empty() {
+            empty_lines += 1;
+            continue;
+        }
+        //empty lines only count if something comes after them
+        for _ in 0..empty_lines {
+            out.extend_from_slice(b"\r\n");
+        }
+        empty_lines = 0;
+        out.extend_from_slice(&line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+///the simple body canonicalization of rfc 6376 3.4.3, only the empty lines
+///at the end go
+pub fn simple_body(body: &[u8]) -> Vec<u8> {
+    let mut end = body.len();
+    while body[..end].ends_with(b"\r\n\r\n") {
+        end -= 2;
+    }
+    let mut out = body[..end].to_vec();
+    if !out.ends_with(b"\r\n") {
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .to_vec()
+}
+
+pub fn base64(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{relaxed_body, relaxed_header, simple_body, split_message};
+
+    #[test]
+    fn test_canonicalization() {
+        //rfc 6376 3.4.5
+        assert_eq!(relaxed_header(b"A: X\r\n"), b"a:X\r\n");
+        assert_eq!(relaxed_header(b"B : Y\t\r\n\tZ  \r\n"), b"b:Y Z\r\n");
+        assert_eq!(relaxed_body(b" C \r\nD \t E\r\n\r\n\r\n"), b" C\r\nD E\r\n");
+        assert_eq!(relaxed_body(b""), b"");
+        assert_eq!(relaxed_body(b"\r\n\r\n"), b"");
+        assert_eq!(relaxed_body(b"a\r\n\r\nb"), b"a\r\n\r\nb\r\n");
+        assert_eq!(
+            simple_body(b" C \r\nD \t E\r\n\r\n\r\n"),
+            b" C \r\nD \t E\r\n"
+        );
+        assert_eq!(simple_body(b""), b"\r\n");
+        assert_eq!(simple_body(b"a"), b"a\r\n");
+        let (fields, body) = split_message(b"A: X\r\nB : Y\t\r\n\tZ  \r\n\r\n C \r\n");
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[1].name(), "B");
+        assert_eq!(fields[1].raw, b"B : Y\t\r\n\tZ  \r\n");
+        assert_eq!(body, b" C \r\n");
+    }
+}
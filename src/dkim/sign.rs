@@ -0,0 +1,288 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair};
+use rustls_pemfile::Item;
+
+use super::{base64, relaxed_body, relaxed_header, sha256, split_message, Field};
+use crate::config::{Config, DkimConfig};
+
+///a private key outgoing mail is signed with
+pub enum SigningKey {
+    Rsa(RsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl SigningKey {
+    ///reads a pkcs8 or pkcs1 pem key
+    pub fn from_pem(pem: &[u8]) -> Result<Self> {
+        match rustls_pemfile::read_one(&mut Cursor::new(pem))? {
+            Some(Item::Pkcs8Key(key)) => {
+                let der = key.secret_pkcs8_der();
+                if let Ok(key) = RsaKeyPair::from_pkcs8(der) {
+                    return Ok(SigningKey::Rsa(key));
+                }
+                Ed25519KeyPair::from_pkcs8_maybe_unchecked(der)
+                    .map(SigningKey::Ed25519)
+                    .map_err(|e| anyhow!("not an rsa or ed25519 key: {e}"))
+            }
+            Some(Item::Pkcs1Key(key)) => RsaKeyPair::from_der(key.secret_pkcs1_der())
+                .map(SigningKey::Rsa)
+                .map_err(|e| anyhow!("invalid rsa key: {e}")),
+            _ => bail!("no private key found"),
+        }
+    }
+
+    ///the a= tag
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            SigningKey::Rsa(_) => "rsa-sha256",
+            SigningKey::Ed25519(_) => "ed25519-sha256",
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            SigningKey::Rsa(key) => {
+                let mut signature = vec![0; key.public().modulus_len()];
+                key.sign(
+                    &ring::signature::RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    data,
+                    &mut signature,
+                )
+                .map_err(|e| anyhow!("rsa signing failed: {e}"))?;
+                Ok(signature)
+            }
+            //rfc 8463, ed25519 signs the hash rather than the data
+            SigningKey::Ed25519(key) => Ok(key.sign(&sha256(data)).as_ref().to_vec()),
+        }
+    }
+}
+
+pub struct DomainKey {
+    pub domain: String,
+    pub selector: String,
+    pub key: SigningKey,
+    pub headers: Vec<String>,
+}
+
+///the dkim keys of every domain we host, loaded at startup
+#[derive(Default)]
+pub struct DkimSigner {
+    pub keys: Vec<DomainKey>,
+}
+
+impl DkimSigner {
+    pub fn load(config: &Config) -> Result<Self> {
+        let mut keys = Vec::new();
+        for domain in &config.domains {
+            for DkimConfig {
+                selector,
+                key,
+                headers,
+            } in &domain.dkim
+            {
+                let pem = std::fs::read(key)
+                    .with_context(|| format!("couldn't read dkim key {}", key.display()))?;
+                let key = SigningKey::from_pem(&pem)
+                    .with_context(|| format!("invalid dkim key {}", key.display()))?;
+                keys.push(DomainKey {
+                    domain: domain.name.clone(),
+                    selector: selector.clone(),
+                    key,
+                    headers: headers.iter().map(|h| h.to_ascii_lowercase()).collect(),
+                });
+            }
+        }
+        Ok(Self { keys })
+    }
+
+    ///adds a DKIM-Signature for every key of the From domain, mail from
+    ///domains without keys is returned as is
+    pub fn sign(&self, data: &[u8], now: i64) -> Result<Vec<u8>> {
+        let Some(domain) = from_domain(data) else {
+            return Ok(data.to_vec());
+        };
+        let mut signatures = Vec::new();
+        for key in self.keys.iter().filter(|k| k.domain == domain) {
+            signatures.extend_from_slice(&signature_header(key, data, now)?);
+        }
+        signatures.extend_from_slice(data);
+        Ok(signatures)
+    }
+
+    ///the key ARC sets for `domain` are signed with, its first one
+    pub fn key_for(&self, domain: &str) -> Option<&DomainKey> {
+        self.keys
+            .iter()
+            .find(|k| k.domain.eq_ignore_ascii_case(domain))
+    }
+}
+
+///the domain of the first address in From
+fn from_domain(data: &[u8]) -> Option<String> {
+    let (headers, _) = mailparse::parse_headers(data).ok()?;
+    let from = headers
+        .iter()
+        .find(|h| h.get_key_ref().eq_ignore_ascii_case("from"))?;
+    let addresses = mailparse::addrparse_header(from).ok()?;
+    let address = addresses.extract_single_info()?.addr;
+    let (_, domain) = address.rsplit_once('@')?;
+    Some(domain.to_ascii_lowercase())
+}
+
+///the h= names and the relaxed fields they cover. every instance is signed,
+///bottom up (rfc 6376 5.4.2)
+pub(super) fn sign_fields<'a>(
+    names: &'a [String],
+    fields: &[Field<'_>],
+) -> (Vec<&'a str>, Vec<u8>) {
+    let mut signed_names = Vec::new();
+    let mut hashed = Vec::new();
+    for name in names {
+        for field in fields
+            .iter()
+            .rev()
+            .filter(|f| f.name().eq_ignore_ascii_case(name))
+        {
+            signed_names.push(name.as_str());
+            hashed.extend_from_slice(&relaxed_header(field.raw));
+        }
+    }
+    (signed_names, hashed)
+}
+
+fn signature_header(key: &DomainKey, data: &[u8], now: i64) -> Result<Vec<u8>> {
+    let (fields, body) = split_message(data);
+    let body_hash = base64(&sha256(&relaxed_body(body)));
+    let (signed_names, mut hashed) = sign_fields(&key.headers, &fields);
+    let header = format!(
+        "DKIM-Signature: v=1; a={}; c=relaxed/relaxed; d={}; s={};\r\n\tt={now}; h={};\r\n\tbh={body_hash};\r\n\tb=",
+        key.key.algorithm(),
+        key.domain,
+        key.selector,
+        signed_names.join(":"),
+    );
+    //the signature's own header is hashed last, without its CRLF
+    let mut own = relaxed_header(header.as_bytes());
+    own.truncate(own.len() - 2);
+    hashed.extend_from_slice(&own);
+    let signature = base64(&key.key.sign(&hashed)?);
+    Ok(format!("{header}{signature}\r\n").into_bytes())
+}
+
+///writes a new private key to `path` and returns the TXT record to publish
+pub fn keygen(algorithm: &str, selector: &str, domain: &str, path: &Path) -> Result<String> {
+    let (pem, key_type, public) = match algorithm {
+        "rsa" => {
+            use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+            let key = rsa::RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 2048)?;
+            let pem = key.to_pkcs8_pem(LineEnding::LF)?.to_string();
+            let public = key.to_public_key().to_public_key_der()?;
+            (pem, "rsa", public.as_bytes().to_vec())
+        }
+        "ed25519" => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|e| anyhow!("couldn't generate a key: {e}"))?;
+            let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                .map_err(|e| anyhow!("generated an invalid key: {e}"))?;
+            //rfc 8463 publishes the bare public key
+            let public = key.public_key().as_ref().to_vec();
+            (pem("PRIVATE KEY", pkcs8.as_ref()), "ed25519", public)
+        }
+        _ => bail!("unknown algorithm {algorithm}, use rsa or ed25519"),
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write_private(path, pem.as_bytes())
+        .with_context(|| format!("couldn't write {}", path.display()))?;
+    let record = format!("v=DKIM1; k={key_type}; p={}", base64(&public));
+    //a TXT string can't be longer than 255 bytes, long ones are split
+    let strings = record
+        .as_bytes()
+        .chunks(255)
+        .map(|chunk| format!("\"{}\"", String::from_utf8_lossy(chunk)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(format!("{selector}._domainkey.{domain}. IN TXT {strings}"))
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem += &String::from_utf8_lossy(line);
+        pem += "\n";
+    }
+    pem + &format!("-----END {label}-----\n")
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DkimSigner, DomainKey, SigningKey};
+    use crate::dkim::{relaxed_header, sha256, split_message};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+    #[test]
+    fn test_sign() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public = key.public_key().as_ref().to_vec();
+        let signer = DkimSigner {
+            keys: vec![DomainKey {
+                domain: "kaki.foo".to_string(),
+                selector: "s1".to_string(),
+                key: SigningKey::Ed25519(key),
+                headers: vec!["from".to_string(), "subject".to_string()],
+            }],
+        };
+        let mail =
+            b"From: Alice <alice@Kaki.foo>\r\nSubject:  hi\r\nTo: bob@example.com\r\n\r\nhello\r\n";
+        let signed = signer.sign(mail, 1700000000).unwrap();
+        let signed = String::from_utf8(signed).unwrap();
+        assert!(signed.starts_with(
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=kaki.foo; s=s1;\r\n\tt=1700000000; h=from:subject;\r\n"
+        ));
+        assert!(signed.ends_with(std::str::from_utf8(mail).unwrap()));
+        //check it like a verifier would
+        let (fields, _) = split_message(signed.as_bytes());
+        let dkim = String::from_utf8_lossy(fields[0].raw).to_string();
+        let (unsigned, b) = dkim.rsplit_once("b=").unwrap();
+        let mut hashed = relaxed_header(fields[1].raw);
+        hashed.extend(relaxed_header(fields[2].raw));
+        let mut own = relaxed_header(format!("{unsigned}b=\r\n").as_bytes());
+        own.truncate(own.len() - 2);
+        hashed.extend(own);
+        let signature =
+            base64::Engine::decode(&base64::engine::general_purpose::STANDARD, b.trim_end())
+                .unwrap();
+        UnparsedPublicKey::new(&ED25519, public)
+            .verify(&sha256(&hashed), &signature)
+            .unwrap();
+        //mail from elsewhere isn't signed
+        let other = b"From: carol@example.com\r\n\r\nhi\r\n";
+        assert_eq!(signer.sign(other, 0).unwrap(), other);
+    }
+}
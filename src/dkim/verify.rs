@@ -0,0 +1,559 @@
+use base64::Engine;
+use ring::signature::{self, UnparsedPublicKey};
+use x509_parser::prelude::FromDer;
+use x509_parser::x509::SubjectPublicKeyInfo;
+
+use super::{relaxed_body, relaxed_header, sha256, simple_body, split_message, Field};
+use crate::email_auth::{AuthResolver, AuthStatus};
+
+///signatures past this many aren't checked, every one costs a dns lookup
+const MAX_SIGNATURES: usize = 5;
+
+///the result of checking one DKIM-Signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimResult {
+    pub status: AuthStatus,
+    ///the d= tag, the domain that vouches for the message
+    pub domain: String,
+    pub selector: String,
+    ///why it didn't pass
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Algorithm {
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+///the tags of a DKIM-Signature we use
+struct Signature {
+    algorithm: Algorithm,
+    signature: Vec<u8>,
+    body_hash: Vec<u8>,
+    header_canon: Canonicalization,
+    body_canon: Canonicalization,
+    domain: String,
+    selector: String,
+    headers: Vec<String>,
+    auid: Option<String>,
+    body_length: Option<usize>,
+    expires: Option<i64>,
+}
+
+///`a=b; c = d` -> [("a", "b"), ("c", "d")]. whitespace is folding and goes
+pub(super) fn tags(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|tag| {
+            let (name, value) = tag.split_once('=')?;
+            let value = value
+                .split(|c: char| c.is_ascii_whitespace())
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+            Some((name.trim().to_string(), value))
+        })
+        .collect()
+}
+
+pub(super) fn decode_base64(value: &str) -> Option<Vec<u8>> {
+    let compact = value.replace(' ', "");
+    base64::engine::general_purpose::STANDARD
+        .decode(compact)
+        .ok()
+}
+
+impl Signature {
+    ///the error is why the signature can't be used
+    fn parse(value: &str) -> Result<Self, String> {
+        let tags = tags(value);
+        let tag = |name: &str| {
+            tags.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        let required = |name: &str| tag(name).ok_or(format!("missing {name}= tag"));
+        if required("v")? != "1" {
+            return Err("unsupported version".to_string());
+        }
+        let algorithm = match required("a")? {
+            "rsa-sha256" => Algorithm::RsaSha256,
+            "ed25519-sha256" => Algorithm::Ed25519Sha256,
+            //rfc 8301 retired rsa-sha1
+            other => return Err(format!("unsupported algorithm {other}")),
+        };
+        let (header_canon, body_canon) = match tag("c").unwrap_or("simple/simple").split_once('/') {
+            Some((header, body)) => (canonicalization(header)?, canonicalization(body)?),
+            None => (
+                canonicalization(tag("c").unwrap_or("simple"))?,
+                Canonicalization::Simple,
+            ),
+        };
+        let domain = required("d")?.to_ascii_lowercase();
+        let headers = required("h")?
+            .split(':')
+            .map(|h| h.trim().to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        if !headers.iter().any(|h| h == "from") {
+            return Err("From isn't signed".to_string());
+        }
+        let auid = tag("i").map(str::to_string);
+        if let Some(auid) = &auid {
+            let auid_domain = auid.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+            if !is_subdomain(auid_domain, &domain) {
+                return Err("i= isn't in the d= domain".to_string());
+            }
+        }
+        let number = |name: &str| -> Result<Option<i64>, String> {
+            tag(name)
+                .map(|v| v.parse::<i64>().map_err(|_| format!("invalid {name}= tag")))
+                .transpose()
+        };
+        Ok(Signature {
+            algorithm,
+            signature: decode_base64(required("b")?).ok_or("invalid b= tag")?,
+            body_hash: decode_base64(required("bh")?).ok_or("invalid bh= tag")?,
+            header_canon,
+            body_canon,
+            domain,
+            selector: required("s")?.to_string(),
+            headers,
+            auid,
+            body_length: number("l")?.map(|l| l.max(0) as usize),
+            expires: number("x")?,
+        })
+    }
+}
+
+pub(super) fn canonicalization(name: &str) -> Result<Canonicalization, String> {
+    match name {
+        "simple" => Ok(Canonicalization::Simple),
+        "relaxed" => Ok(Canonicalization::Relaxed),
+        other => Err(format!("unknown canonicalization {other}")),
+    }
+}
+
+fn is_subdomain(child: &str, parent: &str) -> bool {
+    child.eq_ignore_ascii_case(parent)
+        || child
+            .to_ascii_lowercase()
+            .ends_with(&format!(".{}", parent.to_ascii_lowercase()))
+}
+
+///the public key from a `v=DKIM1; k=rsa; p=...` record
+pub(super) struct PublicKey {
+    pub algorithm: Algorithm,
+    key: Vec<u8>,
+    ///t=s, the i= domain has to be d= exactly
+    strict: bool,
+}
+
+impl PublicKey {
+    pub fn parse(record: &str) -> Result<Self, String> {
+        let tags = tags(record);
+        let tag = |name: &str| {
+            tags.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        if tag("v").is_some_and(|v| v != "DKIM1") {
+            return Err("invalid key record version".to_string());
+        }
+        let algorithm = match tag("k").unwrap_or("rsa") {
+            "rsa" => Algorithm::RsaSha256,
+            "ed25519" => Algorithm::Ed25519Sha256,
+            other => return Err(format!("unknown key type {other}")),
+        };
+        let p = tag("p").ok_or("key record without p=")?;
+        if p.is_empty() {
+            return Err("the key was revoked".to_string());
+        }
+        let key = decode_base64(p).ok_or("invalid p= tag")?;
+        let strict = tag("t").is_some_and(|t| t.split(':').any(|flag| flag.trim() == "s"));
+        Ok(PublicKey {
+            algorithm,
+            key,
+            strict,
+        })
+    }
+
+    pub fn verify(&self, hashed: &[u8], signature: &[u8]) -> bool {
+        match self.algorithm {
+            Algorithm::RsaSha256 => {
+                //p= is a SubjectPublicKeyInfo, ring wants the RSAPublicKey in it
+                let key = match SubjectPublicKeyInfo::from_der(&self.key) {
+                    Ok((_, spki)) => spki.subject_public_key.data.to_vec(),
+                    Err(_) => self.key.clone(),
+                };
+                //rfc 8301 still allows 1024 bit keys
+                UnparsedPublicKey::new(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    key,
+                )
+                .verify(hashed, signature)
+                .is_ok()
+            }
+            Algorithm::Ed25519Sha256 => UnparsedPublicKey::new(&signature::ED25519, &self.key)
+                .verify(&sha256(hashed), signature)
+                .is_ok(),
+        }
+    }
+}
+
+///the DKIM-Signature field with the value of its b= tag removed
+pub(super) fn without_signature(raw: &[u8]) -> Vec<u8> {
+    let (field, crlf) = match raw.strip_suffix(b"\r\n") {
+        Some(field) => (field, &b"\r\n"[..]),
+        None => (raw, &b""[..]),
+    };
+    let colon = field.iter().position(|&b| b == b':').unwrap_or(0);
+    let mut out = field[..=colon].to_vec();
+    let value = &field[colon + 1..];
+    let tags = value.split(|&b| b == b';').collect::<Vec<_>>();
+    for (i, tag) in tags.iter().enumerate() {
+        if i > 0 {
+            out.push(b';');
+        }
+        let eq = tag.iter().position(|&b| b == b'=');
+        match eq {
+            Some(eq) if tag[..eq].trim_ascii() == b"b" => out.extend_from_slice(&tag[..=eq]),
+            _ => out.extend_from_slice(tag),
+        }
+    }
+    out.extend_from_slice(crlf);
+    out
+}
+
+pub(super) fn canonical_header(canon: Canonicalization, raw: &[u8]) -> Vec<u8> {
+    match canon {
+        Canonicalization::Simple => raw.to_vec(),
+        Canonicalization::Relaxed => relaxed_header(raw),
+    }
+}
+
+///the key published for `selector` at `domain`, or the status to give
+///the signature and why
+pub(super) async fn public_key(
+    dns: &impl AuthResolver,
+    selector: &str,
+    domain: &str,
+) -> Result<PublicKey, (AuthStatus, String)> {
+    let name = format!("{selector}._domainkey.{domain}");
+    let records = dns
+        .txt(&name)
+        .await
+        .map_err(|e| (AuthStatus::TempError, format!("key lookup failed: {e}")))?;
+    match records.first().map(|r| PublicKey::parse(r)) {
+        Some(Ok(key)) => Ok(key),
+        Some(Err(reason)) => Err((AuthStatus::PermError, reason)),
+        None => Err((AuthStatus::PermError, "no key published".to_string())),
+    }
+}
+
+///the canonicalized fields a signature's h= names. each name takes the
+///last instance not taken yet (rfc 6376 5.4.2)
+pub(super) fn signed_headers(
+    fields: &[Field<'_>],
+    names: &[String],
+    canon: Canonicalization,
+) -> Vec<u8> {
+    let mut hashed = Vec::new();
+    let mut used = vec![false; fields.len()];
+    for name in names {
+        let found = fields
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(i, f)| !used[*i] && f.name().eq_ignore_ascii_case(name));
+        if let Some((i, f)) = found {
+            used[i] = true;
+            hashed.extend_from_slice(&canonical_header(canon, f.raw));
+        }
+    }
+    hashed
+}
+
+///checks every DKIM-Signature on the message, newest first
+pub async fn verify(dns: &impl AuthResolver, data: &[u8], now: i64) -> Vec<DkimResult> {
+    let (fields, body) = split_message(data);
+    let mut results = Vec::new();
+    for field in fields
+        .iter()
+        .filter(|f| f.name().eq_ignore_ascii_case("DKIM-Signature"))
+        .take(MAX_SIGNATURES)
+    {
+        results.push(verify_one(dns, &fields, field, body, now).await);
+    }
+    results
+}
+
+async fn verify_one(
+    dns: &impl AuthResolver,
+    fields: &[Field<'_>],
+    field: &Field<'_>,
+    body: &[u8],
+    now: i64,
+) -> DkimResult {
+    let value = String::from_utf8_lossy(field.raw);
+    let value = value.split_once(':').map(|(_, v)| v).unwrap_or_default();
+    let signature = match Signature::parse(value) {
+        Ok(signature) => signature,
+        Err(reason) => {
+            let tags = tags(value);
+            let tag = |name: &str| {
+                tags.iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, v)| v.clone())
+                    .unwrap_or_default()
+            };
+            return DkimResult {
+                status: AuthStatus::PermError,
+                domain: tag("d"),
+                selector: tag("s"),
+                reason: Some(reason),
+            };
+        }
+    };
+    let result = |status, reason: Option<&str>| DkimResult {
+        status,
+        domain: signature.domain.clone(),
+        selector: signature.selector.clone(),
+        reason: reason.map(str::to_string),
+    };
+    if signature.expires.is_some_and(|x| x < now) {
+        return result(AuthStatus::Fail, Some("the signature expired"));
+    }
+    let canonical_body = match signature.body_canon {
+        Canonicalization::Simple => simple_body(body),
+        Canonicalization::Relaxed => relaxed_body(body),
+    };
+    let canonical_body = match signature.body_length {
+        Some(l) if l > canonical_body.len() => {
+            return result(AuthStatus::PermError, Some("l= is longer than the body"));
+        }
+        Some(l) => &canonical_body[..l],
+        None => &canonical_body[..],
+    };
+    if sha256(canonical_body) != signature.body_hash {
+        return result(AuthStatus::Fail, Some("body hash mismatch"));
+    }
+    let key = match public_key(dns, &signature.selector, &signature.domain).await {
+        Ok(key) => key,
+        Err((status, reason)) => return result(status, Some(&reason)),
+    };
+    if key.algorithm != signature.algorithm {
+        return result(
+            AuthStatus::PermError,
+            Some("the key is for another algorithm"),
+        );
+    }
+    if key.strict
+        && signature.auid.as_deref().is_some_and(|auid| {
+            !auid
+                .rsplit_once('@')
+                .is_some_and(|(_, d)| d.eq_ignore_ascii_case(&signature.domain))
+        })
+    {
+        return result(AuthStatus::PermError, Some("i= has to be d= for this key"));
+    }
+    let mut hashed = signed_headers(fields, &signature.headers, signature.header_canon);
+    let mut own = canonical_header(signature.header_canon, &without_signature(field.raw));
+    if own.ends_with(b"\r\n") {
+        own.truncate(own.len() - 2);
+    }
+    hashed.extend_from_slice(&own);
+    if key.verify(&hashed, &signature.signature) {
+        result(AuthStatus::Pass, None)
+    } else {
+        result(AuthStatus::Fail, Some("signature mismatch"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use rsa::pkcs8::{DecodePrivateKey, EncodePublicKey};
+
+    use super::{verify, without_signature};
+    use crate::dkim::sign::{DomainKey, SigningKey};
+    use crate::dkim::{base64, relaxed_body, relaxed_header, sha256, DkimSigner};
+    use crate::email_auth::tests::FakeDns;
+    use crate::email_auth::AuthStatus;
+
+    const RSA_KEY: &str = include_str!("testdata/rsa.pem");
+    const NOW: i64 = 1700000000;
+
+    ///an rsa and an ed25519 key for kaki.foo, and dns publishing them
+    fn keys() -> (DkimSigner, FakeDns) {
+        let rsa_public = rsa::RsaPrivateKey::from_pkcs8_pem(RSA_KEY)
+            .unwrap()
+            .to_public_key()
+            .to_public_key_der()
+            .unwrap();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let ed25519 = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let dns = FakeDns::default()
+            .txt(
+                "rsa._domainkey.kaki.foo",
+                &format!("v=DKIM1; k=rsa; p={}", base64(rsa_public.as_bytes())),
+            )
+            .txt(
+                "ed._domainkey.kaki.foo",
+                &format!(
+                    "v=DKIM1; k=ed25519; p={}",
+                    base64(ed25519.public_key().as_ref())
+                ),
+            )
+            .txt("revoked._domainkey.kaki.foo", "v=DKIM1; p=")
+            .broken("broken._domainkey.kaki.foo");
+        let key = |selector: &str, key| DomainKey {
+            domain: "kaki.foo".to_string(),
+            selector: selector.to_string(),
+            key,
+            headers: vec!["from".to_string(), "subject".to_string()],
+        };
+        let signer = DkimSigner {
+            keys: vec![
+                key("rsa", SigningKey::from_pem(RSA_KEY.as_bytes()).unwrap()),
+                key("ed", SigningKey::Ed25519(ed25519)),
+            ],
+        };
+        (signer, dns)
+    }
+
+    ///signs `mail` with the ed25519 key, `tags` go in the signature as is
+    fn sign_with(
+        signer: &DkimSigner,
+        tags: &str,
+        body_length: Option<usize>,
+        mail: &str,
+    ) -> String {
+        let (headers, body) = mail.split_once("\r\n\r\n").unwrap();
+        let body = relaxed_body(body.as_bytes());
+        let body = &body[..body_length.unwrap_or(body.len()).min(body.len())];
+        let length = body_length.map(|l| format!(" l={l};")).unwrap_or_default();
+        let header = format!(
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=kaki.foo; s=ed; h=from;{length} {tags} bh={}; b=",
+            base64(&sha256(body))
+        );
+        let from = headers.lines().find(|l| l.starts_with("From:")).unwrap();
+        let mut hashed = relaxed_header(format!("{from}\r\n").as_bytes());
+        let mut own = relaxed_header(format!("{header}\r\n").as_bytes());
+        own.truncate(own.len() - 2);
+        hashed.extend(own);
+        let signature = base64(&signer.keys[1].key.sign(&hashed).unwrap());
+        format!("{header}{signature}\r\n{mail}")
+    }
+
+    #[test]
+    fn test_without_signature() {
+        assert_eq!(
+            without_signature(b"DKIM-Signature: v=1; bh=abc;\r\n\tb=de\r\n\tf; d=x\r\n"),
+            b"DKIM-Signature: v=1; bh=abc;\r\n\tb=; d=x\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let (signer, dns) = keys();
+        let mail =
+            "From: alice@kaki.foo\r\nSubject: hi\r\nTo: bob@example.com\r\n\r\nhello  \r\n\r\n";
+        let signed = String::from_utf8(signer.sign(mail.as_bytes(), NOW).unwrap()).unwrap();
+        let results = verify(&dns, signed.as_bytes(), NOW).await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results
+                .iter()
+                .map(|r| r.selector.as_str())
+                .collect::<Vec<_>>(),
+            ["rsa", "ed"]
+        );
+        for result in &results {
+            assert_eq!(result.status, AuthStatus::Pass, "{result:?}");
+            assert_eq!(result.domain, "kaki.foo");
+        }
+        //relaxed canonicalization survives whitespace changes and headers
+        //that aren't signed
+        let respaced = format!("Received: by mx\r\n{signed}")
+            .replace("Subject: hi", "Subject:   hi")
+            .replace("hello  ", "hello");
+        let results = verify(&dns, respaced.as_bytes(), NOW).await;
+        assert!(results.iter().all(|r| r.status == AuthStatus::Pass));
+        //but not content changes
+        let tampered = signed.replace("Subject: hi", "Subject: ho");
+        let results = verify(&dns, tampered.as_bytes(), NOW).await;
+        assert!(results.iter().all(|r| r.status == AuthStatus::Fail));
+        assert_eq!(results[0].reason.as_deref(), Some("signature mismatch"));
+        let tampered = signed.replace("hello", "goodbye");
+        let results = verify(&dns, tampered.as_bytes(), NOW).await;
+        assert_eq!(results[1].reason.as_deref(), Some("body hash mismatch"));
+        //a second Subject below the signed one is the one that counts
+        let added = signed.replace("To: bob", "Subject: buy now\r\nTo: bob");
+        let results = verify(&dns, added.as_bytes(), NOW).await;
+        assert!(results.iter().all(|r| r.status == AuthStatus::Fail));
+        //keys that aren't there
+        let results = verify(&FakeDns::default(), signed.as_bytes(), NOW).await;
+        assert_eq!(results[0].status, AuthStatus::PermError);
+        for (selector, status) in [
+            ("revoked", AuthStatus::PermError),
+            ("broken", AuthStatus::TempError),
+        ] {
+            let moved = signed.replace("s=ed;", &format!("s={selector};"));
+            let results = verify(&dns, moved.as_bytes(), NOW).await;
+            assert_eq!(results[1].status, status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_tags() {
+        let (signer, dns) = keys();
+        let mail = "From: alice@kaki.foo\r\nTo: bob@example.com\r\n\r\nhello\r\n";
+        let check = |tags: &str, body_length: Option<usize>, mail: &str| {
+            let signed = sign_with(&signer, tags, body_length, mail);
+            let dns = &dns;
+            async move { verify(dns, signed.as_bytes(), NOW).await.remove(0) }
+        };
+        assert_eq!(check("", None, mail).await.status, AuthStatus::Pass);
+        assert_eq!(
+            check(&format!("x={};", NOW + 60), None, mail).await.status,
+            AuthStatus::Pass
+        );
+        let expired = check(&format!("x={};", NOW - 60), None, mail).await;
+        assert_eq!(expired.status, AuthStatus::Fail);
+        assert_eq!(expired.reason.as_deref(), Some("the signature expired"));
+        assert_eq!(
+            check("i=bob@mail.kaki.foo;", None, mail).await.status,
+            AuthStatus::Pass
+        );
+        assert_eq!(
+            check("i=bob@evil.com;", None, mail).await.status,
+            AuthStatus::PermError
+        );
+        //whatever comes after l= bytes isn't covered
+        let appended = format!("{mail}P.S. buy now\r\n");
+        assert_eq!(check("", Some(7), &appended).await.status, AuthStatus::Pass);
+        let longer = check("", Some(100), mail).await;
+        assert_eq!(longer.status, AuthStatus::PermError);
+        //broken signatures
+        let broken = |tags: &str| {
+            let mail = format!("DKIM-Signature: {tags}\r\n{mail}");
+            let dns = &dns;
+            async move { verify(dns, mail.as_bytes(), NOW).await.remove(0) }
+        };
+        let result = broken("v=1; a=rsa-sha1; d=kaki.foo; s=rsa; h=from; bh=AA==; b=AA==").await;
+        assert_eq!(result.status, AuthStatus::PermError);
+        assert_eq!(result.domain, "kaki.foo");
+        let result = broken("v=1; a=rsa-sha256; d=kaki.foo; s=rsa; h=to; bh=AA==; b=AA==").await;
+        assert_eq!(result.reason.as_deref(), Some("From isn't signed"));
+        let result = broken("v=1; a=rsa-sha256; s=rsa; h=from; bh=AA==; b=AA==").await;
+        assert_eq!(result.reason.as_deref(), Some("missing d= tag"));
+    }
+}
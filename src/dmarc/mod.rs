@@ -0,0 +1,484 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Context, Result};
+use publicsuffix::{List, Psl};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::email_auth::{AuthResolver, AuthStatus};
+
+///aggregate reports, rfc 7489 7.2
+pub mod report;
+
+static PUBLIC_SUFFIXES: OnceLock<List> = OnceLock::new();
+
+///what the domain owner wants done with mail that fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    None,
+    ///deliver it into the junk mailbox
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Some(Policy::None),
+            "quarantine" => Some(Policy::Quarantine),
+            "reject" => Some(Policy::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::None => "none",
+            Policy::Quarantine => "quarantine",
+            Policy::Reject => "reject",
+        }
+    }
+
+    ///applied to the messages pct= leaves out (rfc 7489 6.6.4)
+    fn lesser(self) -> Self {
+        match self {
+            Policy::Reject => Policy::Quarantine,
+            _ => Policy::None,
+        }
+    }
+}
+
+///how closely the authenticated domain has to match From
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    ///the same organizational domain is enough
+    Relaxed,
+    Strict,
+}
+
+impl Alignment {
+    fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some(v) if v.eq_ignore_ascii_case("s") => Alignment::Strict,
+            _ => Alignment::Relaxed,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Alignment::Relaxed => "r",
+            Alignment::Strict => "s",
+        }
+    }
+
+    pub fn aligned(self, a: &str, b: &str) -> bool {
+        match self {
+            Alignment::Strict => a
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(b.trim_end_matches('.')),
+            Alignment::Relaxed => organizational_domain(a) == organizational_domain(b),
+        }
+    }
+}
+
+///a _dmarc TXT record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmarcRecord {
+    ///where it was found, the From domain or its organizational domain
+    pub domain: String,
+    pub policy: Policy,
+    ///sp=, for subdomains of the organizational domain
+    pub subdomain_policy: Option<Policy>,
+    pub dkim_alignment: Alignment,
+    pub spf_alignment: Alignment,
+    ///the share of failing mail the policy is applied to
+    pub percent: u8,
+    ///the rua= uris aggregate reports go to
+    pub aggregate_reports: Vec<String>,
+}
+
+impl DmarcRecord {
+    ///None if it isn't a valid record (rfc 7489 6.3)
+    pub fn parse(domain: &str, txt: &str) -> Option<Self> {
+        let tags = txt
+            .split(';')
+            .filter_map(|tag| tag.split_once('='))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+            .collect::<Vec<_>>();
+        let tag = |name: &str| tags.iter().find(|(n, _)| n == name).map(|(_, v)| *v);
+        if tags.first().map(|(n, v)| (n.as_str(), *v)) != Some(("v", "DMARC1")) {
+            return None;
+        }
+        let aggregate_reports = tag("rua")
+            .map(|rua| {
+                rua.split(',')
+                    .map(|uri| uri.trim().to_string())
+                    .filter(|uri| !uri.is_empty())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        //a record without a valid p= is only used if it asks for reports
+        let policy = match tag("p").and_then(Policy::parse) {
+            Some(policy) => policy,
+            None if !aggregate_reports.is_empty() => Policy::None,
+            None => return None,
+        };
+        Some(DmarcRecord {
+            domain: domain.to_ascii_lowercase(),
+            policy,
+            subdomain_policy: tag("sp").and_then(Policy::parse),
+            dkim_alignment: Alignment::parse(tag("adkim")),
+            spf_alignment: Alignment::parse(tag("aspf")),
+            percent: tag("pct")
+                .and_then(|pct| pct.parse::<u8>().ok())
+                .filter(|&pct| pct <= 100)
+                .unwrap_or(100),
+            aggregate_reports,
+        })
+    }
+
+    ///the record as it would be published, parse gives it back
+    pub fn to_txt(&self) -> String {
+        let mut txt = format!("v=DMARC1; p={}", self.policy.name());
+        if let Some(sp) = self.subdomain_policy {
+            txt += &format!("; sp={}", sp.name());
+        }
+        txt += &format!(
+            "; adkim={}; aspf={}; pct={}",
+            self.dkim_alignment.name(),
+            self.spf_alignment.name(),
+            self.percent
+        );
+        if !self.aggregate_reports.is_empty() {
+            txt += &format!("; rua={}", self.aggregate_reports.join(","));
+        }
+        txt
+    }
+
+    ///the policy for mail from `from_domain`
+    fn policy_for(&self, from_domain: &str) -> Policy {
+        if self.domain.eq_ignore_ascii_case(from_domain) {
+            self.policy
+        } else {
+            self.subdomain_policy.unwrap_or(self.policy)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmarcResult {
+    pub status: AuthStatus,
+    ///the policy of the From domain, if it publishes one
+    pub record: Option<DmarcRecord>,
+    ///what should happen to the message
+    pub disposition: Policy,
+    ///spf passed for a domain aligned with From
+    pub aligned_spf: bool,
+    ///a signature from a domain aligned with From passed
+    pub aligned_dkim: bool,
+    ///the trusted ARC sealer whose results the policy was waived for
+    pub arc_override: Option<String>,
+}
+
+impl DmarcResult {
+    pub fn new(status: AuthStatus) -> Self {
+        Self {
+            status,
+            record: None,
+            disposition: Policy::None,
+            aligned_spf: false,
+            aligned_dkim: false,
+            arc_override: None,
+        }
+    }
+}
+
+///loads the public suffix list organizational domains are found with.
+///without it the last two labels of a name are its organizational domain
+pub fn load_public_suffixes(path: &Path) -> Result<()> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("couldn't read the public suffix list {}", path.display()))?;
+    let list = List::from_bytes(&data).map_err(|e| anyhow!("invalid public suffix list: {e}"))?;
+    let _ = PUBLIC_SUFFIXES.set(list);
+    Ok(())
+}
+
+///the registered domain `domain` is under, eg. example.co.uk for
+///mail.example.co.uk (rfc 7489 3.2)
+pub fn organizational_domain(domain: &str) -> String {
+    organizational_domain_in(PUBLIC_SUFFIXES.get(), domain)
+}
+
+fn organizational_domain_in(list: Option<&List>, domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let found = match list {
+        Some(list) => list
+            .domain(domain.as_bytes())
+            .map(|d| String::from_utf8_lossy(d.as_bytes()).to_string()),
+        None => {
+            let labels = domain.rsplitn(3, '.').collect::<Vec<_>>();
+            (labels.len() >= 2).then(|| format!("{}.{}", labels[1], labels[0]))
+        }
+    };
+    //a public suffix is its own organizational domain
+    found.unwrap_or(domain)
+}
+
+///finds the record for `from_domain`, falling back to its organizational
+///domain. an error means dns failed
+async fn discover(resolver: &impl AuthResolver, from_domain: &str) -> Result<Option<DmarcRecord>> {
+    let org_domain = organizational_domain(from_domain);
+    let mut names = vec![from_domain.to_ascii_lowercase()];
+    if org_domain != names[0] {
+        names.push(org_domain);
+    }
+    for name in names {
+        let records = resolver.txt(&format!("_dmarc.{name}")).await?;
+        let records = records
+            .iter()
+            .filter(|txt| {
+                txt.split(';')
+                    .next()
+                    .is_some_and(|v| v.replace(' ', "") == "v=DMARC1")
+            })
+            .collect::<Vec<_>>();
+        match records[..] {
+            [] => continue,
+            [record] => return Ok(DmarcRecord::parse(&name, record)),
+            //several records are no policy at all
+            _ => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+///checks the message against the policy of `header_domain`.
+///`dkim_domains` are the d= domains of the signatures that passed, the
+///spf result is for `envelope_domain`
+pub async fn check(
+    resolver: &impl AuthResolver,
+    header_domain: &str,
+    envelope_domain: &str,
+    spf: AuthStatus,
+    dkim_domains: &[&str],
+) -> DmarcResult {
+    let record = match discover(resolver, header_domain).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return DmarcResult::new(AuthStatus::None),
+        Err(e) => {
+            tracing::debug!("dmarc lookup for {header_domain} failed: {:?}", e);
+            return DmarcResult::new(AuthStatus::TempError);
+        }
+    };
+    let mut sample = [0; 4];
+    let sample = match SystemRandom::new().fill(&mut sample) {
+        Ok(()) => (u32::from_le_bytes(sample) % 100) as u8,
+        Err(_) => 0,
+    };
+    evaluate(
+        record,
+        header_domain,
+        envelope_domain,
+        spf,
+        dkim_domains,
+        sample,
+    )
+}
+
+///`sample` is a random number below 100, the policy applies if it's below pct=
+fn evaluate(
+    record: DmarcRecord,
+    header_domain: &str,
+    envelope_domain: &str,
+    spf: AuthStatus,
+    dkim_domains: &[&str],
+    sample: u8,
+) -> DmarcResult {
+    let aligned_spf =
+        spf == AuthStatus::Pass && record.spf_alignment.aligned(header_domain, envelope_domain);
+    let aligned_dkim = dkim_domains
+        .iter()
+        .any(|domain| record.dkim_alignment.aligned(header_domain, domain));
+    if aligned_spf || aligned_dkim {
+        return DmarcResult {
+            status: AuthStatus::Pass,
+            record: Some(record),
+            disposition: Policy::None,
+            aligned_spf,
+            aligned_dkim,
+            arc_override: None,
+        };
+    }
+    let policy = record.policy_for(header_domain);
+    let disposition = if sample < record.percent {
+        policy
+    } else {
+        policy.lesser()
+    };
+    DmarcResult {
+        status: AuthStatus::Fail,
+        record: Some(record),
+        disposition,
+        aligned_spf,
+        aligned_dkim,
+        arc_override: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use publicsuffix::List;
+
+    use super::{check, evaluate, organizational_domain_in, Alignment, DmarcRecord, Policy};
+    use crate::email_auth::tests::FakeDns;
+    use crate::email_auth::AuthStatus;
+
+    #[test]
+    fn test_organizational_domain() {
+        let list: List = "// ===BEGIN ICANN DOMAINS===\ncom\nuk\nco.uk\n*.ck\n!www.ck\n"
+            .parse()
+            .unwrap();
+        let org = |domain| organizational_domain_in(Some(&list), domain);
+        assert_eq!(org("mail.example.com"), "example.com");
+        assert_eq!(org("a.b.example.co.uk"), "example.co.uk");
+        assert_eq!(org("Example.COM."), "example.com");
+        assert_eq!(org("co.uk"), "co.uk");
+        assert_eq!(org("shop.foo.ck"), "shop.foo.ck");
+        assert_eq!(org("www.ck"), "www.ck");
+        //without the list
+        assert_eq!(
+            organizational_domain_in(None, "a.b.example.com"),
+            "example.com"
+        );
+        assert_eq!(organizational_domain_in(None, "localhost"), "localhost");
+    }
+
+    #[test]
+    fn test_parse() {
+        let record = DmarcRecord::parse(
+            "kaki.foo",
+            "v=DMARC1; p=quarantine; sp=reject; adkim=s; pct=20; rua=mailto:a@kaki.foo, mailto:b@example.com!10m",
+        )
+        .unwrap();
+        assert_eq!(record.policy, Policy::Quarantine);
+        assert_eq!(record.subdomain_policy, Some(Policy::Reject));
+        assert_eq!(record.dkim_alignment, Alignment::Strict);
+        assert_eq!(record.spf_alignment, Alignment::Relaxed);
+        assert_eq!(record.percent, 20);
+        assert_eq!(
+            record.aggregate_reports,
+            ["mailto:a@kaki.foo", "mailto:b@example.com!10m"]
+        );
+        assert_eq!(
+            DmarcRecord::parse("kaki.foo", &record.to_txt()),
+            Some(record)
+        );
+        assert!(DmarcRecord::parse("kaki.foo", "p=reject; v=DMARC1").is_none());
+        assert!(DmarcRecord::parse("kaki.foo", "v=DMARC1; p=bounce").is_none());
+        //an invalid p= with rua= is p=none
+        let record = DmarcRecord::parse("kaki.foo", "v=DMARC1; rua=mailto:a@kaki.foo").unwrap();
+        assert_eq!(record.policy, Policy::None);
+        let record = DmarcRecord::parse("kaki.foo", "v=DMARC1; p=reject; pct=101").unwrap();
+        assert_eq!(record.percent, 100);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let record = |txt| DmarcRecord::parse("example.com", txt).unwrap();
+        let reject = record("v=DMARC1; p=reject");
+        let eval = |record: &DmarcRecord, from, mail_from, spf, dkim: &[&str], sample| {
+            evaluate(record.clone(), from, mail_from, spf, dkim, sample)
+        };
+        //relaxed alignment takes any name under the organizational domain
+        let result = eval(
+            &reject,
+            "example.com",
+            "bounce.example.com",
+            AuthStatus::Pass,
+            &[],
+            0,
+        );
+        assert_eq!(result.status, AuthStatus::Pass);
+        let result = eval(
+            &reject,
+            "example.com",
+            "x.org",
+            AuthStatus::Pass,
+            &["mail.example.com"],
+            0,
+        );
+        assert_eq!(result.status, AuthStatus::Pass);
+        //a pass that isn't aligned doesn't count
+        let result = eval(
+            &reject,
+            "example.com",
+            "x.org",
+            AuthStatus::Pass,
+            &["x.org"],
+            0,
+        );
+        assert_eq!(result.status, AuthStatus::Fail);
+        assert_eq!(result.disposition, Policy::Reject);
+        //strict alignment wants the exact name
+        let strict = record("v=DMARC1; p=reject; adkim=s; aspf=s");
+        let result = eval(
+            &strict,
+            "example.com",
+            "bounce.example.com",
+            AuthStatus::Pass,
+            &["mail.example.com"],
+            0,
+        );
+        assert_eq!(result.status, AuthStatus::Fail);
+        let result = eval(
+            &strict,
+            "example.com",
+            "x.org",
+            AuthStatus::Fail,
+            &["example.com"],
+            0,
+        );
+        assert_eq!(result.status, AuthStatus::Pass);
+        //subdomains get sp=
+        let sub = record("v=DMARC1; p=reject; sp=quarantine");
+        let result = eval(&sub, "news.example.com", "x.org", AuthStatus::Fail, &[], 0);
+        assert_eq!(result.disposition, Policy::Quarantine);
+        //pct= only applies the policy to a share of the mail
+        let pct = record("v=DMARC1; p=reject; pct=25");
+        assert_eq!(
+            eval(&pct, "example.com", "x.org", AuthStatus::Fail, &[], 24).disposition,
+            Policy::Reject
+        );
+        assert_eq!(
+            eval(&pct, "example.com", "x.org", AuthStatus::Fail, &[], 25).disposition,
+            Policy::Quarantine
+        );
+        let pct = record("v=DMARC1; p=quarantine; pct=0");
+        assert_eq!(
+            eval(&pct, "example.com", "x.org", AuthStatus::Fail, &[], 0).disposition,
+            Policy::None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let dns = FakeDns::default()
+            .txt("_dmarc.kaki.foo", "v=DMARC1; p=reject; sp=quarantine")
+            .txt("_dmarc.kaki.foo", "not dmarc")
+            .txt("_dmarc.two.com", "v=DMARC1; p=reject")
+            .txt("_dmarc.two.com", "v=DMARC1; p=none")
+            .broken("_dmarc.broken.com");
+        let dmarc = |from| check(&dns, from, "x.org", AuthStatus::Fail, &[]);
+        let result = dmarc("kaki.foo").await;
+        assert_eq!(result.status, AuthStatus::Fail);
+        assert_eq!(result.disposition, Policy::Reject);
+        //the organizational domain's record, with its subdomain policy
+        let result = dmarc("mail.kaki.foo").await;
+        assert_eq!(result.record.unwrap().domain, "kaki.foo");
+        assert_eq!(result.disposition, Policy::Quarantine);
+        assert_eq!(dmarc("two.com").await.status, AuthStatus::None);
+        assert_eq!(dmarc("nothing.com").await.status, AuthStatus::None);
+        assert_eq!(dmarc("broken.com").await.status, AuthStatus::TempError);
+    }
+}
@@ -0,0 +1,686 @@
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use mailparse::MailAddr;
+use quick_xml::{escape::escape, events::Event, Reader};
+use tokio::sync::{broadcast::Sender, Mutex, Notify};
+
+use super::{organizational_domain, Alignment, DmarcRecord, DmarcResult, Policy};
+use crate::config::Config;
+use crate::database::{Change, DBClient};
+use crate::dkim::DkimSigner;
+use crate::dns::Dns;
+use crate::email_auth::{AuthResolver, AuthStatus, IncomingAuthResult};
+use crate::smtp_common::Mail;
+
+///a signature in the auth_results of a row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimAuth {
+    pub domain: String,
+    pub selector: String,
+    pub result: AuthStatus,
+}
+
+///the messages from one ip that had the same outcome
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Row {
+    pub source_ip: String,
+    pub count: i64,
+    pub header_from: String,
+    ///the domain spf was checked for
+    pub envelope_from: String,
+    ///what was done with the messages
+    pub disposition: Policy,
+    pub aligned_dkim: bool,
+    pub aligned_spf: bool,
+    pub dkim: Vec<DkimAuth>,
+    pub spf: AuthStatus,
+}
+
+impl Row {
+    ///the row a single incoming message counts towards
+    pub fn new(source_ip: &str, auth: &IncomingAuthResult) -> Self {
+        let DmarcResult {
+            disposition,
+            aligned_dkim,
+            aligned_spf,
+            ..
+        } = auth.dmarc;
+        Self {
+            source_ip: source_ip.to_string(),
+            count: 1,
+            header_from: auth.header_domain.clone(),
+            envelope_from: auth.envelope_domain.clone(),
+            disposition,
+            aligned_dkim,
+            aligned_spf,
+            dkim: auth
+                .dkim_signatures
+                .iter()
+                .map(|s| DkimAuth {
+                    domain: s.domain.clone(),
+                    selector: s.selector.clone(),
+                    result: s.status,
+                })
+                .collect(),
+            spf: auth.spf,
+        }
+    }
+}
+
+///an aggregate report, the <feedback> document of rfc 7489 appendix C
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feedback {
+    ///who made the report
+    pub org_name: String,
+    pub email: String,
+    pub report_id: String,
+    ///the unix timestamps the report covers
+    pub begin: i64,
+    pub end: i64,
+    ///the policy the messages were checked against
+    pub policy: DmarcRecord,
+    pub rows: Vec<Row>,
+}
+
+impl Feedback {
+    pub fn to_xml(&self) -> String {
+        let policy = &self.policy;
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n\
+             <feedback>\r\n\
+             \x20 <report_metadata>\r\n\
+             \x20   <org_name>{}</org_name>\r\n\
+             \x20   <email>{}</email>\r\n\
+             \x20   <report_id>{}</report_id>\r\n\
+             \x20   <date_range>\r\n\
+             \x20     <begin>{}</begin>\r\n\
+             \x20     <end>{}</end>\r\n\
+             \x20   </date_range>\r\n\
+             \x20 </report_metadata>\r\n\
+             \x20 <policy_published>\r\n\
+             \x20   <domain>{}</domain>\r\n\
+             \x20   <adkim>{}</adkim>\r\n\
+             \x20   <aspf>{}</aspf>\r\n\
+             \x20   <p>{}</p>\r\n\
+             \x20   <sp>{}</sp>\r\n\
+             \x20   <pct>{}</pct>\r\n\
+             \x20 </policy_published>\r\n",
+            escape(&self.org_name),
+            escape(&self.email),
+            escape(&self.report_id),
+            self.begin,
+            self.end,
+            escape(&policy.domain),
+            policy.dkim_alignment.name(),
+            policy.spf_alignment.name(),
+            policy.policy.name(),
+            policy.subdomain_policy.unwrap_or(policy.policy).name(),
+            policy.percent,
+        );
+        let result = |aligned| if aligned { "pass" } else { "fail" };
+        for row in &self.rows {
+            xml += &format!(
+                "  <record>\r\n\
+                 \x20   <row>\r\n\
+                 \x20     <source_ip>{}</source_ip>\r\n\
+                 \x20     <count>{}</count>\r\n\
+                 \x20     <policy_evaluated>\r\n\
+                 \x20       <disposition>{}</disposition>\r\n\
+                 \x20       <dkim>{}</dkim>\r\n\
+                 \x20       <spf>{}</spf>\r\n\
+                 \x20     </policy_evaluated>\r\n\
+                 \x20   </row>\r\n\
+                 \x20   <identifiers>\r\n\
+                 \x20     <header_from>{}</header_from>\r\n\
+                 \x20   </identifiers>\r\n\
+                 \x20   <auth_results>\r\n",
+                escape(&row.source_ip),
+                row.count,
+                row.disposition.name(),
+                result(row.aligned_dkim),
+                result(row.aligned_spf),
+                escape(&row.header_from),
+            );
+            for dkim in &row.dkim {
+                xml += &format!(
+                    "      <dkim>\r\n\
+                     \x20       <domain>{}</domain>\r\n\
+                     \x20       <selector>{}</selector>\r\n\
+                     \x20       <result>{}</result>\r\n\
+                     \x20     </dkim>\r\n",
+                    escape(&dkim.domain),
+                    escape(&dkim.selector),
+                    dkim.result.name(),
+                );
+            }
+            xml += &format!(
+                "      <spf>\r\n\
+                 \x20       <domain>{}</domain>\r\n\
+                 \x20       <result>{}</result>\r\n\
+                 \x20     </spf>\r\n\
+                 \x20   </auth_results>\r\n\
+                 \x20 </record>\r\n",
+                escape(&row.envelope_from),
+                row.spf.name(),
+            );
+        }
+        xml += "</feedback>\r\n";
+        xml
+    }
+
+    ///reads a report someone sent us. elements we don't use are skipped
+    pub fn parse(xml: &str) -> Result<Self> {
+        let mut feedback = Feedback {
+            org_name: String::new(),
+            email: String::new(),
+            report_id: String::new(),
+            begin: 0,
+            end: 0,
+            policy: DmarcRecord {
+                domain: String::new(),
+                policy: Policy::None,
+                subdomain_policy: None,
+                dkim_alignment: Alignment::Relaxed,
+                spf_alignment: Alignment::Relaxed,
+                percent: 100,
+                aggregate_reports: vec![],
+            },
+            rows: vec![],
+        };
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+        let mut path = Vec::new();
+        let mut found = false;
+        loop {
+            match reader.read_event().context("invalid report xml")? {
+                Event::Start(start) => {
+                    let name = String::from_utf8_lossy(start.local_name().as_ref()).to_string();
+                    path.push(name);
+                    match path.join("/").as_str() {
+                        "feedback" => found = true,
+                        "feedback/record" => feedback.rows.push(Row::default()),
+                        "feedback/record/auth_results/dkim" => {
+                            if let Some(row) = feedback.rows.last_mut() {
+                                row.dkim.push(DkimAuth {
+                                    domain: String::new(),
+                                    selector: String::new(),
+                                    result: AuthStatus::None,
+                                });
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Event::End(_) => {
+                    path.pop();
+                }
+                Event::Text(text) => {
+                    let text = text.unescape().context("invalid report xml")?;
+                    feedback.set(&path.join("/"), text.trim());
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        if !found {
+            bail!("not a dmarc aggregate report");
+        }
+        if !path.is_empty() {
+            bail!("the report ends in the middle");
+        }
+        Ok(feedback)
+    }
+
+    ///one element of the document, `path` is like feedback/record/row/count
+    fn set(&mut self, path: &str, value: &str) {
+        let Some(path) = path.strip_prefix("feedback/") else {
+            return;
+        };
+        let number = || value.parse::<i64>().unwrap_or_default();
+        let pass = || value.eq_ignore_ascii_case("pass");
+        let status = || AuthStatus::from_name(value).unwrap_or(AuthStatus::Neutral);
+        let policy = &mut self.policy;
+        match path {
+            "report_metadata/org_name" => self.org_name = value.to_string(),
+            "report_metadata/email" => self.email = value.to_string(),
+            "report_metadata/report_id" => self.report_id = value.to_string(),
+            "report_metadata/date_range/begin" => self.begin = number(),
+            "report_metadata/date_range/end" => self.end = number(),
+            "policy_published/domain" => policy.domain = value.to_ascii_lowercase(),
+            "policy_published/adkim" => policy.dkim_alignment = Alignment::parse(Some(value)),
+            "policy_published/aspf" => policy.spf_alignment = Alignment::parse(Some(value)),
+            "policy_published/p" => policy.policy = Policy::parse(value).unwrap_or_default(),
+            "policy_published/sp" => policy.subdomain_policy = Policy::parse(value),
+            "policy_published/pct" => policy.percent = value.parse().unwrap_or(100),
+            _ => {}
+        }
+        let Some(row) = self.rows.last_mut() else {
+            return;
+        };
+        match path {
+            "record/row/source_ip" => row.source_ip = value.to_string(),
+            "record/row/count" => row.count = number(),
+            "record/row/policy_evaluated/disposition" => {
+                row.disposition = Policy::parse(value).unwrap_or_default()
+            }
+            "record/row/policy_evaluated/dkim" => row.aligned_dkim = pass(),
+            "record/row/policy_evaluated/spf" => row.aligned_spf = pass(),
+            "record/identifiers/header_from" => row.header_from = value.to_ascii_lowercase(),
+            "record/identifiers/envelope_from" => row.envelope_from = value.to_ascii_lowercase(),
+            //the envelope domain if the report doesn't name it
+            "record/auth_results/spf/domain" if row.envelope_from.is_empty() => {
+                row.envelope_from = value.to_ascii_lowercase()
+            }
+            "record/auth_results/spf/result" => row.spf = status(),
+            _ => {}
+        }
+        let Some(dkim) = row.dkim.last_mut() else {
+            return;
+        };
+        match path {
+            "record/auth_results/dkim/domain" => dkim.domain = value.to_ascii_lowercase(),
+            "record/auth_results/dkim/selector" => dkim.selector = value.to_string(),
+            "record/auth_results/dkim/result" => dkim.result = status(),
+            _ => {}
+        }
+    }
+
+    ///the attachment name rfc 7489 7.2.1.1 asks for
+    fn filename(&self, receiver: &str) -> String {
+        format!(
+            "{receiver}!{}!{}!{}.xml.gz",
+            self.policy.domain, self.begin, self.end
+        )
+    }
+}
+
+///the report as a mail from `from` to `to`, with the xml gzipped
+pub fn message(
+    feedback: &Feedback,
+    receiver: &str,
+    from: &str,
+    to: &str,
+    now: i64,
+) -> Result<Vec<u8>> {
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(feedback.to_xml().as_bytes())?;
+    let attachment = base64::engine::general_purpose::STANDARD.encode(gz.finish()?);
+    let boundary = format!("{}.{now}/{receiver}", feedback.report_id);
+    let filename = feedback.filename(receiver);
+    let mut data = format!(
+        "From: {from}\r\n\
+         To: {to}\r\n\
+         Subject: Report Domain: {} Submitter: {} Report-ID: <{}>\r\n\
+         Date: {}\r\n\
+         Message-ID: <dmarc.{boundary}>\r\n\
+         Auto-Submitted: auto-generated\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         This is a DMARC aggregate report for {}, see the attachment.\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: application/gzip; name=\"{filename}\"\r\n\
+         Content-Disposition: attachment; filename=\"{filename}\"\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         \r\n",
+        feedback.policy.domain,
+        feedback.org_name,
+        feedback.report_id,
+        DateTime::<Utc>::from_timestamp(now, 0)
+            .unwrap_or_default()
+            .to_rfc2822(),
+        feedback.policy.domain,
+    );
+    for line in attachment.as_bytes().chunks(76) {
+        data += std::str::from_utf8(line)?;
+        data += "\r\n";
+    }
+    data += &format!("--{boundary}--\r\n");
+    Ok(data.into_bytes())
+}
+
+///finds the report attached to a mail, gzipped or as plain xml
+pub fn read_message(data: &[u8]) -> Result<Feedback> {
+    let parsed = mailparse::parse_mail(data)?;
+    let mut parts = vec![&parsed];
+    while let Some(part) = parts.pop() {
+        parts.extend(part.subparts.iter());
+        let xml = match part.ctype.mimetype.as_str() {
+            "application/gzip" | "application/x-gzip" => {
+                let mut xml = String::new();
+                GzDecoder::new(&part.get_body_raw()?[..]).read_to_string(&mut xml)?;
+                xml
+            }
+            "text/xml" | "application/xml" => part.get_body()?,
+            "application/zip" => bail!("zipped reports aren't supported"),
+            _ => continue,
+        };
+        return Feedback::parse(&xml);
+    }
+    bail!("no report attached")
+}
+
+///`mailto:dmarc@example.com!10m` -> ("dmarc@example.com", Some(10485760))
+fn parse_uri(uri: &str) -> Option<(String, Option<u64>)> {
+    let (scheme, rest) = uri.trim().split_once(':')?;
+    if !scheme.eq_ignore_ascii_case("mailto") {
+        return None;
+    }
+    let (address, limit) = match rest.rsplit_once('!') {
+        Some((address, limit)) => {
+            let digits = limit.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+            let unit = match &limit[digits.len()..] {
+                "" => 1,
+                "k" | "K" => 1 << 10,
+                "m" | "M" => 1 << 20,
+                "g" | "G" => 1 << 30,
+                "t" | "T" => 1 << 40,
+                _ => return None,
+            };
+            (
+                address,
+                Some(digits.parse::<u64>().ok()?.saturating_mul(unit)),
+            )
+        }
+        None => (rest, None),
+    };
+    valid_address(address).then(|| (address.to_string(), limit))
+}
+
+///the address comes from someone else's TXT record and ends up in our
+///headers and RCPT TO, so it has to be one plain address and nothing else
+fn valid_address(address: &str) -> bool {
+    let forbidden = |c: char| c.is_control() || c.is_whitespace() || matches!(c, '<' | '>');
+    if address.chars().any(forbidden) {
+        return false;
+    }
+    let Ok(parsed) = mailparse::addrparse(address) else {
+        return false;
+    };
+    match &parsed[..] {
+        [MailAddr::Single(single)] => {
+            single.addr == address && single.display_name.is_none() && address.contains('@')
+        }
+        _ => false,
+    }
+}
+
+///a domain can only have its reports sent to another organization if that
+///one agrees to take them (rfc 7489 7.1)
+async fn accepts_reports(resolver: &impl AuthResolver, domain: &str, address: &str) -> bool {
+    let Some((_, target)) = address.rsplit_once('@') else {
+        return false;
+    };
+    if organizational_domain(domain) == organizational_domain(target) {
+        return true;
+    }
+    match resolver
+        .txt(&format!("{domain}._report._dmarc.{target}"))
+        .await
+    {
+        Ok(records) => records.iter().any(|txt| {
+            txt.split(';')
+                .next()
+                .is_some_and(|v| v.trim() == "v=DMARC1")
+        }),
+        Err(e) => {
+            tracing::debug!("report authorization lookup for {target} failed: {:?}", e);
+            false
+        }
+    }
+}
+
+///counts every incoming message a domain wants reports about, and mails
+///them the day's reports after midnight utc
+pub struct Reporter {
+    db: Arc<Mutex<DBClient>>,
+    config: Arc<Config>,
+    dkim: Arc<DkimSigner>,
+    ///the outbound queue's, the reports go out through it
+    queue: Arc<Notify>,
+    dns: Arc<Dns>,
+}
+
+impl Reporter {
+    pub async fn new(
+        config: Arc<Config>,
+        tx: Sender<Change>,
+        dkim: Arc<DkimSigner>,
+        queue: Arc<Notify>,
+        dns: Arc<Dns>,
+    ) -> Result<Self> {
+        Ok(Self {
+            db: Arc::new(Mutex::new(DBClient::new(tx, &config).await?)),
+            config,
+            dkim,
+            queue,
+            dns,
+        })
+    }
+
+    ///runs forever, the futures aren't Send so it has to be on the LocalSet
+    pub async fn run(self) -> Result<()> {
+        loop {
+            let now = Utc::now();
+            if let Err(e) = self.send_reports(now.date_naive()).await {
+                tracing::error!("error sending dmarc reports: {:?}", e);
+            }
+            let tomorrow = now.date_naive().succ_opt().unwrap_or_default();
+            let midnight = tomorrow.and_hms_opt(0, 5, 0).unwrap_or_default().and_utc();
+            let wait = (midnight - now).to_std().unwrap_or(Duration::from_secs(60));
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    ///sends the reports of every day before `today` and forgets the days
+    async fn send_reports(&self, today: NaiveDate) -> Result<()> {
+        let days = self.db.lock().await.dmarc_days_before(today).await?;
+        for day in days {
+            let policies = self.db.lock().await.dmarc_policies(day).await?;
+            for policy in policies {
+                let rows = self
+                    .db
+                    .lock()
+                    .await
+                    .dmarc_results(day, &policy.domain)
+                    .await?;
+                let begin = day
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap_or_default()
+                    .and_utc()
+                    .timestamp();
+                let feedback = Feedback {
+                    org_name: self.config.primary_domain().to_string(),
+                    email: self.config.dmarc_report_sender(),
+                    report_id: format!("{begin}.{}@{}", policy.domain, self.config.hostname),
+                    begin,
+                    end: begin + 24 * 60 * 60 - 1,
+                    policy,
+                    rows,
+                };
+                if let Err(e) = self.submit(&feedback).await {
+                    tracing::error!(
+                        "couldn't send the dmarc report for {}: {:?}",
+                        feedback.policy.domain,
+                        e
+                    );
+                }
+            }
+            self.db.lock().await.delete_dmarc_day(day).await?;
+        }
+        Ok(())
+    }
+
+    ///queues the report for every rua= address that may have it
+    async fn submit(&self, feedback: &Feedback) -> Result<()> {
+        let now = Utc::now().timestamp();
+        let from = self.config.dmarc_report_sender();
+        let domain = &feedback.policy.domain;
+        for uri in &feedback.policy.aggregate_reports {
+            let Some((to, limit)) = parse_uri(uri) else {
+                tracing::debug!("skipping the report uri {uri} of {domain}");
+                continue;
+            };
+            if !accepts_reports(&*self.dns, domain, &to).await {
+                tracing::info!("{to} doesn't take dmarc reports for {domain}");
+                continue;
+            }
+            let data = message(feedback, &self.config.hostname, &from, &to, now)?;
+            if limit.is_some_and(|limit| data.len() as u64 > limit) {
+                tracing::info!("the dmarc report for {domain} is too big for {to}");
+                continue;
+            }
+            let mail = Mail {
+                from: format!("<{from}>"),
+                to: vec![format!("<{to}>")],
+                data: self.dkim.sign(&data, now)?,
+                ..Default::default()
+            };
+            let id = self.db.lock().await.enqueue(&mail, now).await?;
+            tracing::info!("queued the dmarc report for {domain} to {to} as {id}");
+        }
+        self.queue.notify_one();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mailparse::MailHeaderMap;
+
+    use super::{message, parse_uri, read_message, DkimAuth, Feedback, Row};
+    use crate::dmarc::{DmarcRecord, Policy};
+    use crate::email_auth::AuthStatus;
+
+    fn feedback() -> Feedback {
+        Feedback {
+            org_name: "kaki.foo".to_string(),
+            email: "dmarc-noreply@kaki.foo".to_string(),
+            report_id: "1709251200.example.com@mx.kaki.foo".to_string(),
+            begin: 1709251200,
+            end: 1709337599,
+            policy: DmarcRecord::parse(
+                "example.com",
+                "v=DMARC1; p=reject; sp=quarantine; aspf=s; pct=50; rua=mailto:d@example.com",
+            )
+            .unwrap(),
+            rows: vec![
+                Row {
+                    source_ip: "192.0.2.1".to_string(),
+                    count: 3,
+                    header_from: "example.com".to_string(),
+                    envelope_from: "example.com".to_string(),
+                    disposition: Policy::None,
+                    aligned_dkim: true,
+                    aligned_spf: true,
+                    dkim: vec![DkimAuth {
+                        domain: "example.com".to_string(),
+                        selector: "s<1>".to_string(),
+                        result: AuthStatus::Pass,
+                    }],
+                    spf: AuthStatus::Pass,
+                },
+                Row {
+                    source_ip: "2001:db8::1".to_string(),
+                    count: 1,
+                    header_from: "example.com".to_string(),
+                    envelope_from: "spam.example".to_string(),
+                    disposition: Policy::Reject,
+                    aligned_dkim: false,
+                    aligned_spf: false,
+                    dkim: vec![],
+                    spf: AuthStatus::SoftFail,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_xml() {
+        let feedback = feedback();
+        let xml = feedback.to_xml();
+        assert!(xml.contains("<selector>s&lt;1&gt;</selector>"));
+        assert!(xml.contains("<p>reject</p>\r\n    <sp>quarantine</sp>\r\n    <pct>50</pct>"));
+        let parsed = Feedback::parse(&xml).unwrap();
+        //rua= isn't in the report
+        let mut expected = feedback;
+        expected.policy.aggregate_reports.clear();
+        assert_eq!(parsed, expected);
+        assert!(Feedback::parse("<html></html>").is_err());
+        assert!(Feedback::parse("<feedback><record>").is_err());
+    }
+
+    #[test]
+    fn test_message() {
+        let feedback = feedback();
+        let data = message(
+            &feedback,
+            "mx.kaki.foo",
+            "dmarc-noreply@kaki.foo",
+            "d@example.com",
+            1709337900,
+        )
+        .unwrap();
+        let parsed = mailparse::parse_mail(&data).unwrap();
+        assert_eq!(
+            parsed.headers.get_first_value("Subject").unwrap(),
+            "Report Domain: example.com Submitter: kaki.foo Report-ID: <1709251200.example.com@mx.kaki.foo>"
+        );
+        assert_eq!(parsed.subparts.len(), 2);
+        assert_eq!(
+            parsed.subparts[1].get_content_disposition().params["filename"],
+            "mx.kaki.foo!example.com!1709251200!1709337599.xml.gz"
+        );
+        let mut expected = feedback;
+        expected.policy.aggregate_reports.clear();
+        assert_eq!(read_message(&data).unwrap(), expected);
+        //plain xml works too
+        let plain = format!(
+            "Subject: report\r\nContent-Type: text/xml\r\n\r\n{}",
+            expected.to_xml()
+        );
+        assert_eq!(read_message(plain.as_bytes()).unwrap(), expected);
+        assert!(read_message(b"Subject: hi\r\n\r\nhello\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_uri() {
+        assert_eq!(
+            parse_uri("mailto:d@example.com"),
+            Some(("d@example.com".to_string(), None))
+        );
+        assert_eq!(
+            parse_uri("MAILTO:d@example.com!10m"),
+            Some(("d@example.com".to_string(), Some(10 << 20)))
+        );
+        assert_eq!(
+            parse_uri("mailto:d@example.com!500"),
+            Some(("d@example.com".to_string(), Some(500)))
+        );
+        assert_eq!(parse_uri("https://example.com/dmarc"), None);
+        assert_eq!(parse_uri("mailto:d@example.com!10x"), None);
+        assert_eq!(parse_uri("mailto:nobody"), None);
+        //nothing that could end up as another header or SMTP command
+        assert_eq!(
+            parse_uri("mailto:d@example.com\r\nRCPT TO:<x@evil.example>"),
+            None
+        );
+        assert_eq!(
+            parse_uri("mailto:d@example.com>\r\nBcc: x@evil.example"),
+            None
+        );
+        assert_eq!(parse_uri("mailto:d@example.com x@evil.example"), None);
+        assert_eq!(parse_uri("mailto:d@example.com,x@evil.example"), None);
+        assert_eq!(parse_uri("mailto:\"d\" d@example.com"), None);
+    }
+}
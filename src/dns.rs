@@ -0,0 +1,1193 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use hickory_resolver::config::ResolverConfig;
+use hickory_resolver::lookup::Lookup;
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::dnssec::{Proof, TrustAnchors};
+use hickory_resolver::proto::rr::{RData, RecordType};
+use hickory_resolver::proto::{ProtoError, ProtoErrorKind};
+use hickory_resolver::{Name, ResolveError, TokioResolver};
+use lru::LruCache;
+
+use crate::config::DnsConfig;
+use crate::dane::Tlsa;
+use crate::email_auth::AuthResolver;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Txt,
+    A,
+    Aaaa,
+    Mx,
+    Ptr,
+    Tlsa,
+}
+
+impl Kind {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_uppercase().as_str() {
+            "TXT" => Kind::Txt,
+            "A" => Kind::A,
+            "AAAA" => Kind::Aaaa,
+            "MX" => Kind::Mx,
+            "PTR" => Kind::Ptr,
+            "TLSA" => Kind::Tlsa,
+            _ => return None,
+        })
+    }
+
+    fn record_type(self) -> RecordType {
+        match self {
+            Kind::Txt => RecordType::TXT,
+            Kind::A => RecordType::A,
+            Kind::Aaaa => RecordType::AAAA,
+            Kind::Mx => RecordType::MX,
+            Kind::Ptr => RecordType::PTR,
+            Kind::Tlsa => RecordType::TLSA,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    ///the strings of a TXT record joined into one
+    Txt(String),
+    Ip(IpAddr),
+    ///preference and host, a null MX has an empty host
+    Mx(u16, String),
+    ///the name in a PTR record
+    Name(String),
+    Tlsa(Tlsa),
+}
+
+///what a lookup found. no records is a negative answer, `ttl` is how long
+///either can be kept, the default negative ttl if the server didn't say.
+///`secure` is a DNSSEC signed answer, or a validated one without records
+struct Answer {
+    records: Vec<Record>,
+    secure: bool,
+    ttl: Option<Duration>,
+}
+
+struct Entry {
+    records: Vec<Record>,
+    secure: bool,
+    expires: Instant,
+}
+
+///the type, the name and whether the lookup was DNSSEC validated
+type Key = (Kind, String, bool);
+
+///the answers we've had, kept for their ttl. the least recently used one
+///goes when it's full
+struct Cache {
+    entries: LruCache<Key, Entry>,
+}
+
+impl Cache {
+    fn new(size: NonZeroUsize) -> Self {
+        Self {
+            entries: LruCache::new(size),
+        }
+    }
+
+    fn get(&mut self, key: &Key, now: Instant) -> Option<(Vec<Record>, bool)> {
+        match self.entries.get(key) {
+            Some(entry) if entry.expires > now => Some((entry.records.clone(), entry.secure)),
+            Some(_) => {
+                self.entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(
+        &mut self,
+        key: Key,
+        records: Vec<Record>,
+        secure: bool,
+        ttl: Duration,
+        now: Instant,
+    ) {
+        let expires = now + ttl;
+        let entry = Entry {
+            records,
+            secure,
+            expires,
+        };
+        self.entries.put(key, entry);
+    }
+}
+
+///a fixed set of records, read from a zone file instead of asking dns.
+///names it doesn't have don't exist, and what it has is trusted like a
+///DNSSEC signed answer
+#[derive(Debug, Default)]
+pub struct Zone {
+    records: HashMap<(Kind, String), Vec<Record>>,
+    ttl: Duration,
+}
+
+impl Zone {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read the zone file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid zone file {}", path.display()))
+    }
+
+    ///a simple master file (rfc 1035 5): `name [ttl] [IN] type data` per
+    ///line, names are absolute and `$TTL` sets the ttl of what follows.
+    ///types other than TXT, A, AAAA, MX, PTR and TLSA are skipped
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut zone = Zone {
+            records: HashMap::new(),
+            ttl: Duration::from_secs(3600),
+        };
+        for (number, line) in text.lines().enumerate() {
+            let words = words(line).with_context(|| format!("line {}", number + 1))?;
+            let Some((name, rest)) = words.split_first() else {
+                continue;
+            };
+            if name.eq_ignore_ascii_case("$TTL") {
+                let ttl = rest
+                    .first()
+                    .and_then(|ttl| ttl.parse().ok())
+                    .with_context(|| format!("invalid $TTL on line {}", number + 1))?;
+                zone.ttl = Duration::from_secs(ttl);
+                continue;
+            }
+            let mut rest = rest;
+            //the ttl of each record is ignored, answers are kept for the
+            //zone's
+            if rest.first().is_some_and(|w| w.parse::<u32>().is_ok()) {
+                rest = &rest[1..];
+            }
+            if rest.first().is_some_and(|w| w.eq_ignore_ascii_case("IN")) {
+                rest = &rest[1..];
+            }
+            let Some((kind, data)) = rest.split_first() else {
+                bail!("no type on line {}", number + 1);
+            };
+            let Some(kind) = Kind::parse(kind) else {
+                tracing::debug!("skipping a {kind} record in the zone file");
+                continue;
+            };
+            let record = record(kind, data)
+                .with_context(|| format!("invalid {kind:?} record on line {}", number + 1))?;
+            zone.records
+                .entry((kind, key(name)))
+                .or_default()
+                .push(record);
+        }
+        Ok(zone)
+    }
+
+    fn lookup(&self, kind: Kind, name: &str) -> Answer {
+        Answer {
+            records: self
+                .records
+                .get(&(kind, key(name)))
+                .cloned()
+                .unwrap_or_default(),
+            secure: true,
+            ttl: Some(self.ttl),
+        }
+    }
+}
+
+///splits a zone file line into words, a quoted string is one word
+fn words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                words.push(word);
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ';') {
+                    word.push(c);
+                }
+                words.push(word);
+            }
+        }
+    }
+    Ok(words)
+}
+
+fn record(kind: Kind, data: &[String]) -> Result<Record> {
+    Ok(match (kind, data) {
+        (Kind::Txt, strings) if !strings.is_empty() => Record::Txt(strings.concat()),
+        (Kind::A | Kind::Aaaa, [ip]) => {
+            let ip = ip.parse::<IpAddr>()?;
+            if ip.is_ipv6() != (kind == Kind::Aaaa) {
+                bail!("{ip} is the wrong kind of address");
+            }
+            Record::Ip(ip)
+        }
+        (Kind::Mx, [preference, host]) => Record::Mx(preference.parse()?, key(host)),
+        (Kind::Ptr, [name]) => Record::Name(key(name)),
+        //the data can be split in many words
+        (Kind::Tlsa, [usage, selector, matching, data @ ..]) if !data.is_empty() => {
+            Record::Tlsa(Tlsa {
+                usage: usage.parse()?,
+                selector: selector.parse()?,
+                matching: matching.parse()?,
+                data: hex(&data.concat())?,
+            })
+        }
+        _ => bail!("wrong number of fields"),
+    })
+}
+
+fn hex(text: &str) -> Result<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        bail!("invalid hex {text}");
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&text[i..i + 2], 16).with_context(|| format!("invalid hex {text}"))
+        })
+        .collect()
+}
+
+///how names are compared, `Kaki.FOO.` and `kaki.foo` are the same
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+///where answers come from
+enum Backend {
+    Resolver {
+        resolver: Box<TokioResolver>,
+        ///checks the DNSSEC signatures of the answers that need them
+        validating: Box<TokioResolver>,
+    },
+    Zone(Zone),
+}
+
+impl Backend {
+    ///a plain and a DNSSEC validating resolver asking `upstream`. the
+    ///validating one trusts the root's keys unless given `anchors`
+    fn resolvers(
+        upstream: ResolverConfig,
+        timeout: Duration,
+        anchors: Option<Arc<TrustAnchors>>,
+    ) -> Self {
+        let resolver = |validate| {
+            let provider = TokioConnectionProvider::default();
+            let mut builder = TokioResolver::builder_with_config(upstream.clone(), provider);
+            let opts = builder.options_mut();
+            //two tries fit in the whole lookup's timeout
+            opts.timeout = timeout / 2;
+            opts.attempts = 2;
+            //the answers are cached here
+            opts.cache_size = 0;
+            //the CNAMEs on the way are kept, their proofs count too
+            opts.preserve_intermediates = true;
+            opts.validate = validate;
+            //signed answers rarely fit in 512 bytes
+            opts.edns0 = validate;
+            match &anchors {
+                Some(anchors) if validate => builder.with_trust_anchor(anchors.clone()).build(),
+                _ => builder.build(),
+            }
+        };
+        Backend::Resolver {
+            resolver: Box::new(resolver(false)),
+            validating: Box::new(resolver(true)),
+        }
+    }
+}
+
+///the dns lookups everything shares, with one cache that keeps answers for
+///as long as their ttl says
+pub struct Dns {
+    backend: Backend,
+    ///none if caching is turned off
+    cache: Option<Mutex<Cache>>,
+    negative_ttl: Duration,
+    max_ttl: Duration,
+    timeout: Duration,
+}
+
+impl Dns {
+    pub fn new(config: &DnsConfig) -> Result<Self> {
+        if let Some(path) = &config.zone_file {
+            return Ok(Self::from_zone(Zone::load(path)?, config));
+        }
+        let timeout = Duration::from_secs(config.timeout_secs.max(1));
+        let backend = Backend::resolvers(ResolverConfig::default(), timeout, None);
+        Ok(Self::with_backend(backend, config))
+    }
+
+    ///answers from `zone` only, for tests and machines without dns
+    pub fn from_zone(zone: Zone, config: &DnsConfig) -> Self {
+        Self::with_backend(Backend::Zone(zone), config)
+    }
+
+    fn with_backend(backend: Backend, config: &DnsConfig) -> Self {
+        Self {
+            backend,
+            cache: NonZeroUsize::new(config.cache_size).map(|size| Mutex::new(Cache::new(size))),
+            negative_ttl: Duration::from_secs(config.negative_ttl_secs),
+            max_ttl: Duration::from_secs(config.max_ttl_secs),
+            timeout: Duration::from_secs(config.timeout_secs.max(1)),
+        }
+    }
+
+    ///the hosts mail for `domain` should be sent to, most preferred first.
+    ///a domain without MX records is its own mail host (rfc 5321 5.1), a
+    ///null MX (rfc 7505) gives an empty list
+    pub async fn mx_hosts(&self, domain: &str) -> Result<Vec<String>> {
+        Ok(mail_hosts(domain, self.mx(domain).await?))
+    }
+
+    ///mx_hosts with DNSSEC validation, and whether the answer was signed.
+    ///the TLSA records of the hosts only count if it was, a forged MX
+    ///could name any host (rfc 7672 2.2.2)
+    pub async fn secure_mx_hosts(&self, domain: &str) -> Result<(Vec<String>, bool)> {
+        let (records, secure) = self.answer(Kind::Mx, domain, true).await?;
+        Ok((mail_hosts(domain, exchanges(records)), secure))
+    }
+
+    ///the usable TLSA records of a tls service, `_<port>._tcp.<host>`.
+    ///only DNSSEC signed ones count, anyone could forge the others. there
+    ///are none in a zone that provably isn't signed, but a forged or
+    ///unverifiable answer in a signed one is an error
+    pub async fn tlsa(&self, port: u16, host: &str) -> Result<Vec<Tlsa>> {
+        let name = format!("_{port}._tcp.{host}");
+        let (records, secure) = self.answer(Kind::Tlsa, &name, true).await?;
+        if !secure {
+            return Ok(vec![]);
+        }
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Tlsa(tlsa) => Some(tlsa),
+                _ => None,
+            })
+            .filter(Tlsa::usable)
+            .collect())
+    }
+
+    async fn lookup(&self, kind: Kind, name: &str) -> Result<Vec<Record>> {
+        Ok(self.answer(kind, name, false).await?.0)
+    }
+
+    ///the records and whether they're secure. with `validate` the DNSSEC
+    ///signatures are checked, and an answer that should be signed and
+    ///isn't is an error. without, only the zone file's answers are secure
+    async fn answer(&self, kind: Kind, name: &str, validate: bool) -> Result<(Vec<Record>, bool)> {
+        let key = (kind, key(name), validate);
+        if let Some(cache) = &self.cache {
+            if let Some(found) = lock(cache).get(&key, Instant::now()) {
+                return Ok(found);
+            }
+        }
+        let answer = match &self.backend {
+            Backend::Zone(zone) => zone.lookup(kind, name),
+            //every query on the way has its own timeout
+            Backend::Resolver { validating, .. } if validate => {
+                validated(validating, kind, &key.1).await?
+            }
+            Backend::Resolver { resolver, .. } => {
+                let lookup = resolve(resolver, kind, &key.1);
+                tokio::time::timeout(self.timeout, lookup)
+                    .await
+                    .with_context(|| format!("timed out looking up {} {kind:?}", key.1))??
+            }
+        };
+        if let Some(cache) = &self.cache {
+            let ttl = answer.ttl.unwrap_or(self.negative_ttl).min(self.max_ttl);
+            let records = answer.records.clone();
+            lock(cache).insert(key, records, answer.secure, ttl, Instant::now());
+        }
+        Ok((answer.records, answer.secure))
+    }
+}
+
+///the hosts of an MX answer like mx_hosts gives them
+fn mail_hosts(domain: &str, hosts: Vec<String>) -> Vec<String> {
+    if hosts.is_empty() {
+        return vec![domain.to_string()];
+    }
+    hosts.into_iter().filter(|host| !host.is_empty()).collect()
+}
+
+fn exchanges(records: Vec<Record>) -> Vec<String> {
+    let mut hosts = records
+        .into_iter()
+        .filter_map(|record| match record {
+            Record::Mx(preference, host) => Some((preference, host)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    //lowest preference first, it's sometimes called distance
+    hosts.sort_by_key(|(preference, _)| *preference);
+    hosts.into_iter().map(|(_, host)| host).collect()
+}
+
+fn lock(cache: &Mutex<Cache>) -> std::sync::MutexGuard<'_, Cache> {
+    //a panic while holding it can't leave the cache half updated
+    cache.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+async fn resolve(resolver: &TokioResolver, kind: Kind, name: &str) -> Result<Answer> {
+    let lookup = match resolver
+        .lookup(format!("{name}."), kind.record_type())
+        .await
+    {
+        Ok(lookup) => lookup,
+        //a name without records isn't an error, and it's cached
+        Err(e) => match e.proto().map(ProtoError::kind) {
+            Some(ProtoErrorKind::NoRecordsFound { negative_ttl, .. }) => {
+                return Ok(Answer {
+                    records: vec![],
+                    secure: false,
+                    ttl: negative_ttl.map(|ttl| Duration::from_secs(ttl.into())),
+                })
+            }
+            _ => return Err(e.into()),
+        },
+    };
+    Ok(from_lookup(&lookup))
+}
+
+async fn validated(resolver: &TokioResolver, kind: Kind, name: &str) -> Result<Answer> {
+    let lookup = resolver
+        .lookup(format!("{name}."), kind.record_type())
+        .await;
+    proven(lookup).with_context(|| format!("couldn't validate {name} {kind:?}"))
+}
+
+///the answer of a validated lookup. it's secure if every record of it, the
+///CNAMEs on the way too, has a secure proof and not if every one is
+///provably unsigned. anything else is forged or can't be checked
+fn proven(lookup: Result<Lookup, ResolveError>) -> Result<Answer> {
+    let lookup = match lookup {
+        Ok(lookup) => lookup,
+        Err(e) => match e.proto().map(ProtoError::kind) {
+            //the denial was signed, or all of it is from a zone that
+            //provably isn't. either way it names no other host, a domain
+            //without MX records is its own
+            Some(ProtoErrorKind::NoRecordsFound { negative_ttl, .. }) => {
+                return Ok(Answer {
+                    records: vec![],
+                    secure: true,
+                    ttl: negative_ttl.map(|ttl| Duration::from_secs(ttl.into())),
+                })
+            }
+            Some(ProtoErrorKind::Nsec {
+                proof: Proof::Insecure,
+                ..
+            }) => {
+                return Ok(Answer {
+                    records: vec![],
+                    secure: false,
+                    ttl: None,
+                })
+            }
+            _ => return Err(e.into()),
+        },
+    };
+    let proofs = lookup
+        .record_iter()
+        .map(|record| record.proof())
+        .collect::<Vec<_>>();
+    let secure = if proofs.iter().all(Proof::is_secure) {
+        true
+    } else if proofs.iter().all(Proof::is_insecure) {
+        false
+    } else {
+        bail!("the answer isn't validly signed");
+    };
+    Ok(Answer {
+        secure,
+        ..from_lookup(&lookup)
+    })
+}
+
+fn from_lookup(lookup: &Lookup) -> Answer {
+    let records = lookup
+        .record_iter()
+        .filter_map(|record| convert(record.data()))
+        .collect();
+    let ttl = lookup
+        .valid_until()
+        .saturating_duration_since(Instant::now());
+    Answer {
+        records,
+        secure: false,
+        ttl: Some(ttl),
+    }
+}
+
+fn convert(data: &RData) -> Option<Record> {
+    match data {
+        RData::TXT(txt) => Some(Record::Txt(
+            txt.txt_data()
+                .iter()
+                .map(|part| String::from_utf8_lossy(part))
+                .collect(),
+        )),
+        RData::A(a) => Some(Record::Ip(IpAddr::V4(a.0))),
+        RData::AAAA(aaaa) => Some(Record::Ip(IpAddr::V6(aaaa.0))),
+        RData::MX(mx) => Some(Record::Mx(mx.preference(), key(&mx.exchange().to_utf8()))),
+        RData::PTR(ptr) => Some(Record::Name(key(&ptr.0.to_utf8()))),
+        RData::TLSA(tlsa) => Some(Record::Tlsa(Tlsa {
+            usage: tlsa.cert_usage().into(),
+            selector: tlsa.selector().into(),
+            matching: tlsa.matching().into(),
+            data: tlsa.cert_data().to_vec(),
+        })),
+        //the CNAMEs on the way
+        _ => None,
+    }
+}
+
+impl AuthResolver for Dns {
+    async fn txt(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self
+            .lookup(Kind::Txt, name)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Txt(txt) => Some(txt),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn ips(&self, name: &str, ipv6: bool) -> Result<Vec<IpAddr>> {
+        let kind = if ipv6 { Kind::Aaaa } else { Kind::A };
+        Ok(self
+            .lookup(kind, name)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Ip(ip) => Some(ip),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn mx(&self, domain: &str) -> Result<Vec<String>> {
+        Ok(exchanges(self.lookup(Kind::Mx, domain).await?))
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>> {
+        Ok(self
+            .lookup(Kind::Ptr, &Name::from(ip).to_utf8())
+            .await?
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Name(name) => Some(name),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::num::NonZeroUsize;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig};
+    use hickory_resolver::proto::dnssec::crypto::EcdsaSigningKey;
+    use hickory_resolver::proto::dnssec::rdata::{DNSSECRData, DNSKEY, DS, NSEC, NSEC3, RRSIG};
+    use hickory_resolver::proto::dnssec::{
+        Algorithm, DigestType, Nsec3HashAlgorithm, SigningKey, TrustAnchors, TBS,
+    };
+    use hickory_resolver::proto::op::{Message, MessageType, OpCode, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::{MX, NS, SOA, TLSA};
+    use hickory_resolver::proto::rr::{DNSClass, Name, RData, Record as Rr, RecordType};
+    use tokio::net::UdpSocket;
+
+    use super::{Backend, Cache, Dns, Kind, Record, Zone};
+    use crate::config::DnsConfig;
+    use crate::email_auth::AuthResolver;
+
+    const ZONE: &str = r#"
+$TTL 300
+; kaki.foo
+kaki.foo.           IN MX   10 mx2.kaki.foo.
+kaki.foo.           IN MX   5 MX1.kaki.foo.
+kaki.foo.      3600 IN TXT  "v=spf1 " "ip4:192.0.2.1 -all" ; split in two
+mx1.kaki.foo.       IN A    192.0.2.1
+mx1.kaki.foo.       IN AAAA 2001:db8::1
+1.2.0.192.in-addr.arpa. IN PTR mx1.kaki.foo.
+kaki.foo.           IN SOA  ns.kaki.foo. admin.kaki.foo. 1 7200 3600 1209600 300
+nullmx.example.     IN MX   0 .
+_25._tcp.mx1.kaki.foo. IN TLSA 3 1 1 0C72AC70B745AC19998811B131D662C9 AC69DBDBE7CB23E5B514B56664C5D3D6
+_25._tcp.mx1.kaki.foo. IN TLSA 1 1 1 0C72
+"#;
+
+    #[tokio::test]
+    async fn test_zone() {
+        let dns = Dns::from_zone(Zone::parse(ZONE).unwrap(), &DnsConfig::default());
+        assert_eq!(
+            dns.txt("KAKI.foo.").await.unwrap(),
+            ["v=spf1 ip4:192.0.2.1 -all"]
+        );
+        assert_eq!(
+            dns.mx("kaki.foo").await.unwrap(),
+            ["mx1.kaki.foo", "mx2.kaki.foo"]
+        );
+        assert_eq!(
+            dns.ips("mx1.kaki.foo", false).await.unwrap(),
+            ["192.0.2.1".parse::<std::net::IpAddr>().unwrap()]
+        );
+        assert_eq!(dns.ips("mx1.kaki.foo", true).await.unwrap().len(), 1);
+        assert_eq!(
+            dns.ptr("192.0.2.1".parse().unwrap()).await.unwrap(),
+            ["mx1.kaki.foo"]
+        );
+        assert_eq!(
+            dns.mx_hosts("other.example").await.unwrap(),
+            ["other.example"]
+        );
+        assert!(dns.mx_hosts("nullmx.example").await.unwrap().is_empty());
+        let tlsa = dns.tlsa(25, "MX1.kaki.foo").await.unwrap();
+        assert_eq!(tlsa.len(), 1, "PKIX-EE isn't used");
+        assert_eq!((tlsa[0].usage, tlsa[0].data[0]), (3, 0x0c));
+        assert!(dns.tlsa(25, "mx2.kaki.foo").await.unwrap().is_empty());
+        assert!(Zone::parse("kaki.foo. IN A 2001:db8::1").is_err());
+        assert!(Zone::parse("kaki.foo. IN TLSA 3 1 1 0C7").is_err());
+        assert!(Zone::parse("kaki.foo. IN TXT \"open").is_err());
+    }
+
+    const ALGORITHM: Algorithm = Algorithm::ECDSAP256SHA256;
+    const TTL: u32 = 300;
+    const SALT: &[u8] = &[0xab, 0xcd];
+
+    fn name(name: &str) -> Name {
+        Name::from_ascii(name).unwrap()
+    }
+
+    fn signing_key() -> EcdsaSigningKey {
+        let pkcs8 = EcdsaSigningKey::generate_pkcs8(ALGORITHM).unwrap();
+        EcdsaSigningKey::from_pkcs8(&pkcs8, ALGORITHM).unwrap()
+    }
+
+    fn dnskey(key: &EcdsaSigningKey) -> DNSKEY {
+        DNSKEY::from_key(&key.to_public_key().unwrap())
+    }
+
+    ///base32hex without padding, how NSEC3 owner names are written
+    fn encode(hash: &[u8]) -> String {
+        const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+        let mut text = String::new();
+        let (mut bits, mut count) = (0u32, 0);
+        for byte in hash {
+            bits = bits << 8 | u32::from(*byte);
+            count += 8;
+            while count >= 5 {
+                count -= 5;
+                text.push(DIGITS[(bits >> count & 31) as usize].into());
+            }
+        }
+        text
+    }
+
+    fn hash(name: &Name) -> Vec<u8> {
+        let hash = Nsec3HashAlgorithm::SHA1.hash(SALT, name, 1).unwrap();
+        hash.as_ref().to_vec()
+    }
+
+    ///a zone the stand-in answers for, signed with NSEC or NSEC3 records or
+    ///not at all
+    struct TestZone {
+        apex: Name,
+        key: Option<EcdsaSigningKey>,
+        hashed: bool,
+        records: Vec<Rr>,
+    }
+
+    impl TestZone {
+        fn new(
+            apex: &str,
+            key: Option<EcdsaSigningKey>,
+            hashed: bool,
+            records: Vec<(&str, RData)>,
+        ) -> Self {
+            let apex = name(apex);
+            let soa = SOA::new(
+                name("ns.test."),
+                name("admin.test."),
+                1,
+                7200,
+                3600,
+                86400,
+                60,
+            );
+            let mut all = vec![
+                Rr::from_rdata(apex.clone(), TTL, RData::SOA(soa)),
+                Rr::from_rdata(apex.clone(), TTL, RData::NS(NS(name("ns.test.")))),
+            ];
+            if let Some(key) = &key {
+                let rdata = RData::DNSSEC(DNSSECRData::DNSKEY(dnskey(key)));
+                all.push(Rr::from_rdata(apex.clone(), TTL, rdata));
+            }
+            all.extend(
+                records
+                    .into_iter()
+                    .map(|(owner, rdata)| Rr::from_rdata(name(owner), TTL, rdata)),
+            );
+            Self {
+                apex,
+                key,
+                hashed,
+                records: all,
+            }
+        }
+
+        fn rrset(&self, owner: &Name, kind: RecordType) -> Vec<Rr> {
+            let records = self.records.iter();
+            records
+                .filter(|record| record.name() == owner && record.record_type() == kind)
+                .cloned()
+                .collect()
+        }
+
+        ///the names that are there, with the empty non-terminals above them
+        ///if `terminals` is off
+        fn names(&self, terminals: bool) -> BTreeSet<Name> {
+            let mut names = BTreeSet::new();
+            for record in &self.records {
+                let owner = record.name();
+                let below = if terminals {
+                    owner.num_labels()
+                } else {
+                    self.apex.num_labels()
+                };
+                for labels in below..=owner.num_labels() {
+                    names.insert(owner.trim_to(labels.into()));
+                }
+            }
+            names
+        }
+
+        fn types(&self, owner: &Name) -> Vec<RecordType> {
+            let types = self.records.iter().filter(|record| record.name() == owner);
+            let types = types.map(Rr::record_type).collect::<BTreeSet<_>>();
+            types.into_iter().collect()
+        }
+
+        ///the rrset with its RRSIG, `forged` breaks the signature
+        fn signed(&self, rrset: Vec<Rr>, forged: bool) -> Vec<Rr> {
+            let Some(key) = &self.key else {
+                return rrset;
+            };
+            let first = &rrset[0];
+            let owner = first.name().clone();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            let (inception, expiration) = (now - 3600, now + 86400);
+            let tag = dnskey(key).calculate_key_tag().unwrap();
+            let sig = |signature| {
+                RRSIG::new(
+                    first.record_type(),
+                    ALGORITHM,
+                    owner.num_labels(),
+                    TTL,
+                    expiration,
+                    inception,
+                    tag,
+                    self.apex.clone(),
+                    signature,
+                )
+            };
+            let tbs = TBS::from_sig(&owner, DNSClass::IN, &sig(vec![]), rrset.iter()).unwrap();
+            let mut signature = key.sign(&tbs).unwrap();
+            if forged {
+                signature[0] ^= 1;
+            }
+            let rdata = RData::DNSSEC(DNSSECRData::RRSIG(sig(signature)));
+            let sig = Rr::from_rdata(owner.clone(), TTL, rdata);
+            rrset.into_iter().chain([sig]).collect()
+        }
+
+        ///the NSEC record at `owner`, or the one before it
+        fn nsec(&self, owner: &Name) -> Vec<Rr> {
+            let names = self.names(true);
+            let owner = names
+                .range(..=owner.clone())
+                .next_back()
+                .or(names.last())
+                .unwrap();
+            let next = names
+                .range(owner.clone()..)
+                .nth(1)
+                .or(names.first())
+                .unwrap();
+            let mut types = self.types(owner);
+            types.extend([RecordType::NSEC, RecordType::RRSIG]);
+            let nsec = NSEC::new(next.clone(), types);
+            let rdata = RData::DNSSEC(DNSSECRData::NSEC(nsec));
+            self.signed(vec![Rr::from_rdata(owner.clone(), TTL, rdata)], false)
+        }
+
+        ///the NSEC3 record whose hash is `owner`'s, or the one before it
+        fn nsec3(&self, owner: &Name) -> Vec<Rr> {
+            let hashes = self
+                .names(false)
+                .into_iter()
+                .map(|name| (hash(&name), name))
+                .collect::<BTreeMap<_, _>>();
+            let wanted = hash(owner);
+            let (hash, name) = hashes
+                .range(..=wanted)
+                .next_back()
+                .or(hashes.last_key_value())
+                .unwrap();
+            let next = hashes
+                .range(hash.clone()..)
+                .nth(1)
+                .or(hashes.first_key_value())
+                .unwrap()
+                .0;
+            let mut types = self.types(name);
+            if !types.is_empty() {
+                types.push(RecordType::RRSIG);
+            }
+            let nsec3 = NSEC3::new(
+                Nsec3HashAlgorithm::SHA1,
+                false,
+                1,
+                SALT.to_vec(),
+                next.clone(),
+                types,
+            );
+            let owner = label(&encode(hash)).append_domain(&self.apex).unwrap();
+            let rdata = RData::DNSSEC(DNSSECRData::NSEC3(nsec3));
+            self.signed(vec![Rr::from_rdata(owner, TTL, rdata)], false)
+        }
+
+        ///the records that prove what `owner` doesn't have
+        fn denial(&self, owner: &Name, there: bool) -> Vec<Rr> {
+            let proof = |name: &Name| {
+                if self.hashed {
+                    self.nsec3(name)
+                } else {
+                    self.nsec(name)
+                }
+            };
+            if there {
+                return proof(owner);
+            }
+            let names = self.names(false);
+            let mut next_closer = owner.clone();
+            while !names.contains(&next_closer.base_name()) {
+                next_closer = next_closer.base_name();
+            }
+            let encloser = next_closer.base_name();
+            let wildcard = label("*").append_domain(&encloser).unwrap();
+            let mut records = proof(&next_closer);
+            if self.hashed {
+                records.extend(proof(&encloser));
+            }
+            records.extend(proof(&wildcard));
+            records
+        }
+    }
+
+    fn label(label: &str) -> Name {
+        Name::from_ascii(label).unwrap()
+    }
+
+    ///a name server that answers from its zones like a recursive resolver
+    ///would, signing as it goes
+    struct StandIn {
+        zones: Vec<TestZone>,
+        ///answers whose signatures are broken
+        forged: Vec<(Name, RecordType)>,
+        ///answers without signatures or denials
+        stripped: Vec<(Name, RecordType)>,
+    }
+
+    impl StandIn {
+        ///the root and example. are signed, example. with NSEC3 records.
+        ///insecure.example. and unsigned. are delegated without DS records
+        fn new() -> (Self, TrustAnchors) {
+            let (root_key, example_key) = (signing_key(), signing_key());
+            let mut anchors = TrustAnchors::empty();
+            anchors.insert(&root_key.to_public_key().unwrap());
+            let dnskey = dnskey(&example_key);
+            let digest = dnskey
+                .to_digest(&name("example."), DigestType::SHA256)
+                .unwrap();
+            let ds = DS::new(
+                dnskey.calculate_key_tag().unwrap(),
+                ALGORITHM,
+                DigestType::SHA256,
+                digest.as_ref().to_vec(),
+            );
+            let ns = |host| RData::NS(NS(name(host)));
+            let mx = |host| RData::MX(MX::new(10, name(host)));
+            let a = || RData::A(Ipv4Addr::new(192, 0, 2, 1).into());
+            let tlsa = || RData::TLSA(TLSA::new(3.into(), 1.into(), 1.into(), vec![0x0c; 32]));
+            let root = TestZone::new(
+                ".",
+                Some(root_key),
+                false,
+                vec![
+                    ("example.", ns("ns.example.")),
+                    ("example.", RData::DNSSEC(DNSSECRData::DS(ds))),
+                    ("unsigned.", ns("ns.unsigned.")),
+                ],
+            );
+            let example = TestZone::new(
+                "example.",
+                Some(example_key),
+                true,
+                vec![
+                    ("signed.example.", mx("mx.signed.example.")),
+                    ("mx.signed.example.", a()),
+                    ("_25._tcp.mx.signed.example.", tlsa()),
+                    ("plain.example.", mx("mx.plain.example.")),
+                    ("mx.plain.example.", a()),
+                    ("forged.example.", mx("mx.forged.example.")),
+                    ("_25._tcp.mx.forged.example.", tlsa()),
+                    ("stripped.example.", mx("mx.stripped.example.")),
+                    ("mx.stripped.example.", a()),
+                    ("insecure.example.", ns("ns.insecure.example.")),
+                ],
+            );
+            let insecure = TestZone::new(
+                "insecure.example.",
+                None,
+                false,
+                vec![
+                    ("insecure.example.", mx("mx.insecure.example.")),
+                    ("_25._tcp.mx.insecure.example.", tlsa()),
+                ],
+            );
+            let unsigned = TestZone::new(
+                "unsigned.",
+                None,
+                false,
+                vec![
+                    ("unsigned.", mx("mx.unsigned.")),
+                    ("_25._tcp.mx.unsigned.", tlsa()),
+                ],
+            );
+            let stand_in = Self {
+                zones: vec![root, example, insecure, unsigned],
+                forged: vec![
+                    (name("forged.example."), RecordType::MX),
+                    (name("_25._tcp.mx.forged.example."), RecordType::TLSA),
+                ],
+                stripped: vec![
+                    (name("stripped.example."), RecordType::MX),
+                    (name("_25._tcp.mx.stripped.example."), RecordType::TLSA),
+                ],
+            };
+            (stand_in, anchors)
+        }
+
+        ///answers over udp on a local port for as long as the test runs
+        async fn serve(self) -> SocketAddr {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let address = socket.local_addr().unwrap();
+            tokio::spawn(async move {
+                let mut buf = vec![0; 4096];
+                while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                    let Ok(query) = Message::from_vec(&buf[..n]) else {
+                        continue;
+                    };
+                    let response = self.respond(&query).to_vec().unwrap();
+                    let _ = socket.send_to(&response, peer).await;
+                }
+            });
+            address
+        }
+
+        fn respond(&self, query: &Message) -> Message {
+            let mut response = Message::new();
+            response
+                .set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(OpCode::Query)
+                .set_recursion_desired(query.recursion_desired())
+                .set_recursion_available(true)
+                .add_queries(query.queries().to_vec());
+            let Some(question) = query.queries().first() else {
+                return response;
+            };
+            let (name, kind) = (question.name(), question.query_type());
+            //the parent has the DS records of a child zone
+            let zone = self
+                .zones
+                .iter()
+                .filter(|zone| zone.apex.zone_of(name))
+                .filter(|zone| kind != RecordType::DS || zone.apex != *name || name.is_root())
+                .max_by_key(|zone| zone.apex.num_labels());
+            let Some(zone) = zone else {
+                response.set_response_code(ResponseCode::Refused);
+                return response;
+            };
+            let key = (name.clone(), kind);
+            let stripped = self.stripped.contains(&key);
+            let rrset = zone.rrset(name, kind);
+            if !rrset.is_empty() {
+                let answers = if stripped {
+                    rrset
+                } else {
+                    zone.signed(rrset, self.forged.contains(&key))
+                };
+                response.add_answers(answers);
+                return response;
+            }
+            let there = zone.names(false).contains(name);
+            if !there {
+                response.set_response_code(ResponseCode::NXDomain);
+            }
+            response.add_name_servers(zone.signed(zone.rrset(&zone.apex, RecordType::SOA), false));
+            if zone.key.is_some() && !stripped {
+                response.add_name_servers(zone.denial(name, there));
+            }
+            response
+        }
+    }
+
+    fn validating(server: SocketAddr, anchors: TrustAnchors) -> Dns {
+        let servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+        let upstream = ResolverConfig::from_parts(None, vec![], servers);
+        let timeout = Duration::from_secs(5);
+        let backend = Backend::resolvers(upstream, timeout, Some(Arc::new(anchors)));
+        Dns::with_backend(backend, &DnsConfig::default())
+    }
+
+    #[tokio::test]
+    async fn test_validated() {
+        let (stand_in, anchors) = StandIn::new();
+        let server = stand_in.serve().await;
+        let dns = validating(server, anchors);
+        assert_eq!(
+            dns.secure_mx_hosts("signed.example").await.unwrap(),
+            (vec!["mx.signed.example".to_string()], true)
+        );
+        assert_eq!(
+            dns.secure_mx_hosts("insecure.example").await.unwrap(),
+            (vec!["mx.insecure.example".to_string()], false)
+        );
+        assert_eq!(
+            dns.secure_mx_hosts("unsigned").await.unwrap(),
+            (vec!["mx.unsigned".to_string()], false)
+        );
+        //no MX records, so the domain is its own mail host
+        assert_eq!(
+            dns.secure_mx_hosts("mx.signed.example").await.unwrap(),
+            (vec!["mx.signed.example".to_string()], true)
+        );
+        assert!(dns.secure_mx_hosts("forged.example").await.is_err());
+        //without signatures it's never secure
+        let stripped = dns.secure_mx_hosts("stripped.example").await;
+        assert!(!matches!(stripped, Ok((_, true))));
+        let tlsa = dns.tlsa(25, "mx.signed.example").await.unwrap();
+        assert_eq!((tlsa.len(), tlsa[0].usage), (1, 3));
+        //securely denied, with NSEC3 and the root's NSEC records
+        assert!(dns.tlsa(25, "mx.plain.example").await.unwrap().is_empty());
+        assert!(dns.tlsa(25, "signed.example").await.unwrap().is_empty());
+        assert!(dns.tlsa(25, "nothere").await.unwrap().is_empty());
+        //the records of zones that provably aren't signed don't count
+        assert!(dns
+            .tlsa(25, "mx.insecure.example")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(dns.tlsa(25, "mx.unsigned").await.unwrap().is_empty());
+        assert!(dns.tlsa(25, "nothere.unsigned").await.unwrap().is_empty());
+        assert!(dns.tlsa(25, "mx.forged.example").await.is_err());
+        let stripped = dns.tlsa(25, "mx.stripped.example").await;
+        assert!(stripped.unwrap_or_default().is_empty());
+        //the plain lookup takes the forged answer as it is
+        assert_eq!(
+            dns.mx_hosts("forged.example").await.unwrap(),
+            ["mx.forged.example"]
+        );
+        //a root key that isn't the anchor
+        let (_, other) = StandIn::new();
+        let dns = validating(server, other);
+        assert!(dns.tlsa(25, "mx.signed.example").await.is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut cache = Cache::new(NonZeroUsize::new(2).unwrap());
+        let now = Instant::now();
+        let a = (Kind::Txt, "a.example".to_string(), false);
+        let b = (Kind::Txt, "b.example".to_string(), false);
+        let c = (Kind::A, "a.example".to_string(), false);
+        let record = vec![Record::Txt("v=spf1 -all".to_string())];
+        cache.insert(
+            a.clone(),
+            record.clone(),
+            false,
+            Duration::from_secs(60),
+            now,
+        );
+        //a negative answer
+        cache.insert(b.clone(), vec![], false, Duration::from_secs(10), now);
+        assert_eq!(cache.get(&a, now), Some((record.clone(), false)));
+        assert_eq!(
+            cache.get(&b, now + Duration::from_secs(5)),
+            Some((vec![], false))
+        );
+        assert_eq!(cache.get(&b, now + Duration::from_secs(10)), None);
+        assert_eq!(
+            cache.get(&a, now + Duration::from_secs(30)),
+            Some((record.clone(), false))
+        );
+        //b expired and is gone, so there's room without evicting a
+        cache.insert(c.clone(), vec![], false, Duration::from_secs(60), now);
+        assert!(cache.get(&a, now).is_some());
+        //now c is the least recently used one
+        cache.insert(b.clone(), vec![], false, Duration::from_secs(60), now);
+        assert!(cache.get(&c, now).is_none());
+        assert!(cache.get(&a, now).is_some());
+        //a validated answer is kept apart from the plain one
+        let validated = (Kind::Txt, "a.example".to_string(), true);
+        assert!(cache.get(&validated, now).is_none());
+        cache.insert(
+            validated.clone(),
+            record.clone(),
+            true,
+            Duration::from_secs(60),
+            now,
+        );
+        assert_eq!(cache.get(&validated, now), Some((record, true)));
+    }
+}
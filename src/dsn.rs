@@ -0,0 +1,240 @@
+use chrono::{DateTime, Utc};
+
+use crate::database::{QueuedMessage, QueuedRecipient};
+
+///what a delivery status notification reports, rfc 3464 2.3.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    ///the recipient won't get the message
+    Failed,
+    ///the recipient hasn't got it yet, we're still trying
+    Delayed,
+}
+
+///`550 5.1.1 no such user` -> (550, Some("5.1.1"))
+fn reply_codes(reply: &str) -> Option<(u16, Option<&str>)> {
+    let mut words = reply.split_whitespace();
+    let code = words.next()?;
+    if code.len() != 3 {
+        return None;
+    }
+    let code = code.parse::<u16>().ok()?;
+    let enhanced = words.next().filter(|word| {
+        let parts = word.split('.').collect::<Vec<_>>();
+        parts.len() == 3 && parts.iter().all(|p| p.parse::<u16>().is_ok())
+    });
+    Some((code, enhanced))
+}
+
+///the rfc 3463 status of a recipient. failures without a 5xx reply ran out
+///of retries
+pub fn status(recipient: &QueuedRecipient, action: Action) -> String {
+    let codes = recipient.last_error.as_deref().and_then(reply_codes);
+    match (action, codes) {
+        (Action::Failed, Some((500..=599, Some(enhanced)))) => enhanced.to_string(),
+        (Action::Failed, Some((500..=599, None))) => "5.0.0".to_string(),
+        //delivery time expired
+        (Action::Failed, _) => "4.4.7".to_string(),
+        (Action::Delayed, Some((400..=499, Some(enhanced)))) => enhanced.to_string(),
+        (Action::Delayed, Some(_)) => "4.0.0".to_string(),
+        //no answer from the host
+        (Action::Delayed, None) => "4.4.1".to_string(),
+    }
+}
+
+fn address(path: &str) -> &str {
+    path.trim_start_matches('<').trim_end_matches('>')
+}
+
+fn date(timestamp: i64) -> String {
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .to_rfc2822()
+}
+
+///a `multipart/report` notification (rfc 3464, rfc 6522) for the sender
+///of `message` about `recipients`. `retry_until` is when delayed
+///recipients will be given up on
+pub fn build(
+    hostname: &str,
+    message: &QueuedMessage,
+    recipients: &[QueuedRecipient],
+    action: Action,
+    now: i64,
+    retry_until: i64,
+) -> Vec<u8> {
+    let boundary = format!("{}.{now}/{hostname}", message.id);
+    let subject = match action {
+        Action::Failed => "Undelivered Mail Returned to Sender",
+        Action::Delayed => "Delayed Mail (still being retried)",
+    };
+    let mut dsn = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{hostname}>\r\n\
+         To: {}\r\n\
+         Subject: {subject}\r\n\
+         Date: {}\r\n\
+         Message-ID: <dsn.{boundary}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n",
+        address(&message.mail.from),
+        date(now),
+    );
+    match action {
+        Action::Failed => dsn += "Your message couldn't be delivered to these recipients:\r\n\r\n",
+        Action::Delayed => {
+            dsn += &format!(
+                "Your message hasn't been delivered to these recipients yet. We'll keep\r\n\
+                 trying until {}, you don't need to send it again.\r\n\r\n",
+                date(retry_until)
+            )
+        }
+    }
+    for recipient in recipients {
+        let error = recipient.last_error.as_deref().unwrap_or("unknown error");
+        dsn += &format!("  {}: {error}\r\n", address(&recipient.recipient));
+        if action == Action::Failed && status(recipient, action) == "4.4.7" {
+            dsn += &format!("    (gave up after {} attempts)\r\n", recipient.attempts);
+        }
+    }
+    dsn += &format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; {hostname}\r\n\
+         Arrival-Date: {}\r\n",
+        date(message.created)
+    );
+    for recipient in recipients {
+        dsn += &format!(
+            "\r\nFinal-Recipient: rfc822; {}\r\n\
+             Action: {}\r\n\
+             Status: {}\r\n",
+            address(&recipient.recipient),
+            match action {
+                Action::Failed => "failed",
+                Action::Delayed => "delayed",
+            },
+            status(recipient, action)
+        );
+        if let Some(reply) = recipient
+            .last_error
+            .as_deref()
+            .filter(|e| reply_codes(e).is_some())
+        {
+            dsn += &format!("Diagnostic-Code: smtp; {reply}\r\n");
+        }
+        if recipient.attempts > 0 {
+            //reports are made right after an attempt
+            dsn += &format!("Last-Attempt-Date: {}\r\n", date(now));
+        }
+        if action == Action::Delayed {
+            dsn += &format!("Will-Retry-Until: {}\r\n", date(retry_until));
+        }
+    }
+    dsn += &format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: text/rfc822-headers\r\n\
+         \r\n"
+    );
+    let mut dsn = dsn.into_bytes();
+    let data = &message.mail.data;
+    let headers_end = data
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| i + 2)
+        .unwrap_or(data.len());
+    dsn.extend_from_slice(&data[..headers_end]);
+    dsn.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    dsn
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, status, Action};
+    use crate::database::{QueueStatus, QueuedMessage, QueuedRecipient};
+    use crate::smtp_common::Mail;
+
+    fn recipient(address: &str, error: &str) -> QueuedRecipient {
+        QueuedRecipient {
+            id: 1,
+            message_id: 7,
+            recipient: address.to_string(),
+            status: QueueStatus::Failed,
+            attempts: 3,
+            next_attempt: 0,
+            last_error: Some(error.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_status() {
+        let failed = |error| status(&recipient("<a@b.c>", error), Action::Failed);
+        assert_eq!(failed("550 5.1.1 no such user"), "5.1.1");
+        assert_eq!(failed("554 mailbox unavailable"), "5.0.0");
+        assert_eq!(failed("421 4.3.2 busy"), "4.4.7");
+        assert_eq!(failed("mx.b.c: connection refused"), "4.4.7");
+        let delayed = |error| status(&recipient("<a@b.c>", error), Action::Delayed);
+        assert_eq!(delayed("451 4.7.1 greylisted"), "4.7.1");
+        assert_eq!(delayed("mx.b.c: connection refused"), "4.4.1");
+    }
+
+    #[test]
+    fn test_build() {
+        let message = QueuedMessage {
+            id: 7,
+            mail: Mail {
+                from: "<alice@kaki.foo>".to_string(),
+                data: b"Subject: hi\r\nFrom: alice@kaki.foo\r\n\r\nsecret body\r\n".to_vec(),
+                ..Default::default()
+            },
+            created: 0,
+            warned: false,
+            junk: false,
+        };
+        let recipients = [
+            recipient("<bob@example.com>", "550 5.1.1 no such user"),
+            recipient("<carol@example.org>", "timed out connecting"),
+        ];
+        let dsn = build(
+            "smtp.kaki.foo",
+            &message,
+            &recipients,
+            Action::Failed,
+            60,
+            0,
+        );
+        let dsn = String::from_utf8(dsn).unwrap();
+        let parsed = mailparse::parse_mail(dsn.as_bytes()).unwrap();
+        assert_eq!(parsed.ctype.mimetype, "multipart/report");
+        assert_eq!(parsed.ctype.params["report-type"], "delivery-status");
+        assert_eq!(parsed.subparts.len(), 3);
+        assert_eq!(parsed.subparts[1].ctype.mimetype, "message/delivery-status");
+        let report = parsed.subparts[1].get_body().unwrap();
+        assert!(report.contains("Reporting-MTA: dns; smtp.kaki.foo\r\n"));
+        assert!(report.contains(
+            "Final-Recipient: rfc822; bob@example.com\r\nAction: failed\r\nStatus: 5.1.1\r\n\
+             Diagnostic-Code: smtp; 550 5.1.1 no such user\r\n"
+        ));
+        assert!(report.contains("Final-Recipient: rfc822; carol@example.org\r\nAction: failed\r\nStatus: 4.4.7\r\nLast-Attempt-Date"));
+        let headers = parsed.subparts[2].get_body().unwrap();
+        assert!(headers.contains("Subject: hi"));
+        assert!(!dsn.contains("secret body"));
+        let dsn = build(
+            "smtp.kaki.foo",
+            &message,
+            &recipients[1..],
+            Action::Delayed,
+            60,
+            120,
+        );
+        let dsn = String::from_utf8(dsn).unwrap();
+        assert!(dsn.contains("Action: delayed\r\nStatus: 4.4.1\r\n"));
+        assert!(dsn.contains("Will-Retry-Until: "));
+    }
+}
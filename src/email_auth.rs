@@ -1,306 +1,338 @@
-use std::{
-    collections::HashMap,
-    net::IpAddr,
-    sync::{Mutex, OnceLock},
-};
+use std::net::IpAddr;
 
-use anyhow::{anyhow, Result};
-use hickory_resolver::config::{ResolverConfig, ResolverOpts};
-use mailparse::MailHeaderMap;
+use anyhow::{bail, Result};
+use mailparse::MailAddr;
 
+use crate::dkim::arc::{self, ArcResult};
+use crate::dkim::verify::{self, DkimResult};
+use crate::dmarc::{self, DmarcResult};
 use crate::smtp_common::Mail;
+use crate::spf;
 
-static TXT_CACHE: OnceLock<Mutex<HashMap<String, Vec<String>>>> = OnceLock::new();
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum AuthStatus {
     Pass,
     Fail,
     SoftFail,
     Neutral,
+    #[default]
     None,
+    ///the check couldn't be completed, eg. a dns timeout
+    TempError,
+    ///the records or the signature are broken
+    PermError,
+}
+
+impl AuthStatus {
+    ///the result as written in Authentication-Results, rfc 8601 2.7
+    pub fn name(self) -> &'static str {
+        match self {
+            AuthStatus::Pass => "pass",
+            AuthStatus::Fail => "fail",
+            AuthStatus::SoftFail => "softfail",
+            AuthStatus::Neutral => "neutral",
+            AuthStatus::None => "none",
+            AuthStatus::TempError => "temperror",
+            AuthStatus::PermError => "permerror",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            AuthStatus::Pass,
+            AuthStatus::Fail,
+            AuthStatus::SoftFail,
+            AuthStatus::Neutral,
+            AuthStatus::None,
+            AuthStatus::TempError,
+            AuthStatus::PermError,
+        ]
+        .into_iter()
+        .find(|status| status.name().eq_ignore_ascii_case(name.trim()))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct IncomingAuthResult {
+    ///spf for MAIL FROM, or for the HELO name if the sender is null
     pub spf: AuthStatus,
+    ///spf for the HELO name
+    pub spf_helo: AuthStatus,
+    ///pass if any of the signatures passed
     pub dkim: AuthStatus,
-    pub dmarc: AuthStatus,
-    pub reject: bool,
+    ///every DKIM-Signature that was checked
+    pub dkim_signatures: Vec<DkimResult>,
+    pub dmarc: DmarcResult,
+    ///the ARC chain the message came with
+    pub arc: ArcResult,
+    ///the domain of MAIL FROM, or the HELO name for bounces
+    pub envelope_domain: String,
+    ///the domain of the From header
+    pub header_domain: String,
 }
 
-pub async fn verify_incoming_mail(mail: &Mail, peer_ip: IpAddr) -> IncomingAuthResult {
-    match verify_incoming_mail_inner(mail, peer_ip).await {
+///`helo` is the name the client gave, `hostname` ours. a DMARC failure is
+///let through if one of `trusted_sealers` saw it pass before forwarding
+pub async fn verify_incoming_mail(
+    resolver: &impl AuthResolver,
+    mail: &Mail,
+    peer_ip: IpAddr,
+    helo: &str,
+    hostname: &str,
+    trusted_sealers: &[String],
+) -> IncomingAuthResult {
+    match verify_incoming_mail_inner(resolver, mail, peer_ip, helo, hostname, trusted_sealers).await
+    {
         Ok(result) => result,
         Err(err) => {
             tracing::warn!("mail authentication check failed open: {}", err);
             IncomingAuthResult {
                 spf: AuthStatus::Neutral,
+                spf_helo: AuthStatus::Neutral,
                 dkim: AuthStatus::Neutral,
-                dmarc: AuthStatus::Neutral,
-                reject: false,
+                dkim_signatures: vec![],
+                dmarc: DmarcResult::new(AuthStatus::Neutral),
+                arc: ArcResult::new(AuthStatus::Neutral),
+                envelope_domain: String::new(),
+                header_domain: String::new(),
             }
         }
     }
 }
 
-async fn verify_incoming_mail_inner(mail: &Mail, peer_ip: IpAddr) -> Result<IncomingAuthResult> {
-    let resolver = AuthDns::new();
-    let envelope_domain =
-        address_domain(&mail.from).ok_or_else(|| anyhow!("missing MAIL FROM domain"))?;
-    let header_domain = header_from_domain(&mail.data).unwrap_or_else(|| envelope_domain.clone());
-    let spf = check_spf(&resolver, &envelope_domain, peer_ip, 0).await;
-    let dkim = check_dkim_header(&mail.data);
-    let dmarc = check_dmarc(&resolver, &header_domain, &envelope_domain, spf, dkim).await;
-    let reject = dmarc == AuthStatus::Fail;
+async fn verify_incoming_mail_inner(
+    resolver: &impl AuthResolver,
+    mail: &Mail,
+    peer_ip: IpAddr,
+    helo: &str,
+    hostname: &str,
+    trusted_sealers: &[String],
+) -> Result<IncomingAuthResult> {
+    let helo_sender = format!("postmaster@{helo}");
+    let spf_helo = spf::check(resolver, peer_ip, &helo_sender, helo, hostname).await;
+    //bounces are checked as the HELO name (rfc 7208 2.4)
+    let (envelope_domain, spf) = match address_domain(&mail.from) {
+        Some(domain) => {
+            let spf = spf::check(resolver, peer_ip, &mail.from, helo, hostname).await;
+            (domain, spf)
+        }
+        None if !helo.is_empty() => (helo.to_ascii_lowercase(), spf_helo.clone()),
+        None => bail!("no MAIL FROM or HELO domain"),
+    };
+    if let Some(reason) = &spf.reason {
+        tracing::debug!("spf {:?} for {envelope_domain}: {reason}", spf.status);
+    }
+    let spf = spf.status;
+    let dkim_signatures =
+        verify::verify(resolver, &mail.data, chrono::Utc::now().timestamp()).await;
+    let dkim = dkim_summary(&dkim_signatures);
+    let dkim_domains = dkim_signatures
+        .iter()
+        .filter(|s| s.status == AuthStatus::Pass)
+        .map(|s| s.domain.as_str())
+        .collect::<Vec<_>>();
+    let (header_domain, mut dmarc) = match header_from_domain(&mail.data) {
+        Ok(domain) => {
+            let dmarc = dmarc::check(resolver, &domain, &envelope_domain, spf, &dkim_domains).await;
+            (domain, dmarc)
+        }
+        //there's no one domain whose policy applies, so a forger could pick
+        //the one that suits them (rfc 7489 6.6.1)
+        Err(reason) => {
+            tracing::info!("dmarc fails, the message has {reason}");
+            let mut dmarc = DmarcResult::new(AuthStatus::Fail);
+            dmarc.disposition = dmarc::Policy::Reject;
+            (String::new(), dmarc)
+        }
+    };
+    let arc = arc::verify(resolver, &mail.data).await;
+    if let Some(reason) = &arc.reason {
+        tracing::debug!("arc {:?}: {reason}", arc.status);
+    }
+    //forwarding breaks spf and often dkim, rfc 8617 7.2.1
+    if dmarc.status == AuthStatus::Fail {
+        if let Some(sealer) = arc.trusted_dmarc_pass(trusted_sealers) {
+            tracing::info!("{sealer} saw dmarc pass for {header_domain}, not applying the policy");
+            dmarc.disposition = dmarc::Policy::None;
+            dmarc.arc_override = Some(sealer.to_string());
+        }
+    }
     Ok(IncomingAuthResult {
         spf,
+        spf_helo: spf_helo.status,
         dkim,
+        dkim_signatures,
         dmarc,
-        reject,
+        arc,
+        envelope_domain,
+        header_domain,
     })
 }
 
-struct AuthDns {
-    resolver: hickory_resolver::TokioAsyncResolver,
+///the lookups mail authentication needs, so the checks can run without dns
+pub trait AuthResolver {
+    ///the TXT records of `name`, each joined into one string. empty if
+    ///there are none, an error only if the lookup itself failed
+    async fn txt(&self, name: &str) -> Result<Vec<String>>;
+    ///the A records of `name`, or its AAAA records
+    async fn ips(&self, name: &str, ipv6: bool) -> Result<Vec<IpAddr>>;
+    ///the hosts in the MX records of `domain`, a null MX is an empty host
+    async fn mx(&self, domain: &str) -> Result<Vec<String>>;
+    ///the names in the PTR records of `ip`
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>>;
 }
 
-impl AuthDns {
-    fn new() -> Self {
-        Self {
-            resolver: hickory_resolver::TokioAsyncResolver::tokio(
-                ResolverConfig::default(),
-                ResolverOpts::default(),
-            ),
-        }
-    }
+///one passing signature is enough, otherwise the most telling failure
+fn dkim_summary(signatures: &[DkimResult]) -> AuthStatus {
+    [
+        AuthStatus::Pass,
+        AuthStatus::Fail,
+        AuthStatus::PermError,
+        AuthStatus::TempError,
+    ]
+    .into_iter()
+    .find(|status| signatures.iter().any(|s| s.status == *status))
+    .unwrap_or(AuthStatus::None)
+}
 
-    async fn txt(&self, name: &str) -> Result<Vec<String>> {
-        let key = name.trim_end_matches('.').to_ascii_lowercase();
-        if let Some(cached) = TXT_CACHE
-            .get_or_init(|| Mutex::new(HashMap::new()))
-            .lock()
-            .ok()
-            .and_then(|cache| cache.get(&key).cloned())
-        {
-            return Ok(cached);
-        }
+fn address_domain(address: &str) -> Option<String> {
+    address
+        .trim()
+        .trim_matches('<')
+        .trim_matches('>')
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim().trim_end_matches('>').to_ascii_lowercase())
+}
 
-        let lookup = self.resolver.txt_lookup(format!("{}.", key)).await?;
-        let records = lookup
-            .iter()
-            .map(|txt| {
-                txt.txt_data()
-                    .iter()
-                    .map(|part| String::from_utf8_lossy(part).to_string())
-                    .collect::<String>()
-            })
-            .collect::<Vec<_>>();
-        if let Ok(mut cache) = TXT_CACHE.get_or_init(|| Mutex::new(HashMap::new())).lock() {
-            cache.insert(key, records.clone());
+///the domain of the one address in the one From field, what DMARC checks.
+///the error says what's wrong with From otherwise
+fn header_from_domain(data: &[u8]) -> Result<String, &'static str> {
+    let (headers, _) = mailparse::parse_headers(data).map_err(|_| "a broken header")?;
+    let mut fields = headers
+        .iter()
+        .filter(|h| h.get_key_ref().eq_ignore_ascii_case("from"));
+    let (Some(field), None) = (fields.next(), fields.next()) else {
+        return Err("no From field, or more than one");
+    };
+    let addresses = mailparse::addrparse_header(field).map_err(|_| "a broken From field")?;
+    let [MailAddr::Single(from)] = &addresses[..] else {
+        return Err("more than one From address");
+    };
+    match from.addr.rsplit_once('@') {
+        Some((_, domain)) if !domain.is_empty() => {
+            Ok(domain.trim_end_matches('.').to_ascii_lowercase())
         }
-        Ok(records)
+        _ => Err("no domain in From"),
     }
+}
 
-    async fn lookup_ips(&self, name: &str) -> Vec<IpAddr> {
-        self.resolver
-            .lookup_ip(format!("{}.", name.trim_end_matches('.')))
-            .await
-            .map(|lookup| lookup.iter().collect())
-            .unwrap_or_default()
-    }
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
 
-    async fn lookup_mx_ips(&self, domain: &str) -> Vec<IpAddr> {
-        let mut ips = Vec::new();
-        if let Ok(mx) = self
-            .resolver
-            .mx_lookup(format!("{}.", domain.trim_end_matches('.')))
-            .await
-        {
-            for record in mx.iter() {
-                ips.extend(self.lookup_ips(&record.exchange().to_string()).await);
-            }
-        }
-        ips
-    }
-}
+    use anyhow::{bail, Result};
+
+    use super::{header_from_domain, AuthResolver};
 
-async fn check_spf(resolver: &AuthDns, domain: &str, ip: IpAddr, depth: usize) -> AuthStatus {
-    if depth > 10 {
-        return AuthStatus::Neutral;
+    ///dns from a table, names it doesn't know have no records
+    #[derive(Default)]
+    pub struct FakeDns {
+        txt: HashMap<String, Vec<String>>,
+        ips: HashMap<String, Vec<IpAddr>>,
+        mx: HashMap<String, Vec<String>>,
+        ptr: HashMap<IpAddr, Vec<String>>,
+        ///lookups of these names time out
+        broken: Vec<String>,
     }
-    let Ok(records) = resolver.txt(domain).await else {
-        return AuthStatus::None;
-    };
-    let Some(record) = records
-        .iter()
-        .find(|txt| txt.to_ascii_lowercase().starts_with("v=spf1"))
-    else {
-        return AuthStatus::None;
-    };
 
-    for mechanism in record.split_whitespace().skip(1) {
-        if mechanism.starts_with("redirect=") {
-            let redirect = mechanism.trim_start_matches("redirect=");
-            return Box::pin(check_spf(resolver, redirect, ip, depth + 1)).await;
+    impl FakeDns {
+        pub fn txt(mut self, name: &str, record: &str) -> Self {
+            self.txt
+                .entry(name.to_ascii_lowercase())
+                .or_default()
+                .push(record.to_string());
+            self
         }
-        let (qualifier, body) = match mechanism.as_bytes().first() {
-            Some(b'+') | Some(b'-') | Some(b'~') | Some(b'?') => {
-                (mechanism.as_bytes()[0] as char, &mechanism[1..])
-            }
-            _ => ('+', mechanism),
-        };
-        let matched = spf_mechanism_matches(resolver, domain, body, ip, depth).await;
-        if matched {
-            return match qualifier {
-                '+' => AuthStatus::Pass,
-                '-' => AuthStatus::Fail,
-                '~' => AuthStatus::SoftFail,
-                '?' => AuthStatus::Neutral,
-                _ => AuthStatus::Neutral,
-            };
+
+        pub fn ip(mut self, name: &str, ip: &str) -> Self {
+            self.ips
+                .entry(name.to_ascii_lowercase())
+                .or_default()
+                .push(ip.parse().unwrap());
+            self
         }
-    }
-    AuthStatus::Neutral
-}
 
-async fn spf_mechanism_matches(
-    resolver: &AuthDns,
-    domain: &str,
-    mechanism: &str,
-    ip: IpAddr,
-    depth: usize,
-) -> bool {
-    if mechanism == "all" {
-        return true;
-    }
-    if let Some(rest) = mechanism.strip_prefix("ip4:") {
-        return ip_matches_cidr(ip, rest);
-    }
-    if let Some(rest) = mechanism.strip_prefix("ip6:") {
-        return ip_matches_cidr(ip, rest);
-    }
-    if mechanism == "a" || mechanism.starts_with("a:") {
-        let host = mechanism
-            .strip_prefix("a:")
-            .unwrap_or(domain)
-            .split('/')
-            .next()
-            .unwrap_or(domain);
-        return resolver
-            .lookup_ips(host)
-            .await
-            .into_iter()
-            .any(|candidate| candidate == ip);
-    }
-    if mechanism == "mx" || mechanism.starts_with("mx:") {
-        let host = mechanism
-            .strip_prefix("mx:")
-            .unwrap_or(domain)
-            .split('/')
-            .next()
-            .unwrap_or(domain);
-        return resolver
-            .lookup_mx_ips(host)
-            .await
-            .into_iter()
-            .any(|candidate| candidate == ip);
-    }
-    if let Some(include) = mechanism.strip_prefix("include:") {
-        return Box::pin(check_spf(resolver, include, ip, depth + 1)).await == AuthStatus::Pass;
-    }
-    false
-}
+        pub fn mx(mut self, domain: &str, host: &str) -> Self {
+            self.mx
+                .entry(domain.to_ascii_lowercase())
+                .or_default()
+                .push(host.to_string());
+            self
+        }
 
-async fn check_dmarc(
-    resolver: &AuthDns,
-    header_domain: &str,
-    envelope_domain: &str,
-    spf: AuthStatus,
-    dkim: AuthStatus,
-) -> AuthStatus {
-    let record_name = format!("_dmarc.{}", header_domain);
-    let Ok(records) = resolver.txt(&record_name).await else {
-        return AuthStatus::None;
-    };
-    let Some(record) = records
-        .iter()
-        .find(|txt| txt.to_ascii_lowercase().starts_with("v=dmarc1"))
-    else {
-        return AuthStatus::None;
-    };
-    let policy = record
-        .split(';')
-        .map(str::trim)
-        .find_map(|part| part.strip_prefix("p=").map(str::to_ascii_lowercase))
-        .unwrap_or_else(|| "none".to_string());
-    let aligned_spf = spf == AuthStatus::Pass && domains_align(header_domain, envelope_domain);
-    let aligned_dkim = dkim == AuthStatus::Pass;
-    if aligned_spf || aligned_dkim || policy == "none" {
-        AuthStatus::Pass
-    } else if policy == "reject" || policy == "quarantine" {
-        AuthStatus::Fail
-    } else {
-        AuthStatus::Neutral
-    }
-}
+        pub fn ptr(mut self, ip: &str, name: &str) -> Self {
+            self.ptr
+                .entry(ip.parse().unwrap())
+                .or_default()
+                .push(name.to_string());
+            self
+        }
 
-fn check_dkim_header(data: &str) -> AuthStatus {
-    let Ok(parsed) = mailparse::parse_mail(data.as_bytes()) else {
-        return AuthStatus::None;
-    };
-    if parsed.headers.get_first_value("DKIM-Signature").is_some() {
-        // Full DKIM cryptographic verification needs RSA/Ed25519 verification support.
-        // Keep the signal visible for DMARC, but fail open unless SPF+DMARC rejects.
-        AuthStatus::Neutral
-    } else {
-        AuthStatus::None
+        ///lookups of `name` fail
+        pub fn broken(mut self, name: &str) -> Self {
+            self.broken.push(name.to_ascii_lowercase());
+            self
+        }
     }
-}
 
-fn address_domain(address: &str) -> Option<String> {
-    address
-        .trim()
-        .trim_matches('<')
-        .trim_matches('>')
-        .rsplit_once('@')
-        .map(|(_, domain)| domain.trim().trim_end_matches('>').to_ascii_lowercase())
-}
+    fn lookup<T: Clone>(
+        table: &HashMap<String, Vec<T>>,
+        broken: &[String],
+        name: &str,
+    ) -> Result<Vec<T>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if broken.contains(&name) {
+            bail!("timed out looking up {name}");
+        }
+        Ok(table.get(&name).cloned().unwrap_or_default())
+    }
 
-fn header_from_domain(data: &str) -> Option<String> {
-    let parsed = mailparse::parse_mail(data.as_bytes()).ok()?;
-    let from = parsed.headers.get_first_value("From")?;
-    address_domain(&from)
-}
+    impl AuthResolver for FakeDns {
+        async fn txt(&self, name: &str) -> Result<Vec<String>> {
+            lookup(&self.txt, &self.broken, name)
+        }
 
-fn domains_align(a: &str, b: &str) -> bool {
-    a.eq_ignore_ascii_case(b) || a.ends_with(&format!(".{}", b)) || b.ends_with(&format!(".{}", a))
-}
+        async fn ips(&self, name: &str, ipv6: bool) -> Result<Vec<IpAddr>> {
+            let mut ips = lookup(&self.ips, &self.broken, name)?;
+            ips.retain(|ip| ip.is_ipv6() == ipv6);
+            Ok(ips)
+        }
 
-fn ip_matches_cidr(ip: IpAddr, cidr: &str) -> bool {
-    let (addr, prefix) = cidr.split_once('/').unwrap_or((cidr, ""));
-    let Ok(network) = addr.parse::<IpAddr>() else {
-        return false;
-    };
-    match (ip, network) {
-        (IpAddr::V4(ip), IpAddr::V4(net)) => {
-            let prefix = prefix.parse::<u32>().unwrap_or(32).min(32);
-            let mask = if prefix == 0 {
-                0
-            } else {
-                u32::MAX << (32 - prefix)
-            };
-            (u32::from(ip) & mask) == (u32::from(net) & mask)
+        async fn mx(&self, domain: &str) -> Result<Vec<String>> {
+            lookup(&self.mx, &self.broken, domain)
         }
-        (IpAddr::V6(ip), IpAddr::V6(net)) => {
-            let prefix = prefix.parse::<u32>().unwrap_or(128).min(128);
-            let mask = if prefix == 0 {
-                0
-            } else {
-                u128::MAX << (128 - prefix)
-            };
-            (u128::from(ip) & mask) == (u128::from(net) & mask)
+
+        async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>> {
+            Ok(self.ptr.get(&ip).cloned().unwrap_or_default())
         }
-        _ => false,
+    }
+
+    #[test]
+    fn test_header_from_domain() {
+        let domain = |header: &str| header_from_domain(format!("{header}\r\nhi\r\n").as_bytes());
+        assert_eq!(
+            domain("From: Alice <alice@Kaki.FOO>\r\n").as_deref(),
+            Ok("kaki.foo")
+        );
+        //the comment isn't the address
+        assert_eq!(
+            domain("From: a@paypal.com (x@evil.example)\r\n").as_deref(),
+            Ok("paypal.com")
+        );
+        assert!(domain("Subject: hi\r\n").is_err());
+        assert!(domain("From: a@paypal.com\r\nFrom: x@evil.example\r\n").is_err());
+        assert!(domain("From: a@paypal.com, x@evil.example\r\n").is_err());
+        assert!(domain("From: nobody\r\n").is_err());
     }
 }
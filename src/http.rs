@@ -0,0 +1,165 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::Config;
+
+///the request line and headers, nothing we serve needs more
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const POLICY_PATH: &str = "/.well-known/mta-sts.txt";
+
+///answers one https request for the MTA-STS policy (rfc 8461 3.3) and
+///closes the connection
+pub async fn serve(config: Arc<Config>, stream: TcpStream, acceptor: TlsAcceptor) -> Result<()> {
+    //a handshake that never finishes would hold a connection slot the
+    //mail listeners share
+    let stream = timeout(READ_TIMEOUT, acceptor.accept(stream)).await??;
+    answer(&config, stream).await
+}
+
+async fn answer(config: &Config, mut stream: impl AsyncRead + AsyncWrite + Unpin) -> Result<()> {
+    let head = timeout(READ_TIMEOUT, read_head(&mut stream)).await??;
+    stream.write_all(&respond(config, &head)).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_SIZE {
+            bail!("request head too long");
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            bail!("connection closed mid request");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+///the whole response to a request head
+fn respond(config: &Config, head: &str) -> Vec<u8> {
+    let mut lines = head.lines();
+    let mut request = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = (request.next().unwrap_or_default(), request.next());
+    let host = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim())
+        .unwrap_or_default();
+    //the port, if any, isn't part of the name
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    if method != "GET" && method != "HEAD" {
+        return response("405 Method Not Allowed", "method not allowed\r\n", true);
+    }
+    let policy = path
+        .filter(|path| *path == POLICY_PATH)
+        .and_then(|_| config.mta_sts_policy(host));
+    match policy {
+        Some(policy) => response("200 OK", &policy.to_text(), method == "GET"),
+        None => response("404 Not Found", "not found\r\n", method == "GET"),
+    }
+}
+
+fn response(status: &str, body: &str, with_body: bool) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    if with_body {
+        response += body;
+    }
+    response.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{answer, respond};
+    use crate::config::Config;
+
+    fn config() -> Config {
+        Config::parse(
+            r#"
+            hostname = "mx.kaki.foo"
+            domains = [{ name = "kaki.foo", mta_sts = { mode = "enforce", max_age = 86400 } }]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_respond() {
+        let config = config();
+        let ok = respond(
+            &config,
+            "GET /.well-known/mta-sts.txt HTTP/1.1\r\nHost: mta-sts.kaki.foo:443\r\n\r\n",
+        );
+        let ok = String::from_utf8(ok).unwrap();
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.contains("Content-Type: text/plain\r\n"));
+        assert!(ok.ends_with(
+            "\r\n\r\nversion: STSv1\r\nmode: enforce\r\nmx: mx.kaki.foo\r\nmax_age: 86400\r\n"
+        ));
+        let head = respond(
+            &config,
+            "HEAD /.well-known/mta-sts.txt HTTP/1.1\r\nhost: mta-sts.kaki.foo\r\n\r\n",
+        );
+        assert!(head.ends_with(b"Connection: close\r\n\r\n"));
+        let status = |head: &str| {
+            let response = String::from_utf8(respond(&config, head)).unwrap();
+            response.lines().next().unwrap().to_string()
+        };
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nHost: mta-sts.kaki.foo\r\n\r\n"),
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(
+            status("GET /.well-known/mta-sts.txt HTTP/1.1\r\nHost: kaki.foo\r\n\r\n"),
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(
+            status("POST /.well-known/mta-sts.txt HTTP/1.1\r\nHost: mta-sts.kaki.foo\r\n\r\n"),
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_answer() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let config = config();
+        let serve = answer(&config, server);
+        let request = async {
+            client
+                .write_all(b"GET /.well-known/mta-sts.txt HTTP/1.1\r\n")
+                .await
+                .unwrap();
+            client
+                .write_all(b"Host: mta-sts.kaki.foo\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let (served, response) = tokio::join!(serve, request);
+        served.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("mode: enforce"));
+    }
+}
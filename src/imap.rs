@@ -2,14 +2,15 @@ use std::time::Duration;
 use std::{sync::Arc, u8};
 
 use anyhow::{anyhow, Context, Ok, Result};
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::{mpsc::Sender, Mutex},
+    sync::Mutex,
 };
 
 use crate::{
-    database::{self},
+    config::Config,
+    database::{self, Change},
     imap_op,
     tls::StreamType,
 };
@@ -56,7 +57,8 @@ pub struct IMAP {
     stream: StreamType,
     db: Arc<Mutex<database::DBClient>>,
     tls_acceptor: tokio_rustls::TlsAcceptor,
-    change_receiver: Arc<Mutex<Receiver<String>>>,
+    ///this session's own, so every idling one hears about a change
+    change_receiver: Receiver<Change>,
 }
 
 impl IMAP {
@@ -66,12 +68,13 @@ impl IMAP {
 
     /// Creates a new server from a connected stream
     pub async fn new(
+        config: Arc<Config>,
         stream: tokio::net::TcpStream,
         acceptor: tokio_rustls::TlsAcceptor,
         implicit_tls: bool,
-        tx: Sender<String>,
-        rx: Arc<Mutex<Receiver<String>>>,
+        tx: Sender<Change>,
     ) -> Result<Self> {
+        let change_receiver = tx.subscribe();
         let stream_type = if !implicit_tls {
             StreamType::Plain(stream)
         } else {
@@ -81,9 +84,9 @@ impl IMAP {
         Ok(Self {
             stream: stream_type,
             state: IMAPState::NotAuthed,
-            db: Arc::new(Mutex::new(database::DBClient::new(tx).await?)),
+            db: Arc::new(Mutex::new(database::DBClient::new(tx, &config).await?)),
             tls_acceptor: acceptor,
-            change_receiver: rx,
+            change_receiver,
         })
     }
     //not self bc we need ownership of the stream
@@ -96,7 +99,7 @@ impl IMAP {
         mut state: IMAPState,
         tls_acceptor: tokio_rustls::TlsAcceptor,
         raw_msg: &str,
-        changes: Arc<Mutex<Receiver<String>>>,
+        changes: &mut Receiver<Change>,
     ) -> Result<(IMAPState, StreamType)> {
         if raw_msg == "\r\n" {
             return Ok((state, stream));
@@ -143,7 +146,10 @@ impl IMAP {
                 state = new_state;
             }
             ResponseInfo::Idle => {
-                let mut change_rx = changes.lock().await;
+                let mailbox_id = match state {
+                    IMAPState::Selected(selected) => Some(selected.mailbox_id),
+                    _ => None,
+                };
                 let mut buf = [0u8; 1024];
                 //idk why we have to import it, it complained otherwise
                 use core::result::Result::Ok;
@@ -166,11 +172,9 @@ impl IMAP {
                             }
                         }
                     }
-                    change = change_rx.recv() => {
-                        if let Some(change_str) = change {
-                            tracing::info!("changes: {}" ,change_str);
-                            stream.write_all(change_str.as_bytes()).await?;
-                        }
+                    change_str = next_change(changes, mailbox_id) => {
+                        tracing::info!("changes: {}" ,change_str);
+                        stream.write_all(change_str.as_bytes()).await?;
                     }
                 }
             }
@@ -192,7 +196,7 @@ impl IMAP {
                     self.state,
                     self.tls_acceptor,
                     "logout",
-                    self.change_receiver,
+                    &mut self.change_receiver,
                 )
                 .await
                 .ok();
@@ -205,7 +209,7 @@ impl IMAP {
                 self.state,
                 self.tls_acceptor.clone(),
                 msg,
-                self.change_receiver.clone(),
+                &mut self.change_receiver,
             )
             .await
             .map_err(|e| {
@@ -230,6 +234,18 @@ impl IMAP {
     }
 }
 
+///waits for a change to the mailbox, there are none without one. the ones
+///missed while the session wasn't listening are skipped
+async fn next_change(changes: &mut Receiver<Change>, mailbox_id: Option<i32>) -> String {
+    loop {
+        match changes.recv().await {
+            Result::Ok((id, change)) if Some(id) == mailbox_id => return change,
+            Result::Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
 async fn exec_command(
     command: &str,
 
@@ -275,3 +291,29 @@ async fn exec_command(
         _ => Err(anyhow!("invalid command")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use super::next_change;
+    use crate::database::Change;
+
+    #[tokio::test]
+    async fn test_next_change() {
+        let (tx, _) = broadcast::channel::<Change>(4);
+        //two sessions idling in mailbox 1
+        let (mut a, mut b) = (tx.subscribe(), tx.subscribe());
+        tx.send((2, "* 1 EXPUNGE\r\n".to_string())).unwrap();
+        tx.send((1, "* 1 EXISTS\r\n".to_string())).unwrap();
+        assert_eq!(next_change(&mut a, Some(1)).await, "* 1 EXISTS\r\n");
+        assert_eq!(next_change(&mut b, Some(1)).await, "* 1 EXISTS\r\n");
+        //one that fell behind skips what it missed
+        let mut late = tx.subscribe();
+        for _ in 0..5 {
+            tx.send((1, "* 1 EXPUNGE\r\n".to_string())).unwrap();
+        }
+        tx.send((1, "* 1 EXISTS\r\n".to_string())).unwrap();
+        assert_eq!(next_change(&mut late, Some(1)).await, "* 1 EXPUNGE\r\n");
+    }
+}
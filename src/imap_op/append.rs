@@ -54,7 +54,8 @@ impl IMAPOp for Append {
         let mail = crate::smtp_common::Mail {
             from,
             to: recipients,
-            data: parsed.mail_data,
+            data: parsed.mail_data.into_bytes(),
+            ..Default::default()
         };
         let new_uid = db
             .lock()
@@ -71,7 +71,7 @@ pub(crate) async fn fetch_or_uid(
 
     let mut final_vec: Vec<Vec<u8>> = vec![];
     for row in rows {
-        let mail = mailparse::parse_mail(row.data.as_bytes())?;
+        let mail = mailparse::parse_mail(&row.data)?;
         let mut temp_buf = format!("* {} FETCH (", row.seqnum).into_bytes();
         for item in &fetch_args {
             match item {
@@ -1,153 +1,239 @@
 use anyhow::*;
+use certs::resolver::CertReloader;
+use config::Config;
 use core::result::Result::Ok;
 use dotenv::dotenv;
-use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-use tokio_rustls::TlsAcceptor;
+use supervisor::{Listener, Supervisor};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
 
+mod certs;
+mod config;
+mod dane;
 mod database;
+mod dkim;
+mod dmarc;
+mod dns;
+mod dsn;
 mod email_auth;
+mod http;
 mod imap;
 mod imap_op;
+mod mta_sts;
+mod outbound_tls;
 mod parsing;
+mod queue;
+mod recipients;
+mod smtp_client;
+mod smtp_codec;
 mod smtp_common;
 mod smtp_incoming;
 mod smtp_outgoing;
+mod spf;
+mod supervisor;
 mod tls;
+mod tls_rpt;
+mod trace;
 mod utils;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("dkim-keygen") {
+        return dkim_keygen(&args[2..]);
+    }
     dotenv()?;
 
-    tracing_subscriber::fmt::init();
-    let mut args = std::env::args();
-
-    let smtp_addr = args.nth(1).unwrap_or("127.0.0.1".to_string());
-    let smtp_port = args.next().unwrap_or("25".to_string());
-    let smtp_subm = args.next().unwrap_or("587".to_string());
-    let imap_port = args.next().unwrap_or("143".to_string());
-    let imaps_port = args.next().unwrap_or("993".to_string());
-    let smtps_subm = args.next().unwrap_or("465".to_string());
+    let config_path = args
+        .get(1)
+        .cloned()
+        .or_else(|| std::env::var("KAKIMAIL_CONFIG").ok())
+        .unwrap_or(config::DEFAULT_CONFIG_PATH.to_string());
+    let config = Arc::new(Config::load(config_path)?);
 
-    let domain = &args.next().unwrap_or("smtp.kaki.foo".to_string());
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .or_else(|_| tracing_subscriber::EnvFilter::try_new(&config.logging.filter))?;
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    //go from smtp.kaki.foo to kaki.foo
-    let domain_stripped = &domain.split(".").collect::<Vec<&str>>()[1..].join(".");
-    tracing::info!("requesting certs...");
-    let client = reqwest::Client::new();
-    let mut resp = client
-        .post(format!(
-            "https://api.porkbun.com/api/json/v3/ssl/retrieve/{}",
-            domain_stripped
-        ))
-        .body(format!(
-            //TODO don't hardcode the json, looks ugly
-            "
-            {{
-                \"secretapikey\": \"{}\",
-                \"apikey\": \"{}\"
-            }}
-            ",
-            std::env::var("PORKBUN_SECRET_API_KEY")?,
-            std::env::var("PORKBUN_API_KEY")?
-        ))
-        .send()
-        .await?
-        .json::<HashMap<String, String>>()
-        .await?;
-    let cert_chain = resp
-        .get_mut("certificatechain")
-        .context("should provide certchain")?;
-    let certs = rustls_pemfile::certs(&mut std::io::Cursor::new(cert_chain.clone()))
-        .flatten()
-        .collect::<Vec<_>>();
-    let key_resp = resp
-        .get("privatekey")
-        .context("should provide private key")?;
-    let key = rustls_pemfile::private_key(&mut std::io::Cursor::new(key_resp.clone()))?
-        .context("should be a valid key")?;
-    let config = tokio_rustls::rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key.into())?;
-    let acceptor = &TlsAcceptor::from(Arc::new(config));
+    tracing::info!("loading certs...");
+    let reloader = CertReloader::new(&config);
+    reloader.load_all().await?;
+    let acceptor = reloader.store().acceptor();
+    tokio::spawn(async move {
+        if let Err(e) = reloader.run().await {
+            tracing::error!("certificate reloading stopped: {:?}", e);
+        }
+    });
     tracing::debug!("acceptor ready");
 
-    let incoming_listener = TcpListener::bind(format!("{smtp_addr}:{smtp_port}")).await?;
-    let outgoing_listener = TcpListener::bind(format!("{smtp_addr}:{smtp_subm}")).await?;
-    let imap_listener = TcpListener::bind(format!("{smtp_addr}:{imap_port}")).await?;
-    let imaps_listener = TcpListener::bind(format!("{smtp_addr}:{imaps_port}")).await?;
-    let smtps_listener = TcpListener::bind(format!("{smtp_addr}:{smtps_subm}")).await?;
-    tracing::info!("listening on: {}", smtp_addr);
-    tracing::info!("smtp port is: {}", smtp_port);
-    tracing::info!("submission port is: {}", smtp_subm);
-    tracing::info!("imap port is: {}", imap_port);
-    tracing::info!("imaps port is: {}", imaps_port);
-    tracing::info!("smtps port is: {}", smtps_subm);
-    tracing::info!("smtp server for {domain} started!");
-    let (tx, rx) = tokio::sync::mpsc::channel::<String>(128);
-
-    let new_rx = Arc::new(Mutex::new(rx));
-    let new_tx = &tx;
+    let listen = &config.listen;
+    let address = &listen.address;
+    let incoming_listener = TcpListener::bind((address.as_str(), listen.smtp)).await?;
+    let outgoing_listener = TcpListener::bind((address.as_str(), listen.submission)).await?;
+    let imap_listener = TcpListener::bind((address.as_str(), listen.imap)).await?;
+    let imaps_listener = TcpListener::bind((address.as_str(), listen.imaps)).await?;
+    let smtps_listener = TcpListener::bind((address.as_str(), listen.smtps)).await?;
+    //only opened when there's a policy to serve
+    let https_listener = if config.domains.iter().any(|d| d.mta_sts.is_some()) {
+        Some(TcpListener::bind((address.as_str(), listen.https)).await?)
+    } else {
+        None
+    };
+    tracing::info!("listening on: {}", address);
+    tracing::info!("smtp port is: {}", listen.smtp);
+    tracing::info!("submission port is: {}", listen.submission);
+    tracing::info!("imap port is: {}", listen.imap);
+    tracing::info!("imaps port is: {}", listen.imaps);
+    tracing::info!("smtps port is: {}", listen.smtps);
+    if https_listener.is_some() {
+        tracing::info!("https port is: {}", listen.https);
+    }
+    for domain in &config.domains {
+        let Some(policy) = config.mta_sts_policy(&format!("mta-sts.{}", domain.name)) else {
+            continue;
+        };
+        tracing::info!(
+            "serving an mta-sts policy for {}, publish _mta-sts.{} TXT \"v=STSv1; id={}\"",
+            domain.name,
+            domain.name,
+            policy.id()
+        );
+    }
+    tracing::info!(
+        "smtp server {} for {} started!",
+        config.hostname,
+        config
+            .domains
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    //each imap session subscribes for itself
+    let (tx, _) = tokio::sync::broadcast::channel::<database::Change>(128);
+    let dkim = Arc::new(dkim::DkimSigner::load(&config)?);
+    if let Err(e) = dmarc::load_public_suffixes(&config.dmarc.public_suffix_list) {
+        tracing::warn!("{:#}, organizational domains will be guessed", e);
+    }
+    let dns = Arc::new(dns::Dns::new(&config.dns)?);
+    let queue_wakeup = Arc::new(Notify::new());
+    let queue = queue::Queue::new(
+        config.clone(),
+        tx.clone(),
+        queue_wakeup.clone(),
+        dns.clone(),
+    )
+    .await?;
+    let reporter = if config.dmarc.send_reports {
+        Some(
+            dmarc::report::Reporter::new(
+                config.clone(),
+                tx.clone(),
+                dkim.clone(),
+                queue_wakeup.clone(),
+                dns.clone(),
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+    let supervisor = Supervisor::new(config.connection_limits());
+    //the sessions aren't Send, so they all live on this LocalSet
+    let local = tokio::task::LocalSet::new();
     //main server loop
-    loop {
-        let loop_rx = new_rx.clone();
-        tokio::select! {
-            Ok((incoming_stream, incoming_addr)) = incoming_listener.accept() => {
-                tracing::info!("recieved incoming connection from {}", incoming_addr);
-                tokio::task::LocalSet::new()
-                    .run_until(async move {
-                        let smtp = smtp_incoming::SmtpIncoming::new(domain.to_string(), incoming_stream, domain_stripped.to_string(),
-                            new_tx.clone(), false ,acceptor.clone()).await?;
-                        smtp.serve().await
-                    })
-                    .await
-                    .ok();
-            }
-            Ok((outgoing_stream, outgoing_addr)) = outgoing_listener.accept() => {
-                tracing::info!("recieved outgoing connection from {}", outgoing_addr);
-                tokio::task::LocalSet::new()
-                    .run_until(async move {
-                        let smtp = smtp_outgoing::SmtpOutgoing::new(domain.to_string(), outgoing_stream, new_tx.clone(), false,
-                            acceptor.clone()).await?;
-                        smtp.serve().await
-                    })
-                    .await
-                    .ok();
+    local
+        .run_until(async move {
+            tokio::task::spawn_local(async move {
+                if let Err(e) = queue.run().await {
+                    tracing::error!("the outbound queue stopped: {:?}", e);
+                }
+            });
+            if let Some(reporter) = reporter {
+                tokio::task::spawn_local(async move {
+                    if let Err(e) = reporter.run().await {
+                        tracing::error!("dmarc reporting stopped: {:?}", e);
+                    }
+                });
             }
-            Ok((smtps_stream, smtps_addr)) = smtps_listener.accept() => {
-                tracing::info!("recieved outgoing smtps connection from {}", smtps_addr);
-                tokio::task::LocalSet::new()
-                    .run_until(async move {
-                        let smtp = smtp_outgoing::SmtpOutgoing::new(domain.to_string(), smtps_stream, new_tx.clone(), true,
-                            acceptor.clone()).await?;
-                        smtp.serve().await
-                    })
-                    .await
-                    .ok();
+            loop {
+                let config = config.clone();
+                let queue_wakeup = queue_wakeup.clone();
+                let dkim = dkim.clone();
+                let dns = dns.clone();
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::select! {
+                    Ok((incoming_stream, incoming_addr)) = incoming_listener.accept() => {
+                        tracing::info!("recieved incoming connection from {}", incoming_addr);
+                        supervisor.spawn(Listener::Smtp, incoming_stream, incoming_addr, move |stream| async move {
+                            let smtp = smtp_incoming::SmtpIncoming::new(config, stream, tx, false,
+                                acceptor, smtp_incoming::Forwarder { queue: queue_wakeup, dkim }, dns).await?;
+                            smtp.serve().await
+                        });
+                    }
+                    Ok((outgoing_stream, outgoing_addr)) = outgoing_listener.accept() => {
+                        tracing::info!("recieved outgoing connection from {}", outgoing_addr);
+                        supervisor.spawn(Listener::Submission, outgoing_stream, outgoing_addr, move |stream| async move {
+                            let smtp = smtp_outgoing::SmtpOutgoing::new(config, stream, tx, false,
+                                acceptor, queue_wakeup, dkim).await?;
+                            smtp.serve().await
+                        });
+                    }
+                    Ok((smtps_stream, smtps_addr)) = smtps_listener.accept() => {
+                        tracing::info!("recieved outgoing smtps connection from {}", smtps_addr);
+                        supervisor.spawn(Listener::Smtps, smtps_stream, smtps_addr, move |stream| async move {
+                            let smtp = smtp_outgoing::SmtpOutgoing::new(config, stream, tx, true,
+                                acceptor, queue_wakeup, dkim).await?;
+                            smtp.serve().await
+                        });
+                    }
+                    Ok((imap_stream, imap_addr)) = imap_listener.accept() => {
+                        tracing::info!("recieved imap connection from {}", imap_addr);
+                        supervisor.spawn(Listener::Imap, imap_stream, imap_addr, move |stream| async move {
+                            let imap = imap::IMAP::new(config, stream, acceptor, false, tx).await?;
+                            imap.serve().await
+                        });
+                    }
+                    Ok((imaps_stream, imaps_addr)) = imaps_listener.accept() => {
+                        tracing::info!("recieved imaps connection from {}", imaps_addr);
+                        supervisor.spawn(Listener::Imaps, imaps_stream, imaps_addr, move |stream| async move {
+                            let imap = imap::IMAP::new(config, stream, acceptor, true, tx).await?;
+                            imap.serve().await
+                        });
+                    }
+                    Ok((https_stream, https_addr)) = accept(&https_listener) => {
+                        tracing::debug!("recieved https connection from {}", https_addr);
+                        supervisor.spawn(Listener::Https, https_stream, https_addr, move |stream| {
+                            http::serve(config, stream, acceptor)
+                        });
+                    }
+                }
             }
-            Ok((imap_stream, imap_addr)) = imap_listener.accept() => {
-                tracing::info!("recieved imap connection from {}", imap_addr);
-                tokio::task::LocalSet::new()
-                    .run_until(async move {
-                        let imap = imap::IMAP::new(imap_stream,acceptor.clone(),false, new_tx.clone(),loop_rx).await?;
-                        imap.serve().await
-                    })
-                    .await
-                    .ok();
-            }
-            Ok((imaps_stream, imaps_addr)) = imaps_listener.accept() => {
-                tracing::info!("recieved imaps connection from {}", imaps_addr);
-                tokio::task::LocalSet::new()
-                    .run_until(async move {
-                        let imap = imap::IMAP::new(imaps_stream,acceptor.clone(),true, new_tx.clone(),loop_rx).await?;
-                        imap.serve().await
-                    })
-                    .await
-                    .ok();
-            }
-        }
+        })
+        .await
+}
+
+///waits forever if there's no listener
+async fn accept(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
     }
 }
+
+///`kakimail dkim-keygen <rsa|ed25519> <selector> <domain> <key path>`
+fn dkim_keygen(args: &[String]) -> Result<()> {
+    let [algorithm, selector, domain, path] = args else {
+        bail!("usage: kakimail dkim-keygen <rsa|ed25519> <selector> <domain> <key path>");
+    };
+    let record = dkim::keygen(algorithm, selector, domain, path.as_ref())?;
+    println!("wrote the private key to {path}, publish this record:\n\n{record}\n");
+    println!("and add the key to the domain in the config:\n");
+    println!(
+        "  {{ name = \"{domain}\", dkim = [{{ selector = \"{selector}\", key = \"{path}\" }}] }}"
+    );
+    Ok(())
+}
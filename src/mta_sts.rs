@@ -0,0 +1,446 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use ring::digest;
+use serde::Deserialize;
+
+use crate::email_auth::AuthResolver;
+
+///policies can't ask to be kept longer than a year (rfc 8461 3.2)
+const MAX_AGE_LIMIT: u64 = 31_557_600;
+///a longer policy file is broken
+const MAX_POLICY_SIZE: usize = 64 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    ///only hosts that match get the mail, over tls with a valid certificate
+    Enforce,
+    ///failures are reported but the mail is delivered anyway
+    Testing,
+    None,
+}
+
+///a domain's MTA-STS policy, the file at
+///`https://mta-sts.<domain>/.well-known/mta-sts.txt` (rfc 8461 3.2)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub mode: Mode,
+    ///the allowed mail hosts, `*.example.com` is any one label under it
+    pub mx: Vec<String>,
+    ///how many seconds it can be cached for
+    pub max_age: u64,
+}
+
+impl Policy {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut version = None;
+        let mut mode = None;
+        let mut mx = Vec::new();
+        let mut max_age = None;
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "version" => version = Some(value),
+                "mode" => {
+                    mode = Some(match value {
+                        "enforce" => Mode::Enforce,
+                        "testing" => Mode::Testing,
+                        "none" => Mode::None,
+                        _ => bail!("unknown mode {value}"),
+                    })
+                }
+                "mx" => mx.push(value.trim_end_matches('.').to_ascii_lowercase()),
+                "max_age" => {
+                    let age = value
+                        .parse::<u64>()
+                        .with_context(|| format!("invalid max_age {value}"))?;
+                    max_age = Some(age.min(MAX_AGE_LIMIT));
+                }
+                //other fields are extensions we don't know
+                _ => {}
+            }
+        }
+        if version != Some("STSv1") {
+            bail!("not an STSv1 policy");
+        }
+        let mode = mode.context("no mode in the policy")?;
+        if mode != Mode::None && mx.is_empty() {
+            bail!("no mx in the policy");
+        }
+        Ok(Self {
+            mode,
+            mx,
+            max_age: max_age.context("no max_age in the policy")?,
+        })
+    }
+
+    ///the policy file
+    pub fn to_text(&self) -> String {
+        let mode = match self.mode {
+            Mode::Enforce => "enforce",
+            Mode::Testing => "testing",
+            Mode::None => "none",
+        };
+        let mut text = format!("version: STSv1\r\nmode: {mode}\r\n");
+        for mx in &self.mx {
+            text += &format!("mx: {mx}\r\n");
+        }
+        text += &format!("max_age: {}\r\n", self.max_age);
+        text
+    }
+
+    ///the id= for the TXT record, it changes whenever the policy does
+    pub fn id(&self) -> String {
+        let hash = digest::digest(&digest::SHA256, self.to_text().as_bytes());
+        hash.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    ///whether mail may go to `host` (rfc 8461 4.1)
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.mx
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(parent) => host
+                    .split_once('.')
+                    .is_some_and(|(label, rest)| !label.is_empty() && rest == parent),
+                None => *pattern == host,
+            })
+    }
+}
+
+///the id of the `_mta-sts` TXT record, none unless there's exactly one
+///valid record (rfc 8461 3.1)
+fn record_id(records: &[String]) -> Option<String> {
+    let mut records = records.iter().filter(|r| {
+        r.split(';')
+            .next()
+            .is_some_and(|version| version.trim() == "v=STSv1")
+    });
+    let record = records.next()?;
+    if records.next().is_some() {
+        return None;
+    }
+    let id = record
+        .split(';')
+        .filter_map(|field| field.trim().split_once('='))
+        .find(|(name, _)| name.trim() == "id")
+        .map(|(_, id)| id.trim())?;
+    let valid = (1..=32).contains(&id.len()) && id.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then(|| id.to_string())
+}
+
+///gets the policy file of a domain
+pub trait PolicyFetcher {
+    async fn fetch(&self, domain: &str) -> Result<String>;
+}
+
+///fetches the policy over https from the policy host
+pub struct HttpsFetcher {
+    http: reqwest::Client,
+    ///always 443 outside of tests
+    port: u16,
+}
+
+impl HttpsFetcher {
+    pub fn new() -> Result<Self> {
+        Self::with_client(reqwest::Client::builder(), 443)
+    }
+
+    fn with_client(builder: reqwest::ClientBuilder, port: u16) -> Result<Self> {
+        let http = builder
+            //redirects mustn't be followed (rfc 8461 3.3)
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(FETCH_TIMEOUT)
+            .build()?;
+        Ok(Self { http, port })
+    }
+}
+
+impl PolicyFetcher for HttpsFetcher {
+    async fn fetch(&self, domain: &str) -> Result<String> {
+        let url = match self.port {
+            443 => format!("https://mta-sts.{domain}/.well-known/mta-sts.txt"),
+            port => format!("https://mta-sts.{domain}:{port}/.well-known/mta-sts.txt"),
+        };
+        let mut response = self.http.get(&url).send().await?;
+        if response.status() != reqwest::StatusCode::OK {
+            bail!("{url} answered {}", response.status());
+        }
+        let text_plain = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.to_ascii_lowercase().starts_with("text/plain"));
+        if !text_plain {
+            bail!("{url} isn't text/plain");
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_POLICY_SIZE {
+                bail!("{url} is too big");
+            }
+        }
+        Ok(String::from_utf8(body)?)
+    }
+}
+
+struct Cached {
+    id: String,
+    policy: Policy,
+    ///unix time
+    expires: i64,
+}
+
+///finds the policies of the domains we deliver to and remembers them for
+///their max_age, so an attacker can't just hide the TXT record
+pub struct MtaSts<F> {
+    fetcher: F,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+impl<F: PolicyFetcher> MtaSts<F> {
+    pub fn new(fetcher: F) -> Self {
+        Self {
+            fetcher,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///the policy of `domain` at unix time `now`, if it has one. a policy
+    ///that can't be refreshed is used until it expires
+    pub async fn policy(&self, dns: &impl AuthResolver, domain: &str, now: i64) -> Option<Policy> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let cached = self
+            .cache
+            .lock()
+            .ok()?
+            .get(&domain)
+            .filter(|cached| cached.expires > now)
+            .map(|cached| (cached.id.clone(), cached.policy.clone()));
+        let id = match dns.txt(&format!("_mta-sts.{domain}")).await {
+            Ok(records) => record_id(&records),
+            Err(e) => {
+                tracing::debug!("mta-sts lookup for {domain} failed: {:?}", e);
+                None
+            }
+        };
+        let Some(id) = id else {
+            return cached.map(|(_, policy)| policy);
+        };
+        if let Some((cached_id, policy)) = &cached {
+            if *cached_id == id {
+                return Some(policy.clone());
+            }
+        }
+        let fetched = self
+            .fetcher
+            .fetch(&domain)
+            .await
+            .and_then(|text| Policy::parse(&text));
+        let policy = match fetched {
+            Ok(policy) => policy,
+            Err(e) => {
+                tracing::info!("couldn't get the mta-sts policy of {domain}: {:#}", e);
+                return cached.map(|(_, policy)| policy);
+            }
+        };
+        tracing::info!("{domain} has the mta-sts policy {id}: {:?}", policy.mode);
+        if let Ok(mut cache) = self.cache.lock() {
+            let expires = now.saturating_add(policy.max_age as i64);
+            cache.insert(
+                domain,
+                Cached {
+                    id,
+                    policy: policy.clone(),
+                    expires,
+                },
+            );
+        }
+        Some(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use anyhow::{Context, Result};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+    use super::{record_id, HttpsFetcher, Mode, MtaSts, Policy, PolicyFetcher, MAX_POLICY_SIZE};
+    use crate::certs::{self_signed, CertBundle};
+    use crate::email_auth::tests::FakeDns;
+
+    ///serves the policies it was given, counting the requests
+    #[derive(Default)]
+    struct FakeFetcher {
+        policies: HashMap<String, String>,
+        fetches: Cell<usize>,
+    }
+
+    impl PolicyFetcher for FakeFetcher {
+        async fn fetch(&self, domain: &str) -> Result<String> {
+            self.fetches.set(self.fetches.get() + 1);
+            self.policies.get(domain).cloned().context("404")
+        }
+    }
+
+    const POLICY: &str = "version: STSv1\r\nmode: enforce\r\nmx: mail.example.com\r\n\
+                          mx: *.example.net\r\nmax_age: 86400\r\n";
+
+    #[test]
+    fn test_parse() {
+        let policy = Policy::parse(POLICY).unwrap();
+        assert_eq!(policy.mode, Mode::Enforce);
+        assert_eq!(policy.max_age, 86400);
+        assert!(policy.matches("MAIL.example.com."));
+        assert!(policy.matches("mx1.example.net"));
+        assert!(!policy.matches("example.net"));
+        assert!(!policy.matches("a.mx1.example.net"));
+        assert!(!policy.matches("evil.example.com"));
+        assert_eq!(Policy::parse(&policy.to_text()).unwrap(), policy);
+        assert_eq!(policy.id().len(), 16);
+        assert!(Policy::parse("version: STSv1\nmode: none\nmax_age: 99999999999\n").is_ok());
+        assert!(Policy::parse("version: STSv1\nmode: enforce\nmax_age: 60\n").is_err());
+        assert!(Policy::parse("mode: enforce\nmx: a.example\nmax_age: 60\n").is_err());
+        assert_eq!(
+            record_id(&["v=STSv1; id=20160831085700Z;".to_string()]).as_deref(),
+            Some("20160831085700Z")
+        );
+        assert_eq!(
+            record_id(&["v=STSv1; id=1".to_string(), "v=STSv1; id=2".to_string()]),
+            None
+        );
+        assert_eq!(record_id(&["v=STSv1; id=no-dashes".to_string()]), None);
+    }
+
+    #[tokio::test]
+    async fn test_policy() {
+        let mut fetcher = FakeFetcher::default();
+        fetcher
+            .policies
+            .insert("example.com".to_string(), POLICY.to_string());
+        let mut sts = MtaSts::new(fetcher);
+        let dns = FakeDns::default().txt("_mta-sts.example.com", "v=STSv1; id=1");
+        let policy = sts.policy(&dns, "Example.com", 1000).await.unwrap();
+        assert_eq!(policy.mode, Mode::Enforce);
+        //the same id doesn't fetch it again
+        sts.policy(&dns, "example.com", 2000).await.unwrap();
+        assert_eq!(sts.fetcher.fetches.get(), 1);
+        //removing the record doesn't remove the policy before it expires
+        let gone = FakeDns::default();
+        assert!(sts.policy(&gone, "example.com", 3000).await.is_some());
+        assert!(sts
+            .policy(&gone, "example.com", 1000 + 86400)
+            .await
+            .is_none());
+        //a new id is fetched, if that fails the old policy stays
+        let changed = FakeDns::default().txt("_mta-sts.example.com", "v=STSv1; id=2");
+        sts.fetcher.policies.clear();
+        assert!(sts.policy(&changed, "example.com", 4000).await.is_some());
+        assert_eq!(sts.fetcher.fetches.get(), 2);
+        assert!(sts.policy(&dns, "example.org", 4000).await.is_none());
+    }
+
+    ///what the policy host of `name`.example.com answers, with a status
+    ///line, a content type and a body
+    fn respond(name: &str, port: u16) -> String {
+        let (status, content_type, body) = match name {
+            "ok" => ("200 OK", "text/plain; charset=utf-8", POLICY.to_string()),
+            "max" => ("200 OK", "text/plain", "a".repeat(MAX_POLICY_SIZE)),
+            "big" => ("200 OK", "text/plain", "a".repeat(MAX_POLICY_SIZE + 1)),
+            "html" => ("200 OK", "text/html", POLICY.to_string()),
+            "redirect" => ("301 Moved Permanently", "text/plain", String::new()),
+            _ => ("404 Not Found", "text/plain", "not found".to_string()),
+        };
+        let location = match name {
+            "redirect" => format!(
+                "Location: https://mta-sts.ok.example.com:{port}/.well-known/mta-sts.txt\r\n"
+            ),
+            _ => String::new(),
+        };
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n{location}\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_https_fetcher() {
+        let names = ["ok", "max", "big", "html", "redirect", "missing"];
+        let hosts = names.map(|name| format!("mta-sts.{name}.example.com"));
+        let (cert, key) = self_signed::generate_pem(&hosts).unwrap();
+        let bundle = CertBundle::from_pem(&cert, &key).unwrap();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(bundle.certs, bundle.key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await.unwrap();
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        assert!(n > 0, "connection closed mid request");
+                        head.extend_from_slice(&buf[..n]);
+                    }
+                    let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+                    let name = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("host: mta-sts."))
+                        .and_then(|host| host.split_once('.'))
+                        .map(|(name, _)| name.to_string())
+                        .unwrap_or_default();
+                    let response = respond(&name, addr.port());
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.shutdown().await.ok();
+                });
+            }
+        });
+        let mut builder = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(cert.as_bytes()).unwrap());
+        for host in &hosts {
+            builder = builder.resolve(host, addr);
+        }
+        let fetcher = HttpsFetcher::with_client(builder, addr.port()).unwrap();
+
+        assert_eq!(fetcher.fetch("ok.example.com").await.unwrap(), POLICY);
+        assert_eq!(
+            fetcher.fetch("max.example.com").await.unwrap().len(),
+            MAX_POLICY_SIZE
+        );
+        //(the domain, what's wrong with its policy)
+        let broken = [
+            ("big.example.com", "too big"),
+            ("html.example.com", "isn't text/plain"),
+            ("redirect.example.com", "answered 301"),
+            ("missing.example.com", "answered 404"),
+        ];
+        for (domain, error) in broken {
+            let e = fetcher.fetch(domain).await.unwrap_err().to_string();
+            assert!(e.contains(error), "{domain}: {e}");
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, Der, ServerName, TrustAnchor, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::dane::{DaneVerifier, Tlsa};
+
+///how hard we insist on encryption when delivering to a domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsPolicy {
+    ///STARTTLS when it's offered, without checking the certificate
+    ///(rfc 7435). plaintext otherwise
+    Opportunistic,
+    ///STARTTLS with a certificate that's valid for the mail host, or the
+    ///delivery is put off
+    Required,
+}
+
+///the client side tls configs outbound delivery picks from
+pub struct Connectors {
+    opportunistic: TlsConnector,
+    verified: TlsConnector,
+    provider: Arc<CryptoProvider>,
+}
+
+impl Connectors {
+    ///checks certificates against the webpki roots
+    pub fn new() -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.extend(
+            webpki_roots::TLS_SERVER_ROOTS
+                .iter()
+                .map(|root| TrustAnchor {
+                    subject: Der::from_slice(root.subject),
+                    subject_public_key_info: Der::from_slice(root.spki),
+                    name_constraints: root.name_constraints.map(Der::from_slice),
+                }),
+        );
+        Self::with_roots(roots)
+    }
+
+    pub fn with_roots(roots: RootCertStore) -> Self {
+        let provider = Arc::new(ring::default_provider());
+        let verified = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut opportunistic = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default versions")
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        opportunistic
+            .dangerous()
+            .set_certificate_verifier(Arc::new(AnyCertificate(provider.clone())));
+        Self {
+            opportunistic: TlsConnector::from(Arc::new(opportunistic)),
+            verified: TlsConnector::from(Arc::new(verified)),
+            provider,
+        }
+    }
+
+    pub fn get(&self, policy: TlsPolicy) -> &TlsConnector {
+        match policy {
+            TlsPolicy::Opportunistic => &self.opportunistic,
+            TlsPolicy::Required => &self.verified,
+        }
+    }
+
+    ///checks the certificate against a host's TLSA records instead
+    pub fn dane(&self, records: Vec<Tlsa>) -> TlsConnector {
+        let mut config = ClientConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default versions")
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(DaneVerifier::new(records, self.provider.clone())));
+        TlsConnector::from(Arc::new(config))
+    }
+}
+
+///takes any certificate, most mail hosts don't have one that's valid for
+///their name. the handshake is still checked, so it's encrypted but not
+///authenticated
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
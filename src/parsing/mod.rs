@@ -1,4 +1,5 @@
 pub mod imap;
+pub mod smtp;
 //old, only difference is no timezone
 // pub const DB_DATETIME_FMT: &'static str = "%Y-%m-%d %H:%M:%S%.3f";
 pub const DB_DATETIME_FMT: &'static str = "%F %T%.3f%:z";
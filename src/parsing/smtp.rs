@@ -0,0 +1,63 @@
+///the path and ESMTP parameters of a MAIL FROM or RCPT TO command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathArgs {
+    ///the path including its angle brackets, eg. `<user@kaki.foo>`
+    pub path: String,
+    ///uppercased keywords with their values, in the order they were given
+    pub params: Vec<(String, Option<String>)>,
+}
+
+///`FROM:<a@b.c> SIZE=10 BODY=8BITMIME` with `prefix` "FROM:" ->
+///`<a@b.c>` and `[("SIZE", Some("10")), ("BODY", Some("8BITMIME"))]`.
+///the prefix is matched case-insensitively and a space after it is tolerated
+pub fn path_args(prefix: &str, args: &str) -> Option<PathArgs> {
+    let args = args.trim();
+    let head = args.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = args[prefix.len()..].trim_start();
+    if !rest.starts_with('<') {
+        return None;
+    }
+    let end = rest.find('>')?;
+    let path = rest[..=end].to_string();
+    let params = rest[end + 1..]
+        .split_whitespace()
+        .map(|param| match param.split_once('=') {
+            Some((keyword, value)) => (keyword.to_ascii_uppercase(), Some(value.to_string())),
+            None => (param.to_ascii_uppercase(), None),
+        })
+        .collect();
+    Some(PathArgs { path, params })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path_args, PathArgs};
+
+    #[test]
+    fn test_path_args() {
+        assert_eq!(
+            path_args("FROM:", "from: <a@b.c> size=10 BODY=8BITMIME SMTPUTF8"),
+            Some(PathArgs {
+                path: "<a@b.c>".to_string(),
+                params: vec![
+                    ("SIZE".to_string(), Some("10".to_string())),
+                    ("BODY".to_string(), Some("8BITMIME".to_string())),
+                    ("SMTPUTF8".to_string(), None),
+                ],
+            })
+        );
+        assert_eq!(
+            path_args("FROM:", "FROM:<>"),
+            Some(PathArgs {
+                path: "<>".to_string(),
+                params: vec![],
+            })
+        );
+        assert_eq!(path_args("TO:", "FROM:<a@b.c>"), None);
+        assert_eq!(path_args("TO:", "TO:a@b.c"), None);
+        assert_eq!(path_args("TO:", "T"), None);
+    }
+}
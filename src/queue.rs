@@ -0,0 +1,532 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast::Sender, Mutex, Notify};
+
+use crate::config::{Config, QueueConfig};
+use crate::dane::Tlsa;
+use crate::database::{self, Change, QueueStatus, QueuedMessage, QueuedRecipient};
+use crate::dns::Dns;
+use crate::dsn::{self, Action};
+use crate::mta_sts::{self, HttpsFetcher, Mode, MtaSts};
+use crate::outbound_tls::{Connectors, TlsPolicy};
+use crate::recipients;
+use crate::smtp_client::{Outcome, SmtpClient};
+use crate::smtp_common::{split_address, Mail};
+
+///delivers the mail in the outbound queue, retrying the recipients that
+///couldn't be reached until they expire
+pub struct Queue {
+    db: Arc<Mutex<database::DBClient>>,
+    config: Arc<Config>,
+    dns: Arc<Dns>,
+    tls: Connectors,
+    mta_sts: MtaSts<HttpsFetcher>,
+    ///notified when something is queued, so it doesn't wait for the next poll
+    wakeup: Arc<Notify>,
+}
+
+impl Queue {
+    pub async fn new(
+        config: Arc<Config>,
+        tx: Sender<Change>,
+        wakeup: Arc<Notify>,
+        dns: Arc<Dns>,
+    ) -> Result<Self> {
+        Ok(Self {
+            db: Arc::new(Mutex::new(database::DBClient::new(tx, &config).await?)),
+            config,
+            dns,
+            tls: Connectors::new(),
+            mta_sts: MtaSts::new(HttpsFetcher::new()?),
+            wakeup,
+        })
+    }
+
+    ///runs forever, the futures aren't Send so it has to be on the LocalSet
+    pub async fn run(self) -> Result<()> {
+        let poll = Duration::from_secs(self.config.queue.poll_secs.max(1));
+        loop {
+            if let Err(e) = self.run_once().await {
+                tracing::error!("error processing the queue: {:?}", e);
+            }
+            tokio::select! {
+                _ = self.wakeup.notified() => {}
+                _ = tokio::time::sleep(poll) => {}
+            }
+        }
+    }
+
+    ///tries every recipient that's due, one connection per message and domain
+    async fn run_once(&self) -> Result<()> {
+        let now = now();
+        let due = self.db.lock().await.due_recipients(now).await?;
+        let mut messages: BTreeMap<i64, BTreeMap<String, Vec<QueuedRecipient>>> = BTreeMap::new();
+        for recipient in due {
+            let domain = split_address(&recipient.recipient)
+                .map(|(_, domain)| domain.to_ascii_lowercase())
+                .unwrap_or_default();
+            messages
+                .entry(recipient.message_id)
+                .or_default()
+                .entry(domain)
+                .or_default()
+                .push(recipient);
+        }
+        //a message that's done but still queued had its report or removal
+        //fail, finishing it is tried again
+        for message_id in self.db.lock().await.finished_messages(now).await? {
+            messages.entry(message_id).or_default();
+        }
+        //one message failing doesn't hold up the others, it's put off a while
+        for (message_id, domains) in messages {
+            if let Err(e) = self.process(message_id, domains, now).await {
+                tracing::error!("error processing queued message {message_id}: {:?}", e);
+                let retry = now + backoff(&self.config.queue, 1) as i64;
+                if let Err(e) = self.db.lock().await.defer_queued(message_id, retry).await {
+                    tracing::error!("couldn't put off queued message {message_id}: {:?}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///delivers a message to its due recipients, grouped by `domains`
+    async fn process(
+        &self,
+        message_id: i64,
+        domains: BTreeMap<String, Vec<QueuedRecipient>>,
+        now: i64,
+    ) -> Result<()> {
+        let message = self.db.lock().await.queued_message(message_id).await?;
+        for (domain, recipients) in domains {
+            let mail = Mail {
+                to: recipients.iter().map(|r| r.recipient.clone()).collect(),
+                ..message.mail.clone()
+            };
+            let outcomes = if self.config.serves_domain(&domain) {
+                self.deliver_locally(&message, &mail).await
+            } else {
+                self.deliver_remotely(&domain, &mail).await
+            };
+            for (recipient, outcome) in recipients.into_iter().zip(outcomes) {
+                let updated = after_attempt(
+                    &self.config.queue,
+                    recipient,
+                    &outcome,
+                    message.created,
+                    now,
+                );
+                match updated.status {
+                    QueueStatus::Pending => tracing::info!(
+                        "delivery to {} deferred: {:?}",
+                        updated.recipient,
+                        updated.last_error
+                    ),
+                    QueueStatus::Delivered => {
+                        tracing::info!("delivered to {}", updated.recipient)
+                    }
+                    QueueStatus::Failed => tracing::warn!(
+                        "delivery to {} failed: {:?}",
+                        updated.recipient,
+                        updated.last_error
+                    ),
+                }
+                self.db
+                    .lock()
+                    .await
+                    .update_queue_recipient(&updated)
+                    .await?;
+            }
+        }
+        self.finish(&message).await
+    }
+
+    ///copies the mail into the recipients' mailboxes. the copies that made
+    ///it are remembered, so a retry after one failed doesn't make another
+    async fn deliver_locally(&self, message: &QueuedMessage, mail: &Mail) -> Vec<Outcome> {
+        let message_id = message.id;
+        let mut stored = match self.db.lock().await.stored_mailboxes(message_id).await {
+            Ok(stored) => stored,
+            Err(e) => return vec![Outcome::Transient(e.to_string()); mail.to.len()],
+        };
+        let mut outcomes = Vec::with_capacity(mail.to.len());
+        for rcpt in &mail.to {
+            let found = if message.junk {
+                recipients::junk_mailboxes(&self.db, &self.config, rcpt).await
+            } else {
+                recipients::mailboxes(&self.db, &self.config, rcpt).await
+            };
+            let outcome = match found {
+                //forwarded only, that was done when it came in
+                Ok(mailboxes)
+                    if mailboxes.is_empty()
+                        && !recipients::forwards(&self.config, rcpt).is_empty() =>
+                {
+                    Outcome::Delivered
+                }
+                Ok(mailboxes) if mailboxes.is_empty() => {
+                    Outcome::Permanent("550 5.1.1 no such user".to_string())
+                }
+                Ok(mailboxes) => {
+                    let db = self.db.lock().await;
+                    let mut outcome = Outcome::Delivered;
+                    for m_id in mailboxes {
+                        if stored.contains(&m_id) {
+                            continue;
+                        }
+                        if let Err(e) = db.replicate(mail.clone(), m_id, None).await {
+                            outcome = Outcome::Transient(e.to_string());
+                            continue;
+                        }
+                        stored.push(m_id);
+                        if let Err(e) = db.set_queue_stored(message_id, m_id).await {
+                            tracing::error!("couldn't remember the copy in {m_id}: {:?}", e);
+                        }
+                    }
+                    outcome
+                }
+                Err(e) => Outcome::Transient(e.to_string()),
+            };
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+
+    ///tries the domain's mail hosts in order until one of them answers
+    async fn deliver_remotely(&self, domain: &str, mail: &Mail) -> Vec<Outcome> {
+        let everyone = |outcome: Outcome| vec![outcome; mail.to.len()];
+        //with DANE the MX answer has to be signed for the hosts' TLSA
+        //records to count, and a forged one puts the delivery off
+        let hosts = if self.config.queue.dane {
+            self.dns.secure_mx_hosts(domain).await
+        } else {
+            self.dns.mx_hosts(domain).await.map(|hosts| (hosts, false))
+        };
+        let (hosts, secure) = match hosts {
+            Ok((hosts, _)) if hosts.is_empty() => {
+                return everyone(Outcome::Permanent(
+                    "556 5.1.10 the domain doesn't accept mail".to_string(),
+                ))
+            }
+            Ok(found) => found,
+            Err(e) => return everyone(Outcome::Transient(format!("dns lookup failed: {e}"))),
+        };
+        let mut policy = self.config.queue.tls_policy(domain);
+        let mut hosts = hosts;
+        if let Some(sts) = self.mta_sts_policy(domain).await {
+            let (allowed, others): (Vec<_>, Vec<_>) =
+                hosts.into_iter().partition(|host| sts.matches(host));
+            if !others.is_empty() {
+                tracing::warn!(
+                    "the mta-sts policy of {domain} doesn't allow {}",
+                    others.join(", ")
+                );
+            }
+            hosts = match sts.mode {
+                Mode::Enforce => {
+                    policy = TlsPolicy::Required;
+                    allowed
+                }
+                Mode::Testing | Mode::None => allowed.into_iter().chain(others).collect(),
+            };
+            if hosts.is_empty() {
+                return everyone(Outcome::Transient(
+                    "no mail host matches the mta-sts policy".to_string(),
+                ));
+            }
+        }
+        let mut last_error = String::new();
+        for host in hosts {
+            match self.send_to_host(&host, mail, policy, secure).await {
+                Ok(outcomes) => return outcomes,
+                Err(e) => {
+                    tracing::warn!("couldn't deliver to {host}: {:?}", e);
+                    last_error = format!("{host}: {e:#}");
+                }
+            }
+        }
+        everyone(Outcome::Transient(last_error))
+    }
+
+    async fn mta_sts_policy(&self, domain: &str) -> Option<mta_sts::Policy> {
+        if !self.config.queue.mta_sts {
+            return None;
+        }
+        self.mta_sts.policy(&*self.dns, domain, now()).await
+    }
+
+    async fn send_to_host(
+        &self,
+        host: &str,
+        mail: &Mail,
+        policy: TlsPolicy,
+        secure: bool,
+    ) -> Result<Vec<Outcome>> {
+        //a host with TLSA records needs tls with a certificate they name,
+        //whatever the domain asked for. MTA-STS only picks the hosts then
+        //(rfc 8461 2)
+        let tlsa = self.tlsa(host, secure).await?;
+        let dane;
+        let (connector, policy) = if tlsa.is_empty() {
+            (self.tls.get(policy), policy)
+        } else {
+            dane = self.tls.dane(tlsa);
+            (&dane, TlsPolicy::Required)
+        };
+        let client = self.dial(host).await?;
+        if !client.supports("STARTTLS") {
+            if policy == TlsPolicy::Required {
+                bail!("{host} doesn't offer STARTTLS");
+            }
+            tracing::info!("{host} doesn't offer STARTTLS, sending in plaintext");
+            return deliver(client, mail).await;
+        }
+        match client.starttls(connector, host).await {
+            Ok(client) => {
+                if let Some((version, cipher)) = client.tls() {
+                    tracing::info!("talking to {host} over {version} with {cipher}");
+                }
+                deliver(client, mail).await
+            }
+            Err(e)
+                if policy == TlsPolicy::Opportunistic && self.config.queue.plaintext_fallback =>
+            {
+                tracing::warn!("STARTTLS with {host} failed, sending in plaintext: {:#}", e);
+                let client = self.dial(host).await?;
+                deliver(client, mail).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    ///the TLSA records of the host, if DANE is on and the MX answer that
+    ///named it was `secure`. a lookup that fails puts the delivery off, or
+    ///blocking it would turn DANE off (rfc 7672 2.2)
+    async fn tlsa(&self, host: &str, secure: bool) -> Result<Vec<Tlsa>> {
+        if !self.config.queue.dane || !secure {
+            return Ok(vec![]);
+        }
+        let records = self
+            .dns
+            .tlsa(self.config.queue.remote_port, host)
+            .await
+            .context("TLSA lookup failed")?;
+        if !records.is_empty() {
+            tracing::debug!("{host} has {} usable TLSA records", records.len());
+        }
+        Ok(records)
+    }
+
+    async fn dial(&self, host: &str) -> Result<SmtpClient<TcpStream>> {
+        let timeout = Duration::from_secs(self.config.queue.timeout_secs);
+        //BIG TODO: this will timeout on port 25 unless you request to unblock port 25
+        let stream = tokio::time::timeout(
+            timeout,
+            TcpStream::connect((host, self.config.queue.remote_port)),
+        )
+        .await
+        .context("timed out connecting")??;
+        SmtpClient::connect(stream, &self.config.hostname, timeout).await
+    }
+
+    ///once no recipient is pending the message leaves the queue and the
+    ///sender gets one report about the ones that failed. if delivery takes
+    ///long they're told about the pending ones, once. the report is queued
+    ///with the change that says it was made, so a failure leaves both to
+    ///the next pass
+    async fn finish(&self, message: &QueuedMessage) -> Result<()> {
+        let now = now();
+        let recipients = self.db.lock().await.queue_recipients(message.id).await?;
+        let pending = recipients
+            .iter()
+            .filter(|r| r.status == QueueStatus::Pending)
+            .cloned()
+            .collect::<Vec<_>>();
+        if !pending.is_empty() {
+            let warn_after = hours(self.config.queue.delay_warning_hours);
+            if warn_after != 0 && !message.warned && now - message.created >= warn_after {
+                let report = self.report(message, &pending, Action::Delayed, now);
+                self.db
+                    .lock()
+                    .await
+                    .set_queue_warned(message.id, report.as_ref(), now)
+                    .await?;
+                if report.is_some() {
+                    self.wakeup.notify_one();
+                }
+            }
+            return Ok(());
+        }
+        let failed = recipients
+            .into_iter()
+            .filter(|r| r.status == QueueStatus::Failed)
+            .collect::<Vec<_>>();
+        let report = if failed.is_empty() {
+            None
+        } else {
+            self.report(message, &failed, Action::Failed, now)
+        };
+        self.db
+            .lock()
+            .await
+            .dequeue(message.id, report.as_ref(), now)
+            .await?;
+        if report.is_some() {
+            self.wakeup.notify_one();
+        }
+        Ok(())
+    }
+
+    ///the delivery status notification for the sender of `message`, it goes
+    ///through the queue like any mail so our own users get it in their
+    ///INBOX. none for a bounce, they're never bounced (rfc 5321 4.5.5)
+    fn report(
+        &self,
+        message: &QueuedMessage,
+        about: &[QueuedRecipient],
+        action: Action,
+        now: i64,
+    ) -> Option<Mail> {
+        let sender = &message.mail.from;
+        if sender == "<>" {
+            return None;
+        }
+        let retry_until = message.created + hours(self.config.queue.max_age_hours);
+        Some(Mail {
+            from: "<>".to_string(),
+            to: vec![sender.clone()],
+            data: dsn::build(
+                &self.config.hostname,
+                message,
+                about,
+                action,
+                now,
+                retry_until,
+            ),
+            ..Default::default()
+        })
+    }
+}
+
+///one transaction, then the session is over
+async fn deliver<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client: SmtpClient<S>,
+    mail: &Mail,
+) -> Result<Vec<Outcome>> {
+    let outcomes = client.send(mail).await?;
+    client.quit().await;
+    Ok(outcomes)
+}
+
+fn hours(hours: u64) -> i64 {
+    hours.saturating_mul(60 * 60) as i64
+}
+
+///the seconds to wait after the `attempts`th failed attempt
+pub fn backoff(config: &QueueConfig, attempts: u32) -> u64 {
+    let doublings = attempts.saturating_sub(1).min(32);
+    config
+        .retry_base_secs
+        .saturating_mul(1 << doublings)
+        .min(config.retry_max_secs)
+}
+
+///where a recipient stands after a delivery attempt at `now`
+fn after_attempt(
+    config: &QueueConfig,
+    mut recipient: QueuedRecipient,
+    outcome: &Outcome,
+    created: i64,
+    now: i64,
+) -> QueuedRecipient {
+    recipient.attempts += 1;
+    match outcome {
+        Outcome::Delivered => {
+            recipient.status = QueueStatus::Delivered;
+            recipient.last_error = None;
+        }
+        Outcome::Permanent(error) => {
+            recipient.status = QueueStatus::Failed;
+            recipient.last_error = Some(error.clone());
+        }
+        Outcome::Transient(error) => {
+            recipient.last_error = Some(error.clone());
+            if now - created >= hours(config.max_age_hours) {
+                recipient.status = QueueStatus::Failed;
+            } else {
+                recipient.next_attempt = now + backoff(config, recipient.attempts) as i64;
+            }
+        }
+    }
+    recipient
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{after_attempt, backoff};
+    use crate::config::QueueConfig;
+    use crate::database::{QueueStatus, QueuedRecipient};
+    use crate::smtp_client::Outcome;
+
+    fn recipient() -> QueuedRecipient {
+        QueuedRecipient {
+            id: 1,
+            message_id: 1,
+            recipient: "<bob@example.com>".to_string(),
+            status: QueueStatus::Pending,
+            attempts: 0,
+            next_attempt: 0,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = QueueConfig {
+            retry_base_secs: 60,
+            retry_max_secs: 60 * 60,
+            ..Default::default()
+        };
+        let waits = (1..=8).map(|n| backoff(&config, n)).collect::<Vec<_>>();
+        assert_eq!(waits, [60, 120, 240, 480, 960, 1920, 3600, 3600]);
+        assert_eq!(backoff(&config, u32::MAX), 3600);
+    }
+
+    #[test]
+    fn test_after_attempt() {
+        let config = QueueConfig {
+            retry_base_secs: 60,
+            max_age_hours: 1,
+            ..Default::default()
+        };
+        let transient = Outcome::Transient("421 4.3.2 busy".to_string());
+        let deferred = after_attempt(&config, recipient(), &transient, 0, 100);
+        assert_eq!(deferred.status, QueueStatus::Pending);
+        assert_eq!(deferred.attempts, 1);
+        assert_eq!(deferred.next_attempt, 160);
+        let deferred = after_attempt(&config, deferred, &transient, 0, 160);
+        assert_eq!(deferred.next_attempt, 280);
+        //too old to keep trying
+        let expired = after_attempt(&config, deferred, &transient, 0, 3600);
+        assert_eq!(expired.status, QueueStatus::Failed);
+        assert_eq!(expired.attempts, 3);
+        assert_eq!(expired.last_error.as_deref(), Some("421 4.3.2 busy"));
+        let permanent = Outcome::Permanent("550 5.1.1 no such user".to_string());
+        assert_eq!(
+            after_attempt(&config, recipient(), &permanent, 0, 100).status,
+            QueueStatus::Failed
+        );
+        let delivered = after_attempt(&config, recipient(), &Outcome::Delivered, 0, 100);
+        assert_eq!(delivered.status, QueueStatus::Delivered);
+        assert_eq!(delivered.last_error, None);
+    }
+}
@@ -0,0 +1,308 @@
+use anyhow::{Context, Result};
+use tokio::sync::Mutex;
+
+use crate::{config::Config, database, smtp_common::split_address};
+
+///where a local recipient's mail ends up
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Recipient {
+    pub user_id: i32,
+    ///the `invoices` in `alice+invoices@kaki.foo`. delivered to the mailbox
+    ///with that name if the user has one, INBOX otherwise
+    pub detail: Option<String>,
+}
+
+///the lookups recipient resolution needs
+pub trait UserDirectory {
+    ///the users `address` is an alias for. `@kaki.foo` is the catch-all
+    ///of kaki.foo
+    async fn alias_targets(&self, address: &str) -> Result<Vec<i32>>;
+    async fn user_id(&self, name: &str, domain: &str) -> Result<Option<i32>>;
+}
+
+impl UserDirectory for Mutex<database::DBClient> {
+    async fn alias_targets(&self, address: &str) -> Result<Vec<i32>> {
+        self.lock().await.get_alias_targets(address).await
+    }
+
+    async fn user_id(&self, name: &str, domain: &str) -> Result<Option<i32>> {
+        self.lock().await.get_user_id(name, domain).await
+    }
+}
+
+///finds who mail for `local@domain` should go to, empty if nobody. an
+///exact alias wins over the address without its detail, which wins over a
+///user of that name, which wins over the domain's catch-all alias, which
+///wins over the catch-all user in the config
+pub async fn resolve(
+    users: &impl UserDirectory,
+    config: &Config,
+    local: &str,
+    domain: &str,
+) -> Result<Vec<Recipient>> {
+    let delimiter = &config.smtp.recipient_delimiter;
+    let local = local.to_lowercase();
+    let domain = domain.to_lowercase();
+    let targets = users.alias_targets(&format!("{local}@{domain}")).await?;
+    if !targets.is_empty() {
+        return Ok(recipients(targets, None));
+    }
+    let (base, detail) = split_detail(&local, delimiter);
+    if detail.is_some() {
+        let targets = users.alias_targets(&format!("{base}@{domain}")).await?;
+        if !targets.is_empty() {
+            return Ok(recipients(targets, detail));
+        }
+    }
+    if let Some(user_id) = users.user_id(base, &domain).await? {
+        return Ok(recipients(vec![user_id], detail));
+    }
+    let targets = users.alias_targets(&format!("@{domain}")).await?;
+    if !targets.is_empty() {
+        return Ok(recipients(targets, None));
+    }
+    let catch_all = config.domain(&domain).and_then(|d| d.catch_all.as_deref());
+    if let Some(user_id) = match catch_all {
+        Some(name) => users.user_id(name, &domain).await?,
+        None => None,
+    } {
+        return Ok(recipients(vec![user_id], None));
+    }
+    Ok(vec![])
+}
+
+///whether `local@domain`, or it without its detail, is an alias. that's
+///how reserved names are routed on purpose
+pub async fn is_alias(
+    users: &impl UserDirectory,
+    config: &Config,
+    local: &str,
+    domain: &str,
+) -> Result<bool> {
+    let local = local.to_lowercase();
+    let domain = domain.to_lowercase();
+    let (base, _) = split_detail(&local, &config.smtp.recipient_delimiter);
+    for address in [format!("{local}@{domain}"), format!("{base}@{domain}")] {
+        if !users.alias_targets(&address).await?.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+///the mailboxes mail for `to` (with its angle brackets) is stored in,
+///empty if nobody gets it
+pub async fn mailboxes(
+    db: &Mutex<database::DBClient>,
+    config: &Config,
+    to: &str,
+) -> Result<Vec<i32>> {
+    resolve_mailboxes(db, config, to, None).await
+}
+
+///like `mailboxes`, but every recipient gets it in their junk mailbox,
+///for mail a DMARC policy says to quarantine
+pub async fn junk_mailboxes(
+    db: &Mutex<database::DBClient>,
+    config: &Config,
+    to: &str,
+) -> Result<Vec<i32>> {
+    resolve_mailboxes(db, config, to, Some(&config.dmarc.junk_mailbox)).await
+}
+
+async fn resolve_mailboxes(
+    db: &Mutex<database::DBClient>,
+    config: &Config,
+    to: &str,
+    folder: Option<&str>,
+) -> Result<Vec<i32>> {
+    let (local, domain) = split_address(to).with_context(|| format!("invalid address: {to}"))?;
+    let mut mailboxes = Vec::new();
+    for recipient in resolve(db, config, local, domain).await? {
+        let m_id = match folder {
+            Some(name) => folder_for(db, recipient.user_id, name).await?,
+            None => mailbox_for(db, &recipient).await?,
+        };
+        if !mailboxes.contains(&m_id) {
+            mailboxes.push(m_id);
+        }
+    }
+    Ok(mailboxes)
+}
+
+///the mailbox named after the detail if the user has one, INBOX otherwise
+async fn mailbox_for(db: &Mutex<database::DBClient>, recipient: &Recipient) -> Result<i32> {
+    let db = db.lock().await;
+    if let Some(detail) = &recipient.detail {
+        if let Some(m_id) = db.find_mailbox_id(recipient.user_id, detail).await {
+            return Ok(m_id);
+        }
+    }
+    db.get_mailbox_id(recipient.user_id, "INBOX").await
+}
+
+///the user's mailbox called `name`, made if it doesn't exist
+async fn folder_for(db: &Mutex<database::DBClient>, user_id: i32, name: &str) -> Result<i32> {
+    let db = db.lock().await;
+    if let Some(m_id) = db.find_mailbox_id(user_id, name).await {
+        return Ok(m_id);
+    }
+    db.create_mailbox(user_id, name).await?;
+    db.get_mailbox_id(user_id, name).await
+}
+
+///the outside addresses mail for `to` (with its angle brackets) is
+///forwarded to. `bob+x` is forwarded like `bob`
+pub fn forwards<'a>(config: &'a Config, to: &str) -> &'a [String] {
+    let Some((local, domain)) = split_address(to) else {
+        return &[];
+    };
+    let local = local.to_lowercase();
+    let (base, _) = split_detail(&local, &config.smtp.recipient_delimiter);
+    config
+        .domain(domain)
+        .and_then(|d| d.forward.get(base))
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
+///`alice+invoices` -> ("alice", Some("invoices"))
+pub fn split_detail<'a>(local: &'a str, delimiter: &str) -> (&'a str, Option<&'a str>) {
+    if delimiter.is_empty() {
+        return (local, None);
+    }
+    match local.split_once(delimiter) {
+        Some((base, detail)) if !base.is_empty() && !detail.is_empty() => (base, Some(detail)),
+        _ => (local, None),
+    }
+}
+
+fn recipients(user_ids: Vec<i32>, detail: Option<&str>) -> Vec<Recipient> {
+    user_ids
+        .into_iter()
+        .map(|user_id| Recipient {
+            user_id,
+            detail: detail.map(str::to_string),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::{bail, Result};
+
+    use super::{forwards, resolve, Recipient, UserDirectory};
+    use crate::config::{Config, DomainConfig};
+
+    ///users and aliases kept in memory, users are keyed by `name@domain`
+    #[derive(Default)]
+    pub struct FakeDirectory {
+        pub users: HashMap<&'static str, i32>,
+        pub aliases: HashMap<&'static str, Vec<i32>>,
+        ///every lookup fails, like a database that's down
+        pub broken: bool,
+    }
+
+    impl UserDirectory for FakeDirectory {
+        async fn alias_targets(&self, address: &str) -> Result<Vec<i32>> {
+            if self.broken {
+                bail!("database is locked");
+            }
+            Ok(self.aliases.get(address).cloned().unwrap_or_default())
+        }
+
+        async fn user_id(&self, name: &str, domain: &str) -> Result<Option<i32>> {
+            if self.broken {
+                bail!("database is locked");
+            }
+            Ok(self.users.get(format!("{name}@{domain}").as_str()).copied())
+        }
+    }
+
+    fn to(user_id: i32, detail: Option<&str>) -> Recipient {
+        Recipient {
+            user_id,
+            detail: detail.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let directory = FakeDirectory {
+            users: HashMap::from([
+                ("alice@kaki.foo", 1),
+                ("bob@kaki.foo", 2),
+                ("alice@third.com", 3),
+            ]),
+            aliases: HashMap::from([
+                ("team@kaki.foo", vec![1, 2]),
+                ("alice+boss@kaki.foo", vec![2]),
+                ("@other.com", vec![2]),
+            ]),
+            ..Default::default()
+        };
+        let mut config = Config {
+            domains: ["kaki.foo", "other.com", "third.com"]
+                .map(DomainConfig::new)
+                .to_vec(),
+            ..Default::default()
+        };
+        config.domains[2].catch_all = Some("alice".to_string());
+        let lookup = |local, domain| resolve(&directory, &config, local, domain);
+        assert_eq!(
+            lookup("Alice", "kaki.foo").await.unwrap(),
+            vec![to(1, None)]
+        );
+        //every domain has its own users
+        assert_eq!(
+            lookup("alice", "third.com").await.unwrap(),
+            vec![to(3, None)]
+        );
+        //third.com's catch-all is its alice
+        assert_eq!(lookup("bob", "third.com").await.unwrap(), vec![to(3, None)]);
+        assert_eq!(
+            lookup("alice+invoices", "kaki.foo").await.unwrap(),
+            vec![to(1, Some("invoices"))]
+        );
+        assert_eq!(
+            lookup("team+x", "kaki.foo").await.unwrap(),
+            vec![to(1, Some("x")), to(2, Some("x"))]
+        );
+        //an exact alias beats the user
+        assert_eq!(
+            lookup("alice+boss", "kaki.foo").await.unwrap(),
+            vec![to(2, None)]
+        );
+        assert!(lookup("nobody", "kaki.foo").await.unwrap().is_empty());
+        assert_eq!(
+            lookup("nobody", "other.com").await.unwrap(),
+            vec![to(2, None)]
+        );
+        //sub-addressing can be turned off
+        config.smtp.recipient_delimiter = String::new();
+        assert!(resolve(&directory, &config, "alice+invoices", "kaki.foo")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_forwards() {
+        let mut config = Config {
+            domains: vec![DomainConfig::new("kaki.foo")],
+            ..Default::default()
+        };
+        config.domains[0]
+            .forward
+            .insert("bob".to_string(), vec!["bob@example.com".to_string()]);
+        assert_eq!(forwards(&config, "<Bob@kaki.foo>"), ["bob@example.com"]);
+        assert_eq!(
+            forwards(&config, "<bob+lists@KAKI.foo>"),
+            ["bob@example.com"]
+        );
+        assert!(forwards(&config, "<alice@kaki.foo>").is_empty());
+        assert!(forwards(&config, "<bob@other.com>").is_empty());
+    }
+}
@@ -0,0 +1,514 @@
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_rustls::{client::TlsStream, rustls::pki_types::ServerName, TlsConnector};
+
+use crate::smtp_codec;
+use crate::smtp_common::{Body, Mail};
+use crate::tls;
+
+///a reply line longer than this is a broken server
+const MAX_REPLY_LINE: u64 = 4096;
+
+///a complete, possibly multi-line, reply from the remote server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    ///the text after the code, one entry per line
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn is_positive(&self) -> bool {
+        (200..400).contains(&self.code)
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+///what happened to a recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Delivered,
+    ///worth trying again later
+    Transient(String),
+    Permanent(String),
+}
+
+impl Outcome {
+    pub fn from_reply(reply: &Reply) -> Self {
+        match reply.code {
+            200..=399 => Outcome::Delivered,
+            500..=599 => Outcome::Permanent(reply.to_string()),
+            _ => Outcome::Transient(reply.to_string()),
+        }
+    }
+}
+
+///the sending side of an SMTP session, used to hand queued mail to other servers
+pub struct SmtpClient<S> {
+    stream: BufReader<S>,
+    ///the keywords from the EHLO reply, eg. "8BITMIME" or "SIZE 1000"
+    extensions: Vec<String>,
+    timeout: Duration,
+    ///the name we introduce ourselves with
+    hostname: String,
+    ///the protocol version and cipher suite after STARTTLS
+    tls: Option<(String, String)>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SmtpClient<S> {
+    ///reads the greeting and introduces us as `hostname`, falls back to
+    ///HELO for servers that don't know EHLO
+    pub async fn connect(stream: S, hostname: &str, timeout: Duration) -> Result<Self> {
+        let mut client = Self {
+            stream: BufReader::new(stream),
+            extensions: vec![],
+            timeout,
+            hostname: hostname.to_string(),
+            tls: None,
+        };
+        let greeting = client.read_reply().await?;
+        if greeting.code != 220 {
+            bail!("refused by the server: {greeting}");
+        }
+        client.hello().await?;
+        Ok(client)
+    }
+
+    async fn hello(&mut self) -> Result<()> {
+        let ehlo = self.command(&format!("EHLO {}", self.hostname)).await?;
+        if ehlo.code == 250 {
+            self.extensions = ehlo.lines.into_iter().skip(1).collect();
+            return Ok(());
+        }
+        self.extensions.clear();
+        let helo = self.command(&format!("HELO {}", self.hostname)).await?;
+        if helo.code != 250 {
+            bail!("HELO failed: {helo}");
+        }
+        Ok(())
+    }
+
+    ///upgrades the session with STARTTLS (rfc 3207), `host` is the name the
+    ///certificate is checked for. the connection is unusable after an error
+    pub async fn starttls(
+        mut self,
+        connector: &TlsConnector,
+        host: &str,
+    ) -> Result<SmtpClient<TlsStream<S>>> {
+        let reply = self.command("STARTTLS").await?;
+        if reply.code != 220 {
+            bail!("STARTTLS refused: {reply}");
+        }
+        //whatever came with the 220 was sent in plaintext and could be
+        //injected, it mustn't be read as if it came over tls
+        if !self.stream.buffer().is_empty() {
+            bail!("the server sent data before the tls handshake");
+        }
+        let name = ServerName::try_from(host.to_string())
+            .with_context(|| format!("invalid host name {host}"))?;
+        let stream = tokio::time::timeout(
+            self.timeout,
+            connector.connect(name, self.stream.into_inner()),
+        )
+        .await
+        .context("timed out in the tls handshake")?
+        .context("tls handshake failed")?;
+        let tls = tls::session_info(stream.get_ref().1);
+        let mut client = SmtpClient {
+            stream: BufReader::new(stream),
+            extensions: vec![],
+            timeout: self.timeout,
+            hostname: self.hostname,
+            tls,
+        };
+        //what the server said before doesn't count anymore
+        client.hello().await?;
+        Ok(client)
+    }
+
+    ///the protocol version and cipher suite, if the session is encrypted
+    pub fn tls(&self) -> Option<&(String, String)> {
+        self.tls.as_ref()
+    }
+
+    pub fn supports(&self, keyword: &str) -> bool {
+        self.extensions.iter().any(|ext| {
+            ext.split_whitespace()
+                .next()
+                .is_some_and(|k| k.eq_ignore_ascii_case(keyword))
+        })
+    }
+
+    ///sends one transaction, returns what happened to each of `mail.to`.
+    ///an error means the connection broke and nothing was delivered
+    pub async fn send(&mut self, mail: &Mail) -> Result<Vec<Outcome>> {
+        let everyone = |outcome: Outcome| vec![outcome; mail.to.len()];
+        let binary = mail.params.body == Body::BinaryMime;
+        if binary && !(self.supports("BINARYMIME") && self.supports("CHUNKING")) {
+            return Ok(everyone(Outcome::Permanent(
+                "554 5.6.3 the remote server can't receive binary mail".to_string(),
+            )));
+        }
+        //8-bit data can't go to a server that doesn't say it takes it, and
+        //we don't convert it, declared 8BITMIME that's really 7-bit can
+        //still go without the BODY parameter (rfc 6152 3)
+        if !binary && !mail.data.is_ascii() && !self.supports("8BITMIME") {
+            return Ok(everyone(Outcome::Permanent(
+                "554 5.6.3 the remote server can't receive 8-bit mail".to_string(),
+            )));
+        }
+        if mail.params.smtputf8 && !self.supports("SMTPUTF8") {
+            return Ok(everyone(Outcome::Permanent(
+                "553 5.6.7 the remote server doesn't support SMTPUTF8".to_string(),
+            )));
+        }
+        //mail.from and mail.to already have their angle brackets
+        let mut mail_from = format!("MAIL FROM:{}", mail.from);
+        if self.supports("SIZE") {
+            mail_from += &format!(" SIZE={}", mail.data.len());
+        }
+        if (mail.params.body != Body::SevenBit && self.supports("8BITMIME")) || binary {
+            mail_from += &format!(" BODY={}", mail.params.body.keyword());
+        }
+        if mail.params.smtputf8 {
+            mail_from += " SMTPUTF8";
+        }
+        let reply = self.command(&mail_from).await?;
+        if !reply.is_positive() {
+            self.command("RSET").await?;
+            return Ok(everyone(Outcome::from_reply(&reply)));
+        }
+        let mut outcomes = Vec::with_capacity(mail.to.len());
+        for rcpt in &mail.to {
+            let reply = self.command(&format!("RCPT TO:{rcpt}")).await?;
+            outcomes.push(Outcome::from_reply(&reply));
+        }
+        if !outcomes.contains(&Outcome::Delivered) {
+            self.command("RSET").await?;
+            return Ok(outcomes);
+        }
+        let reply = if binary {
+            let command = format!("BDAT {} LAST\r\n", mail.data.len());
+            self.write(command.as_bytes()).await?;
+            self.write(&mail.data).await?;
+            self.read_reply().await?
+        } else {
+            let reply = self.command("DATA").await?;
+            if reply.code != 354 {
+                self.command("RSET").await?;
+                reply
+            } else {
+                self.write(&smtp_codec::encode_data(&mail.data)).await?;
+                self.read_reply().await?
+            }
+        };
+        //the end of data reply is for every recipient that was accepted
+        for outcome in outcomes.iter_mut() {
+            if *outcome == Outcome::Delivered {
+                *outcome = Outcome::from_reply(&reply);
+            }
+        }
+        Ok(outcomes)
+    }
+
+    ///ends the session, the reply doesn't matter anymore
+    pub async fn quit(mut self) {
+        if let Err(e) = self.command("QUIT").await {
+            tracing::debug!("error quitting: {:?}", e);
+        }
+    }
+
+    async fn command(&mut self, command: &str) -> Result<Reply> {
+        tracing::debug!("sending: {command}");
+        self.write(format!("{command}\r\n").as_bytes()).await?;
+        self.read_reply().await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        tokio::time::timeout(self.timeout, self.stream.get_mut().write_all(data))
+            .await
+            .context("timed out writing to the server")??;
+        Ok(())
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            let n = tokio::time::timeout(
+                self.timeout,
+                (&mut self.stream)
+                    .take(MAX_REPLY_LINE)
+                    .read_until(b'\n', &mut line),
+            )
+            .await
+            .context("timed out waiting for the server")??;
+            if n == 0 {
+                bail!("connection closed by the server");
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            tracing::debug!("read: {line}");
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .with_context(|| format!("invalid reply: {line}"))?;
+            let text = line.get(4..).unwrap_or_default().to_string();
+            lines.push(text);
+            //"250-" continues, "250 " or a bare "250" ends the reply
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(Reply { code, lines });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::{Outcome, SmtpClient};
+    use crate::certs::{self_signed, CertBundle};
+    use crate::dane::{Tlsa, DANE_EE};
+    use crate::outbound_tls::{Connectors, TlsPolicy};
+    use crate::smtp_common::{Body, Mail, MailParams};
+
+    trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+    impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+    ///answers like a server that knows `nobody@` doesn't exist, returns
+    ///everything it was sent. offers STARTTLS if it has an acceptor, only
+    ///knows HELO if it isn't `esmtp`
+    async fn fake_server(
+        stream: tokio::io::DuplexStream,
+        mut acceptor: Option<TlsAcceptor>,
+        esmtp: bool,
+    ) -> String {
+        let mut stream: BufReader<Box<dyn Io>> = BufReader::new(Box::new(stream));
+        let mut transcript = String::new();
+        stream
+            .get_mut()
+            .write_all(b"220 mx.example.com ready\r\n")
+            .await
+            .unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return transcript;
+            }
+            transcript += &line;
+            let reply: &[u8] = if in_data {
+                if line != ".\r\n" {
+                    continue;
+                }
+                in_data = false;
+                b"250 2.0.0 queued\r\n"
+            } else if line.starts_with("EHLO") && !esmtp {
+                b"502 5.5.1 what\r\n"
+            } else if line.starts_with("EHLO") && acceptor.is_some() {
+                b"250-mx.example.com\r\n250-STARTTLS\r\n250-SIZE 1000\r\n250 8BITMIME\r\n"
+            } else if line.starts_with("EHLO") {
+                b"250-mx.example.com\r\n250-SIZE 1000\r\n250 8BITMIME\r\n"
+            } else if line.starts_with("STARTTLS") && acceptor.is_some() {
+                let acceptor = acceptor.take().unwrap();
+                stream
+                    .get_mut()
+                    .write_all(b"220 go ahead\r\n")
+                    .await
+                    .unwrap();
+                match acceptor.accept(stream.into_inner()).await {
+                    Ok(tls) => stream = BufReader::new(Box::new(tls)),
+                    Err(_) => return transcript,
+                }
+                continue;
+            } else if line.starts_with("RCPT TO:<nobody@") {
+                b"550 5.1.1 no such user\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                b"221 bye\r\n"
+            } else {
+                b"250 ok\r\n"
+            };
+            stream.get_mut().write_all(reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(fake_server(server, None, true));
+        let mut client = SmtpClient::connect(client, "smtp.kaki.foo", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(client.supports("8bitmime"));
+        assert!(!client.supports("CHUNKING"));
+        let mail = Mail {
+            from: "<alice@kaki.foo>".to_string(),
+            to: vec![
+                "<bob@example.com>".to_string(),
+                "<nobody@example.com>".to_string(),
+            ],
+            data: "Subject: hi\r\n\r\n.dot\r\nä\r\n".as_bytes().to_vec(),
+            params: MailParams {
+                body: Body::EightBitMime,
+                ..Default::default()
+            },
+        };
+        let outcomes = client.send(&mail).await.unwrap();
+        assert_eq!(outcomes[0], Outcome::Delivered);
+        assert_eq!(
+            outcomes[1],
+            Outcome::Permanent("550 5.1.1 no such user".to_string())
+        );
+        //binary mail can't go to a server without CHUNKING
+        let binary = Mail {
+            params: MailParams {
+                body: Body::BinaryMime,
+                ..Default::default()
+            },
+            ..mail.clone()
+        };
+        assert!(matches!(
+            client.send(&binary).await.unwrap()[0],
+            Outcome::Permanent(_)
+        ));
+        client.quit().await;
+        let transcript = server.await.unwrap();
+        assert!(transcript.contains("MAIL FROM:<alice@kaki.foo> SIZE=25 BODY=8BITMIME\r\n"));
+        assert!(transcript.contains("RCPT TO:<bob@example.com>\r\n"));
+        assert!(transcript.contains("..dot\r\n"));
+        assert!(transcript.ends_with("QUIT\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_send_without_8bitmime() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(fake_server(server, None, false));
+        let mut client = SmtpClient::connect(client, "smtp.kaki.foo", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(!client.supports("8BITMIME"));
+        let mail = Mail {
+            from: "<alice@kaki.foo>".to_string(),
+            to: vec!["<bob@example.com>".to_string()],
+            data: "Subject: hi\r\n\r\nä\r\n".as_bytes().to_vec(),
+            params: MailParams {
+                body: Body::EightBitMime,
+                ..Default::default()
+            },
+        };
+        assert!(matches!(
+            &client.send(&mail).await.unwrap()[0],
+            Outcome::Permanent(reply) if reply.starts_with("554 5.6.3")
+        ));
+        //undeclared 8-bit data is just as bad
+        let undeclared = Mail {
+            params: MailParams::default(),
+            ..mail.clone()
+        };
+        assert!(matches!(
+            client.send(&undeclared).await.unwrap()[0],
+            Outcome::Permanent(_)
+        ));
+        //declared 8BITMIME but really 7-bit goes without the parameter
+        let ascii = Mail {
+            data: b"Subject: hi\r\n\r\nhi\r\n".to_vec(),
+            ..mail.clone()
+        };
+        assert_eq!(client.send(&ascii).await.unwrap(), vec![Outcome::Delivered]);
+        client.quit().await;
+        let transcript = server.await.unwrap();
+        assert!(transcript.contains("MAIL FROM:<alice@kaki.foo>\r\n"));
+        assert!(!transcript.contains("BODY="));
+        assert!(!transcript.contains("ä"));
+    }
+
+    #[tokio::test]
+    async fn test_starttls() {
+        let (cert, key) = self_signed::generate_pem(&["mx.example.com".to_string()]).unwrap();
+        let bundle = CertBundle::from_pem(&cert, &key).unwrap();
+        let bundle_cert = bundle.certs[0].to_vec();
+        let mut roots = RootCertStore::empty();
+        roots.add(bundle.certs[0].clone()).unwrap();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(bundle.certs, bundle.key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let trusting = Connectors::with_roots(roots);
+        let webpki = Connectors::new();
+        let tlsa = |cert: &[u8]| Tlsa {
+            usage: DANE_EE,
+            selector: 0,
+            matching: 0,
+            data: cert.to_vec(),
+        };
+        let dane = webpki.dane(vec![tlsa(&bundle_cert)]);
+        let wrong_dane = webpki.dane(vec![tlsa(b"another certificate")]);
+        //(who checks the certificate, the name it's checked for, whether it's fine)
+        let cases = [
+            (
+                trusting.get(TlsPolicy::Opportunistic),
+                "mx.example.com",
+                true,
+            ),
+            (webpki.get(TlsPolicy::Opportunistic), "mx.example.com", true),
+            (trusting.get(TlsPolicy::Required), "mx.example.com", true),
+            (
+                trusting.get(TlsPolicy::Required),
+                "other.example.com",
+                false,
+            ),
+            (webpki.get(TlsPolicy::Required), "mx.example.com", false),
+            //the TLSA record is trusted instead of the roots, whatever the name
+            (&dane, "other.example.com", true),
+            (&wrong_dane, "mx.example.com", false),
+        ];
+        for (connector, host, ok) in cases {
+            let (client, server) = tokio::io::duplex(16384);
+            let server = tokio::spawn(fake_server(server, Some(acceptor.clone()), true));
+            let client = SmtpClient::connect(client, "smtp.kaki.foo", Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert!(client.supports("STARTTLS"));
+            let mut client = match client.starttls(connector, host).await {
+                Ok(client) => client,
+                Err(e) => {
+                    assert!(!ok, "{host}: {e:#}");
+                    continue;
+                }
+            };
+            assert!(ok, "{host} shouldn't have been trusted");
+            assert!(client.tls().is_some());
+            //the extensions are from the second EHLO
+            assert!(!client.supports("STARTTLS"));
+            assert!(client.supports("8BITMIME"));
+            let mail = Mail {
+                from: "<alice@kaki.foo>".to_string(),
+                to: vec!["<bob@example.com>".to_string()],
+                data: b"Subject: hi\r\n\r\nhi\r\n".to_vec(),
+                ..Default::default()
+            };
+            assert_eq!(client.send(&mail).await.unwrap(), [Outcome::Delivered]);
+            client.quit().await;
+            let transcript = server.await.unwrap();
+            assert_eq!(transcript.matches("EHLO smtp.kaki.foo\r\n").count(), 2);
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+///what the codec pulled out of the stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmtpFrame {
+    ///a command line without the CRLF
+    Command(String),
+    ///a whole DATA payload, dot-unstuffed and without the terminating "."
+    Data(Vec<u8>),
+    ///the payload of a BDAT command, rfc 3030
+    Chunk { data: Vec<u8>, last: bool },
+    ///a line went over the line limit, it's discarded up to its CRLF
+    LineTooLong,
+    ///the DATA or BDAT payload went over the message size limit,
+    ///it's discarded up to the terminating "." or the end of the chunk
+    TooLarge,
+}
+
+///where we are in a DATA payload
+#[derive(Debug, Default)]
+struct DataState {
+    data: Vec<u8>,
+    too_large: bool,
+    line_too_long: bool,
+    ///set while discarding the rest of an overlong line, none of it can
+    ///end the payload
+    discarding: bool,
+}
+
+///a BDAT payload that's still being read
+#[derive(Debug)]
+struct ChunkState {
+    remaining: usize,
+    last: bool,
+    too_large: bool,
+    data: Vec<u8>,
+}
+
+///a buffered, byte based SMTP reader. commands are framed by CRLF, so
+///pipelined commands or commands split across reads come out one by one,
+///and DATA is read up to the CRLF.CRLF terminator. BDAT payloads are read
+///along with their command, so they never get mistaken for commands
+#[derive(Debug)]
+pub struct SmtpCodec {
+    buf: Vec<u8>,
+    max_line: usize,
+    max_message: usize,
+    ///set while discarding the rest of an overlong command line
+    discarding: bool,
+    chunk: Option<ChunkState>,
+}
+
+impl SmtpCodec {
+    pub fn new(max_line: usize, max_message: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_line,
+            max_message,
+            discarding: false,
+            chunk: None,
+        }
+    }
+
+    ///drops the buffered input, needed after STARTTLS so that plaintext
+    ///sent before the handshake can't be injected into the tls session
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.discarding = false;
+        self.chunk = None;
+    }
+
+    ///whether anything the client sent is still buffered
+    pub fn has_buffered(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    ///whether a whole command is already buffered, ie. the client pipelined
+    pub fn has_complete_line(&self) -> bool {
+        self.buf.windows(2).any(|w| w == b"\r\n")
+    }
+
+    pub fn max_message(&self) -> usize {
+        self.max_message
+    }
+
+    ///reads the next command or BDAT chunk, returns None on EOF
+    pub async fn read_command<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+    ) -> Result<Option<SmtpFrame>> {
+        loop {
+            if let Some(frame) = self.decode_command() {
+                return Ok(Some(frame));
+            }
+            if !self.fill(stream).await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    ///reads a DATA payload up to the terminator, returns None on EOF
+    pub async fn read_data<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+    ) -> Result<Option<SmtpFrame>> {
+        let mut state = DataState::default();
+        loop {
+            if let Some(frame) = self.decode_data(&mut state) {
+                return Ok(Some(frame));
+            }
+            if !self.fill(stream).await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    ///returns false on EOF
+    async fn fill<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<bool> {
+        let mut chunk = [0u8; 8192];
+        let n = stream.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n != 0)
+    }
+
+    ///takes one line (including the CRLF) out of the buffer
+    fn take_line(&mut self) -> Option<Vec<u8>> {
+        let end = self.buf.windows(2).position(|w| w == b"\r\n")? + 2;
+        Some(self.buf.drain(..end).collect())
+    }
+
+    fn decode_command(&mut self) -> Option<SmtpFrame> {
+        loop {
+            if self.chunk.is_some() {
+                return self.decode_chunk();
+            }
+            let Some(line) = self.take_line() else {
+                if self.buf.len() > self.max_line {
+                    //keep the last byte, it might be the \r of the CRLF
+                    self.buf.drain(..self.buf.len() - 1);
+                    if !self.discarding {
+                        self.discarding = true;
+                        return Some(SmtpFrame::LineTooLong);
+                    }
+                }
+                return None;
+            };
+            if self.discarding {
+                //the rest of a line that was already reported
+                self.discarding = false;
+                continue;
+            }
+            if line.len() > self.max_line {
+                return Some(SmtpFrame::LineTooLong);
+            }
+            let line = &line[..line.len() - 2];
+            if let Some((size, last)) = bdat_args(line) {
+                self.chunk = Some(ChunkState {
+                    remaining: size,
+                    last,
+                    too_large: size > self.max_message,
+                    data: Vec::new(),
+                });
+                continue;
+            }
+            return Some(SmtpFrame::Command(
+                String::from_utf8_lossy(line).into_owned(),
+            ));
+        }
+    }
+
+    fn decode_chunk(&mut self) -> Option<SmtpFrame> {
+        let chunk = self.chunk.as_mut()?;
+        let n = chunk.remaining.min(self.buf.len());
+        let bytes = self.buf.drain(..n);
+        if chunk.too_large {
+            drop(bytes);
+        } else {
+            chunk.data.extend(bytes);
+        }
+        chunk.remaining -= n;
+        if chunk.remaining > 0 {
+            return None;
+        }
+        let chunk = self.chunk.take()?;
+        Some(if chunk.too_large {
+            SmtpFrame::TooLarge
+        } else {
+            SmtpFrame::Chunk {
+                data: chunk.data,
+                last: chunk.last,
+            }
+        })
+    }
+
+    fn decode_data(&mut self, state: &mut DataState) -> Option<SmtpFrame> {
+        loop {
+            let Some(line) = self.take_line() else {
+                if self.buf.len() > self.max_line {
+                    //keep the last byte, it might be the \r of the CRLF
+                    self.buf.drain(..self.buf.len() - 1);
+                    state.line_too_long = true;
+                    state.discarding = true;
+                }
+                return None;
+            };
+            if state.discarding {
+                //the rest of a line that was already too long
+                state.discarding = false;
+                continue;
+            }
+            if line == b".\r\n" {
+                let state = std::mem::take(state);
+                return Some(if state.line_too_long {
+                    SmtpFrame::LineTooLong
+                } else if state.too_large {
+                    SmtpFrame::TooLarge
+                } else {
+                    SmtpFrame::Data(state.data)
+                });
+            }
+            if line.len() > self.max_line {
+                state.line_too_long = true;
+            }
+            if state.too_large || state.line_too_long {
+                continue;
+            }
+            //rfc 5321 4.5.2, the client doubled every leading dot
+            let line = line.strip_prefix(b".").unwrap_or(&line);
+            if state.data.len() + line.len() > self.max_message {
+                state.too_large = true;
+                state.data = Vec::new();
+                continue;
+            }
+            state.data.extend_from_slice(line);
+        }
+    }
+}
+
+///`BDAT 1000 LAST` -> (1000, true). anything malformed is left for the
+///state machine to reject as a command
+fn bdat_args(line: &[u8]) -> Option<(usize, bool)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut args = line.split(' ');
+    if !args.next()?.eq_ignore_ascii_case("bdat") {
+        return None;
+    }
+    let size = args.next()?.parse().ok()?;
+    let last = match args.next() {
+        None => false,
+        Some(x) if x.eq_ignore_ascii_case("last") => true,
+        Some(_) => return None,
+    };
+    if args.next().is_some() {
+        return None;
+    }
+    Some((size, last))
+}
+
+///the inverse of reading DATA: dot-stuffs `data` and appends the terminator
+pub fn encode_data(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5);
+    let mut line_start = true;
+    for (i, &b) in data.iter().enumerate() {
+        if line_start && b == b'.' {
+            out.push(b'.');
+        }
+        out.push(b);
+        line_start = b == b'\n' && i > 0 && data[i - 1] == b'\r';
+    }
+    if !data.is_empty() && !data.ends_with(b"\r\n") {
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b".\r\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::{encode_data, SmtpCodec, SmtpFrame};
+
+    fn command(s: &str) -> Option<SmtpFrame> {
+        Some(SmtpFrame::Command(s.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_and_split_commands() {
+        let mut codec = SmtpCodec::new(1000, 1000);
+        let mut input: &[u8] = b"MAIL FROM:<a@b.c>\r\nRCPT TO:<d@e.f>\r\nDA";
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("MAIL FROM:<a@b.c>")
+        );
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("RCPT TO:<d@e.f>")
+        );
+        //the rest of DATA never arrives
+        assert_eq!(codec.read_command(&mut input).await.unwrap(), None);
+        let mut input: &[u8] = b"TA\r\n";
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("DATA")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_data_unstuffing() {
+        let mut codec = SmtpCodec::new(1000, 1000);
+        let mut input: &[u8] =
+            b"Subject: hi\r\n\r\n..leading dot\r\n..\r\nnot the end\r\n\xff\r\n.\r\nQUIT\r\n";
+        assert_eq!(
+            codec.read_data(&mut input).await.unwrap(),
+            Some(SmtpFrame::Data(
+                b"Subject: hi\r\n\r\n.leading dot\r\n.\r\nnot the end\r\n\xff\r\n".to_vec()
+            ))
+        );
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("QUIT")
+        );
+        let mut input: &[u8] = b".\r\n";
+        assert_eq!(
+            codec.read_data(&mut input).await.unwrap(),
+            Some(SmtpFrame::Data(vec![]))
+        );
+        //a bare LF before the dot isn't a terminator
+        let mut input: &[u8] = b"a\n.\nb\r\n.\r\n";
+        assert_eq!(
+            codec.read_data(&mut input).await.unwrap(),
+            Some(SmtpFrame::Data(b"a\n.\nb\r\n".to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encode_round_trip() {
+        let data = b".hi\r\n.\r\na\n.b\r\n";
+        let encoded = encode_data(data);
+        assert_eq!(encoded, b"..hi\r\n..\r\na\n.b\r\n.\r\n");
+        let mut codec = SmtpCodec::new(1000, 1000);
+        assert_eq!(
+            codec.read_data(&mut encoded.as_slice()).await.unwrap(),
+            Some(SmtpFrame::Data(data.to_vec()))
+        );
+        assert_eq!(encode_data(b"no newline"), b"no newline\r\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_bdat() {
+        let mut codec = SmtpCodec::new(1000, 20);
+        //the payload is binary and contains what looks like commands
+        let mut input: &[u8] = b"BDAT 11\r\nQUIT\r\n.\r\n\x00\xffbdat 0 LAST\r\nNOOP\r\n";
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            Some(SmtpFrame::Chunk {
+                data: b"QUIT\r\n.\r\n\x00\xff".to_vec(),
+                last: false
+            })
+        );
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            Some(SmtpFrame::Chunk {
+                data: vec![],
+                last: true
+            })
+        );
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("NOOP")
+        );
+        //split across reads
+        let mut input: &[u8] = b"BDAT 4 LAST\r\nab";
+        assert_eq!(codec.read_command(&mut input).await.unwrap(), None);
+        let mut input: &[u8] = b"cd";
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            Some(SmtpFrame::Chunk {
+                data: b"abcd".to_vec(),
+                last: true
+            })
+        );
+        //oversized chunks are skipped over
+        let mut input: &[u8] = b"BDAT 30\r\n012345678901234567890123456789BDAT x\r\n";
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            Some(SmtpFrame::TooLarge)
+        );
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("BDAT x")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let mut codec = SmtpCodec::new(10, 20);
+        let mut input: &[u8] = b"0123456789abcdef\r\nNOOP\r\n";
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            Some(SmtpFrame::LineTooLong)
+        );
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("NOOP")
+        );
+        let mut input: &[u8] = b"12345678\r\n12345678\r\n12345678\r\n.\r\nQUIT\r\n";
+        assert_eq!(
+            codec.read_data(&mut input).await.unwrap(),
+            Some(SmtpFrame::TooLarge)
+        );
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("QUIT")
+        );
+        //the rest of an overlong line can't end the payload, even when it
+        //looks like the terminator
+        let mut input = b"0123456789abc.".chain(&b"\r\n.\r\nQUIT\r\n"[..]);
+        assert_eq!(
+            codec.read_data(&mut input).await.unwrap(),
+            Some(SmtpFrame::LineTooLong)
+        );
+        assert_eq!(
+            codec.read_command(&mut input).await.unwrap(),
+            command("QUIT")
+        );
+    }
+}
@@ -1,11 +1,14 @@
 use std::sync::Arc;
 
-use anyhow::Context;
 use anyhow::Result;
 use base64::Engine;
 use tokio::sync::Mutex;
 
+use crate::config::Config;
 use crate::database;
+use crate::parsing;
+use crate::recipients::{self, UserDirectory};
+use crate::smtp_codec::{SmtpCodec, SmtpFrame};
 use crate::utils;
 
 //naïve
@@ -13,8 +16,58 @@ use crate::utils;
 pub struct Mail {
     pub from: String,
     pub to: Vec<String>,
-    pub data: String,
+    ///the raw message, kept as bytes so 8-bit and binary bodies aren't mangled
+    pub data: Vec<u8>,
+    pub params: MailParams,
 }
+
+///the BODY= parameter of MAIL FROM, rfc 6152 and rfc 3030
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Body {
+    #[default]
+    SevenBit,
+    EightBitMime,
+    ///can only be sent with BDAT
+    BinaryMime,
+}
+
+impl Body {
+    ///the value of BODY= for this body type
+    pub fn keyword(self) -> &'static str {
+        match self {
+            Body::SevenBit => "7BIT",
+            Body::EightBitMime => "8BITMIME",
+            Body::BinaryMime => "BINARYMIME",
+        }
+    }
+
+    pub fn from_keyword(keyword: &str) -> Option<Self> {
+        [Body::SevenBit, Body::EightBitMime, Body::BinaryMime]
+            .into_iter()
+            .find(|body| body.keyword().eq_ignore_ascii_case(keyword))
+    }
+}
+
+///the ESMTP parameters given with MAIL FROM
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct MailParams {
+    ///the size the client declared, rfc 1870
+    pub size: Option<usize>,
+    pub body: Body,
+    ///the client asked for SMTPUTF8, rfc 6531
+    pub smtputf8: bool,
+}
+
+///`<user@kaki.foo>` -> ("user", "kaki.foo")
+pub fn split_address(path: &str) -> Option<(&str, &str)> {
+    let address = path.strip_prefix('<')?.strip_suffix('>')?;
+    let (user, domain) = address.rsplit_once('@')?;
+    if user.is_empty() || domain.is_empty() {
+        return None;
+    }
+    Some((user, domain))
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum SMTPState {
     Fresh,
@@ -22,13 +75,21 @@ pub enum SMTPState {
     Authed(i32),
     ReceivingRcpt(Mail, Option<i32>),
     ReceivingData(Mail, Option<i32>),
-    Received(Mail, Option<i32>),
+    ///between BDAT chunks, the chunks so far are in the mail's data
+    ReceivingChunks(Mail, Option<i32>),
 }
 
 pub struct SMTPStateMachine {
     pub state: SMTPState,
+    pub greeting: String,
     pub ehlo_greeting: String,
+    ///the name the client gave in EHLO or HELO
+    pub helo: String,
+    ///the client greeted with EHLO rather than HELO
+    pub esmtp: bool,
     pub outgoing: bool,
+    pub codec: SmtpCodec,
+    pub config: Arc<Config>,
 }
 
 /// An state machine capable of handling SMTP commands
@@ -38,140 +99,337 @@ pub struct SMTPStateMachine {
 /// that should be sent back to the client.
 /// Copied from edgemail, temporary
 impl SMTPStateMachine {
-    pub const OH_HAI: &'static [u8] = b"220 smtp.kaki.foo ESMTP Server\r\n";
-    pub const KK: &'static [u8] = b"250 Ok\r\n";
-    pub const AUTH_OK: &'static [u8] = b"235 Ok\r\n";
-    pub const AUTH_NOT_OK: &'static [u8] = b"535 Authentication error\r\n";
-    pub const NOT_AUTHED_YET: &'static [u8] = b"530 Need authentication\r\n";
+    //every reply after the greeting carries an rfc 3463 enhanced status code
+    pub const KK: &'static [u8] = b"250 2.0.0 Ok\r\n";
+    pub const AUTH_OK: &'static [u8] = b"235 2.7.0 Authentication successful\r\n";
+    pub const AUTH_NOT_OK: &'static [u8] = b"535 5.7.8 Authentication credentials invalid\r\n";
+    pub const NOT_AUTHED_YET: &'static [u8] = b"530 5.7.0 Authentication required\r\n";
     pub const SEND_DATA_PLZ: &'static [u8] = b"354 End data with <CR><LF>.<CR><LF>\r\n";
-    pub const READY_FOR_ENCRYPTION: &'static [u8] = b"220 Ready to start TLS\r\n";
-    pub const KTHXBYE: &'static [u8] = b"221 Bye\r\n";
-    pub const HOLD_YOUR_HORSES: &'static [u8] = &[];
-
-    pub fn new(domain: impl AsRef<str>, outgoing: bool) -> Self {
-        let domain = domain.as_ref();
-        let ehlo_greeting =
-            format!("250-{domain} Hello {domain}\r\n250-AUTH PLAIN LOGIN\r\n250 STARTTLS\r\n");
+    pub const READY_FOR_ENCRYPTION: &'static [u8] = b"220 2.0.0 Ready to start TLS\r\n";
+    pub const KTHXBYE: &'static [u8] = b"221 2.0.0 Bye\r\n";
+    pub const PIPELINED_STARTTLS: &'static [u8] =
+        b"554 5.5.1 Commands can't be pipelined after STARTTLS\r\n";
+    pub const LINE_TOO_LONG: &'static [u8] = b"500 5.5.2 Line too long\r\n";
+    pub const TOO_BIG: &'static [u8] = b"552 5.3.4 Message exceeds fixed maximum message size\r\n";
+    pub const LOCAL_ERROR: &'static [u8] =
+        b"451 4.3.0 Requested action aborted: local error in processing\r\n";
+    pub const REJECTED: &'static [u8] = b"550 5.7.1 Message rejected\r\n";
+    pub const SENDER_OK: &'static [u8] = b"250 2.1.0 Sender ok\r\n";
+    pub const RECIPIENT_OK: &'static [u8] = b"250 2.1.5 Recipient ok\r\n";
+    pub const UNRECOGNIZED: &'static [u8] = b"500 5.5.2 Command not recognized\r\n";
+    pub const SYNTAX_ERROR: &'static [u8] = b"501 5.5.4 Syntax error in parameters\r\n";
+    pub const BAD_SEQUENCE: &'static [u8] = b"503 5.5.1 Bad sequence of commands\r\n";
+    pub const NO_RECIPIENTS: &'static [u8] = b"554 5.5.1 No valid recipients\r\n";
+    pub const UNKNOWN_PARAM: &'static [u8] = b"555 5.5.4 Parameter not recognized\r\n";
+    pub const UTF8_REQUIRED: &'static [u8] = b"553 5.6.7 SMTPUTF8 is required\r\n";
+    pub const CHUNK_OK: &'static [u8] = b"250 2.0.0 Chunk received\r\n";
+    pub const BDAT_REQUIRED: &'static [u8] = b"503 5.5.1 BINARYMIME requires BDAT\r\n";
+    pub const BAD_ADDRESS: &'static [u8] = b"501 5.1.3 Bad recipient address syntax\r\n";
+    pub const NO_SUCH_USER: &'static [u8] = b"550 5.1.1 No such user here\r\n";
+    pub const RELAY_DENIED: &'static [u8] = b"550 5.7.1 Relaying denied\r\n";
+    pub const RESERVED_NAME: &'static [u8] = b"550 5.7.1 Mailbox unavailable\r\n";
+
+    pub fn new(config: Arc<Config>, outgoing: bool) -> Self {
+        let domain = &config.hostname;
+        let max_message = config.smtp.max_message_size;
+        let greeting = format!("220 {domain} ESMTP Server\r\n");
+        let ehlo_greeting = format!(
+            "250-{domain} Hello {domain}\r\n250-SIZE {max_message}\r\n250-8BITMIME\r\n\
+             250-PIPELINING\r\n250-CHUNKING\r\n250-BINARYMIME\r\n\
+             250-ENHANCEDSTATUSCODES\r\n250-SMTPUTF8\r\n\
+             250-AUTH PLAIN LOGIN\r\n250 STARTTLS\r\n"
+        );
         Self {
             state: SMTPState::Fresh,
+            greeting,
             ehlo_greeting,
+            helo: String::new(),
+            esmtp: false,
             outgoing,
+            codec: SmtpCodec::new(config.smtp.max_line_length, max_message),
+            config,
+        }
+    }
+
+    ///reads the next command, or the message if we're receiving DATA.
+    ///returns None on EOF
+    pub async fn read_frame<S>(&mut self, stream: &mut S) -> Result<Option<SmtpFrame>>
+    where
+        S: tokio::io::AsyncRead + Unpin,
+    {
+        match self.state {
+            SMTPState::ReceivingData(..) => self.codec.read_data(stream).await,
+            _ => self.codec.read_command(stream).await,
         }
     }
 
+    ///whether `response` should be written out now. replies to a pipelined
+    ///batch are held back until we've handled every command the client sent
+    ///(rfc 2920), except for the ones the client has to wait for
+    pub fn should_flush(&self, response: &[u8]) -> bool {
+        !self.codec.has_complete_line()
+            || matches!(self.state, SMTPState::ReceivingData(..))
+            || response == Self::READY_FOR_ENCRYPTION
+            || response == Self::KTHXBYE
+    }
+
+    ///takes the finished mail out of the transaction and resets it, so the
+    ///client can start another one. the caller commits the mail and replies
+    pub fn handle_data(&mut self, data: Vec<u8>) -> Result<Mail> {
+        let SMTPState::ReceivingData(mut mail, x) = self.state.clone() else {
+            anyhow::bail!("Received data in state {:?}", self.state);
+        };
+        mail.data = data;
+        tracing::trace!(
+            "Received data: FROM: {} TO:{} DATA:{}",
+            mail.from,
+            mail.to.join(", "),
+            String::from_utf8_lossy(&mail.data)
+        );
+        self.end_transaction(x);
+        Ok(mail)
+    }
+
+    ///handles a BDAT chunk. returns the mail after the LAST chunk and None
+    ///for the ones before it, the error is the reply to send
+    pub fn handle_chunk(
+        &mut self,
+        data: Vec<u8>,
+        last: bool,
+    ) -> std::result::Result<Option<Mail>, &'static [u8]> {
+        let (mut mail, x) = match self.state.clone() {
+            SMTPState::ReceivingRcpt(mail, _) if mail.to.is_empty() => {
+                return Err(SMTPStateMachine::NO_RECIPIENTS)
+            }
+            SMTPState::ReceivingRcpt(mail, x) | SMTPState::ReceivingChunks(mail, x) => (mail, x),
+            _ => return Err(SMTPStateMachine::BAD_SEQUENCE),
+        };
+        if mail.data.len() + data.len() > self.codec.max_message() {
+            self.reset_transaction();
+            return Err(SMTPStateMachine::TOO_BIG);
+        }
+        mail.data.extend_from_slice(&data);
+        if !last {
+            self.state = SMTPState::ReceivingChunks(mail, x);
+            return Ok(None);
+        }
+        self.end_transaction(x);
+        Ok(Some(mail))
+    }
+
+    fn end_transaction(&mut self, x: Option<i32>) {
+        self.state = match x {
+            Some(x) => SMTPState::Authed(x),
+            None => SMTPState::Greeted,
+        };
+    }
+
+    ///drops the current mail transaction, keeping the greeting and auth
+    pub fn reset_transaction(&mut self) {
+        self.state = match &self.state {
+            SMTPState::Fresh => SMTPState::Fresh,
+            SMTPState::Authed(x)
+            | SMTPState::ReceivingRcpt(_, Some(x))
+            | SMTPState::ReceivingData(_, Some(x))
+            | SMTPState::ReceivingChunks(_, Some(x)) => SMTPState::Authed(*x),
+            _ => SMTPState::Greeted,
+        };
+    }
+
     /// Handles a single SMTP command and returns a proper SMTP response
-    pub fn handle_smtp_incoming(&mut self, raw_msg: &str) -> Result<&[u8]> {
+    pub async fn handle_smtp_incoming(
+        &mut self,
+        raw_msg: &str,
+        users: &impl UserDirectory,
+    ) -> Result<&[u8]> {
         tracing::info!("Received {raw_msg} in state {:?}", self.state);
-        let mut msg = raw_msg.split_whitespace();
-        let command = msg.next().context("received empty command")?.to_lowercase();
+        let (command, args) = raw_msg.split_once(' ').unwrap_or((raw_msg, ""));
+        let command = command.to_lowercase();
         let state = self.state.clone();
         match (command.as_str(), state) {
             ("ehlo", _) => {
                 tracing::trace!("Sending AUTH info");
                 self.state = SMTPState::Greeted;
+                self.helo = args.trim().to_string();
+                self.esmtp = true;
                 Ok(self.ehlo_greeting.as_bytes())
             }
             ("helo", SMTPState::Fresh) => {
                 self.state = SMTPState::Greeted;
+                self.helo = args.trim().to_string();
+                self.esmtp = false;
                 Ok(SMTPStateMachine::KK)
             }
-            ("starttls", _) => Ok(SMTPStateMachine::READY_FOR_ENCRYPTION),
+            //what was sent after it in plaintext would be taken as sent
+            //over tls, so it's all dropped
+            ("starttls", _) if self.codec.has_buffered() => {
+                self.codec.clear();
+                Ok(SMTPStateMachine::PIPELINED_STARTTLS)
+            }
+            //nothing the client said before the handshake counts after it,
+            //it has to say EHLO and log in again (rfc 3207 4.2)
+            ("starttls", _) => {
+                self.state = SMTPState::Fresh;
+                self.helo.clear();
+                self.esmtp = false;
+                Ok(SMTPStateMachine::READY_FOR_ENCRYPTION)
+            }
             ("noop", _) | ("help", _) | ("info", _) | ("vrfy", _) | ("expn", _) => {
                 tracing::trace!("Got {command}");
                 Ok(SMTPStateMachine::KK)
             }
             ("rset", _) => {
-                self.state = SMTPState::Fresh;
+                self.reset_transaction();
                 Ok(SMTPStateMachine::KK)
             }
+            (
+                "mail",
+                SMTPState::ReceivingRcpt(..)
+                | SMTPState::ReceivingData(..)
+                | SMTPState::ReceivingChunks(..),
+            ) => Ok(SMTPStateMachine::BAD_SEQUENCE),
             ("mail", curr_state) => {
                 tracing::trace!("Receiving MAIL");
-                let from = msg.next().context("received empty MAIL")?;
-                let from = from
-                    .strip_prefix("FROM:")
-                    .context("received incorrect MAIL")?;
-                if self.outgoing {
+                let Some(args) = parsing::smtp::path_args("FROM:", args) else {
+                    return Ok(SMTPStateMachine::SYNTAX_ERROR);
+                };
+                let params = match self.mail_params(&args.params) {
+                    Ok(params) => params,
+                    Err(response) => return Ok(response),
+                };
+                if !args.path.is_ascii() && !params.smtputf8 {
+                    return Ok(SMTPStateMachine::UTF8_REQUIRED);
+                }
+                let id = if self.outgoing {
                     if let SMTPState::Authed(x) = curr_state {
-                        self.state = SMTPState::ReceivingRcpt(
-                            Mail {
-                                from: from.to_string(),
-
-                                ..Default::default()
-                            },
-                            Some(x),
-                        );
+                        Some(x)
                     } else {
                         tracing::warn!("Didn't sign in!");
                         return Ok(SMTPStateMachine::NOT_AUTHED_YET);
                     }
                 } else {
-                    self.state = SMTPState::ReceivingRcpt(
-                        Mail {
-                            from: from.to_string(),
-
-                            ..Default::default()
-                        },
-                        None,
-                    );
-                }
-                Ok(SMTPStateMachine::KK)
+                    None
+                };
+                self.state = SMTPState::ReceivingRcpt(
+                    Mail {
+                        from: args.path,
+                        params,
+                        ..Default::default()
+                    },
+                    id,
+                );
+                Ok(SMTPStateMachine::SENDER_OK)
             }
             ("rcpt", SMTPState::ReceivingRcpt(mut mail, x)) => {
                 tracing::trace!("Receiving rcpt");
-                let to = msg.next().context("received empty RCPT")?;
-                let to = to.strip_prefix("TO:").context("received incorrect RCPT")?;
-                let to = to.to_lowercase();
-                if Self::legal_recipient(&to) {
-                    mail.to.push(to);
-                } else {
-                    tracing::warn!("Illegal recipient: {to}")
+                let Some(args) = parsing::smtp::path_args("TO:", args) else {
+                    return Ok(SMTPStateMachine::SYNTAX_ERROR);
+                };
+                if !args.params.is_empty() {
+                    return Ok(SMTPStateMachine::UNKNOWN_PARAM);
+                }
+                if !args.path.is_ascii() && !mail.params.smtputf8 {
+                    return Ok(SMTPStateMachine::UTF8_REQUIRED);
+                }
+                let to = args.path.to_lowercase();
+                //submission relays to anyone, the rest has to be ours
+                if !self.outgoing {
+                    if let Err(response) = self.check_recipient(&to, users).await {
+                        tracing::warn!("Rejected recipient: {to}");
+                        return Ok(response);
+                    }
                 }
+                mail.to.push(to);
                 self.state = SMTPState::ReceivingRcpt(mail, x);
-                Ok(SMTPStateMachine::KK)
+                Ok(SMTPStateMachine::RECIPIENT_OK)
+            }
+            ("data", SMTPState::ReceivingRcpt(mail, _)) if mail.to.is_empty() => {
+                Ok(SMTPStateMachine::NO_RECIPIENTS)
+            }
+            ("data", SMTPState::ReceivingRcpt(mail, _)) if mail.params.body == Body::BinaryMime => {
+                Ok(SMTPStateMachine::BDAT_REQUIRED)
             }
             ("data", SMTPState::ReceivingRcpt(mail, x)) => {
                 tracing::trace!("Receiving data");
                 self.state = SMTPState::ReceivingData(mail, x);
                 Ok(SMTPStateMachine::SEND_DATA_PLZ)
             }
-            ("quit", SMTPState::ReceivingData(mail, x)) => {
-                tracing::trace!(
-                    "Received data: FROM: {} TO:{} DATA:{}",
-                    mail.from,
-                    mail.to.join(", "),
-                    mail.data
+            ("quit", _) => Ok(SMTPStateMachine::KTHXBYE),
+            ("rcpt" | "data", _) => Ok(SMTPStateMachine::BAD_SEQUENCE),
+            //well formed BDATs are handled by the codec
+            ("bdat", _) => Ok(SMTPStateMachine::SYNTAX_ERROR),
+            _ => {
+                tracing::warn!(
+                    "Unexpected message received in state {:?}: {raw_msg}",
+                    self.state
                 );
-                self.state = SMTPState::Received(mail, x);
-                Ok(SMTPStateMachine::KTHXBYE)
-            }
-            ("quit", _) => {
-                tracing::warn!("Received quit before getting any data");
-                Ok(SMTPStateMachine::KTHXBYE)
+                Ok(SMTPStateMachine::UNRECOGNIZED)
             }
-            (_, SMTPState::ReceivingData(mut mail, x)) => {
-                tracing::trace!("Receiving data");
-                let resp = if raw_msg.ends_with("\r\n.\r\n") {
-                    SMTPStateMachine::KK
-                } else {
-                    SMTPStateMachine::HOLD_YOUR_HORSES
-                };
-                mail.data += &raw_msg;
-                self.state = SMTPState::ReceivingData(mail, x);
-                Ok(resp)
+        }
+    }
+
+    ///parses the MAIL FROM parameters, the error is the reply to send
+    fn mail_params(
+        &self,
+        raw: &[(String, Option<String>)],
+    ) -> std::result::Result<MailParams, &'static [u8]> {
+        let mut params = MailParams::default();
+        for (keyword, value) in raw {
+            match (keyword.as_str(), value.as_deref()) {
+                ("SIZE", Some(size)) => {
+                    let size = size
+                        .parse::<usize>()
+                        .map_err(|_| SMTPStateMachine::SYNTAX_ERROR)?;
+                    //rfc 1870, refuse it before the client sends the whole thing
+                    if size > self.codec.max_message() {
+                        return Err(SMTPStateMachine::TOO_BIG);
+                    }
+                    params.size = Some(size);
+                }
+                ("BODY", Some(body)) => {
+                    params.body =
+                        Body::from_keyword(body).ok_or(SMTPStateMachine::UNKNOWN_PARAM)?;
+                }
+                ("SMTPUTF8", None) => params.smtputf8 = true,
+                _ => return Err(SMTPStateMachine::UNKNOWN_PARAM),
             }
-            _ => anyhow::bail!(
-                "Unexpected message received in state {:?}: {raw_msg}",
-                self.state
-            ),
         }
+        Ok(params)
     }
 
-    /// Filter out admin, administrator, postmaster and hostmaster
-    /// to prevent being able to register certificates for the domain.
-    /// The check is over-eager, but it also makes it simpler.
-    /// Assumes lowercased.
-    fn legal_recipient(to: &str) -> bool {
-        !to.contains("admin") && !to.contains("postmaster") && !to.contains("hostmaster")
+    ///rcpt-time checks for mail we receive, the error is the reply to send
+    async fn check_recipient(
+        &self,
+        to: &str,
+        users: &impl UserDirectory,
+    ) -> std::result::Result<(), &'static [u8]> {
+        let (user, domain) = split_address(to).ok_or(SMTPStateMachine::BAD_ADDRESS)?;
+        if !self.config.serves_domain(domain) {
+            return Err(SMTPStateMachine::RELAY_DENIED);
+        }
+        if !recipients::forwards(&self.config, to).is_empty() {
+            return Ok(());
+        }
+        let (base, _) = recipients::split_detail(user, &self.config.smtp.recipient_delimiter);
+        if self.config.smtp.is_reserved(base) {
+            //nobody can register a reserved name, but an alias routes it
+            match recipients::is_alias(users, &self.config, user, domain).await {
+                Ok(true) => {}
+                Ok(false) => return Err(SMTPStateMachine::RESERVED_NAME),
+                Err(e) => {
+                    tracing::error!("couldn't look up {to}: {:?}", e);
+                    return Err(SMTPStateMachine::LOCAL_ERROR);
+                }
+            }
+        }
+        match recipients::resolve(users, &self.config, user, domain).await {
+            Ok(found) if found.is_empty() => Err(SMTPStateMachine::NO_SUCH_USER),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                tracing::error!("couldn't look up {to}: {:?}", e);
+                Err(SMTPStateMachine::LOCAL_ERROR)
+            }
+        }
     }
 
     pub async fn handle_smtp_outgoing(
@@ -181,13 +439,12 @@ impl SMTPStateMachine {
     ) -> Result<&[u8]> {
         tracing::trace!("Received {raw_msg} in state {:?}", self.state);
         let mut msg = raw_msg.split_whitespace();
-        let command = msg.next().context("received empty command")?.to_lowercase();
+        let command = msg.next().unwrap_or_default().to_lowercase();
         match command.as_str() {
             "auth" => {
-                let auth_type = msg
-                    .next()
-                    .context("should provide auth type")?
-                    .to_lowercase();
+                let Some(auth_type) = msg.next().map(str::to_lowercase) else {
+                    return Ok(Self::SYNTAX_ERROR);
+                };
                 //TODO support other types
                 if auth_type != "plain" {
                     tracing::warn!("used other auth mechanism: {}", auth_type);
@@ -195,11 +452,11 @@ impl SMTPStateMachine {
                     return Ok(Self::AUTH_NOT_OK);
                 }
                 tracing::trace!("Acknowledging AUTH");
-                let encoded = msg.next().context("should provide auth info").map_err(|e| {
+                let Some(encoded) = msg.next() else {
                     tracing::error!("didn't have auth info");
-                    e
-                });
-                match crate::utils::DECODER.decode(encoded?) {
+                    return Ok(Self::SYNTAX_ERROR);
+                };
+                match crate::utils::DECODER.decode(encoded) {
                     Err(x) => {
                         self.state = SMTPState::Greeted;
                         tracing::error!("decode error: {}", x);
@@ -224,7 +481,201 @@ impl SMTPStateMachine {
                     }
                 }
             }
-            _ => self.handle_smtp_incoming(raw_msg),
+            _ => self.handle_smtp_incoming(raw_msg, &*db).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::{Body, SMTPState, SMTPStateMachine};
+    use crate::{
+        config::{Config, DomainConfig},
+        recipients::tests::FakeDirectory,
+    };
+
+    fn machine(mut config: Config) -> SMTPStateMachine {
+        config.domains = vec![DomainConfig::new("kaki.foo")];
+        SMTPStateMachine::new(Arc::new(config), false)
+    }
+
+    async fn send(sm: &mut SMTPStateMachine, command: &str) -> Vec<u8> {
+        let users = FakeDirectory {
+            users: HashMap::from([
+                ("user@kaki.foo", 1),
+                ("other@kaki.foo", 2),
+                ("sysadmin-team@kaki.foo", 3),
+            ]),
+            aliases: HashMap::from([
+                ("team@kaki.foo", vec![1, 2]),
+                ("postmaster@kaki.foo", vec![1]),
+                ("hostmaster@kaki.foo", vec![1]),
+            ]),
+            ..Default::default()
+        };
+        sm.handle_smtp_incoming(command, &users)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_several_transactions() {
+        let mut sm = machine(Config::default());
+        send(&mut sm, "EHLO client.example").await;
+        for n in 0..2 {
+            send(&mut sm, "MAIL FROM:<a@example.com>").await;
+            send(&mut sm, "RCPT TO:<user@kaki.foo>").await;
+            assert_eq!(send(&mut sm, "DATA").await, SMTPStateMachine::SEND_DATA_PLZ);
+            let mail = sm.handle_data(format!("message {n}\r\n").into()).unwrap();
+            assert_eq!(mail.to, vec!["<user@kaki.foo>"]);
+            assert_eq!(mail.data, format!("message {n}\r\n").into_bytes());
+            assert_eq!(sm.state, SMTPState::Greeted);
+        }
+        send(&mut sm, "MAIL FROM:<a@example.com>").await;
+        send(&mut sm, "RSET").await;
+        assert_eq!(sm.state, SMTPState::Greeted);
+    }
+
+    #[tokio::test]
+    async fn test_mail_params() {
+        let mut config = Config::default();
+        config.smtp.max_message_size = 1000;
+        let mut sm = machine(config);
+        send(&mut sm, "EHLO client.example").await;
+        assert_eq!(
+            send(&mut sm, "RCPT TO:<user@kaki.foo>").await,
+            SMTPStateMachine::BAD_SEQUENCE
+        );
+        assert_eq!(
+            send(&mut sm, "MAIL FROM:<a@example.com> SIZE=1001").await,
+            SMTPStateMachine::TOO_BIG
+        );
+        assert_eq!(
+            send(&mut sm, "MAIL FROM:<a@example.com> FOO=BAR").await,
+            SMTPStateMachine::UNKNOWN_PARAM
+        );
+        assert_eq!(
+            send(&mut sm, "MAIL FROM:<kä@example.com>").await,
+            SMTPStateMachine::UTF8_REQUIRED
+        );
+        assert_eq!(
+            send(
+                &mut sm,
+                "MAIL FROM:<kä@example.com> SIZE=10 BODY=8BITMIME SMTPUTF8"
+            )
+            .await,
+            SMTPStateMachine::SENDER_OK
+        );
+        let SMTPState::ReceivingRcpt(mail, _) = &sm.state else {
+            panic!("expected a transaction, got {:?}", sm.state);
+        };
+        assert_eq!(mail.params.size, Some(10));
+        assert_eq!(mail.params.body, Body::EightBitMime);
+        assert!(mail.params.smtputf8);
+        assert_eq!(send(&mut sm, "DATA").await, SMTPStateMachine::NO_RECIPIENTS);
+        assert_eq!(send(&mut sm, "BOGUS").await, SMTPStateMachine::UNRECOGNIZED);
+    }
+
+    #[tokio::test]
+    async fn test_recipients() {
+        let mut config = Config::default();
+        config.smtp.reserved_names = ["Postmaster", "admin", "hostmaster"]
+            .map(String::from)
+            .to_vec();
+        let mut sm = machine(config);
+        send(&mut sm, "EHLO client.example").await;
+        send(&mut sm, "MAIL FROM:<a@example.com>").await;
+        for (rcpt, response) in [
+            ("<user@KAKI.foo>", SMTPStateMachine::RECIPIENT_OK),
+            ("<sysadmin-team@kaki.foo>", SMTPStateMachine::RECIPIENT_OK),
+            ("<team@kaki.foo>", SMTPStateMachine::RECIPIENT_OK),
+            ("<user+tag@kaki.foo>", SMTPStateMachine::RECIPIENT_OK),
+            ("<admin+tag@kaki.foo>", SMTPStateMachine::RESERVED_NAME),
+            ("<nobody@kaki.foo>", SMTPStateMachine::NO_SUCH_USER),
+            ("<user@example.com>", SMTPStateMachine::RELAY_DENIED),
+            ("<ADMIN@kaki.foo>", SMTPStateMachine::RESERVED_NAME),
+            //postmaster is never reserved, and an alias routes a reserved name
+            ("<PostMaster@kaki.foo>", SMTPStateMachine::RECIPIENT_OK),
+            ("<hostmaster@kaki.foo>", SMTPStateMachine::RECIPIENT_OK),
+            ("<kaki.foo>", SMTPStateMachine::BAD_ADDRESS),
+        ] {
+            assert_eq!(send(&mut sm, &format!("RCPT TO:{rcpt}")).await, response);
         }
+        let SMTPState::ReceivingRcpt(mail, _) = &sm.state else {
+            panic!("expected a transaction, got {:?}", sm.state);
+        };
+        assert_eq!(
+            mail.to,
+            vec![
+                "<user@kaki.foo>",
+                "<sysadmin-team@kaki.foo>",
+                "<team@kaki.foo>",
+                "<user+tag@kaki.foo>",
+                "<postmaster@kaki.foo>",
+                "<hostmaster@kaki.foo>"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_failure() {
+        let mut sm = machine(Config::default());
+        send(&mut sm, "EHLO client.example").await;
+        send(&mut sm, "MAIL FROM:<a@example.com>").await;
+        let broken = FakeDirectory {
+            broken: true,
+            ..Default::default()
+        };
+        //a database that's down is a reason to try later, not a 550
+        let response = sm
+            .handle_smtp_incoming("RCPT TO:<user@kaki.foo>", &broken)
+            .await
+            .unwrap();
+        assert_eq!(response, SMTPStateMachine::LOCAL_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_bdat() {
+        let mut sm = machine(Config::default());
+        send(&mut sm, "EHLO client.example").await;
+        assert_eq!(
+            sm.handle_chunk(b"early".to_vec(), true),
+            Err(SMTPStateMachine::BAD_SEQUENCE)
+        );
+        send(&mut sm, "MAIL FROM:<a@example.com> BODY=BINARYMIME").await;
+        send(&mut sm, "RCPT TO:<user@kaki.foo>").await;
+        assert_eq!(send(&mut sm, "DATA").await, SMTPStateMachine::BDAT_REQUIRED);
+        assert_eq!(sm.handle_chunk(b"\x00\xff\r\n".to_vec(), false), Ok(None));
+        assert_eq!(
+            send(&mut sm, "RCPT TO:<other@kaki.foo>").await,
+            SMTPStateMachine::BAD_SEQUENCE
+        );
+        let mail = sm.handle_chunk(b".\r\n".to_vec(), true).unwrap().unwrap();
+        assert_eq!(mail.data, b"\x00\xff\r\n.\r\n");
+        assert_eq!(mail.params.body, Body::BinaryMime);
+        assert_eq!(sm.state, SMTPState::Greeted);
+    }
+
+    #[tokio::test]
+    async fn test_starttls() {
+        let mut sm = machine(Config::default());
+        send(&mut sm, "EHLO client.example").await;
+        send(&mut sm, "MAIL FROM:<a@example.com>").await;
+        assert_eq!(
+            send(&mut sm, "STARTTLS").await,
+            SMTPStateMachine::READY_FOR_ENCRYPTION
+        );
+        assert_eq!((&sm.state, sm.helo.as_str()), (&SMTPState::Fresh, ""));
+        //a command pipelined after it
+        let mut input: &[u8] = b"STARTTLS\r\nMAIL FROM:<evil@example.com>\r\n";
+        sm.read_frame(&mut input).await.unwrap();
+        assert_eq!(
+            send(&mut sm, "STARTTLS").await,
+            SMTPStateMachine::PIPELINED_STARTTLS
+        );
+        assert!(!sm.codec.has_buffered());
     }
 }
@@ -3,34 +3,55 @@ use std::{
     sync::Arc,
 };
 
-use crate::{smtp_common::*, tls::StreamType};
+use crate::{
+    config::Config,
+    dkim::{arc, DkimSigner},
+    dmarc::{report, Policy},
+    dns::Dns,
+    recipients,
+    smtp_codec::SmtpFrame,
+    smtp_common::*,
+    tls::StreamType,
+    tls_rpt, trace,
+};
 use anyhow::*;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::{mpsc::Sender, Mutex},
+    io::AsyncWriteExt,
+    sync::{broadcast::Sender, Mutex, Notify},
 };
 
 use crate::database;
+use crate::email_auth::IncomingAuthResult;
 
 pub struct SmtpIncoming {
     // pub stream: tokio::net::TcpStream,
     pub stream: StreamType,
     pub state_machine: SMTPStateMachine,
     pub db: Arc<Mutex<database::DBClient>>,
-    pub domain: String,
     pub acceptor: tokio_rustls::TlsAcceptor,
     pub peer_ip: IpAddr,
+    pub forwarder: Forwarder,
+    pub dns: Arc<Dns>,
+}
+
+///what passing mail on to outside addresses needs
+pub struct Forwarder {
+    ///wakes the queue up after mail was queued
+    pub queue: Arc<Notify>,
+    ///seals the forwarded mail
+    pub dkim: Arc<DkimSigner>,
 }
 
 impl SmtpIncoming {
     /// Creates a new server from a connected stream
     pub async fn new(
-        domain: String,
+        config: Arc<Config>,
         stream: tokio::net::TcpStream,
-        domain_stripped: String,
-        tx: Sender<String>,
+        tx: Sender<database::Change>,
         implicit_tls: bool,
         acceptor: tokio_rustls::TlsAcceptor,
+        forwarder: Forwarder,
+        dns: Arc<Dns>,
     ) -> Result<Self> {
         let peer_ip = stream
             .peer_addr()
@@ -44,11 +65,12 @@ impl SmtpIncoming {
         };
         Ok(Self {
             stream: stream_type,
-            state_machine: SMTPStateMachine::new(domain.clone(), false),
-            db: Arc::new(Mutex::new(database::DBClient::new(tx).await?)),
-            domain: domain_stripped,
+            db: Arc::new(Mutex::new(database::DBClient::new(tx, &config).await?)),
+            state_machine: SMTPStateMachine::new(config, false),
             acceptor,
             peer_ip,
+            forwarder,
+            dns,
         })
     }
 
@@ -56,97 +78,379 @@ impl SmtpIncoming {
     pub async fn serve(mut self) -> Result<()> {
         self.greet().await?;
 
-        // let mut buf = vec![0; 65536];
-        let mut buf: [u8; 65536] = [0; 65536];
+        let mut pending = Vec::new();
         loop {
-            let n = self.stream.read(&mut buf).await?;
-
-            if n == 0 {
+            let Some(frame) = self.state_machine.read_frame(&mut self.stream).await? else {
                 tracing::info!("Received EOF");
-                self.state_machine.handle_smtp_incoming("quit").ok();
                 break;
-            }
-            let msg = std::str::from_utf8(&buf[0..n])?;
-            let response = self.state_machine.handle_smtp_incoming(msg)?;
-            if response != SMTPStateMachine::HOLD_YOUR_HORSES {
-                self.stream.write_all(response).await?;
-                if response == SMTPStateMachine::READY_FOR_ENCRYPTION {
-                    self.stream = self.stream.upgrade_to_tls(self.acceptor.clone()).await?;
+            };
+            let response = match frame {
+                SmtpFrame::Command(msg) => {
+                    self.state_machine
+                        .handle_smtp_incoming(&msg, &*self.db)
+                        .await?
+                }
+                SmtpFrame::Data(data) => {
+                    let mail = self.state_machine.handle_data(data)?;
+                    tracing::info!("got mail!");
+                    self.store_mail(&mail).await
+                }
+                SmtpFrame::Chunk { data, last } => {
+                    match self.state_machine.handle_chunk(data, last) {
+                        Result::Ok(Some(mail)) => {
+                            tracing::info!("got mail!");
+                            self.store_mail(&mail).await
+                        }
+                        Result::Ok(None) => SMTPStateMachine::CHUNK_OK,
+                        Err(response) => response,
+                    }
+                }
+                SmtpFrame::LineTooLong => SMTPStateMachine::LINE_TOO_LONG,
+                SmtpFrame::TooLarge => {
+                    self.state_machine.reset_transaction();
+                    SMTPStateMachine::TOO_BIG
                 }
-            } else {
-                tracing::debug!("Not responding, awaiting for more data");
             }
-            if response == SMTPStateMachine::KTHXBYE {
-                break;
+            //the ehlo response borrows the state machine
+            .to_vec();
+            pending.extend_from_slice(&response);
+            if self.state_machine.should_flush(&response) {
+                self.stream.write_all(&pending).await?;
+                pending.clear();
             }
-        }
-        match self.state_machine.state {
-            SMTPState::Received(ref mail, _) => {
-                tracing::info!("got mail!");
-                self.store_mail(mail).await;
+            if response == SMTPStateMachine::READY_FOR_ENCRYPTION {
+                self.stream = self.stream.upgrade_to_tls(self.acceptor.clone()).await?;
+                self.state_machine.codec.clear();
             }
-            SMTPState::ReceivingData(ref mail, _) => {
-                tracing::info!("Received EOF before receiving QUIT");
-                self.store_mail(mail).await;
+            if response == SMTPStateMachine::KTHXBYE {
+                break;
             }
-            _ => {}
         }
         Ok(())
     }
-    ///saves the mail in the recipients' INBOXes
-    async fn store_mail(&self, mail: &Mail) {
-        let auth = crate::email_auth::verify_incoming_mail(mail, self.peer_ip).await;
+    ///saves the mail in the recipients' INBOXes, returns the reply for the
+    ///end of DATA
+    async fn store_mail(&self, mail: &Mail) -> &'static [u8] {
+        let auth = crate::email_auth::verify_incoming_mail(
+            &*self.dns,
+            mail,
+            self.peer_ip,
+            &self.state_machine.helo,
+            &self.state_machine.config.hostname,
+            &self.state_machine.config.dmarc.trusted_arc_sealers,
+        )
+        .await;
         tracing::info!(
-            "incoming mail auth: spf={:?} dkim={:?} dmarc={:?}",
+            "incoming mail auth: spf={:?} ({}) helo={:?} dkim={:?} dmarc={:?} ({}) disposition={:?} arc={:?}",
             auth.spf,
+            auth.envelope_domain,
+            auth.spf_helo,
             auth.dkim,
-            auth.dmarc
+            auth.dmarc.status,
+            auth.header_domain,
+            auth.dmarc.disposition,
+            auth.arc.status
         );
-        if auth.reject {
-            tracing::warn!("rejecting incoming message after SPF/DMARC evaluation");
-            return;
+        for signature in &auth.dkim_signatures {
+            tracing::debug!(
+                "dkim signature d={} s={}: {:?} {}",
+                signature.domain,
+                signature.selector,
+                signature.status,
+                signature.reason.as_deref().unwrap_or_default()
+            );
         }
-        let db = self.db.lock().await;
+        if let Err(e) = self.count_for_report(&auth).await {
+            tracing::error!("couldn't count the message for a dmarc report: {:?}", e);
+        }
+        let quarantine = match auth.dmarc.disposition {
+            Policy::Reject => {
+                tracing::warn!(
+                    "rejecting mail from {}, its DMARC policy says so",
+                    auth.header_domain
+                );
+                return SMTPStateMachine::REJECTED;
+            }
+            Policy::Quarantine => {
+                tracing::info!("quarantining mail from {}", auth.header_domain);
+                true
+            }
+            Policy::None => false,
+        };
+        let mail = self.stamp(mail, &auth);
+        //the mailboxes of each local recipient, a group alias and its member
+        //can share one
+        let mut targets: Vec<(&String, Vec<i32>)> = Vec::new();
+        //the recipients to try again from the queue
+        let mut retry = Vec::new();
+        //the outside addresses to pass it on to, by the domain that forwards
+        let mut forwards: Vec<(&str, Vec<String>)> = Vec::new();
         for i in &mail.to {
-            //go from <user@domain.com> to user@domain.com. strip the angle brackets
-            let i = &i[1..i.len() - 1];
-            let mut parts = i.split("@").into_iter();
-            let Some(user) = parts.next() else {
-                tracing::warn!("no user: {i}");
-                continue;
-            };
-            let Some(domain) = parts.next() else {
-                tracing::warn!("no domain: {i}");
-                continue;
-            };
-            if domain != self.domain {
-                tracing::warn!("invalid domain: {i}");
-                continue;
+            //the recipients were checked at RCPT TO
+            let config = &self.state_machine.config;
+            let forward_to = recipients::forwards(config, i);
+            //quarantined mail is forwarded too, the next hop sees our
+            //dmarc=fail and can file it away itself
+            if let Some((_, domain)) = split_address(i).filter(|_| !forward_to.is_empty()) {
+                match forwards
+                    .iter_mut()
+                    .find(|(d, _)| d.eq_ignore_ascii_case(domain))
+                {
+                    Some((_, to)) => to.extend(forward_to.iter().map(|t| format!("<{t}>"))),
+                    None => forwards.push((
+                        domain,
+                        forward_to.iter().map(|t| format!("<{t}>")).collect(),
+                    )),
+                }
             }
-            let Some(user_id) = db.get_user_id(user).await else {
-                //TODO: make this check earlier, while doing smtp so client can know
-                tracing::warn!("invalid user: {i}");
-                continue;
+            let found = if quarantine {
+                recipients::junk_mailboxes(&self.db, config, i).await
+            } else {
+                recipients::mailboxes(&self.db, config, i).await
             };
-            let Some(m_id) = db.get_mailbox_id(user_id, "INBOX").await.ok() else {
-                tracing::warn!("invalid inbox for user: {i}");
+            match found {
+                //forwarded only
+                Result::Ok(found) if found.is_empty() && !forward_to.is_empty() => {}
+                Result::Ok(found) if found.is_empty() => {
+                    tracing::warn!("recipient disappeared during the session: {i}");
+                    retry.push(i.clone());
+                }
+                Result::Ok(found) => targets.push((i, found)),
+                Err(e) => {
+                    tracing::error!("{:?}", e);
+                    retry.push(i.clone());
+                }
+            }
+        }
+        let mut stored = Vec::new();
+        let mut failed = Vec::new();
+        let db = self.db.lock().await;
+        for m_id in targets.iter().flat_map(|(_, found)| found) {
+            if stored.contains(m_id) || failed.contains(m_id) {
                 continue;
-            };
-            db.replicate(mail.clone(), m_id, None)
-                .await
-                .map_err(|e| {
+            }
+            match db.replicate(mail.clone(), *m_id, None).await {
+                Result::Ok(_) => stored.push(*m_id),
+                Err(e) => {
                     tracing::error!("{}", e);
+                    failed.push(*m_id);
+                }
+            }
+        }
+        drop(db);
+        retry.extend(
+            targets
+                .iter()
+                .filter(|(_, found)| found.iter().any(|m_id| failed.contains(m_id)))
+                .map(|(i, _)| i.to_string()),
+        );
+        let mut forwarded = false;
+        let mut unforwarded = Vec::new();
+        for (domain, to) in forwards {
+            match self.forward(&mail, &auth, domain, to.clone()).await {
+                Result::Ok(()) => forwarded = true,
+                Err(e) => {
+                    tracing::error!("couldn't forward mail for {domain}: {:?}", e);
+                    unforwarded.push((domain, to));
+                }
+            }
+        }
+        self.save_report(&mail).await;
+        self.save_tls_report(&mail).await;
+        if retry.is_empty() && unforwarded.is_empty() {
+            return SMTPStateMachine::KK;
+        }
+        if stored.is_empty() && !forwarded {
+            return SMTPStateMachine::LOCAL_ERROR;
+        }
+        //some of it made it, sending it again would double those copies.
+        //the queue delivers the rest
+        match self
+            .retry(&mail, &auth, quarantine, retry, &stored, unforwarded)
+            .await
+        {
+            Result::Ok(()) => SMTPStateMachine::KK,
+            Err(e) => {
+                tracing::error!(
+                    "couldn't queue the undelivered copies, the client will send them all again: {:?}",
                     e
-                })
-                .ok();
+                );
+                SMTPStateMachine::LOCAL_ERROR
+            }
+        }
+    }
+
+    ///queues what couldn't be delivered while the client waited: the local
+    ///recipients whose copies failed, leaving out the `stored` mailboxes, and
+    ///the forwards. a forward is only ever queued, so this is its second try
+    async fn retry(
+        &self,
+        mail: &Mail,
+        auth: &IncomingAuthResult,
+        junk: bool,
+        to: Vec<String>,
+        stored: &[i32],
+        forwards: Vec<(&str, Vec<String>)>,
+    ) -> Result<()> {
+        if !to.is_empty() {
+            let now = chrono::Utc::now().timestamp();
+            let mail = Mail { to, ..mail.clone() };
+            let id = self
+                .db
+                .lock()
+                .await
+                .enqueue_stored(&mail, now, junk, stored)
+                .await?;
+            tracing::info!("queued the copies for {} as {id}", mail.to.join(", "));
+            self.forwarder.queue.notify_one();
+        }
+        for (domain, to) in forwards {
+            self.forward(mail, auth, domain, to).await?;
+        }
+        Ok(())
+    }
+
+    ///queues the mail for outside addresses a `domain` address forwards to,
+    ///with our ARC set on it so they can trust what we saw
+    async fn forward(
+        &self,
+        mail: &Mail,
+        auth: &IncomingAuthResult,
+        domain: &str,
+        mut to: Vec<String>,
+    ) -> Result<()> {
+        to.dedup();
+        let now = chrono::Utc::now().timestamp();
+        let config = &self.state_machine.config;
+        let data = match self.forwarder.dkim.key_for(domain) {
+            Some(key) => {
+                let results =
+                    trace::results(&config.hostname, auth, &mail.from, &self.state_machine.helo);
+                match arc::seal(key, &mail.data, &results, &auth.arc, now) {
+                    Result::Ok(data) => data,
+                    Err(e) => {
+                        tracing::info!("forwarding without an ARC seal: {:#}", e);
+                        mail.data.clone()
+                    }
+                }
+            }
+            None => {
+                tracing::info!("no key to seal forwarded mail for {domain} with");
+                mail.data.clone()
+            }
+        };
+        let forwarded = Mail {
+            to,
+            data,
+            ..mail.clone()
+        };
+        let id = self.db.lock().await.enqueue(&forwarded, now).await?;
+        tracing::info!("forwarding mail to {} as {id}", forwarded.to.join(", "));
+        self.forwarder.queue.notify_one();
+        Ok(())
+    }
+
+    ///counts the message for the aggregate report its From domain asked for
+    async fn count_for_report(&self, auth: &IncomingAuthResult) -> Result<()> {
+        let Some(record) = &auth.dmarc.record else {
+            return Ok(());
+        };
+        if !self.state_machine.config.dmarc.send_reports || record.aggregate_reports.is_empty() {
+            return Ok(());
+        }
+        let row = report::Row::new(&self.peer_ip.to_string(), auth);
+        let today = chrono::Utc::now().date_naive();
+        self.db
+            .lock()
+            .await
+            .count_dmarc_result(today, record, &row)
+            .await
+    }
+
+    ///keeps the aggregate reports sent to our report address
+    async fn save_report(&self, mail: &Mail) {
+        if !addressed_to(mail, &self.state_machine.config.dmarc.report_address) {
+            return;
+        }
+        let feedback = match report::read_message(&mail.data) {
+            Result::Ok(feedback) => feedback,
+            Err(e) => {
+                tracing::info!("not keeping a dmarc report: {:#}", e);
+                return;
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        match self.db.lock().await.save_dmarc_report(&feedback, now).await {
+            Result::Ok(()) => tracing::info!(
+                "saved the dmarc report {} from {} about {}",
+                feedback.report_id,
+                feedback.org_name,
+                feedback.policy.domain
+            ),
+            Err(e) => tracing::error!("couldn't save a dmarc report: {:?}", e),
+        }
+    }
+
+    ///keeps the SMTP TLS reports sent to our TLS-RPT address
+    async fn save_tls_report(&self, mail: &Mail) {
+        if !addressed_to(mail, &self.state_machine.config.tls_rpt.report_address) {
+            return;
+        }
+        let report = match tls_rpt::read_message(&mail.data) {
+            Result::Ok(report) => report,
+            Err(e) => {
+                tracing::info!("not keeping a tls report: {:#}", e);
+                return;
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        match self.db.lock().await.save_tls_report(&report, now).await {
+            Result::Ok(()) => tracing::info!(
+                "saved the tls report {} from {}",
+                report.report_id,
+                report.organization_name
+            ),
+            Err(e) => tracing::error!("couldn't save a tls report: {:?}", e),
+        }
+    }
+
+    ///the mail with our Received and Authentication-Results on top, and
+    ///without any Authentication-Results forged in our name
+    fn stamp(&self, mail: &Mail, auth: &IncomingAuthResult) -> Mail {
+        let hostname = &self.state_machine.config.hostname;
+        let session = trace::Session {
+            peer_ip: self.peer_ip,
+            helo: &self.state_machine.helo,
+            hostname,
+            esmtp: self.state_machine.esmtp,
+            tls: self.stream.tls_info(),
+        };
+        let headers = [
+            trace::authentication_results(hostname, auth, &mail.from, &self.state_machine.helo),
+            trace::received(&session, &mail.to, chrono::Utc::now()),
+        ];
+        let data = trace::strip_authentication_results(&mail.data, hostname);
+        Mail {
+            data: trace::prepend(&data, &headers),
+            ..mail.clone()
         }
     }
 
     /// Sends the initial SMTP greeting
     async fn greet(&mut self) -> Result<()> {
         self.stream
-            .write_all(SMTPStateMachine::OH_HAI)
+            .write_all(self.state_machine.greeting.as_bytes())
             .await
             .map_err(|e| e.into())
     }
 }
+
+///whether `address` is one of the recipients, never if it's empty
+fn addressed_to(mail: &Mail, address: &str) -> bool {
+    !address.is_empty()
+        && mail.to.iter().any(|to| {
+            to.trim_start_matches('<')
+                .trim_end_matches('>')
+                .eq_ignore_ascii_case(address)
+        })
+}
@@ -1,17 +1,17 @@
 use std::sync::Arc;
 
+use crate::config::Config;
 use crate::database;
+use crate::dkim::DkimSigner;
+use crate::smtp_codec::SmtpFrame;
 use crate::smtp_common::Mail;
 use crate::smtp_common::SMTPState;
 use crate::smtp_common::SMTPStateMachine;
 use crate::tls::StreamType;
-use crate::utils;
-use anyhow::Context;
 use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::{Mutex, Notify};
 
 pub struct SmtpOutgoing {
     // pub stream: tokio::net::TcpStream,
@@ -19,16 +19,21 @@ pub struct SmtpOutgoing {
     pub state_machine: SMTPStateMachine,
     pub db: Arc<Mutex<database::DBClient>>,
     pub acceptor: tokio_rustls::TlsAcceptor,
+    ///wakes the queue up after something was queued
+    pub queue: Arc<Notify>,
+    pub dkim: Arc<DkimSigner>,
 }
 
 impl SmtpOutgoing {
     /// Creates a new server from a connected stream
     pub async fn new(
-        domain: String,
+        config: Arc<Config>,
         stream: tokio::net::TcpStream,
-        tx: Sender<String>,
+        tx: Sender<database::Change>,
         implicit_tls: bool,
         acceptor: tokio_rustls::TlsAcceptor,
+        queue: Arc<Notify>,
+        dkim: Arc<DkimSigner>,
     ) -> Result<Self> {
         let stream_type = if !implicit_tls {
             StreamType::Plain(stream)
@@ -38,163 +43,109 @@ impl SmtpOutgoing {
         };
         Ok(Self {
             stream: stream_type,
-            state_machine: SMTPStateMachine::new(domain.clone(), true),
-            db: Arc::new(Mutex::new(database::DBClient::new(tx).await?)),
+            db: Arc::new(Mutex::new(database::DBClient::new(tx, &config).await?)),
+            state_machine: SMTPStateMachine::new(config, true),
             acceptor,
+            queue,
+            dkim,
         })
     }
     pub async fn serve(mut self) -> Result<()> {
         self.greet().await?;
-        // let mut buf = vec![0; 65536];
-        let mut buf: &mut [u8] = &mut [0; 65536];
+        let mut pending = Vec::new();
         loop {
-            let n = self.stream.read(&mut buf).await?;
-            if n == 0 {
+            let Some(frame) = self.state_machine.read_frame(&mut self.stream).await? else {
                 tracing::info!("Received EOF");
-                self.state_machine
-                    //not handling auth so don't need handle_smtp_outgoing()
-                    .handle_smtp_incoming("quit")
-                    .ok();
                 break;
-            }
-            let msg = std::str::from_utf8(&buf[0..n])?;
-            let response = self
-                .state_machine
-                .handle_smtp_outgoing(msg, self.db.clone())
-                .await?;
-            if response != SMTPStateMachine::HOLD_YOUR_HORSES {
-                self.stream.write_all(response).await?;
-                if response == SMTPStateMachine::READY_FOR_ENCRYPTION {
-                    self.stream = self.stream.upgrade_to_tls(self.acceptor.clone()).await?;
+            };
+            let response = match frame {
+                SmtpFrame::Command(msg) => {
+                    self.state_machine
+                        .handle_smtp_outgoing(&msg, self.db.clone())
+                        .await?
+                }
+                SmtpFrame::Data(data) => {
+                    let mail = self.state_machine.handle_data(data)?;
+                    self.commit(&mail).await?
+                }
+                SmtpFrame::Chunk { data, last } => {
+                    match self.state_machine.handle_chunk(data, last) {
+                        Ok(Some(mail)) => self.commit(&mail).await?,
+                        Ok(None) => SMTPStateMachine::CHUNK_OK,
+                        Err(response) => response,
+                    }
                 }
-            } else {
-                tracing::debug!("Not responding, awaiting for more data");
+                SmtpFrame::LineTooLong => SMTPStateMachine::LINE_TOO_LONG,
+                SmtpFrame::TooLarge => {
+                    self.state_machine.reset_transaction();
+                    SMTPStateMachine::TOO_BIG
+                }
+            }
+            //the ehlo response borrows the state machine
+            .to_vec();
+            pending.extend_from_slice(&response);
+            if self.state_machine.should_flush(&response) {
+                self.stream.write_all(&pending).await?;
+                pending.clear();
+            }
+            if response == SMTPStateMachine::READY_FOR_ENCRYPTION {
+                self.stream = self.stream.upgrade_to_tls(self.acceptor.clone()).await?;
+                self.state_machine.codec.clear();
             }
             if response == SMTPStateMachine::KTHXBYE {
                 break;
             }
         }
-        match self.state_machine.state {
-            SMTPState::Received(ref mail, id) => {
-                //send mail, everything was succesful!
-                self.handle_mail(mail, id.context("should exist")?).await?;
-            }
-            SMTPState::ReceivingData(ref mail, x) => {
-                tracing::info!("Received EOF before receiving QUIT");
-                tracing::info!("{:?}", (mail, x));
-                self.handle_mail(mail, x.context("should exist")?).await?;
+        Ok(())
+    }
+    ///sends a finished transaction, returns the reply for it
+    async fn commit(&self, mail: &Mail) -> Result<&'static [u8]> {
+        let SMTPState::Authed(id) = self.state_machine.state else {
+            anyhow::bail!("received mail without being authed");
+        };
+        //250 once it's queued, the queue takes it from here
+        match self.handle_mail(mail, id).await {
+            Ok(()) => Ok(SMTPStateMachine::KK),
+            Err(e) => {
+                tracing::error!("{:?}", e);
+                Ok(SMTPStateMachine::LOCAL_ERROR)
             }
-            _ => {}
         }
-        Ok(())
     }
+
+    ///queues the mail for delivery and keeps a copy for the sender. the mail
+    ///is on its way once it's queued, a copy that couldn't be kept doesn't
+    ///make the client send it again
     async fn handle_mail(&self, mail: &Mail, id: i32) -> Result<()> {
-        SmtpOutgoing::send_mail(&mail).await.map_err(|e| {
-            tracing::error!("{:?}", e);
-            e
-        })?;
-        let id = self.db.lock().await.get_mailbox_id(id, "INBOX").await?;
-        self.db
-            .lock()
-            .await
-            .replicate(mail.clone(), id, None)
-            .await?;
+        let now = chrono::Utc::now().timestamp();
+        let mail = &Mail {
+            data: self.dkim.sign(&mail.data, now)?,
+            ..mail.clone()
+        };
+        let queue_id = self.db.lock().await.enqueue(mail, now).await?;
+        tracing::info!("queued message {queue_id}");
+        self.queue.notify_one();
+        if let Err(e) = self.keep_copy(mail, id).await {
+            tracing::error!("couldn't keep a copy of message {queue_id}: {:?}", e);
+        }
+        Ok(())
+    }
+
+    async fn keep_copy(&self, mail: &Mail, id: i32) -> Result<()> {
+        let db = self.db.lock().await;
+        let id = db.get_mailbox_id(id, "INBOX").await?;
+        db.replicate(mail.clone(), id, None).await?;
         Ok(())
     }
 
     /// Sends the initial SMTP greeting
     async fn greet(&mut self) -> Result<()> {
         self.stream
-            .write_all(SMTPStateMachine::OH_HAI)
+            .write_all(self.state_machine.greeting.as_bytes())
             .await
             .map_err(|e| {
                 tracing::error!("error greeting: {}", e);
                 e.into()
             })
     }
-    async fn send_mail(mail: &crate::smtp_common::Mail) -> Result<()> {
-        let resolver = utils::DnsResolver::default_new();
-        for rcpt in &mail.to {
-            if let Some((_, domain)) = rcpt.split_once("@") {
-                let domain = domain
-                    .strip_suffix(">")
-                    .context("should be formatted inside angle brackets")?; //NOTE: hacky
-                let ip = resolver.resolve_mx(domain).await?;
-                // let ip = "127.0.0.1";
-                //BIG TODO: this will timeout on port 25 unless you request to unblock port 25
-                let mut connection = TcpStream::connect((ip, 25)).await?;
-                tracing::debug!("connection succesful");
-                // let mut buf = vec![0; 65536];
-                let mut buf: [u8; 65536] = [0; 65536];
-                let commands = Self::gen_commands(&mail);
-                let n = connection.read(&mut buf).await?;
-                let string = std::str::from_utf8(&buf[0..n])?;
-                tracing::debug!("greeting: {string}");
-                for cmd in commands {
-                    connection.write_all(cmd.as_bytes()).await?;
-                    tracing::debug!("wrote: {cmd}");
-                    let n = connection.read(&mut buf).await?;
-                    let string = std::str::from_utf8(&buf[0..n])?;
-                    tracing::debug!("read: {string}");
-                    let statuscode = &string[..3];
-                    match statuscode
-                        .chars()
-                        .next()
-                        .ok_or(anyhow::anyhow!("bad statuscode"))?
-                    {
-                        '2' | '3' => {
-                            //2yz (Positive Completion Reply): The requested action has been successfully completed.
-                            //3yz (Positive Intermediate Reply): The command has been accepted, but the requested action
-                            //is being held in abeyance, pending receipt of further information.
-
-                            //everything was ok
-                            //3yx codes usually appear like: "354 End data with <CR><LF>.<CR><LF>\n"
-                            //so don't do anything
-                            tracing::info!("{statuscode}");
-                        }
-                        '4' => {
-                            //4yz (Transient Negative Completion Reply): The command was not accepted, and the requested action did not occur.
-                            //However, the error condition is temporary, and the action may be requested again.
-                            tracing::warn!("got a 4yx statuscode: {statuscode}, trying again");
-                            // try again?
-                            connection.write_all(cmd.as_bytes()).await?;
-                        }
-                        '5' => {
-                            //5yz (Permanent Negative Completion Reply): The command was not accepted and the requested action did not occur.
-                            //The SMTP client SHOULD NOT repeat the exact request (in the same sequence).
-                            tracing::warn!("got a 5yx statuscode: {statuscode}, ending connection");
-                            //quit?
-                            connection.write_all("quit\r\n".as_bytes()).await?;
-                        }
-                        _ => {
-                            tracing::warn!("invalid statuscode: {statuscode}");
-                        }
-                    }
-                }
-                connection.write_all("quit\r\n".as_bytes()).await?;
-                tracing::info!("all succesful!");
-            }
-        }
-        Ok(())
-    }
-    fn gen_commands(mail: &crate::smtp_common::Mail) -> Vec<String> {
-        let mut commands: Vec<String> = Vec::new();
-        let domain = std::env::args()
-            .nth(4)
-            .unwrap_or("smtp.kaki.foo".to_string());
-        commands.push(format!("ehlo {domain}\r\n"));
-        commands.push(format!("mail FROM:<{}>\r\n", mail.from));
-        for rcpt in &mail.to {
-            commands.push(format!("rcpt TO:<{rcpt}>\r\n"));
-        }
-        commands.push("data\r\n".to_string());
-        if mail.data.ends_with("\r\n.\r\n") {
-            commands.push(mail.data.clone());
-        } else {
-            commands.push(format!("{}\r\n.\r\n", mail.data));
-        }
-        //don't push "quit", it will be seperate
-
-        commands
-    }
 }
@@ -0,0 +1,1075 @@
+use std::net::IpAddr;
+
+use crate::email_auth::{AuthResolver, AuthStatus};
+
+///mechanisms and modifiers that query dns, per check (rfc 7208 4.6.4)
+const MAX_LOOKUPS: u32 = 10;
+///lookups that find nothing, per check
+const MAX_VOID_LOOKUPS: u32 = 2;
+///the mx hosts or ptr names one mechanism looks at
+const MAX_NAMES: usize = 10;
+
+///the outcome of an spf check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpfResult {
+    pub status: AuthStatus,
+    ///the domain's exp= explanation of a fail, or what went wrong for the
+    ///errors
+    pub reason: Option<String>,
+}
+
+enum Error {
+    ///dns failed, the check might work later
+    Temp(String),
+    ///the records are broken
+    Perm(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+impl Qualifier {
+    fn status(self) -> AuthStatus {
+        match self {
+            Qualifier::Pass => AuthStatus::Pass,
+            Qualifier::Fail => AuthStatus::Fail,
+            Qualifier::SoftFail => AuthStatus::SoftFail,
+            Qualifier::Neutral => AuthStatus::Neutral,
+        }
+    }
+}
+
+///the domains are unexpanded macro strings
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mechanism {
+    All,
+    Include(String),
+    ///the domain and the prefix lengths for ipv4 and ipv6
+    A(Option<String>, u8, u8),
+    Mx(Option<String>, u8, u8),
+    Ptr(Option<String>),
+    ///ip4 and ip6
+    Ip(IpAddr, u8),
+    Exists(String),
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Record {
+    directives: Vec<(Qualifier, Mechanism)>,
+    redirect: Option<String>,
+    exp: Option<String>,
+}
+
+fn is_spf(txt: &str) -> bool {
+    let version = txt.get(..6).unwrap_or_default();
+    version.eq_ignore_ascii_case("v=spf1") && matches!(txt.as_bytes().get(6), None | Some(b' '))
+}
+
+impl Record {
+    ///any syntax error makes the whole record invalid (rfc 7208 4.6)
+    fn parse(record: &str) -> Result<Self, String> {
+        let mut parsed = Record::default();
+        for term in record.split(' ').filter(|t| !t.is_empty()).skip(1) {
+            if let Some((name, value)) = modifier(term) {
+                let slot = match name.to_ascii_lowercase().as_str() {
+                    "redirect" => &mut parsed.redirect,
+                    "exp" => &mut parsed.exp,
+                    //unknown modifiers are ignored, as long as they parse
+                    _ => {
+                        tokens(value)?;
+                        continue;
+                    }
+                };
+                if slot.is_some() {
+                    return Err(format!("{name}= appears twice"));
+                }
+                check_domain_spec(value)?;
+                *slot = Some(value.to_string());
+                continue;
+            }
+            let (qualifier, mechanism) = match term.as_bytes()[0] {
+                b'+' => (Qualifier::Pass, &term[1..]),
+                b'-' => (Qualifier::Fail, &term[1..]),
+                b'~' => (Qualifier::SoftFail, &term[1..]),
+                b'?' => (Qualifier::Neutral, &term[1..]),
+                _ => (Qualifier::Pass, term),
+            };
+            let mechanism =
+                Mechanism::parse(mechanism).ok_or_else(|| format!("invalid mechanism {term}"))?;
+            parsed.directives.push((qualifier, mechanism));
+        }
+        Ok(parsed)
+    }
+}
+
+///`name=value` if the term is a modifier
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    valid.then_some((name, value))
+}
+
+impl Mechanism {
+    fn parse(term: &str) -> Option<Self> {
+        let split = term.find([':', '/']).unwrap_or(term.len());
+        let (name, args) = term.split_at(split);
+        match name.to_ascii_lowercase().as_str() {
+            "all" if args.is_empty() => Some(Mechanism::All),
+            "include" => Some(Mechanism::Include(domain_arg(args)?)),
+            "exists" => Some(Mechanism::Exists(domain_arg(args)?)),
+            "ptr" => Some(Mechanism::Ptr(optional_domain_arg(args)?)),
+            "a" => {
+                let (domain, v4, v6) = dual_cidr(args)?;
+                Some(Mechanism::A(optional_domain_arg(domain)?, v4, v6))
+            }
+            "mx" => {
+                let (domain, v4, v6) = dual_cidr(args)?;
+                Some(Mechanism::Mx(optional_domain_arg(domain)?, v4, v6))
+            }
+            "ip4" | "ip6" => {
+                let spec = args.strip_prefix(':')?;
+                let (ip, prefix) = match spec.split_once('/') {
+                    Some((ip, prefix)) => (ip, Some(prefix)),
+                    None => (spec, None),
+                };
+                let ip = if name.eq_ignore_ascii_case("ip4") {
+                    IpAddr::V4(ip.parse().ok()?)
+                } else {
+                    IpAddr::V6(ip.parse().ok()?)
+                };
+                let max = if ip.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix_length(prefix, max)?,
+                    None => max,
+                };
+                Some(Mechanism::Ip(ip, prefix))
+            }
+            _ => None,
+        }
+    }
+}
+
+///`:domain`
+fn domain_arg(args: &str) -> Option<String> {
+    let spec = args.strip_prefix(':')?;
+    check_domain_spec(spec).ok()?;
+    Some(spec.to_string())
+}
+
+///nothing, or `:domain`
+fn optional_domain_arg(args: &str) -> Option<Option<String>> {
+    if args.is_empty() {
+        return Some(None);
+    }
+    domain_arg(args).map(Some)
+}
+
+///splits `:domain/24//64` into the domain and the prefix lengths
+fn dual_cidr(args: &str) -> Option<(&str, u8, u8)> {
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let (rest, v6) = match args.rsplit_once("//") {
+        Some((rest, prefix)) if digits(prefix) => (rest, prefix_length(prefix, 128)?),
+        _ => (args, 128),
+    };
+    match rest.rsplit_once('/') {
+        Some((rest, prefix)) if digits(prefix) => Some((rest, prefix_length(prefix, 32)?, v6)),
+        _ => Some((rest, 32, v6)),
+    }
+}
+
+///no leading zeroes, at most `max`
+fn prefix_length(prefix: &str, max: u8) -> Option<u8> {
+    if prefix.len() > 1 && prefix.starts_with('0') {
+        return None;
+    }
+    prefix.parse::<u8>().ok().filter(|&p| p <= max)
+}
+
+///a domain-spec has to end in a macro or a top label that isn't all digits
+fn check_domain_spec(spec: &str) -> Result<(), String> {
+    tokens(spec)?;
+    if spec.ends_with('}') {
+        return Ok(());
+    }
+    let trimmed = spec.strip_suffix('.').unwrap_or(spec);
+    let top = trimmed.rsplit_once('.').map(|(_, top)| top).unwrap_or("");
+    let valid = !top.is_empty()
+        && top.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !top.starts_with('-')
+        && !top.ends_with('-')
+        && top.bytes().any(|b| b.is_ascii_alphabetic() || b == b'-');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("invalid domain {spec}"))
+    }
+}
+
+///a macro like `%{l1r-}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Macro {
+    ///lowercase
+    letter: char,
+    ///uppercase letters are url escaped
+    escape: bool,
+    ///how many parts to keep from the right
+    parts: Option<usize>,
+    reverse: bool,
+    delimiters: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    Macro(Macro),
+}
+
+///splits a macro string into literals and macros (rfc 7208 7.1)
+fn tokens(spec: &str) -> Result<Vec<Token>, String> {
+    let invalid = || format!("invalid macro in {spec}");
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = spec.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => literal.push('%'),
+            Some('_') => literal.push(' '),
+            Some('-') => literal += "%20",
+            Some('{') => {
+                let rest = chars.as_str();
+                let end = rest.find('}').ok_or_else(invalid)?;
+                let body = &rest[..end];
+                chars = rest[end + 1..].chars();
+                let mut body_chars = body.chars();
+                let letter = body_chars.next().ok_or_else(invalid)?;
+                if !"slodiphcrtv".contains(letter.to_ascii_lowercase()) {
+                    return Err(invalid());
+                }
+                let body = body_chars.as_str();
+                let digits = body.bytes().take_while(u8::is_ascii_digit).count();
+                //zero parts isn't allowed, more than there are is all of them
+                let parts = match &body[..digits] {
+                    "" => None,
+                    n => match n.parse::<usize>().unwrap_or(usize::MAX) {
+                        0 => return Err(invalid()),
+                        n => Some(n),
+                    },
+                };
+                let body = &body[digits..];
+                let (reverse, delimiters) = match body.strip_prefix(['r', 'R']) {
+                    Some(delimiters) => (true, delimiters),
+                    None => (false, body),
+                };
+                if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+                    return Err(invalid());
+                }
+                if !literal.is_empty() {
+                    tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                }
+                tokens.push(Token::Macro(Macro {
+                    letter: letter.to_ascii_lowercase(),
+                    escape: letter.is_ascii_uppercase(),
+                    parts,
+                    reverse,
+                    delimiters: delimiters.to_string(),
+                }));
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    Ok(tokens)
+}
+
+///splits on the delimiters, reverses, keeps the rightmost parts and joins
+///with dots
+fn transform(value: &str, m: &Macro) -> String {
+    let delimiters = if m.delimiters.is_empty() {
+        "."
+    } else {
+        &m.delimiters
+    };
+    let mut parts = value.split(|c| delimiters.contains(c)).collect::<Vec<_>>();
+    if m.reverse {
+        parts.reverse();
+    }
+    let keep = m.parts.unwrap_or(parts.len()).min(parts.len());
+    parts[parts.len() - keep..].join(".")
+}
+
+fn url_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            escaped.push(b as char);
+        } else {
+            escaped += &format!("%{b:02X}");
+        }
+    }
+    escaped
+}
+
+///the `i` macro, dotted decimal for ipv4 and dotted nibbles for ipv6
+fn dotted_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .flat_map(|b| [b >> 4, b & 0xf])
+            .map(|nibble| format!("{nibble:x}"))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+///a name longer than dns allows loses labels from the left (rfc 7208 7.3)
+fn truncate_domain(name: &str) -> String {
+    let mut name = name.strip_suffix('.').unwrap_or(name);
+    while name.len() > 253 {
+        match name.split_once('.') {
+            Some((_, rest)) => name = rest,
+            None => break,
+        }
+    }
+    name.to_string()
+}
+
+///names that can't be looked up have no record (rfc 7208 4.3)
+fn valid_domain(domain: &str) -> bool {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    let labels = domain.split('.').collect::<Vec<_>>();
+    domain.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|l| !l.is_empty() && l.len() <= 63)
+}
+
+fn is_subdomain(child: &str, parent: &str) -> bool {
+    let child = child.trim_end_matches('.').to_ascii_lowercase();
+    let parent = parent.trim_end_matches('.').to_ascii_lowercase();
+    child == parent || child.ends_with(&format!(".{parent}"))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+fn temp(e: anyhow::Error) -> Error {
+    Error::Temp(format!("dns lookup failed: {e}"))
+}
+
+///the state of one check_host() evaluation, the limits count across
+///includes and redirects
+struct Checker<'a, R> {
+    resolver: &'a R,
+    ip: IpAddr,
+    ///`local@domain`
+    sender: &'a str,
+    helo: &'a str,
+    hostname: &'a str,
+    lookups: u32,
+    void_lookups: u32,
+}
+
+///checks whether `ip` may send mail as `sender` (rfc 7208 check_host()).
+///for the HELO identity the sender is `postmaster@helo`. `helo` and
+///`hostname`, our name, are only used in macros
+pub async fn check(
+    resolver: &impl AuthResolver,
+    ip: IpAddr,
+    sender: &str,
+    helo: &str,
+    hostname: &str,
+) -> SpfResult {
+    let sender = sender.trim_start_matches('<').trim_end_matches('>');
+    let (local, domain) = sender.rsplit_once('@').unwrap_or(("", sender));
+    //a sender without a local part is postmaster (rfc 7208 4.3)
+    let sender = if local.is_empty() {
+        format!("postmaster@{domain}")
+    } else {
+        sender.to_string()
+    };
+    let mut checker = Checker {
+        resolver,
+        ip: ip.to_canonical(),
+        sender: &sender,
+        helo,
+        hostname,
+        lookups: 0,
+        void_lookups: 0,
+    };
+    match checker.check_host(domain).await {
+        Ok((status, reason)) => SpfResult { status, reason },
+        Err(Error::Temp(reason)) => SpfResult {
+            status: AuthStatus::TempError,
+            reason: Some(reason),
+        },
+        Err(Error::Perm(reason)) => SpfResult {
+            status: AuthStatus::PermError,
+            reason: Some(reason),
+        },
+    }
+}
+
+impl<R: AuthResolver> Checker<'_, R> {
+    async fn check_host(&mut self, domain: &str) -> Result<(AuthStatus, Option<String>), Error> {
+        if !valid_domain(domain) {
+            return Ok((AuthStatus::None, None));
+        }
+        let records = self.resolver.txt(domain).await.map_err(temp)?;
+        let records = records.iter().filter(|r| is_spf(r)).collect::<Vec<_>>();
+        let record = match records[..] {
+            [] => return Ok((AuthStatus::None, None)),
+            [record] => Record::parse(record).map_err(Error::Perm)?,
+            _ => return Err(Error::Perm(format!("{domain} has several spf records"))),
+        };
+        for (qualifier, mechanism) in &record.directives {
+            if self.matches(mechanism, domain).await? {
+                let status = qualifier.status();
+                let reason = match &record.exp {
+                    Some(exp) if status == AuthStatus::Fail => self.explanation(exp, domain).await,
+                    _ => None,
+                };
+                return Ok((status, reason));
+            }
+        }
+        let Some(redirect) = &record.redirect else {
+            return Ok((AuthStatus::Neutral, None));
+        };
+        self.count_lookup()?;
+        let target = self.expand_domain(redirect, domain).await?;
+        match Box::pin(self.check_host(&target)).await? {
+            (AuthStatus::None, _) => Err(Error::Perm(format!(
+                "redirect to {target}, which has no record"
+            ))),
+            result => Ok(result),
+        }
+    }
+
+    async fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, Error> {
+        let ipv6 = self.ip.is_ipv6();
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip(network, prefix) => Ok(in_network(self.ip, *network, *prefix)),
+            Mechanism::Include(spec) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(spec, domain).await?;
+                match Box::pin(self.check_host(&target)).await?.0 {
+                    AuthStatus::Pass => Ok(true),
+                    AuthStatus::Fail | AuthStatus::SoftFail | AuthStatus::Neutral => Ok(false),
+                    _ => Err(Error::Perm(format!(
+                        "include of {target}, which has no record"
+                    ))),
+                }
+            }
+            Mechanism::A(spec, v4, v6) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain).await?;
+                let ips = self.resolver.ips(&target, ipv6).await.map_err(temp)?;
+                self.count_void(ips.is_empty())?;
+                let prefix = if ipv6 { *v6 } else { *v4 };
+                Ok(ips.iter().any(|ip| in_network(self.ip, *ip, prefix)))
+            }
+            Mechanism::Mx(spec, v4, v6) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain).await?;
+                let hosts = self.resolver.mx(&target).await.map_err(temp)?;
+                self.count_void(hosts.is_empty())?;
+                //a null mx is an empty host
+                let hosts = hosts.iter().filter(|h| !h.is_empty()).collect::<Vec<_>>();
+                if hosts.len() > MAX_NAMES {
+                    return Err(Error::Perm(format!("{target} has too many mx hosts")));
+                }
+                let prefix = if ipv6 { *v6 } else { *v4 };
+                for host in hosts {
+                    let ips = self.resolver.ips(host, ipv6).await.map_err(temp)?;
+                    if ips.iter().any(|ip| in_network(self.ip, *ip, prefix)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(spec) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain).await?;
+                let names = self.validated_names(true).await?;
+                Ok(names.iter().any(|name| is_subdomain(name, &target)))
+            }
+            Mechanism::Exists(spec) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(spec, domain).await?;
+                //always an A lookup, whatever the client's address
+                let ips = self.resolver.ips(&target, false).await.map_err(temp)?;
+                self.count_void(ips.is_empty())?;
+                Ok(!ips.is_empty())
+            }
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Error> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(Error::Perm("too many dns lookups".to_string()));
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self, void: bool) -> Result<(), Error> {
+        if void {
+            self.void_lookups += 1;
+        }
+        if self.void_lookups > MAX_VOID_LOOKUPS {
+            return Err(Error::Perm("too many lookups found nothing".to_string()));
+        }
+        Ok(())
+    }
+
+    ///the domain of a mechanism, the current one if it has none
+    async fn target(&mut self, spec: Option<&str>, domain: &str) -> Result<String, Error> {
+        match spec {
+            Some(spec) => self.expand_domain(spec, domain).await,
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    async fn expand_domain(&mut self, spec: &str, domain: &str) -> Result<String, Error> {
+        Ok(truncate_domain(&self.expand(spec, domain, false).await?))
+    }
+
+    ///the names the client's address has PTR records for that resolve back
+    ///to it. dns errors just leave names out (rfc 7208 5.5)
+    async fn validated_names(&mut self, count_void: bool) -> Result<Vec<String>, Error> {
+        let Ok(names) = self.resolver.ptr(self.ip).await else {
+            return Ok(vec![]);
+        };
+        if count_void {
+            self.count_void(names.is_empty())?;
+        }
+        let mut validated = Vec::new();
+        for name in names.into_iter().take(MAX_NAMES) {
+            let ips = self.resolver.ips(&name, self.ip.is_ipv6()).await;
+            if ips.is_ok_and(|ips| ips.contains(&self.ip)) {
+                validated.push(name);
+            }
+        }
+        Ok(validated)
+    }
+
+    ///expands the macros in `spec`, `exp` allows the ones only explanations
+    ///can use
+    async fn expand(&mut self, spec: &str, domain: &str, exp: bool) -> Result<String, Error> {
+        let mut expanded = String::new();
+        for token in tokens(spec).map_err(Error::Perm)? {
+            let m = match token {
+                Token::Literal(literal) => {
+                    expanded += &literal;
+                    continue;
+                }
+                Token::Macro(m) => m,
+            };
+            let (local, sender_domain) = self.sender.rsplit_once('@').unwrap_or(("", ""));
+            let value = match m.letter {
+                's' => self.sender.to_string(),
+                'l' => local.to_string(),
+                'o' => sender_domain.to_string(),
+                'd' => domain.to_string(),
+                'i' => dotted_ip(self.ip),
+                'p' => self.validated_name(domain).await?,
+                'v' if self.ip.is_ipv4() => "in-addr".to_string(),
+                'v' => "ip6".to_string(),
+                'h' => self.helo.to_string(),
+                'c' if exp => self.ip.to_string(),
+                'r' if exp => self.hostname.to_string(),
+                't' if exp => chrono::Utc::now().timestamp().to_string(),
+                letter => {
+                    return Err(Error::Perm(format!(
+                        "%{{{letter}}} only works in explanations"
+                    )))
+                }
+            };
+            let value = transform(&value, &m);
+            if m.escape {
+                expanded += &url_escape(&value);
+            } else {
+                expanded += &value;
+            }
+        }
+        Ok(expanded)
+    }
+
+    ///the `p` macro, a validated name of the client, preferably in `domain`
+    async fn validated_name(&mut self, domain: &str) -> Result<String, Error> {
+        let names = self.validated_names(false).await?;
+        let name = names
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(domain))
+            .or_else(|| names.iter().find(|name| is_subdomain(name, domain)))
+            .or(names.first());
+        Ok(name.cloned().unwrap_or_else(|| "unknown".to_string()))
+    }
+
+    ///the text the domain gives for failing, nothing if it can't be had
+    async fn explanation(&mut self, exp: &str, domain: &str) -> Option<String> {
+        let target = self.expand_domain(exp, domain).await.ok()?;
+        let records = self.resolver.txt(&target).await.ok()?;
+        let [text] = &records[..] else {
+            return None;
+        };
+        self.expand(text, domain, true).await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use anyhow::{bail, Result};
+
+    use super::{check, tokens, Checker, Mechanism, Record};
+    use crate::config::DnsConfig;
+    use crate::dns::{Dns, Zone};
+    use crate::email_auth::tests::FakeDns;
+    use crate::email_auth::{AuthResolver, AuthStatus};
+
+    fn checker<'a>(dns: &'a FakeDns, ip: &str, sender: &'a str) -> Checker<'a, FakeDns> {
+        Checker {
+            resolver: dns,
+            ip: ip.parse().unwrap(),
+            sender,
+            helo: "mx.example.org",
+            hostname: "smtp.kaki.foo",
+            lookups: 0,
+            void_lookups: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_macros() {
+        //rfc 7208 7.4
+        let dns = FakeDns::default();
+        let mut c = checker(&dns, "192.0.2.3", "strong-bad@email.example.com");
+        let domain = "email.example.com";
+        for (spec, expected) in [
+            ("%{s}", "strong-bad@email.example.com"),
+            ("%{o}", "email.example.com"),
+            ("%{d}", "email.example.com"),
+            ("%{d4}", "email.example.com"),
+            ("%{d3}", "email.example.com"),
+            ("%{d2}", "example.com"),
+            ("%{d1}", "com"),
+            ("%{dr}", "com.example.email"),
+            ("%{d2r}", "example.email"),
+            ("%{l}", "strong-bad"),
+            ("%{l-}", "strong.bad"),
+            ("%{lr}", "strong-bad"),
+            ("%{lr-}", "bad.strong"),
+            ("%{l1r-}", "strong"),
+            (
+                "%{ir}.%{v}._spf.%{d2}",
+                "3.2.0.192.in-addr._spf.example.com",
+            ),
+            ("%{lr-}.lp._spf.%{d2}", "bad.strong.lp._spf.example.com"),
+            (
+                "%{lr-}.lp.%{ir}.%{v}._spf.%{d2}",
+                "bad.strong.lp.3.2.0.192.in-addr._spf.example.com",
+            ),
+            (
+                "%{ir}.%{v}.%{l1r-}.lp._spf.%{d2}",
+                "3.2.0.192.in-addr.strong.lp._spf.example.com",
+            ),
+            (
+                "%{d2}.trusted-domains.example.net",
+                "example.com.trusted-domains.example.net",
+            ),
+            ("%{S}", "strong-bad%40email.example.com"),
+            ("%%%_%-", "% %20"),
+            ("%{p}.%{h}", "unknown.mx.example.org"),
+        ] {
+            assert_eq!(
+                c.expand(spec, domain, false).await.ok().as_deref(),
+                Some(expected),
+                "{spec}"
+            );
+        }
+        let mut c = checker(&dns, "2001:db8::cb01", "strong-bad@email.example.com");
+        assert_eq!(
+            c.expand("%{ir}.%{v}._spf.%{d2}", domain, false).await.ok().as_deref(),
+            Some(
+                "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+            )
+        );
+        //explanation only macros
+        assert!(c.expand("%{c}", domain, false).await.is_err());
+        assert_eq!(
+            c.expand("%{c} via %{r}", domain, true)
+                .await
+                .ok()
+                .as_deref(),
+            Some("2001:db8::cb01 via smtp.kaki.foo")
+        );
+        for invalid in ["%", "%{", "%{x}", "%{d0}", "%{d2q}", "%a"] {
+            assert!(tokens(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let record = Record::parse("v=spf1 a/24//64 -mx:%{d}.example.com ~ip4:10.0.0.0/8 ?ip6:2001:db8::/32 ptr exists:%{i}.bl.example.com foo=bar redirect=_spf.example.com").unwrap();
+        assert_eq!(record.directives.len(), 6);
+        assert_eq!(record.directives[0].1, Mechanism::A(None, 24, 64));
+        assert_eq!(
+            record.directives[2].1,
+            Mechanism::Ip("10.0.0.0".parse().unwrap(), 8)
+        );
+        assert_eq!(record.redirect.as_deref(), Some("_spf.example.com"));
+        for invalid in [
+            "v=spf1 moo",
+            "v=spf1 a/33",
+            "v=spf1 a//129",
+            "v=spf1 ip4:1.2.3.4/032",
+            "v=spf1 ip4:1.2.3",
+            "v=spf1 ip4:1.2.3.4:8080",
+            "v=spf1 ip4",
+            "v=spf1 ip6:::1/129",
+            "v=spf1 a:111.222.33.44",
+            "v=spf1 a:museum",
+            "v=spf1 include:",
+            "v=spf1 include:example.com/24",
+            "v=spf1 exists",
+            "v=spf1 all:example.com",
+            "v=spf1 redirect=a.example.com redirect=b.example.com",
+            "v=spf1 exp=",
+            "v=spf1 foo=%",
+        ] {
+            assert!(Record::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    ///the zone the scenarios below run against, modelled on the rfc 7208
+    ///test suite
+    fn zone() -> FakeDns {
+        let mut dns = FakeDns::default()
+            .txt("none.example.com", "not spf")
+            .txt("nospace.example.com", "v=spf1mx")
+            .txt("empty.example.com", "v=spf1")
+            .txt("two.example.com", "v=spf1 -all")
+            .txt("two.example.com", "v=spf1 +all")
+            .txt("anywhere.example.com", "v=spf1 ip4:1.1.1.1 -all moo")
+            .broken("timeout.example.com")
+            .txt("mail.example.com", "v=spf1 a -all")
+            .ip("mail.example.com", "1.2.3.4")
+            .ip("mail.example.com", "2001:db8::1")
+            .txt("cidr.example.com", "v=spf1 a:mail.example.com/24//64 -all")
+            .txt("mx.example.com", "v=spf1 mx -all")
+            .mx("mx.example.com", "mail.example.com")
+            .mx("mx.example.com", "")
+            .txt("incl.example.com", "v=spf1 include:mx.example.com ~all")
+            .txt(
+                "inclnone.example.com",
+                "v=spf1 include:none.example.com ~all",
+            )
+            .txt(
+                "incltemp.example.com",
+                "v=spf1 include:timeout.example.com ~all",
+            )
+            .txt(
+                "inclbroken.example.com",
+                "v=spf1 include:anywhere.example.com ~all",
+            )
+            .txt("redirect.example.com", "v=spf1 redirect=mail.example.com")
+            .txt("redirnone.example.com", "v=spf1 redirect=none.example.com")
+            .txt(
+                "redirall.example.com",
+                "v=spf1 ?all redirect=mail.example.com",
+            )
+            .txt("loop.example.com", "v=spf1 include:loop.example.com")
+            .txt(
+                "exists.example.com",
+                "v=spf1 exists:%{i}.bl.example.com -all",
+            )
+            .ip("1.2.3.4.bl.example.com", "127.0.0.2")
+            .txt("ptr.example.com", "v=spf1 ptr -all")
+            .ptr("1.2.3.4", "mail.ptr.example.com")
+            .ptr("1.2.3.5", "fake.ptr.example.com")
+            .ip("mail.ptr.example.com", "1.2.3.4")
+            .ip("fake.ptr.example.com", "9.9.9.9")
+            .txt(
+                "void.example.com",
+                "v=spf1 a:n1.example.com a:n2.example.com a:n3.example.com +all",
+            )
+            .txt(
+                "voidok.example.com",
+                "v=spf1 a:n1.example.com a:n2.example.com +all",
+            )
+            .txt("exp.example.com", "v=spf1 -all exp=why.example.com")
+            .txt("why.example.com", "%{l} can't send from %{d} through %{i}")
+            .txt("mapped.example.com", "v=spf1 ip4:1.2.3.4 -all")
+            .txt("helo.example.org", "v=spf1 ip4:1.2.3.4 -all")
+            .txt("toplabel.example.com", "v=spf1 a:foo.123 -all")
+            .txt("manymx.example.com", "v=spf1 mx -all");
+        for i in 0..11 {
+            dns = dns.mx("manymx.example.com", &format!("mx{i}.example.com"));
+        }
+        let at_limit = (0..10)
+            .map(|i| format!("include:l{i}.example.com"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        dns = dns
+            .txt("atlimit.example.com", &format!("v=spf1 {at_limit} +all"))
+            .txt(
+                "overlimit.example.com",
+                &format!("v=spf1 {at_limit} a:mail.example.com +all"),
+            );
+        for i in 0..10 {
+            dns = dns.txt(&format!("l{i}.example.com"), "v=spf1 -all");
+        }
+        dns
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let dns = zone();
+        let long_label = format!("{}.com", "a".repeat(64));
+        for (domain, ip, expected) in [
+            //record selection
+            ("nothing.example.com", "1.2.3.4", AuthStatus::None),
+            ("none.example.com", "1.2.3.4", AuthStatus::None),
+            ("nospace.example.com", "1.2.3.4", AuthStatus::None),
+            ("empty.example.com", "1.2.3.4", AuthStatus::Neutral),
+            ("two.example.com", "1.2.3.4", AuthStatus::PermError),
+            ("anywhere.example.com", "1.1.1.1", AuthStatus::PermError),
+            ("timeout.example.com", "1.2.3.4", AuthStatus::TempError),
+            ("foo..example.com", "1.2.3.4", AuthStatus::None),
+            ("localhost", "1.2.3.4", AuthStatus::None),
+            (long_label.as_str(), "1.2.3.4", AuthStatus::None),
+            //a, mx and cidr
+            ("mail.example.com", "1.2.3.4", AuthStatus::Pass),
+            ("mail.example.com", "1.2.3.5", AuthStatus::Fail),
+            ("mail.example.com", "2001:db8::1", AuthStatus::Pass),
+            ("cidr.example.com", "1.2.3.200", AuthStatus::Pass),
+            ("cidr.example.com", "1.2.4.1", AuthStatus::Fail),
+            ("cidr.example.com", "2001:db8::ffff", AuthStatus::Pass),
+            ("mx.example.com", "1.2.3.4", AuthStatus::Pass),
+            ("mx.example.com", "5.6.7.8", AuthStatus::Fail),
+            ("manymx.example.com", "1.2.3.4", AuthStatus::PermError),
+            ("toplabel.example.com", "1.2.3.4", AuthStatus::PermError),
+            //include and redirect
+            ("incl.example.com", "1.2.3.4", AuthStatus::Pass),
+            ("incl.example.com", "5.6.7.8", AuthStatus::SoftFail),
+            ("inclnone.example.com", "1.2.3.4", AuthStatus::PermError),
+            ("incltemp.example.com", "1.2.3.4", AuthStatus::TempError),
+            ("inclbroken.example.com", "1.2.3.4", AuthStatus::PermError),
+            ("redirect.example.com", "1.2.3.4", AuthStatus::Pass),
+            ("redirect.example.com", "5.6.7.8", AuthStatus::Fail),
+            ("redirnone.example.com", "1.2.3.4", AuthStatus::PermError),
+            ("redirall.example.com", "5.6.7.8", AuthStatus::Neutral),
+            //exists and ptr
+            ("exists.example.com", "1.2.3.4", AuthStatus::Pass),
+            ("exists.example.com", "1.2.3.5", AuthStatus::Fail),
+            ("ptr.example.com", "1.2.3.4", AuthStatus::Pass),
+            ("ptr.example.com", "1.2.3.5", AuthStatus::Fail),
+            //limits
+            ("loop.example.com", "1.2.3.4", AuthStatus::PermError),
+            ("atlimit.example.com", "1.2.3.4", AuthStatus::Pass),
+            ("overlimit.example.com", "1.2.3.4", AuthStatus::PermError),
+            ("voidok.example.com", "1.2.3.4", AuthStatus::Pass),
+            ("void.example.com", "1.2.3.4", AuthStatus::PermError),
+            //mapped addresses are ipv4
+            ("mapped.example.com", "::ffff:1.2.3.4", AuthStatus::Pass),
+        ] {
+            let ip = ip.parse::<IpAddr>().unwrap();
+            let sender = format!("alice@{domain}");
+            let result = check(&dns, ip, &sender, "mx.example.org", "smtp.kaki.foo").await;
+            assert_eq!(result.status, expected, "{domain} {ip}: {result:?}");
+        }
+        let result = check(
+            &dns,
+            "5.6.7.8".parse().unwrap(),
+            "<alice@exp.example.com>",
+            "mx.example.org",
+            "smtp.kaki.foo",
+        )
+        .await;
+        assert_eq!(result.status, AuthStatus::Fail);
+        assert_eq!(
+            result.reason.as_deref(),
+            Some("alice can't send from exp.example.com through 5.6.7.8")
+        );
+        //the HELO identity
+        let helo = check(
+            &dns,
+            "1.2.3.4".parse().unwrap(),
+            "postmaster@helo.example.org",
+            "helo.example.org",
+            "smtp.kaki.foo",
+        )
+        .await;
+        assert_eq!(helo.status, AuthStatus::Pass);
+    }
+
+    ///the mechanisms, macros and limits again, through the real resolver
+    ///answering from a zone file
+    const ZONE: &str = r#"
+a.example.com.             IN TXT "v=spf1 a/24 -all"
+a.example.com.             IN A    192.0.2.1
+a.example.com.             IN AAAA 2001:db8::1
+a6.example.com.            IN TXT "v=spf1 a:a.example.com//64 -all"
+mx.example.com.            IN TXT "v=spf1 mx/24//64 -all"
+mx.example.com.            IN MX   10 mail.example.com.
+mail.example.com.          IN A    198.51.100.1
+mail.example.com.          IN AAAA 2001:db8:1::1
+exists.example.com.        IN TXT "v=spf1 exists:%{i}.bl.example.com -all"
+192.0.2.1.bl.example.com.  IN A    127.0.0.2
+rev.example.com.           IN TXT "v=spf1 exists:%{ir}.%{v}.%{d2}.allow.example.net -all"
+1.2.0.192.in-addr.example.com.allow.example.net. IN A 127.0.0.2
+local.example.com.         IN TXT "v=spf1 exists:%{l1r-.}.lp.%{d2} -all"
+bob.lp.example.com.        IN A    127.0.0.2
+ptr.example.com.           IN TXT "v=spf1 ptr -all"
+1.2.0.192.in-addr.arpa.    IN PTR  host.ptr.example.com.
+2.2.0.192.in-addr.arpa.    IN PTR  liar.ptr.example.com.
+host.ptr.example.com.      IN A    192.0.2.1
+liar.ptr.example.com.      IN A    203.0.113.1
+atlimit.example.com.       IN TXT "v=spf1 a:l1.example.com a:l2.example.com a:l3.example.com a:l4.example.com a:l5.example.com a:l6.example.com a:l7.example.com a:l8.example.com a:l9.example.com a:a.example.com -all"
+overlimit.example.com.     IN TXT "v=spf1 a:l1.example.com a:l2.example.com a:l3.example.com a:l4.example.com a:l5.example.com a:l6.example.com a:l7.example.com a:l8.example.com a:l9.example.com a:l10.example.com a:a.example.com -all"
+l1.example.com.            IN A    203.0.113.1
+l2.example.com.            IN A    203.0.113.2
+l3.example.com.            IN A    203.0.113.3
+l4.example.com.            IN A    203.0.113.4
+l5.example.com.            IN A    203.0.113.5
+l6.example.com.            IN A    203.0.113.6
+l7.example.com.            IN A    203.0.113.7
+l8.example.com.            IN A    203.0.113.8
+l9.example.com.            IN A    203.0.113.9
+l10.example.com.           IN A    203.0.113.10
+twovoid.example.com.       IN TXT "v=spf1 a:v1.example.com mx:v2.example.com +all"
+threevoid.example.com.     IN TXT "v=spf1 a:v1.example.com mx:v2.example.com exists:v3.example.com +all"
+temp.example.com.          IN TXT "v=spf1 a:down.example.com +all"
+tempexists.example.com.    IN TXT "v=spf1 exists:%{i}.down.example.com +all"
+"#;
+
+    ///the zone, except that lookups of names under down.example.com fail
+    struct Flaky(Dns);
+
+    impl Flaky {
+        fn check(&self, name: &str) -> Result<()> {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            if name == "down.example.com" || name.ends_with(".down.example.com") {
+                bail!("timed out looking up {name}");
+            }
+            Ok(())
+        }
+    }
+
+    impl AuthResolver for Flaky {
+        async fn txt(&self, name: &str) -> Result<Vec<String>> {
+            self.check(name)?;
+            self.0.txt(name).await
+        }
+
+        async fn ips(&self, name: &str, ipv6: bool) -> Result<Vec<IpAddr>> {
+            self.check(name)?;
+            self.0.ips(name, ipv6).await
+        }
+
+        async fn mx(&self, domain: &str) -> Result<Vec<String>> {
+            self.check(domain)?;
+            self.0.mx(domain).await
+        }
+
+        async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>> {
+            self.0.ptr(ip).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_zone() {
+        let dns = Flaky(Dns::from_zone(
+            Zone::parse(ZONE).unwrap(),
+            &DnsConfig::default(),
+        ));
+        for (sender, ip, expected) in [
+            //a and mx with cidr lengths
+            ("alice@a.example.com", "192.0.2.200", AuthStatus::Pass),
+            ("alice@a.example.com", "192.0.3.1", AuthStatus::Fail),
+            ("alice@a.example.com", "2001:db8::2", AuthStatus::Fail),
+            ("alice@a6.example.com", "2001:db8::ffff", AuthStatus::Pass),
+            ("alice@a6.example.com", "2001:db8:0:1::1", AuthStatus::Fail),
+            ("alice@a6.example.com", "192.0.2.1", AuthStatus::Pass),
+            ("alice@a6.example.com", "192.0.2.2", AuthStatus::Fail),
+            ("alice@mx.example.com", "198.51.100.77", AuthStatus::Pass),
+            ("alice@mx.example.com", "198.51.101.1", AuthStatus::Fail),
+            ("alice@mx.example.com", "2001:db8:1::abcd", AuthStatus::Pass),
+            ("alice@mx.example.com", "2001:db8:2::1", AuthStatus::Fail),
+            //exists with %{i}, %{ir}, %{v} and %{d2}
+            ("alice@exists.example.com", "192.0.2.1", AuthStatus::Pass),
+            ("alice@exists.example.com", "192.0.2.2", AuthStatus::Fail),
+            ("alice@rev.example.com", "192.0.2.1", AuthStatus::Pass),
+            ("alice@rev.example.com", "192.0.2.2", AuthStatus::Fail),
+            //the local part split on both '-' and '.', rightmost part last
+            (
+                "alice-x.bob@local.example.com",
+                "192.0.2.9",
+                AuthStatus::Fail,
+            ),
+            (
+                "bob.x-alice@local.example.com",
+                "192.0.2.9",
+                AuthStatus::Pass,
+            ),
+            ("bob@local.example.com", "192.0.2.9", AuthStatus::Pass),
+            //ptr only counts names that resolve back to the address
+            ("alice@ptr.example.com", "192.0.2.1", AuthStatus::Pass),
+            ("alice@ptr.example.com", "192.0.2.2", AuthStatus::Fail),
+            ("alice@ptr.example.com", "192.0.2.3", AuthStatus::Fail),
+            //at most 10 mechanisms that look things up (rfc 7208 4.6.4)
+            ("alice@atlimit.example.com", "192.0.2.1", AuthStatus::Pass),
+            (
+                "alice@overlimit.example.com",
+                "192.0.2.1",
+                AuthStatus::PermError,
+            ),
+            //and at most 2 of them finding nothing
+            ("alice@twovoid.example.com", "192.0.2.1", AuthStatus::Pass),
+            (
+                "alice@threevoid.example.com",
+                "192.0.2.1",
+                AuthStatus::PermError,
+            ),
+            //a lookup that fails isn't a lookup that found nothing
+            ("alice@temp.example.com", "192.0.2.1", AuthStatus::TempError),
+            (
+                "alice@tempexists.example.com",
+                "192.0.2.1",
+                AuthStatus::TempError,
+            ),
+            ("alice@down.example.com", "192.0.2.1", AuthStatus::TempError),
+        ] {
+            let ip = ip.parse::<IpAddr>().unwrap();
+            let result = check(&dns, ip, sender, "mx.example.org", "smtp.kaki.foo").await;
+            assert_eq!(result.status, expected, "{sender} {ip}: {result:?}");
+        }
+    }
+}
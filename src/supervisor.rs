@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+///the listeners kakimail accepts connections on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Listener {
+    Smtp,
+    Submission,
+    Smtps,
+    Imap,
+    Imaps,
+    Https,
+}
+
+impl Listener {
+    ///what we tell a client before hanging up when it's over the connection cap.
+    ///implicit tls ports don't get anything because they expect a handshake first
+    fn reject_message(self) -> &'static [u8] {
+        match self {
+            Listener::Smtp | Listener::Submission => {
+                b"421 Too many connections, try again later\r\n"
+            }
+            Listener::Imap => b"* BYE Too many connections, try again later\r\n",
+            Listener::Smtps | Listener::Imaps | Listener::Https => &[],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    ///maximum amount of live sessions across all listeners, 0 means unlimited
+    pub max_connections: usize,
+    ///maximum amount of live sessions from a single ip, 0 means unlimited
+    pub max_per_ip: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 512,
+            max_per_ip: 16,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct Sessions {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_listener: HashMap<Listener, usize>,
+}
+
+///spawns every accepted connection as its own task so that one slow client
+///can't stall the other listeners. also keeps count of the live sessions
+///so that the connection caps can be enforced
+#[derive(Clone)]
+pub struct Supervisor {
+    limits: ConnectionLimits,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+///a slot for a live session, gets released when dropped
+pub struct SessionGuard {
+    sessions: Arc<Mutex<Sessions>>,
+    listener: Listener,
+    ip: IpAddr,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return;
+        };
+        sessions.total = sessions.total.saturating_sub(1);
+        if let Some(count) = sessions.per_listener.get_mut(&self.listener) {
+            *count = count.saturating_sub(1);
+        }
+        if let Some(count) = sessions.per_ip.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                sessions.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Supervisor {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+        }
+    }
+
+    ///reserves a slot for a new session, returns None if a cap would be exceeded
+    pub fn admit(&self, listener: Listener, ip: IpAddr) -> Option<SessionGuard> {
+        let mut sessions = self.sessions.lock().ok()?;
+        if self.limits.max_connections != 0 && sessions.total >= self.limits.max_connections {
+            tracing::warn!("global connection cap reached, refusing {ip}");
+            return None;
+        }
+        let from_ip = sessions.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.limits.max_per_ip != 0 && from_ip >= self.limits.max_per_ip {
+            tracing::warn!("connection cap for {ip} reached, refusing");
+            return None;
+        }
+        sessions.total += 1;
+        *sessions.per_ip.entry(ip).or_insert(0) += 1;
+        *sessions.per_listener.entry(listener).or_insert(0) += 1;
+        Some(SessionGuard {
+            sessions: self.sessions.clone(),
+            listener,
+            ip,
+        })
+    }
+
+    ///the amount of live sessions on a listener
+    pub fn live_sessions(&self, listener: Listener) -> usize {
+        self.sessions
+            .lock()
+            .map(|s| s.per_listener.get(&listener).copied().unwrap_or(0))
+            .unwrap_or(0)
+    }
+
+    ///runs `session` on its own task, must be called from inside a `LocalSet`.
+    ///the sessions aren't `Send` (the db connection lives in them), which is why
+    ///they're spawned locally instead of on the multithreaded runtime
+    pub fn spawn<F, Fut>(
+        &self,
+        listener: Listener,
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        session: F,
+    ) where
+        F: FnOnce(TcpStream) -> Fut + 'static,
+        Fut: Future<Output = Result<()>> + 'static,
+    {
+        let Some(guard) = self.admit(listener, addr.ip()) else {
+            tokio::task::spawn_local(async move {
+                stream.write_all(listener.reject_message()).await.ok();
+                stream.shutdown().await.ok();
+            });
+            return;
+        };
+        tracing::debug!(
+            "{:?} session from {addr} started, {} live",
+            listener,
+            self.live_sessions(listener)
+        );
+        tokio::task::spawn_local(async move {
+            if let Err(e) = session(stream).await {
+                tracing::warn!(
+                    "{:?} session from {addr} ended with an error: {:?}",
+                    listener,
+                    e
+                );
+            }
+            drop(guard);
+            tracing::debug!("{:?} session from {addr} ended", listener);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{ConnectionLimits, Listener, Supervisor};
+
+    #[test]
+    fn test_per_ip_cap() {
+        let supervisor = Supervisor::new(ConnectionLimits {
+            max_connections: 0,
+            max_per_ip: 2,
+        });
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let first = supervisor.admit(Listener::Smtp, ip);
+        let second = supervisor.admit(Listener::Imap, ip);
+        assert!(first.is_some() && second.is_some());
+        assert!(supervisor.admit(Listener::Smtp, ip).is_none());
+        assert!(supervisor.admit(Listener::Smtp, other).is_some());
+        drop(first);
+        assert!(supervisor.admit(Listener::Smtp, ip).is_some());
+    }
+
+    #[test]
+    fn test_global_cap() {
+        let supervisor = Supervisor::new(ConnectionLimits {
+            max_connections: 1,
+            max_per_ip: 0,
+        });
+        let guard = supervisor.admit(Listener::Imaps, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(supervisor.live_sessions(Listener::Imaps), 1);
+        assert!(supervisor
+            .admit(Listener::Smtp, IpAddr::V4(Ipv4Addr::BROADCAST))
+            .is_none());
+        drop(guard);
+        assert_eq!(supervisor.live_sessions(Listener::Imaps), 0);
+    }
+}
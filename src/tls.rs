@@ -3,7 +3,10 @@ use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpStream,
 };
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{
+    rustls::{CommonState, ProtocolVersion},
+    TlsAcceptor,
+};
 
 pub enum StreamType {
     Plain(TcpStream),
@@ -23,6 +26,14 @@ impl StreamType {
             }
         }
     }
+    ///the protocol version and cipher suite of an encrypted stream, like
+    ///("TLSv1.3", "TLS13_AES_256_GCM_SHA384")
+    pub fn tls_info(&self) -> Option<(String, String)> {
+        let StreamType::Tls(stream) = self else {
+            return None;
+        };
+        session_info(stream.get_ref().1)
+    }
     // pub async fn upgrade_to_tls_new(&mut self, tls_acceptor: &TlsAcceptor) -> Result<()> {
     //     let new_stream_type = match self {
     //         StreamType::Plain(stream) => {
@@ -39,6 +50,17 @@ impl StreamType {
     // }
 }
 
+///the protocol version and cipher suite of a tls connection, either side
+pub fn session_info(connection: &CommonState) -> Option<(String, String)> {
+    let version = match connection.protocol_version()? {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+        ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+        other => format!("{:?}", other),
+    };
+    let cipher = format!("{:?}", connection.negotiated_cipher_suite()?.suite());
+    Some((version, cipher))
+}
+
 impl AsyncWrite for StreamType {
     fn poll_write(
         self: std::pin::Pin<&mut Self>,
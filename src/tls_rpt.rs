@@ -0,0 +1,177 @@
+use std::io::Read;
+
+use anyhow::{bail, Context, Result};
+use chrono::DateTime;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+
+///an SMTP TLS report, rfc 8460 4.4
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsReport {
+    pub organization_name: String,
+    pub date_range: DateRange,
+    #[serde(default)]
+    pub contact_info: String,
+    pub report_id: String,
+    pub policies: Vec<PolicyResults>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DateRange {
+    pub start_datetime: String,
+    pub end_datetime: String,
+}
+
+///how the sessions under one policy went
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PolicyResults {
+    pub policy: PolicyInfo,
+    pub summary: Summary,
+    #[serde(default)]
+    pub failure_details: Vec<FailureDetails>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PolicyInfo {
+    ///"sts", "tlsa" or "no-policy-found"
+    pub policy_type: String,
+    pub policy_domain: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Summary {
+    pub total_successful_session_count: i64,
+    pub total_failure_session_count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FailureDetails {
+    ///what went wrong, eg. "certificate-expired" or "sts-policy-invalid"
+    pub result_type: String,
+    #[serde(default)]
+    pub sending_mta_ip: String,
+    #[serde(default)]
+    pub receiving_mx_hostname: String,
+    #[serde(default)]
+    pub receiving_ip: String,
+    pub failed_session_count: i64,
+    #[serde(default)]
+    pub failure_reason_code: String,
+}
+
+impl TlsReport {
+    pub fn parse(json: &str) -> Result<Self> {
+        serde_json::from_str(json).context("invalid tls report")
+    }
+
+    ///the start and end of the report as unix time
+    pub fn range(&self) -> Result<(i64, i64)> {
+        let time = |t: &str| {
+            DateTime::parse_from_rfc3339(t)
+                .map(|t| t.timestamp())
+                .with_context(|| format!("invalid datetime {t}"))
+        };
+        Ok((
+            time(&self.date_range.start_datetime)?,
+            time(&self.date_range.end_datetime)?,
+        ))
+    }
+}
+
+///finds the report attached to a mail (rfc 8460 5.3)
+pub fn read_message(data: &[u8]) -> Result<TlsReport> {
+    let parsed = mailparse::parse_mail(data)?;
+    let mut parts = vec![&parsed];
+    while let Some(part) = parts.pop() {
+        parts.extend(part.subparts.iter());
+        let json = match part.ctype.mimetype.as_str() {
+            "application/tlsrpt+gzip" => {
+                let mut json = String::new();
+                GzDecoder::new(&part.get_body_raw()?[..]).read_to_string(&mut json)?;
+                json
+            }
+            "application/tlsrpt+json" => part.get_body()?,
+            _ => continue,
+        };
+        return TlsReport::parse(&json);
+    }
+    bail!("no tls report attached")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use base64::Engine;
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::read_message;
+
+    const REPORT: &str = r#"{
+        "organization-name": "Company-X",
+        "date-range": {
+            "start-datetime": "2016-04-01T00:00:00Z",
+            "end-datetime": "2016-04-01T23:59:59Z"
+        },
+        "contact-info": "sts-reporting@company-x.example",
+        "report-id": "5065427c-23d3-47ca-b6e0-946ea0e8c4be",
+        "policies": [{
+            "policy": {
+                "policy-type": "sts",
+                "policy-string": ["version: STSv1", "mode: testing"],
+                "policy-domain": "kaki.foo",
+                "mx-host": ["*.mail.kaki.foo"]
+            },
+            "summary": {
+                "total-successful-session-count": 5326,
+                "total-failure-session-count": 303
+            },
+            "failure-details": [{
+                "result-type": "certificate-expired",
+                "sending-mta-ip": "2001:db8:abcd:0012::1",
+                "receiving-mx-hostname": "mx1.mail.kaki.foo",
+                "failed-session-count": 100
+            }]
+        }]
+    }"#;
+
+    #[test]
+    fn test_read_message() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(REPORT.as_bytes()).unwrap();
+        let attachment = base64::engine::general_purpose::STANDARD.encode(gz.finish().unwrap());
+        let mail = format!(
+            "From: tlsrpt@company-x.example\r\n\
+             Subject: Report Domain: kaki.foo\r\n\
+             TLS-Report-Domain: kaki.foo\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/report; report-type=tlsrpt; boundary=\"b\"\r\n\
+             \r\n\
+             --b\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             the report\r\n\
+             --b\r\n\
+             Content-Type: application/tlsrpt+gzip\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             {attachment}\r\n\
+             --b--\r\n"
+        );
+        let report = read_message(mail.as_bytes()).unwrap();
+        assert_eq!(report.organization_name, "Company-X");
+        assert_eq!(report.range().unwrap(), (1459468800, 1459555199));
+        let policy = &report.policies[0];
+        assert_eq!(policy.policy.policy_domain, "kaki.foo");
+        assert_eq!(policy.summary.total_failure_session_count, 303);
+        assert_eq!(policy.failure_details[0].result_type, "certificate-expired");
+        assert_eq!(policy.failure_details[0].receiving_ip, "");
+        assert!(read_message(b"Subject: hi\r\n\r\nno report\r\n").is_err());
+    }
+}
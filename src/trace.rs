@@ -0,0 +1,308 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+
+use crate::dkim::split_message;
+use crate::email_auth::IncomingAuthResult;
+
+///what the Received header tells about the session a message came in on
+pub struct Session<'a> {
+    pub peer_ip: IpAddr,
+    ///the name the client gave in EHLO or HELO
+    pub helo: &'a str,
+    ///our hostname
+    pub hostname: &'a str,
+    pub esmtp: bool,
+    ///the protocol version and cipher suite, if the session was encrypted
+    pub tls: Option<(String, String)>,
+}
+
+///the Received trace field, rfc 5321 4.4. the recipient is only named when
+///there's one, so they don't learn about each other
+pub fn received(session: &Session, recipients: &[String], now: DateTime<Utc>) -> String {
+    let ip = match session.peer_ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("IPv6:{ip}"),
+    };
+    //the client can send anything as its name
+    let helo = session
+        .helo
+        .chars()
+        .filter(|c| c.is_ascii_graphic() && !matches!(c, '(' | ')' | ';'))
+        .collect::<String>();
+    let helo = if helo.is_empty() { "unknown" } else { &helo };
+    //rfc 3848
+    let protocol = match (session.esmtp, &session.tls) {
+        (_, Some(_)) => "ESMTPS",
+        (true, None) => "ESMTP",
+        (false, None) => "SMTP",
+    };
+    let mut header = format!(
+        "Received: from {helo} ([{ip}])\r\n\tby {} (kakimail) with {protocol}",
+        session.hostname
+    );
+    if let Some((version, cipher)) = &session.tls {
+        header += &format!("\r\n\t(using {version} with cipher {cipher})");
+    }
+    if let [recipient] = recipients {
+        header += &format!("\r\n\tfor <{}>", address(recipient));
+    }
+    header += &format!(";\r\n\t{}\r\n", now.to_rfc2822());
+    header
+}
+
+///the Authentication-Results field for the checks in `auth`, rfc 8601
+pub fn authentication_results(
+    authserv_id: &str,
+    auth: &IncomingAuthResult,
+    mail_from: &str,
+    helo: &str,
+) -> String {
+    format!(
+        "Authentication-Results: {}\r\n",
+        results(authserv_id, auth, mail_from, helo)
+    )
+}
+
+///the value of our Authentication-Results, also what an ARC seal records
+pub fn results(
+    authserv_id: &str,
+    auth: &IncomingAuthResult,
+    mail_from: &str,
+    helo: &str,
+) -> String {
+    let mut results = Vec::new();
+    //bounces were checked as the HELO name
+    results.push(match address(mail_from) {
+        "" => format!("spf={} smtp.helo={}", auth.spf.name(), value(helo)),
+        from => format!("spf={} smtp.mailfrom={}", auth.spf.name(), value(from)),
+    });
+    for signature in &auth.dkim_signatures {
+        let mut result = format!("dkim={}", signature.status.name());
+        if let Some(reason) = &signature.reason {
+            result += &format!(" reason={}", quoted(reason));
+        }
+        result += &format!(
+            " header.d={} header.s={}",
+            value(&signature.domain),
+            value(&signature.selector)
+        );
+        results.push(result);
+    }
+    if auth.dkim_signatures.is_empty() {
+        results.push(format!("dkim={}", auth.dkim.name()));
+    }
+    let mut dmarc = format!("dmarc={}", auth.dmarc.status.name());
+    if let Some(record) = &auth.dmarc.record {
+        dmarc += &format!(
+            " (p={} dis={}",
+            record.policy.name(),
+            auth.dmarc.disposition.name()
+        );
+        if let Some(sealer) = &auth.dmarc.arc_override {
+            dmarc += &format!(" arc.sealer={sealer}");
+        }
+        dmarc += ")";
+    }
+    if !auth.header_domain.is_empty() {
+        dmarc += &format!(" header.from={}", value(&auth.header_domain));
+    }
+    results.push(dmarc);
+    let mut arc = format!("arc={}", auth.arc.status.name());
+    if let Some(newest) = auth.arc.sets.last() {
+        arc += &format!(" (i={} d={})", newest.instance, newest.sealer);
+    }
+    results.push(arc);
+    format!("{authserv_id};\r\n\t{}", results.join(";\r\n\t"))
+}
+
+///removes the Authentication-Results fields that claim to be ours, anyone
+///else's are left alone (rfc 8601 5)
+pub fn strip_authentication_results(data: &[u8], authserv_id: &str) -> Vec<u8> {
+    let (fields, _) = split_message(data);
+    let headers_end = fields.iter().map(|field| field.raw.len()).sum::<usize>();
+    let mut out = Vec::with_capacity(data.len());
+    for field in &fields {
+        let spoofed = field.name().eq_ignore_ascii_case("Authentication-Results")
+            && claimed_id(field.raw).is_some_and(|id| id.eq_ignore_ascii_case(authserv_id));
+        if spoofed {
+            tracing::info!("removing a forged Authentication-Results");
+            continue;
+        }
+        out.extend_from_slice(field.raw);
+    }
+    out.extend_from_slice(&data[headers_end..]);
+    out
+}
+
+///the authserv-id at the start of an Authentication-Results field
+fn claimed_id(raw: &[u8]) -> Option<String> {
+    let raw = String::from_utf8_lossy(raw);
+    let (_, value) = raw.split_once(':')?;
+    let mut rest = value.trim_start();
+    //skip comments in front of it
+    while let Some(comment) = rest.strip_prefix('(') {
+        rest = comment.split_once(')')?.1.trim_start();
+    }
+    let end = rest
+        .find(|c: char| c == ';' || c == '(' || c.is_whitespace())
+        .unwrap_or(rest.len());
+    Some(rest[..end].to_string())
+}
+
+///starts a message with `headers`
+pub fn prepend(data: &[u8], headers: &[String]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + headers.iter().map(String::len).sum::<usize>());
+    for header in headers {
+        out.extend_from_slice(header.as_bytes());
+    }
+    out.extend_from_slice(data);
+    out
+}
+
+fn address(path: &str) -> &str {
+    path.trim().trim_start_matches('<').trim_end_matches('>')
+}
+
+///a property value, quoted unless it's a plain token
+fn value(v: &str) -> String {
+    //an address is allowed as is, so @ is fine
+    let token = !v.is_empty()
+        && v.chars()
+            .all(|c| c.is_ascii_graphic() && !"()<>,;:\\\"[]?=".contains(c));
+    if token {
+        v.to_string()
+    } else {
+        quoted(v)
+    }
+}
+
+fn quoted(v: &str) -> String {
+    let escaped = v
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"");
+    format!("\"{escaped}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::dkim::arc::{ArcResult, ArcSet};
+    use crate::dkim::verify::DkimResult;
+    use crate::dmarc::DmarcResult;
+    use crate::email_auth::AuthStatus;
+
+    #[test]
+    fn test_received() {
+        let mut session = Session {
+            peer_ip: "192.0.2.1".parse::<IpAddr>().unwrap(),
+            helo: "client.example",
+            hostname: "mx.kaki.foo",
+            esmtp: true,
+            tls: Some((
+                "TLSv1.3".to_string(),
+                "TLS13_AES_256_GCM_SHA384".to_string(),
+            )),
+        };
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let header = received(&session, &["<user@kaki.foo>".to_string()], now);
+        assert_eq!(
+            header,
+            "Received: from client.example ([192.0.2.1])\r\n\tby mx.kaki.foo (kakimail) with ESMTPS\r\n\
+             \t(using TLSv1.3 with cipher TLS13_AES_256_GCM_SHA384)\r\n\tfor <user@kaki.foo>;\r\n\
+             \tFri, 1 Mar 2024 12:00:00 +0000\r\n"
+        );
+        session.peer_ip = "2001:db8::1".parse().unwrap();
+        session.helo = "evil (forged)";
+        session.esmtp = false;
+        session.tls = None;
+        let header = received(
+            &session,
+            &["<a@kaki.foo>".into(), "<b@kaki.foo>".into()],
+            now,
+        );
+        assert!(header.starts_with(
+            "Received: from evilforged ([IPv6:2001:db8::1])\r\n\tby mx.kaki.foo (kakimail) with SMTP;"
+        ));
+        assert!(!header.contains("\tfor "));
+    }
+
+    #[test]
+    fn test_authentication_results() {
+        let mut auth = IncomingAuthResult {
+            spf: AuthStatus::Pass,
+            spf_helo: AuthStatus::None,
+            dkim: AuthStatus::Pass,
+            dkim_signatures: vec![
+                DkimResult {
+                    status: AuthStatus::Pass,
+                    domain: "example.org".to_string(),
+                    selector: "sel".to_string(),
+                    reason: None,
+                },
+                DkimResult {
+                    status: AuthStatus::Fail,
+                    domain: "list.example".to_string(),
+                    selector: "s1".to_string(),
+                    reason: Some("body hash \"bh\" mismatch".to_string()),
+                },
+            ],
+            dmarc: DmarcResult::new(AuthStatus::Pass),
+            arc: ArcResult::new(AuthStatus::None),
+            envelope_domain: "example.org".to_string(),
+            header_domain: "example.org".to_string(),
+        };
+        let header = authentication_results("mx.kaki.foo", &auth, "<alice@example.org>", "");
+        assert_eq!(
+            header,
+            "Authentication-Results: mx.kaki.foo;\r\n\tspf=pass smtp.mailfrom=alice@example.org;\r\n\
+             \tdkim=pass header.d=example.org header.s=sel;\r\n\
+             \tdkim=fail reason=\"body hash \\\"bh\\\" mismatch\" header.d=list.example header.s=s1;\r\n\
+             \tdmarc=pass header.from=example.org;\r\n\
+             \tarc=none\r\n"
+        );
+        auth.dkim_signatures.clear();
+        auth.dkim = AuthStatus::None;
+        auth.spf = AuthStatus::SoftFail;
+        let header = authentication_results("mx.kaki.foo", &auth, "<>", "client.example");
+        assert!(header.contains("spf=softfail smtp.helo=client.example;"));
+        assert!(header.contains("dkim=none;"));
+        auth.arc.status = AuthStatus::Pass;
+        auth.arc.sets.push(ArcSet {
+            instance: 1,
+            sealer: "lists.example".to_string(),
+            results: "lists.example; dmarc=pass".to_string(),
+        });
+        let header = authentication_results("mx.kaki.foo", &auth, "<>", "client.example");
+        assert!(header.ends_with("\tarc=pass (i=1 d=lists.example)\r\n"));
+        let parsed = mailparse::parse_header(header.as_bytes()).unwrap().0;
+        assert_eq!(parsed.get_key(), "Authentication-Results");
+    }
+
+    #[test]
+    fn test_strip_authentication_results() {
+        let data = b"Authentication-Results: MX.kaki.foo; spf=pass\r\n\
+            Authentication-Results: (forged) mx.kaki.foo;\r\n\tdkim=pass\r\n\
+            Authentication-Results: mx.example.com; spf=fail\r\n\
+            Subject: Authentication-Results: mx.kaki.foo;\r\n\
+            \r\n\
+            Authentication-Results: mx.kaki.foo; in the body\r\n";
+        let stripped = strip_authentication_results(data, "mx.kaki.foo");
+        assert_eq!(
+            stripped,
+            b"Authentication-Results: mx.example.com; spf=fail\r\n\
+              Subject: Authentication-Results: mx.kaki.foo;\r\n\
+              \r\n\
+              Authentication-Results: mx.kaki.foo; in the body\r\n"
+        );
+        let prepended = prepend(&stripped, &["A: 1\r\n".to_string(), "B: 2\r\n".to_string()]);
+        assert!(prepended.starts_with(b"A: 1\r\nB: 2\r\nAuthentication-Results: mx.example.com"));
+    }
+}